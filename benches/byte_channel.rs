@@ -0,0 +1,82 @@
+//! Splitting a stream of decompressed bytes into lines, one byte at a time versus one chunk at a time
+
+use std::io::Write;
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use logsplitter2::byte_channel::{self, BytesRx, TryRecv};
+
+/// About as much as one write of the gzip decoder
+const CHUNK_SIZE: usize = 32 * 1024;
+
+fn input() -> Vec<u8> {
+    (0..20_000)
+        .map(|i| {
+            format!(
+                r#"{{"message":"request {i} handled","@timestamp":"2024-10-20T12:00:00Z","@meta":{{"service":"s{}","env":"prod"}}}}"#,
+                i % 13
+            ) + "\n"
+        })
+        .collect::<String>()
+        .into_bytes()
+}
+
+/// A receiver with all of `input` already sent, in decoder-sized chunks
+fn filled(input: &[u8]) -> BytesRx {
+    let (mut tx, rx) = byte_channel::bounded(input.len());
+    for chunk in input.chunks(CHUNK_SIZE) {
+        tx.write_all(chunk).unwrap();
+    }
+    rx
+}
+
+/// How `read_input` splits lines
+fn split_bytes(mut rx: BytesRx) -> usize {
+    let mut lines = 0;
+    let mut curr_line = String::new();
+    while let TryRecv::Ready(b) = rx.try_recv() {
+        curr_line.push(b as char);
+        if b == b'\n' {
+            lines += 1;
+            curr_line = String::new();
+        }
+    }
+    lines
+}
+
+fn split_chunks(mut rx: BytesRx) -> usize {
+    let mut lines = 0;
+    let mut curr_line = Vec::new();
+    while let TryRecv::Ready(chunk) = rx.try_recv_chunk() {
+        match memchr::memchr(b'\n', chunk) {
+            Some(i) => {
+                curr_line.extend_from_slice(&chunk[..=i]);
+                let tail = chunk.len() - i - 1;
+                rx.push_back(tail);
+                lines += 1;
+                curr_line = Vec::new();
+            }
+            None => curr_line.extend_from_slice(chunk),
+        }
+    }
+    lines
+}
+
+fn bench_split_lines(c: &mut Criterion) {
+    let input = input();
+    let lines = input.iter().filter(|&&b| b == b'\n').count();
+    assert_eq!(split_bytes(filled(&input)), lines);
+    assert_eq!(split_chunks(filled(&input)), lines);
+
+    let mut group = c.benchmark_group("split_lines");
+    group.throughput(Throughput::Bytes(input.len() as u64));
+    group.bench_function("try_recv", |b| {
+        b.iter_batched(|| filled(&input), split_bytes, BatchSize::LargeInput)
+    });
+    group.bench_function("try_recv_chunk", |b| {
+        b.iter_batched(|| filled(&input), split_chunks, BatchSize::LargeInput)
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_split_lines);
+criterion_main!(benches);
@@ -0,0 +1,82 @@
+//! Compressing generated lines, whose messages are random-ish, versus highly repetitive lines,
+//! with each [`DeflateStrategy`]
+
+use std::io::Write;
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use flate2::Compression;
+use logsplitter2::{
+    deflate::{DeflateStrategy, StrategyGzEncoder},
+    testdata_gen::{generate_testdata, TestdataCfg},
+};
+
+const LINES: usize = 50_000;
+
+const STRATEGIES: [(&str, DeflateStrategy); 5] = [
+    ("default", DeflateStrategy::Default),
+    ("filtered", DeflateStrategy::Filtered),
+    ("huffman_only", DeflateStrategy::HuffmanOnly),
+    ("rle", DeflateStrategy::Rle),
+    ("fixed", DeflateStrategy::Fixed),
+];
+
+fn generated() -> Vec<u8> {
+    let mut plain = vec![];
+    generate_testdata(
+        TestdataCfg {
+            lines: LINES,
+            seed: Some(0),
+            ..Default::default()
+        },
+        &mut std::io::sink(),
+        Some(&mut plain),
+    )
+    .unwrap();
+    plain
+}
+
+/// The same few lines over and over, with long runs of padding
+fn repetitive() -> Vec<u8> {
+    (0..LINES)
+        .map(|i| {
+            format!(
+                r#"{{"service":"api","env":"prod","timestamp":"2024-10-20T00:00:00Z","message":"health check ok{}"}}"#,
+                " ".repeat(i % 4 * 16)
+            ) + "\n"
+        })
+        .collect::<String>()
+        .into_bytes()
+}
+
+fn compress(input: &[u8], strategy: DeflateStrategy) -> Vec<u8> {
+    let mut enc = StrategyGzEncoder::new(vec![], "out.json", 0, Compression::default(), strategy);
+    // In pieces, like the output threads write lines
+    for chunk in input.chunks(4096) {
+        enc.write_all(chunk).unwrap();
+    }
+    enc.try_finish().unwrap();
+    enc.get_ref().clone()
+}
+
+fn bench_deflate_strategy(c: &mut Criterion) {
+    for (data_name, input) in [("generated", generated()), ("repetitive", repetitive())] {
+        for (name, strategy) in STRATEGIES {
+            eprintln!(
+                "{data_name}/{name}: {} bytes compressed to {}",
+                input.len(),
+                compress(&input, strategy).len()
+            );
+        }
+
+        let mut group = c.benchmark_group(format!("deflate_strategy_{data_name}"));
+        group.sample_size(10);
+        group.throughput(Throughput::Bytes(input.len() as u64));
+        for (name, strategy) in STRATEGIES {
+            group.bench_function(name, |b| b.iter(|| compress(&input, strategy)));
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, bench_deflate_strategy);
+criterion_main!(benches);
@@ -0,0 +1,95 @@
+//! Taking and giving back files of a [`FilePool`] which has many more keys than it can keep open
+
+use std::{io, path::Path, path::PathBuf};
+
+use chrono::NaiveDate;
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use logsplitter2::{
+    data::MsgKey,
+    file_pool::{ExistingFilePolicy, FileBackend, FilePool},
+};
+
+const MAX_OPEN_FILES: usize = 1000;
+const ROUNDS: usize = 5;
+
+/// A backend whose operations all succeed without doing anything, so only the pool's own bookkeeping is measured
+#[derive(Clone, Copy, Default)]
+struct NullBackend;
+
+impl FileBackend for NullBackend {
+    type File = ();
+
+    async fn create(&self, _path: &Path) -> io::Result<()> {
+        Ok(())
+    }
+    async fn open(&self, _path: &Path) -> io::Result<()> {
+        Ok(())
+    }
+    async fn file_len(&self, _path: &Path) -> io::Result<u64> {
+        Ok(0)
+    }
+    async fn remove(&self, _path: &Path) -> io::Result<()> {
+        Ok(())
+    }
+    async fn write_at(&self, _file: &(), buf: Vec<u8>, _pos: u64) -> (io::Result<usize>, Vec<u8>) {
+        (Ok(buf.len()), buf)
+    }
+    async fn sync(&self, _file: &()) -> io::Result<()> {
+        Ok(())
+    }
+    async fn close(&self, _file: ()) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn keys() -> Vec<MsgKey> {
+    let date = NaiveDate::from_ymd_opt(2024, 10, 20).unwrap();
+    (0..2 * MAX_OPEN_FILES)
+        .map(|i| MsgKey::new(&format!("s{i}"), date))
+        .collect()
+}
+
+/// Takes and gives back every key of `order`
+fn run(keys: &[MsgKey], order: &[usize]) {
+    let mut pool = FilePool::with_backend(
+        MAX_OPEN_FILES,
+        PathBuf::from("/out"),
+        ExistingFilePolicy::Truncate,
+        NullBackend,
+    );
+    tokio_uring::start(async {
+        for &i in order {
+            let f = pool.take(keys[i].clone()).await.unwrap();
+            pool.give(keys[i].clone(), f).await.unwrap();
+        }
+        assert!(pool.finish().await.is_empty());
+    });
+}
+
+fn bench_take_give(c: &mut Criterion) {
+    let keys = keys();
+    // Round robin, so every take has to evict the least recently given file
+    let cycle = (0..ROUNDS * keys.len())
+        .map(|i| i % keys.len())
+        .collect::<Vec<_>>();
+    // About half of the takes find the file still open, somewhere in the middle of the queue
+    let mut state = 0x2545_f491_4f6c_dd1d_u64;
+    let random = (0..ROUNDS * keys.len())
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state % keys.len() as u64) as usize
+        })
+        .collect::<Vec<_>>();
+
+    let mut group = c.benchmark_group("file_pool");
+    group.throughput(Throughput::Elements((ROUNDS * keys.len()) as u64));
+    for (name, order) in [("cycle", &cycle), ("random", &random)] {
+        group.bench_function(name, |b| b.iter(|| run(&keys, order)));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_take_give);
+criterion_main!(benches);
@@ -0,0 +1,80 @@
+//! Parsing lines with a filter which drops 90% of them by service, which is checked before the lines' timestamps are parsed,
+//! versus parsing every line without a filter and with a filter on the date (which needs every timestamp)
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use logsplitter2::{
+    data::LineData,
+    filter::LineFilter,
+    testdata_gen::{generate_testdata, TestdataCfg},
+};
+
+const LINES: usize = 100_000;
+
+fn generated() -> Vec<String> {
+    let mut cfg = TestdataCfg {
+        lines: LINES,
+        seed: Some(0),
+        ..Default::default()
+    };
+    cfg.set_unique_dates(1)
+        .set_services(10, 3..6)
+        .set_envs(2, 3..6);
+    let mut plain = vec![];
+    generate_testdata(cfg, &mut std::io::sink(), Some(&mut plain)).unwrap();
+    String::from_utf8(plain)
+        .unwrap()
+        .lines()
+        .map(|l| l.to_string() + "\n")
+        .collect()
+}
+
+fn bench_filtered_parse(c: &mut Criterion) {
+    let lines = generated();
+    let first = LineData::parse(lines[0].clone()).unwrap();
+    let service = json::parse(first.original_line_text()).unwrap()["@meta"]["service"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    let date = first.key().date().format("%Y-%m-%d");
+    let filter =
+        |terms: &[String]| LineFilter::new(terms.iter().map(|t| t.parse().unwrap()).collect());
+    let filters = [
+        ("no_filter", LineFilter::default()),
+        ("service_filter", filter(&[format!("service={service}")])),
+        ("date_filter", filter(&[format!("date={date}")])),
+    ];
+
+    let mut group = c.benchmark_group("filtered_parse");
+    group.throughput(Throughput::Elements(LINES as u64));
+    for (name, filter) in filters {
+        let kept = lines
+            .iter()
+            .filter(|l| {
+                LineData::parse_filtered(l.to_string(), &filter)
+                    .unwrap()
+                    .is_some()
+            })
+            .count();
+        eprintln!(
+            "{name} keeps {:.1}% of the lines",
+            kept as f64 * 100.0 / LINES as f64
+        );
+
+        group.bench_function(name, |b| {
+            b.iter_batched(
+                || lines.clone(),
+                |lines| {
+                    lines
+                        .into_iter()
+                        .filter_map(|l| LineData::parse_filtered(l, &filter).unwrap())
+                        .count()
+                },
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_filtered_parse);
+criterion_main!(benches);
@@ -0,0 +1,67 @@
+//! Reading and decoding one big generated input with `io_uring` reads, and with the input memory-mapped,
+//! in bytes of the compressed input per second.
+//!
+//! The input has `INPUT_MMAP_BENCH_LINES` lines (10 million by default, which is about 2 GB before compression),
+//! and is generated once before measuring, which takes a while.
+//! Needs the `bench-internals` feature, for [`JsonLinesRecv::into_raw_lines`]
+
+use std::{fs::File, io::BufWriter, path::PathBuf};
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use logsplitter2::{
+    input::JsonLinesRecv,
+    testdata_gen::{generate_testdata, TestdataCfg},
+};
+use tempdir::TempDir;
+
+fn bench_input_mmap(c: &mut Criterion) {
+    let lines = std::env::var("INPUT_MMAP_BENCH_LINES")
+        .map(|l| {
+            l.parse()
+                .expect("INPUT_MMAP_BENCH_LINES should be a number")
+        })
+        .unwrap_or(10_000_000);
+    let input_dir = TempDir::new("input_mmap").unwrap();
+    let input = input_dir.path().join("input.json.gz");
+    let mut cfg = TestdataCfg {
+        lines,
+        gzip_members: 16,
+        gen_threads: std::thread::available_parallelism().map_or(1, |n| n.get()),
+        seed: Some(0),
+        ..Default::default()
+    };
+    cfg.set_unique_dates(10)
+        .set_services(10, 4..8)
+        .set_envs(3, 3..6);
+    generate_testdata(
+        cfg,
+        &mut BufWriter::new(File::create(&input).unwrap()),
+        None,
+    )
+    .unwrap();
+
+    let read = |input: &PathBuf, mmap| {
+        let mut read = 0;
+        let recv = JsonLinesRecv::spawn_files_with(
+            vec![input.clone()],
+            Default::default(),
+            Default::default(),
+            mmap,
+        );
+        for l in recv.into_raw_lines() {
+            l.unwrap();
+            read += 1;
+        }
+        assert_eq!(read, lines);
+    };
+
+    let mut group = c.benchmark_group("input_mmap");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(input.metadata().unwrap().len()));
+    group.bench_function("uring", |b| b.iter(|| read(&input, false)));
+    group.bench_function("mmap", |b| b.iter(|| read(&input, true)));
+    group.finish();
+}
+
+criterion_group!(benches, bench_input_mmap);
+criterion_main!(benches);
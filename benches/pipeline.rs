@@ -0,0 +1,120 @@
+//! Each stage of splitting generated input on its own, and all of them together, in lines per second:
+//! reading and decoding the input, parsing lines, and splitting end-to-end with 1 and 8 output threads,
+//! as well as with far more keys than the output threads can keep open.
+//!
+//! Needs the `bench-internals` feature, for [`JsonLinesRecv::into_raw_lines`]
+
+use std::{
+    fs::File,
+    io::BufWriter,
+    path::{Path, PathBuf},
+};
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use logsplitter2::{
+    data::LineData,
+    file_pool::ExistingFilePolicy,
+    input::JsonLinesRecv,
+    testdata_gen::{generate_testdata, TestdataCfg},
+    OutputTarget, RunCfg, Threads,
+};
+use tempdir::TempDir;
+
+const LINES: usize = 200_000;
+
+/// Writes an input of `services` services to `dir`, returning its path and its plain lines
+fn write_input(dir: &Path, name: &str, services: usize) -> (PathBuf, Vec<String>) {
+    let mut cfg = TestdataCfg {
+        lines: LINES,
+        seed: Some(0),
+        ..Default::default()
+    };
+    cfg.set_unique_dates(10)
+        .set_services(services, 4..8)
+        .set_envs(3, 3..6);
+
+    let path = dir.join(format!("{name}.json.gz"));
+    let mut plain = vec![];
+    generate_testdata(
+        cfg,
+        &mut BufWriter::new(File::create(&path).unwrap()),
+        Some(&mut plain),
+    )
+    .unwrap();
+    let lines = String::from_utf8(plain)
+        .unwrap()
+        .lines()
+        .map(|l| format!("{l}\n"))
+        .collect();
+    (path, lines)
+}
+
+/// Splits `input` into a new directory, which is returned so that deleting it isn't measured
+fn split(input: &Path, threads: usize, max_active_files: usize) -> TempDir {
+    let output_dir = TempDir::new("pipeline_out").unwrap();
+    logsplitter2::run(RunCfg {
+        input_files: vec![input.to_owned()],
+        output: OutputTarget::Dir(output_dir.path().to_owned()),
+        output_threads: Threads::Fixed(threads),
+        max_active_files,
+        existing_files: Some(ExistingFilePolicy::Truncate),
+        ..Default::default()
+    })
+    .unwrap();
+    output_dir
+}
+
+fn bench_pipeline(c: &mut Criterion) {
+    let input_dir = TempDir::new("pipeline").unwrap();
+    // About 300 keys, which 8 threads can keep open
+    let (input, lines) = write_input(input_dir.path(), "input", 10);
+    // About 30000 keys
+    let (wide_input, _) = write_input(input_dir.path(), "wide", 1000);
+
+    let mut group = c.benchmark_group("pipeline");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(LINES as u64));
+
+    group.bench_function("read_decode", |b| {
+        b.iter(|| {
+            let mut read = 0;
+            for l in JsonLinesRecv::spawn_files(vec![input.clone()]).into_raw_lines() {
+                l.unwrap();
+                read += 1;
+            }
+            assert_eq!(read, LINES);
+        })
+    });
+    group.bench_function("parse", |b| {
+        b.iter_batched(
+            || lines.clone(),
+            |lines| {
+                for l in lines {
+                    LineData::parse(l).unwrap();
+                }
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    for threads in [1, 8] {
+        group.bench_function(format!("split_{threads}_threads"), |b| {
+            b.iter_batched(
+                || (),
+                |()| split(&input, threads, 64),
+                BatchSize::PerIteration,
+            )
+        });
+    }
+    // Every thread keeps 8 files open, so nearly every line closes a file to reopen another
+    group.bench_function("split_file_pool_thrash", |b| {
+        b.iter_batched(
+            || (),
+            |()| split(&wide_input, 4, 32),
+            BatchSize::PerIteration,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_pipeline);
+criterion_main!(benches);
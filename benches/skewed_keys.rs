@@ -0,0 +1,109 @@
+//! Splitting input where a few services have most of the lines (see [`KeyDistribution::Zipf`]),
+//! with keys assigned to output threads round-robin versus by [`RunCfg::balance_threads`]
+
+use std::{fs::File, io::BufWriter, path::Path};
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use logsplitter2::{
+    data::{LineData, MsgKeyMap},
+    file_pool::ExistingFilePolicy,
+    math_utils,
+    testdata_gen::{generate_testdata, KeyDistribution, TestdataCfg},
+    OutputTarget, RunCfg, Threads,
+};
+use tempdir::TempDir;
+
+const LINES: usize = 200_000;
+const THREADS: usize = 4;
+
+/// Writes the input to `dir`, returning its path and its plain lines
+fn input(dir: &Path) -> (std::path::PathBuf, String) {
+    let mut cfg = TestdataCfg {
+        lines: LINES,
+        key_distribution: KeyDistribution::Zipf { exponent: 1.2 },
+        seed: Some(0),
+        ..Default::default()
+    };
+    // A single date, so that each service is a single key
+    cfg.set_unique_dates(1)
+        .set_services(200, 3..6)
+        .set_envs(1, 4..5);
+
+    let path = dir.join("input.json.gz");
+    let mut plain = vec![];
+    generate_testdata(
+        cfg,
+        &mut BufWriter::new(File::create(&path).unwrap()),
+        Some(&mut plain),
+    )
+    .unwrap();
+    (path, String::from_utf8(plain).unwrap())
+}
+
+/// Prints how uneven the output threads' loads are when keys are assigned round-robin as they first show up,
+/// and when they're assigned by [`math_utils::partition_items`]
+fn print_imbalance(plain: &str) {
+    let mut sizes = MsgKeyMap::<u64>::default();
+    let mut first_seen = vec![];
+    for line in plain.lines() {
+        let line = LineData::parse(line.to_owned()).unwrap();
+        let size = sizes.entry(line.key().clone()).or_insert_with(|| {
+            first_seen.push(line.key().clone());
+            0
+        });
+        *size += line.original_line_text().len() as u64;
+    }
+
+    let mut round_robin = vec![0; THREADS];
+    for (i, key) in first_seen.iter().enumerate() {
+        round_robin[i % THREADS] += sizes[key];
+    }
+    let items = sizes.into_iter().collect::<Vec<_>>();
+    let balanced = math_utils::partition_items(&items, THREADS)
+        .iter()
+        .map(|keys| {
+            keys.iter()
+                .map(|k| items.iter().find(|(key, _)| key == k).unwrap().1)
+                .sum()
+        })
+        .collect::<Vec<_>>();
+    eprintln!(
+        "Busiest of {THREADS} output threads has {:.2}x the average load round-robin, {:.2}x balanced",
+        math_utils::imbalance_ratio(&round_robin),
+        math_utils::imbalance_ratio(&balanced)
+    );
+}
+
+fn bench_skewed_keys(c: &mut Criterion) {
+    let input_dir = TempDir::new("skewed_keys").unwrap();
+    let (input, plain) = input(input_dir.path());
+    print_imbalance(&plain);
+
+    let mut group = c.benchmark_group("skewed_keys");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(plain.len() as u64));
+    for (name, balance_threads) in [("round_robin", false), ("balanced", true)] {
+        group.bench_function(name, |b| {
+            b.iter_batched(
+                || TempDir::new("skewed_keys_out").unwrap(),
+                |output_dir| {
+                    logsplitter2::run(RunCfg {
+                        input_files: vec![input.clone()],
+                        output: OutputTarget::Dir(output_dir.path().to_owned()),
+                        output_threads: Threads::Fixed(THREADS),
+                        existing_files: Some(ExistingFilePolicy::Truncate),
+                        balance_threads,
+                        ..Default::default()
+                    })
+                    .unwrap();
+                    output_dir
+                },
+                BatchSize::PerIteration,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_skewed_keys);
+criterion_main!(benches);
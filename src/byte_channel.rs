@@ -1,30 +1,144 @@
-use std::io::Write;
+//! A channel of bytes, with a [`Write`] sending end for encoders to write into
+
+use std::{
+    io::{Read, Write},
+    sync::{Arc, Condvar, Mutex},
+};
 
 use kanal::{Receiver, Sender};
 
-/// The supplied bound is how many chunks of bytes can be buffered at once
-pub fn bounded(bound: usize) -> (BytesTx, BytesRx) {
-    let (tx, rx) = kanal::bounded(bound);
+/// What [`BytesTx::write`] does when sending would go over the channel's byte budget
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WhenFull {
+    /// Wait for the receiver to consume enough. Only for receivers on a different thread than the sender
+    #[default]
+    Block,
+    /// Send what fits, or fail with [`WouldBlock`](std::io::ErrorKind::WouldBlock) if nothing does.
+    /// For receivers drained by the same thread that writes, where blocking would never end
+    Error,
+}
+
+/// Bytes which have been sent, but not yet consumed by the receiver
+#[derive(Debug)]
+struct Budget {
+    limit: usize,
+    state: Mutex<BudgetState>,
+    released: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct BudgetState {
+    in_flight: usize,
+    rx_closed: bool,
+}
+
+impl Budget {
+    fn release(&self, n: usize) {
+        if n == 0 {
+            return;
+        }
+        self.state.lock().unwrap().in_flight -= n;
+        self.released.notify_all();
+    }
+}
+
+/// A channel which holds at most `budget` bytes which the receiver hasn't consumed yet,
+/// blocking the sender when it's full
+pub fn bounded(budget: usize) -> (BytesTx, BytesRx) {
+    bounded_with(budget, WhenFull::Block)
+}
+
+/// Like [`bounded`], with a choice of what happens when the channel is full
+pub fn bounded_with(budget: usize, when_full: WhenFull) -> (BytesTx, BytesRx) {
+    assert!(
+        budget > 0,
+        "A byte channel needs a budget of at least one byte"
+    );
+    let budget = Arc::new(Budget {
+        limit: budget,
+        state: Mutex::default(),
+        released: Condvar::new(),
+    });
+    // The budget bounds the channel instead
+    let (tx, rx) = kanal::unbounded();
     (
-        BytesTx { tx },
+        BytesTx {
+            tx,
+            budget: budget.clone(),
+            when_full,
+        },
         BytesRx {
             rx,
+            budget,
             buffered: vec![],
             buffered_idx: 0,
+            charged: 0,
         },
     )
 }
 
+fn receiver_closed() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::BrokenPipe,
+        "The receiving end of the byte channel was closed",
+    )
+}
+
 pub struct BytesTx {
     tx: Sender<Vec<u8>>,
+    budget: Arc<Budget>,
+    when_full: WhenFull,
+}
+
+impl BytesTx {
+    /// How many bytes have been sent but not consumed yet
+    pub fn in_flight(&self) -> usize {
+        self.budget.state.lock().unwrap().in_flight
+    }
+
+    /// Takes `n` bytes out of the budget, or returns `false` if they don't fit and the channel doesn't block
+    fn reserve(&self, n: usize) -> std::io::Result<bool> {
+        let budget = &self.budget;
+        let mut state = budget.state.lock().unwrap();
+        loop {
+            if state.rx_closed {
+                return Err(receiver_closed());
+            }
+            if state.in_flight + n <= budget.limit {
+                state.in_flight += n;
+                return Ok(true);
+            }
+            match self.when_full {
+                WhenFull::Block => state = budget.released.wait(state).unwrap(),
+                WhenFull::Error => return Ok(false),
+            }
+        }
+    }
 }
 
 impl Write for BytesTx {
+    /// Writes larger than the budget are split into chunks which each fit it.
+    ///
+    /// Fails with [`BrokenPipe`](std::io::ErrorKind::BrokenPipe) once the receiver is gone
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        self.tx
-            .send(buf.to_vec())
-            .expect("Receiver closed earlier than expected!");
-        Ok(buf.len())
+        let mut sent = 0;
+        for chunk in buf.chunks(self.budget.limit) {
+            if !self.reserve(chunk.len())? {
+                break;
+            }
+            if self.tx.send(chunk.to_vec()).is_err() {
+                self.budget.release(chunk.len());
+                return Err(receiver_closed());
+            }
+            sent += chunk.len();
+        }
+        match sent {
+            0 if !buf.is_empty() => Err(std::io::Error::new(
+                std::io::ErrorKind::WouldBlock,
+                "The byte channel is full",
+            )),
+            _ => Ok(sent),
+        }
     }
 
     fn flush(&mut self) -> std::io::Result<()> {
@@ -32,28 +146,456 @@ impl Write for BytesTx {
     }
 }
 
+/// What [`BytesRx::try_recv`] and [`BytesRx::try_recv_chunk`] found
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecv<T> {
+    Ready(T),
+    /// Nothing has been sent yet, but more may be
+    Empty,
+    /// The sender has been dropped, and everything it sent has been received
+    Closed,
+}
+
+impl<T> TryRecv<T> {
+    pub fn ready(self) -> Option<T> {
+        match self {
+            Self::Ready(t) => Some(t),
+            Self::Empty | Self::Closed => None,
+        }
+    }
+}
+
 pub struct BytesRx {
     rx: Receiver<Vec<u8>>,
+    budget: Arc<Budget>,
     buffered: Vec<u8>,
     buffered_idx: usize,
+    /// How much of the budget `buffered` still holds,
+    /// which is released once it's all consumed and the next chunk is asked for
+    charged: usize,
 }
 
 impl BytesRx {
-    #[track_caller]
-    pub fn try_recv(&mut self) -> Option<u8> {
-        if self.buffered_idx >= self.buffered.len() {
+    fn set_buffered(&mut self, new_buf: Vec<u8>) {
+        self.buffered_idx = 0;
+        self.charged = new_buf.len();
+        self.buffered = new_buf;
+    }
+
+    /// Called once the current chunk is used up. Releasing only then keeps locking off the path of every byte
+    fn release_consumed(&mut self) {
+        self.budget.release(std::mem::take(&mut self.charged));
+    }
+
+    /// Makes sure there are bytes left in the current chunk, receiving a new one if needed
+    fn fill(&mut self) -> TryRecv<()> {
+        while self.buffered_idx >= self.buffered.len() {
+            self.release_consumed();
             match self.rx.try_recv() {
-                Ok(Some(new_buf)) => {
-                    self.buffered_idx = 0;
-                    self.buffered = new_buf;
-                }
-                Ok(None) => return None,
-                Err(_) => panic!("Closed unexpectedly!"),
+                Ok(Some(new_buf)) => self.set_buffered(new_buf),
+                Ok(None) => return TryRecv::Empty,
+                Err(_) => return TryRecv::Closed,
+            }
+        }
+        TryRecv::Ready(())
+    }
+
+    /// Receives a single byte without blocking
+    pub fn try_recv(&mut self) -> TryRecv<u8> {
+        match self.fill() {
+            TryRecv::Ready(()) => {}
+            TryRecv::Empty => return TryRecv::Empty,
+            TryRecv::Closed => return TryRecv::Closed,
+        }
+
+        let b = self.buffered[self.buffered_idx];
+        self.buffered_idx += 1;
+        TryRecv::Ready(b)
+    }
+
+    /// Like [`try_recv`](BytesRx::try_recv), but returns every byte left of the current chunk at once,
+    /// or the whole next chunk if the current one is used up.
+    ///
+    /// Bytes which the caller doesn't use can be handed back with [`push_back`](BytesRx::push_back)
+    pub fn try_recv_chunk(&mut self) -> TryRecv<&[u8]> {
+        match self.fill() {
+            TryRecv::Ready(()) => {}
+            TryRecv::Empty => return TryRecv::Empty,
+            TryRecv::Closed => return TryRecv::Closed,
+        }
+
+        let chunk = &self.buffered[self.buffered_idx..];
+        self.buffered_idx = self.buffered.len();
+        TryRecv::Ready(chunk)
+    }
+
+    /// Like [`fill`](BytesRx::fill), but awaits the next chunk instead of returning [`TryRecv::Empty`].
+    ///
+    /// Returns `false` once the channel is [closed](TryRecv::Closed)
+    async fn fill_async(&mut self) -> bool {
+        while self.buffered_idx >= self.buffered.len() {
+            self.release_consumed();
+            match self.rx.as_async().recv().await {
+                Ok(new_buf) => self.set_buffered(new_buf),
+                Err(_) => return false,
             }
         }
+        true
+    }
 
+    /// Awaits a single byte, without blocking the runtime it's polled on.
+    ///
+    /// Returns `None` once the sender has been dropped and everything it sent has been received.
+    /// The sender must be on a different thread, since [`BytesTx::write`] is always synchronous
+    pub async fn recv(&mut self) -> Option<u8> {
+        if !self.fill_async().await {
+            return None;
+        }
         let b = self.buffered[self.buffered_idx];
         self.buffered_idx += 1;
         Some(b)
     }
+
+    /// Like [`recv`](BytesRx::recv), but returns the rest of the current chunk, like [`try_recv_chunk`](BytesRx::try_recv_chunk)
+    pub async fn recv_chunk(&mut self) -> Option<&[u8]> {
+        if !self.fill_async().await {
+            return None;
+        }
+        let chunk = &self.buffered[self.buffered_idx..];
+        self.buffered_idx = self.buffered.len();
+        Some(chunk)
+    }
+
+    /// Un-receives the last `n` bytes of the chunk returned by [`try_recv_chunk`](BytesRx::try_recv_chunk)
+    /// (or the last `n` bytes from [`try_recv`](BytesRx::try_recv)), so that they're received again next.
+    ///
+    /// Panics if `n` is more than what has been received of the current chunk
+    #[track_caller]
+    pub fn push_back(&mut self, n: usize) {
+        self.buffered_idx = self
+            .buffered_idx
+            .checked_sub(n)
+            .expect("Pushed back more bytes than were received from the current chunk");
+        // If the chunk was already released, the bytes count against the budget again,
+        // without waiting for room since they were already sent
+        if n > 0 && self.charged == 0 {
+            self.budget.state.lock().unwrap().in_flight += n;
+            self.charged = n;
+        }
+    }
+}
+
+impl Read for BytesRx {
+    /// Blocks until a chunk is available, and returns `Ok(0)` once the sender has been dropped and every chunk has been read
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        while self.buffered_idx >= self.buffered.len() {
+            self.release_consumed();
+            match self.rx.recv() {
+                Ok(new_buf) => self.set_buffered(new_buf),
+                Err(_) => return Ok(0),
+            }
+        }
+
+        let available = &self.buffered[self.buffered_idx..];
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.buffered_idx += n;
+        Ok(n)
+    }
+}
+
+impl Drop for BytesRx {
+    /// Wakes up a blocked sender, so that it fails instead of waiting forever
+    fn drop(&mut self) {
+        self.budget.state.lock().unwrap().rx_closed = true;
+        self.budget.released.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+
+    use flate2::{write::GzEncoder, Compression};
+
+    use super::{bounded, bounded_with, TryRecv::*, WhenFull};
+
+    #[test]
+    fn test_mixed_byte_and_chunk_recv() {
+        let (mut tx, mut rx) = bounded(64);
+        assert_eq!(rx.try_recv_chunk(), Empty);
+        tx.write_all(b"ab\ncd").unwrap();
+        tx.write_all(b"").unwrap();
+        tx.write_all(b"ef\n").unwrap();
+
+        assert_eq!(rx.try_recv(), Ready(b'a'));
+        // The rest of the current chunk
+        let chunk = rx.try_recv_chunk().ready().unwrap();
+        assert_eq!(chunk, b"b\ncd");
+        // Only up to the newline is used
+        let tail = chunk.len() - 2;
+        rx.push_back(tail);
+        assert_eq!(rx.try_recv(), Ready(b'c'));
+        assert_eq!(rx.try_recv_chunk().ready().unwrap(), b"d");
+        // Empty chunks are skipped
+        assert_eq!(rx.try_recv_chunk().ready().unwrap(), b"ef\n");
+        rx.push_back(3);
+        let mut rest = vec![];
+        while let Ready(b) = rx.try_recv() {
+            rest.push(b);
+        }
+        assert_eq!(rest, b"ef\n");
+        assert_eq!(rx.try_recv_chunk(), Empty);
+
+        // Reads and chunks share the same buffer
+        tx.write_all(b"123456").unwrap();
+        let mut buf = [0; 2];
+        rx.read_exact(&mut buf).unwrap();
+        assert_eq!(rx.try_recv_chunk().ready().unwrap(), b"3456");
+        rx.push_back(1);
+        assert_eq!(rx.read(&mut buf).unwrap(), 1);
+        assert_eq!(buf[0], b'6');
+    }
+
+    #[test]
+    #[should_panic = "Pushed back more bytes"]
+    fn test_push_back_too_much() {
+        let (mut tx, mut rx) = bounded(64);
+        tx.write_all(b"ab").unwrap();
+        tx.write_all(b"cd").unwrap();
+        rx.try_recv_chunk().ready().unwrap();
+        rx.try_recv_chunk().ready().unwrap();
+        // Only the current chunk can be pushed back
+        rx.push_back(3);
+    }
+
+    #[test]
+    fn test_write_after_receiver_closed() {
+        let (mut tx, rx) = bounded(4);
+        tx.write_all(b"sent").unwrap();
+        drop(rx);
+        let e = tx.write(b"lost").unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::BrokenPipe);
+
+        // The error reaches whoever writes through an encoder, instead of panicking inside it
+        let (tx, rx) = bounded(4);
+        drop(rx);
+        let mut enc = GzEncoder::new(tx, Compression::default());
+        let result = enc
+            .write_all(&[b'x'; 64 * 1024])
+            .and_then(|()| enc.finish().map(drop));
+        assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn test_short_reads() {
+        let (mut tx, mut rx) = bounded(64);
+        tx.write_all(b"hello").unwrap();
+
+        let mut buf = [0; 2];
+        assert_eq!(rx.read(&mut buf).unwrap(), 2);
+        assert_eq!(&buf, b"he");
+        assert_eq!(rx.read(&mut buf).unwrap(), 2);
+        assert_eq!(&buf, b"ll");
+        // A read never spans two chunks
+        tx.write_all(b"world").unwrap();
+        assert_eq!(rx.read(&mut buf).unwrap(), 1);
+        assert_eq!(&buf[..1], b"o");
+        assert_eq!(rx.read(&mut buf).unwrap(), 2);
+        assert_eq!(&buf, b"wo");
+        assert_eq!(rx.read(&mut []).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_exact_fit_reads() {
+        let (mut tx, mut rx) = bounded(64);
+        tx.write_all(b"abc").unwrap();
+        tx.write_all(b"def").unwrap();
+
+        let mut buf = [0; 3];
+        assert_eq!(rx.read(&mut buf).unwrap(), 3);
+        assert_eq!(&buf, b"abc");
+        assert_eq!(rx.read(&mut buf).unwrap(), 3);
+        assert_eq!(&buf, b"def");
+    }
+
+    #[test]
+    fn test_read_after_close() {
+        let (mut tx, mut rx) = bounded(64);
+        tx.write_all(b"first ").unwrap();
+        tx.write_all(b"second").unwrap();
+        // Empty chunks don't look like the end of the stream
+        tx.write_all(b"").unwrap();
+        drop(tx);
+
+        // Chunks sent before closing are still read
+        let mut s = String::new();
+        rx.read_to_string(&mut s).unwrap();
+        assert_eq!(s, "first second");
+        assert_eq!(rx.read(&mut [0; 8]).unwrap(), 0);
+
+        // Reading from a different thread than the one writing
+        let (mut tx, mut rx) = bounded(1);
+        let writer = std::thread::spawn(move || {
+            for i in 0..100 {
+                write!(tx, "{i},").unwrap();
+            }
+        });
+        let mut s = String::new();
+        rx.read_to_string(&mut s).unwrap();
+        writer.join().unwrap();
+        let expected = (0..100).map(|i| format!("{i},")).collect::<String>();
+        assert_eq!(s, expected);
+    }
+
+    #[test]
+    fn test_budget_accounting() {
+        let (mut tx, mut rx) = bounded_with(8, WhenFull::Error);
+        // Writes over the budget are split, and only what fits is sent
+        assert_eq!(tx.write(b"0123456789").unwrap(), 8);
+        assert_eq!(tx.in_flight(), 8);
+        let e = tx.write(b"89").unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::WouldBlock);
+
+        // Budget is released once a whole chunk is consumed, and more is asked for
+        let mut buf = [0; 5];
+        rx.read_exact(&mut buf).unwrap();
+        rx.read_exact(&mut buf[..3]).unwrap();
+        assert_eq!(tx.in_flight(), 8);
+        assert_eq!(rx.try_recv(), Empty);
+        assert_eq!(tx.in_flight(), 0);
+
+        tx.write_all(b"89").unwrap();
+        tx.write_all(b"abc").unwrap();
+        assert_eq!(tx.in_flight(), 5);
+        assert_eq!(rx.try_recv_chunk().ready().unwrap(), b"89");
+        rx.push_back(1);
+        assert_eq!(rx.try_recv(), Ready(b'9'));
+        assert_eq!(rx.try_recv_chunk().ready().unwrap(), b"abc");
+        assert_eq!(tx.in_flight(), 3);
+        assert_eq!(rx.try_recv_chunk(), Empty);
+        assert_eq!(tx.in_flight(), 0);
+        // Pushed back bytes of a released chunk count again until they're consumed
+        rx.push_back(2);
+        assert_eq!(tx.in_flight(), 2);
+        assert_eq!(rx.try_recv_chunk().ready().unwrap(), b"bc");
+        assert_eq!(rx.try_recv_chunk(), Empty);
+        assert_eq!(tx.in_flight(), 0);
+    }
+
+    #[test]
+    fn test_blocking_budget() {
+        const BUDGET: usize = 100;
+        // Some writes are larger than the whole budget
+        let writes = (0..2000u32)
+            .map(|i| vec![(i % 251) as u8; (i as usize * 37) % 250])
+            .collect::<Vec<_>>();
+        let (mut tx, mut rx) = bounded(BUDGET);
+
+        let writer = std::thread::spawn({
+            let writes = writes.clone();
+            move || {
+                let mut max_in_flight = 0;
+                for w in writes {
+                    tx.write_all(&w).unwrap();
+                    max_in_flight = max_in_flight.max(tx.in_flight());
+                }
+                max_in_flight
+            }
+        });
+
+        let mut received = vec![];
+        let mut buf = [0; 33];
+        loop {
+            match rx.read(&mut buf).unwrap() {
+                0 => break,
+                n => received.extend_from_slice(&buf[..n]),
+            }
+        }
+        assert!(writer.join().unwrap() <= BUDGET);
+        assert_eq!(received, writes.concat());
+    }
+
+    #[test]
+    fn test_blocked_writer_fails_when_receiver_closes() {
+        let (mut tx, rx) = bounded(4);
+        tx.write_all(b"full").unwrap();
+        let writer = std::thread::spawn(move || tx.write(b"more"));
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        drop(rx);
+        let e = writer.join().unwrap().unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn test_sender_dropped_first() {
+        let (mut tx, mut rx) = bounded(64);
+        assert_eq!(rx.try_recv(), Empty);
+        tx.write_all(b"ab").unwrap();
+        tx.write_all(b"cd").unwrap();
+        drop(tx);
+
+        // Everything sent is still received before the channel reports being closed
+        assert_eq!(rx.try_recv(), Ready(b'a'));
+        assert_eq!(rx.try_recv_chunk(), Ready(&b"b"[..]));
+        assert_eq!(rx.try_recv_chunk(), Ready(&b"cd"[..]));
+        assert_eq!(rx.try_recv(), Closed);
+        assert_eq!(rx.try_recv_chunk(), Closed);
+        // Pushed back bytes are received again even after closing
+        rx.push_back(1);
+        assert_eq!(rx.try_recv(), Ready(b'd'));
+        assert_eq!(rx.try_recv(), Closed);
+    }
+
+    #[test]
+    fn test_receiver_dropped_first() {
+        let (mut tx, mut rx) = bounded(64);
+        tx.write_all(b"ab").unwrap();
+        assert_eq!(rx.try_recv(), Ready(b'a'));
+        drop(rx);
+        assert_eq!(
+            tx.write_all(b"cd").unwrap_err().kind(),
+            std::io::ErrorKind::BrokenPipe
+        );
+        assert_eq!(tx.flush().ok(), Some(()));
+    }
+
+    #[test]
+    fn test_async_recv() {
+        let (mut tx, mut rx) = bounded(16);
+        let (tx_started, rx_started) = std::sync::mpsc::channel();
+
+        let writer = std::thread::spawn(move || {
+            // Only writes once the receiving runtime is known to be waiting
+            rx_started.recv().unwrap();
+            for i in 0..100u8 {
+                // Larger than the budget, so the writer blocks on the receiver too
+                tx.write_all(&[i; 20]).unwrap();
+            }
+        });
+
+        let (received, other_task_ran) = tokio_uring::start(async move {
+            let other_task = tokio_uring::spawn(async move {
+                tx_started.send(()).unwrap();
+            });
+
+            // Awaiting data doesn't stall the runtime, so the other task gets to start the writer
+            assert_eq!(rx.recv().await, Some(0));
+            rx.push_back(1);
+            let mut received = vec![];
+            while let Some(chunk) = rx.recv_chunk().await {
+                received.extend_from_slice(chunk);
+            }
+            assert_eq!(rx.recv().await, None);
+            (received, other_task.await.is_ok())
+        });
+        writer.join().unwrap();
+
+        assert!(other_task_ran);
+        let expected = (0..100u8).flat_map(|i| [i; 20]).collect::<Vec<_>>();
+        assert_eq!(received, expected);
+    }
 }
@@ -0,0 +1,795 @@
+//! Configuring a run from a TOML file, see [`RunCfg::from_config`].
+//!
+//! Every key is optional and named like the command line flag it stands for, taking the same values:
+//! ```toml
+//! input = ["/logs/2024-10-20.json.gz", "/logs/2024-10-21.json.gz"]
+//! output = "/logs/split"
+//! output-threads = "auto"
+//! filter = ["env=prod"]
+//! append = true
+//! ```
+//!
+//! When running from the command line, each option is taken from (in order of precedence):
+//! 1. Its command line flag
+//! 2. Its `LOGSPLITTER_*` environment variable, such as `LOGSPLITTER_OUTPUT_THREADS`
+//! 3. The config file given with `--config` (or `LOGSPLITTER_CONFIG`)
+//! 4. Its default
+//!
+//! Permission modes (`file-mode` and `dir-mode`) are always octal, so they're written as `640` or `"640"`,
+//! rather than as TOML's `0o640`.
+//!
+//! `input` and `input-list` count as one option, and so do `append` and `truncate`,
+//! so giving one of them on the command line overrides both in the environment and the file.
+//! Likewise, an option overrides any option of a lower precedence source which it can't be used with,
+//! such as a `--write-index` flag overriding `LOGSPLITTER_APPEND`, while giving both in the same place is an error
+
+use std::{
+    fmt::Display,
+    marker::PhantomData,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+
+use flate2::Compression;
+use serde::{de, Deserialize, Deserializer};
+
+#[cfg(unix)]
+use crate::file_pool::UnixMode;
+use crate::{
+    data::{
+        component_key, hash_shard_key, normalized_key, EmptyComponentPolicy, EnvMap, EnvRename,
+    },
+    deflate::DeflateStrategy,
+    file_complete::OnFileComplete,
+    file_pool::{ExistingFilePolicy, RetryPolicy},
+    filter::{FilterTerm, JsonPath, LineFilter},
+    input::{read_input_list, GzipErrorPolicy, TrailingLinePolicy},
+    invalid_lines::InvalidLineLimit,
+    output::{GzipMtime, OutputFormat, ReserializeMode, ThreadAssignment},
+    stripes::{KeyStripes, StripeCount},
+    Error, ErrorKind, OutputTarget, RunCfg, Threads,
+};
+
+/// The default of `index-interval`
+pub const DEFAULT_INDEX_INTERVAL: u64 = 10_000;
+/// The default of `parquet-batch-size`
+pub const DEFAULT_PARQUET_BATCH_SIZE: u64 = 65_536;
+/// The default of `on-file-complete-jobs`
+pub const DEFAULT_ON_FILE_COMPLETE_JOBS: usize = 4;
+
+/// The options of a run, as given on the command line or in a config file.
+/// `None` means the option wasn't given, so a lower precedence source (or the default) decides it
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    pub input: Option<Vec<PathBuf>>,
+    pub input_list: Option<PathBuf>,
+    pub trailing_line: Option<TrailingLinePolicy>,
+    pub gzip_error_policy: Option<GzipErrorPolicy>,
+    pub input_mmap: Option<bool>,
+    /// How many input files are read at once
+    pub parallel_inputs: Option<usize>,
+    /// Whether the last input is tailed, which only ends once [`RunCfg::tail`] is set
+    pub tail: Option<bool>,
+    /// A directory, or `-` for stdout
+    pub output: Option<String>,
+    /// A single gzip file which every line is written to, instead of `output`
+    pub single_output: Option<PathBuf>,
+    pub filter: Option<Vec<FilterTerm>>,
+    pub normalize_keys: Option<bool>,
+    pub hash_shards: Option<usize>,
+    pub env_map: Option<Vec<EnvRename>>,
+    pub env_map_default: Option<String>,
+    pub empty_component_policy: Option<EmptyComponentPolicy>,
+    pub max_lines: Option<usize>,
+    pub max_output_bytes: Option<u64>,
+    pub strict: Option<bool>,
+    pub redact: Option<Vec<JsonPath>>,
+    pub gzip: Option<bool>,
+    pub append: Option<bool>,
+    pub truncate: Option<bool>,
+    pub gzip_mtime: Option<GzipMtime>,
+    pub deflate_strategy: Option<DeflateStrategy>,
+    pub reserialize: Option<ReserializeMode>,
+    pub write_index: Option<bool>,
+    pub index_interval: Option<u64>,
+    #[cfg(feature = "parquet")]
+    pub parquet: Option<bool>,
+    #[cfg(feature = "parquet")]
+    pub parquet_batch_size: Option<u64>,
+    pub plain_below: Option<usize>,
+    pub force: Option<bool>,
+    pub output_threads: Option<Threads>,
+    pub balance_threads: Option<bool>,
+    pub thread_assignment: Option<ThreadAssignment>,
+    pub stripes: Option<StripeCount>,
+    /// The names of the keys which are striped from their first line
+    pub stripe_keys: Option<Vec<String>>,
+    pub hash_seed: Option<u64>,
+    pub write_attempts: Option<u32>,
+    pub retry_delay_ms: Option<u64>,
+    pub max_invalid_lines: Option<InvalidLineLimit>,
+    pub sync_every: Option<u64>,
+    /// A program which is run with the path of every output file as soon as it's finished
+    pub on_file_complete_cmd: Option<PathBuf>,
+    /// How many `on-file-complete-cmd` programs may run at once
+    pub on_file_complete_jobs: Option<usize>,
+    /// Roughly how many bytes the output threads' writers may use at once
+    pub max_memory: Option<u64>,
+    #[cfg(unix)]
+    pub file_mode: Option<UnixMode>,
+    #[cfg(unix)]
+    pub dir_mode: Option<UnixMode>,
+}
+
+/// The options which can't be used together, by name.
+/// Setting one of them overrides the other in a lower precedence source, see [`Config::or`]
+const EXCLUSIVE: &[(&str, &str)] = &[
+    ("input", "input-list"),
+    ("normalize-keys", "hash-shards"),
+    ("env-map", "hash-shards"),
+    ("empty-component-policy", "hash-shards"),
+    ("output", "single-output"),
+    ("single-output", "write-index"),
+    ("single-output", "plain-below"),
+    ("append", "truncate"),
+    ("append", "write-index"),
+    ("append", "plain-below"),
+    ("write-index", "plain-below"),
+    ("parquet", "single-output"),
+    ("single-output", "on-file-complete-cmd"),
+    ("single-output", "max-memory"),
+    ("single-output", "stripes"),
+    ("single-output", "parallel-inputs"),
+    ("stripes", "parallel-inputs"),
+    ("tail", "parallel-inputs"),
+    ("parquet", "append"),
+    ("parquet", "write-index"),
+    ("parquet", "plain-below"),
+];
+
+fn invalid(detail: impl Into<String>) -> Error {
+    Error {
+        kind: Box::new(ErrorKind::InvalidConfig(detail.into())),
+    }
+}
+
+impl Config {
+    /// Reads the TOML config file at `path`
+    pub fn read(path: &Path) -> Result<Self, Error> {
+        let text = std::fs::read_to_string(path).map_err(|e| Error::io(path, e))?;
+        toml::from_str(&text).map_err(|e| invalid(format!("{}: {e}", path.display())))
+    }
+
+    /// Whether the option `name` of [`EXCLUSIVE`] is set, and to something which rules out the other options
+    fn is_set(&self, name: &str) -> bool {
+        match name {
+            "input" => self.input.is_some(),
+            "input-list" => self.input_list.is_some(),
+            "normalize-keys" => self.normalize_keys == Some(true),
+            "hash-shards" => self.hash_shards.is_some(),
+            "env-map" => self.env_map.is_some() || self.env_map_default.is_some(),
+            "empty-component-policy" => self.empty_component_policy.is_some(),
+            "output" => self.output.is_some(),
+            "single-output" => self.single_output.is_some(),
+            "write-index" => self.write_index == Some(true),
+            "plain-below" => self.plain_below.is_some(),
+            "append" => self.append == Some(true),
+            "truncate" => self.truncate == Some(true),
+            #[cfg(feature = "parquet")]
+            "parquet" => self.parquet == Some(true),
+            #[cfg(not(feature = "parquet"))]
+            "parquet" => false,
+            "on-file-complete-cmd" => self.on_file_complete_cmd.is_some(),
+            "max-memory" => self.max_memory.is_some(),
+            "stripes" => self.stripes.is_some(),
+            "parallel-inputs" => self.parallel_inputs.is_some(),
+            "tail" => self.tail == Some(true),
+            _ => unreachable!("Unknown option `{name}`"),
+        }
+    }
+
+    /// Unsets the option `name` of [`EXCLUSIVE`]
+    fn clear(&mut self, name: &str) {
+        match name {
+            "input" => self.input = None,
+            "input-list" => self.input_list = None,
+            "normalize-keys" => self.normalize_keys = None,
+            "hash-shards" => self.hash_shards = None,
+            "env-map" => {
+                self.env_map = None;
+                self.env_map_default = None;
+            }
+            "empty-component-policy" => self.empty_component_policy = None,
+            "output" => self.output = None,
+            "single-output" => self.single_output = None,
+            "write-index" => self.write_index = None,
+            "plain-below" => self.plain_below = None,
+            "append" => self.append = None,
+            "truncate" => self.truncate = None,
+            #[cfg(feature = "parquet")]
+            "parquet" => self.parquet = None,
+            #[cfg(not(feature = "parquet"))]
+            "parquet" => {}
+            "on-file-complete-cmd" => self.on_file_complete_cmd = None,
+            "max-memory" => self.max_memory = None,
+            "stripes" => self.stripes = None,
+            "parallel-inputs" => self.parallel_inputs = None,
+            "tail" => self.tail = None,
+            _ => unreachable!("Unknown option `{name}`"),
+        }
+    }
+
+    /// Takes every option which isn't set in `self` from `fallback`.
+    ///
+    /// Options of `fallback` which can't be used with one set in `self` are dropped first,
+    /// so that `self` overrides them instead of conflicting with them
+    pub fn or(self, mut fallback: Config) -> Self {
+        for &(a, b) in EXCLUSIVE {
+            if self.is_set(a) {
+                fallback.clear(b);
+            }
+            if self.is_set(b) {
+                fallback.clear(a);
+            }
+        }
+        let (input, input_list) = match (&self.input, &self.input_list) {
+            (None, None) => (fallback.input, fallback.input_list),
+            _ => (self.input, self.input_list),
+        };
+        let (output, single_output) = match (&self.output, &self.single_output) {
+            (None, None) => (fallback.output, fallback.single_output),
+            _ => (self.output, self.single_output),
+        };
+        let (append, truncate) = match (self.append, self.truncate) {
+            (None, None) => (fallback.append, fallback.truncate),
+            _ => (self.append, self.truncate),
+        };
+        Self {
+            input,
+            input_list,
+            trailing_line: self.trailing_line.or(fallback.trailing_line),
+            gzip_error_policy: self.gzip_error_policy.or(fallback.gzip_error_policy),
+            input_mmap: self.input_mmap.or(fallback.input_mmap),
+            parallel_inputs: self.parallel_inputs.or(fallback.parallel_inputs),
+            tail: self.tail.or(fallback.tail),
+            output,
+            single_output,
+            filter: self.filter.or(fallback.filter),
+            normalize_keys: self.normalize_keys.or(fallback.normalize_keys),
+            hash_shards: self.hash_shards.or(fallback.hash_shards),
+            env_map: self.env_map.or(fallback.env_map),
+            env_map_default: self.env_map_default.or(fallback.env_map_default),
+            empty_component_policy: self
+                .empty_component_policy
+                .or(fallback.empty_component_policy),
+            max_lines: self.max_lines.or(fallback.max_lines),
+            max_output_bytes: self.max_output_bytes.or(fallback.max_output_bytes),
+            strict: self.strict.or(fallback.strict),
+            redact: self.redact.or(fallback.redact),
+            gzip: self.gzip.or(fallback.gzip),
+            append,
+            truncate,
+            gzip_mtime: self.gzip_mtime.or(fallback.gzip_mtime),
+            deflate_strategy: self.deflate_strategy.or(fallback.deflate_strategy),
+            reserialize: self.reserialize.or(fallback.reserialize),
+            write_index: self.write_index.or(fallback.write_index),
+            index_interval: self.index_interval.or(fallback.index_interval),
+            #[cfg(feature = "parquet")]
+            parquet: self.parquet.or(fallback.parquet),
+            #[cfg(feature = "parquet")]
+            parquet_batch_size: self.parquet_batch_size.or(fallback.parquet_batch_size),
+            plain_below: self.plain_below.or(fallback.plain_below),
+            force: self.force.or(fallback.force),
+            output_threads: self.output_threads.or(fallback.output_threads),
+            balance_threads: self.balance_threads.or(fallback.balance_threads),
+            thread_assignment: self.thread_assignment.or(fallback.thread_assignment),
+            hash_seed: self.hash_seed.or(fallback.hash_seed),
+            write_attempts: self.write_attempts.or(fallback.write_attempts),
+            retry_delay_ms: self.retry_delay_ms.or(fallback.retry_delay_ms),
+            max_invalid_lines: self.max_invalid_lines.or(fallback.max_invalid_lines),
+            sync_every: self.sync_every.or(fallback.sync_every),
+            on_file_complete_cmd: self.on_file_complete_cmd.or(fallback.on_file_complete_cmd),
+            on_file_complete_jobs: self
+                .on_file_complete_jobs
+                .or(fallback.on_file_complete_jobs),
+            max_memory: self.max_memory.or(fallback.max_memory),
+            stripes: self.stripes.or(fallback.stripes),
+            stripe_keys: self.stripe_keys.or(fallback.stripe_keys),
+            #[cfg(unix)]
+            file_mode: self.file_mode.or(fallback.file_mode),
+            #[cfg(unix)]
+            dir_mode: self.dir_mode.or(fallback.dir_mode),
+        }
+    }
+
+    /// The run these options describe, with every option which isn't set left to its default.
+    ///
+    /// Fails if options which can't be combined are set, or if `input` (or `input-list`) or `output` (or `single-output`) aren't set
+    pub fn into_run_cfg(self) -> Result<RunCfg, Error> {
+        let append = self.append.unwrap_or(false);
+        let truncate = self.truncate.unwrap_or(false);
+        let write_index = self.write_index.unwrap_or(false);
+        let tail = self.tail.unwrap_or(false);
+        #[cfg(feature = "parquet")]
+        let parquet = self.parquet.unwrap_or(false);
+
+        for &(a, b) in EXCLUSIVE {
+            if self.is_set(a) && self.is_set(b) {
+                return Err(invalid(format!("`{a}` and `{b}` can't be used together")));
+            }
+        }
+        #[cfg(feature = "parquet")]
+        let parquet_batch_size = self.parquet_batch_size;
+        #[cfg(not(feature = "parquet"))]
+        let parquet_batch_size = None;
+        for (name, zero) in [
+            ("index-interval", self.index_interval == Some(0)),
+            ("parquet-batch-size", parquet_batch_size == Some(0)),
+            ("write-attempts", self.write_attempts == Some(0)),
+            ("sync-every", self.sync_every == Some(0)),
+            ("hash-shards", self.hash_shards == Some(0)),
+            (
+                "on-file-complete-jobs",
+                self.on_file_complete_jobs == Some(0),
+            ),
+            ("max-memory", self.max_memory == Some(0)),
+            ("parallel-inputs", self.parallel_inputs == Some(0)),
+        ] {
+            if zero {
+                return Err(invalid(format!("`{name}` must be positive")));
+            }
+        }
+        let stripe_keys = self.stripe_keys.as_ref().filter(|keys| !keys.is_empty());
+        match (self.stripes, stripe_keys) {
+            (None, Some(_)) => return Err(invalid("`stripe-keys` needs `stripes`")),
+            (Some(StripeCount::Fixed(_)), None) => {
+                return Err(invalid("`stripes` needs `stripe-keys`, unless it's `auto`"))
+            }
+            _ => {}
+        }
+
+        let input_files = match (self.input, self.input_list) {
+            (Some(input), _) => input,
+            (None, Some(list)) => read_input_list(&list)?,
+            (None, None) => return Err(invalid("`input` or `input-list` is required")),
+        };
+        if input_files.is_empty() {
+            return Err(invalid("`input` is empty"));
+        }
+        let output = match (self.output.as_deref(), self.single_output) {
+            (_, Some(path)) => OutputTarget::File {
+                path,
+                compression: Compression::default(),
+            },
+            (None, None) => return Err(invalid("`output` or `single-output` is required")),
+            (Some("-"), None) => OutputTarget::Stdout {
+                compression: self.gzip.unwrap_or(false).then(Compression::default),
+            },
+            (Some(dir), None) => OutputTarget::Dir(dir.into()),
+        };
+        #[allow(unused_mut)]
+        let mut format = OutputFormat::Gzip;
+        #[cfg(feature = "parquet")]
+        if parquet {
+            format = OutputFormat::Parquet {
+                batch_size: parquet_batch_size.unwrap_or(DEFAULT_PARQUET_BATCH_SIZE) as usize,
+            };
+        }
+
+        let defaults = RunCfg::default();
+        let retry_defaults = RetryPolicy::default();
+        Ok(RunCfg {
+            input_files,
+            trailing_line: self.trailing_line.unwrap_or_default(),
+            gzip_error_policy: self.gzip_error_policy.unwrap_or_default(),
+            input_mmap: self.input_mmap.unwrap_or(false),
+            parallel_inputs: self.parallel_inputs,
+            tail: tail.then(Default::default),
+            output,
+            output_threads: self.output_threads.unwrap_or(defaults.output_threads),
+            balance_threads: self.balance_threads.unwrap_or(false),
+            thread_assignment: self.thread_assignment.unwrap_or_default(),
+            hash_seed: self.hash_seed.unwrap_or(defaults.hash_seed),
+            filter: LineFilter::new(self.filter.unwrap_or_default()),
+            key_fn: match (self.hash_shards, self.normalize_keys.unwrap_or(false)) {
+                (Some(shards), _) => hash_shard_key(shards),
+                (None, normalize)
+                    if self.env_map.is_some()
+                        || self.env_map_default.is_some()
+                        || self
+                            .empty_component_policy
+                            .is_some_and(|p| p != EmptyComponentPolicy::Keep) =>
+                {
+                    let env_map = EnvMap {
+                        envs: self
+                            .env_map
+                            .unwrap_or_default()
+                            .into_iter()
+                            .map(|r| (r.from, r.to))
+                            .collect(),
+                        default: self.env_map_default,
+                    };
+                    component_key(
+                        env_map,
+                        normalize,
+                        self.empty_component_policy.unwrap_or_default(),
+                    )
+                }
+                (None, true) => Arc::new(normalized_key),
+                (None, false) => defaults.key_fn.clone(),
+            },
+            existing_files: match (append, truncate) {
+                (true, _) => Some(ExistingFilePolicy::Append),
+                (_, true) => Some(ExistingFilePolicy::Truncate),
+                _ => None,
+            },
+            gzip_mtime: self.gzip_mtime.unwrap_or_default(),
+            deflate_strategy: self.deflate_strategy.unwrap_or_default(),
+            reserialize: self.reserialize.unwrap_or_default(),
+            redact_fields: self.redact.unwrap_or_default(),
+            max_lines: self.max_lines,
+            max_output_bytes: self.max_output_bytes,
+            strict: self.strict.unwrap_or(false),
+            format,
+            index_interval: write_index
+                .then(|| self.index_interval.unwrap_or(DEFAULT_INDEX_INTERVAL)),
+            plain_below: self.plain_below,
+            force_lock: self.force.unwrap_or(false),
+            max_invalid_lines: self.max_invalid_lines.unwrap_or_default(),
+            sync_every: self.sync_every.map(|n| n as usize),
+            on_file_complete: self.on_file_complete_cmd.map(|program| {
+                OnFileComplete::command(
+                    program,
+                    self.on_file_complete_jobs
+                        .unwrap_or(DEFAULT_ON_FILE_COMPLETE_JOBS),
+                )
+            }),
+            max_memory: self.max_memory,
+            stripes: self.stripes.map(|count| KeyStripes {
+                keys: self.stripe_keys.unwrap_or_default(),
+                count,
+            }),
+            #[cfg(unix)]
+            file_mode: self.file_mode,
+            #[cfg(unix)]
+            dir_mode: self.dir_mode,
+            retry: RetryPolicy {
+                attempts: self.write_attempts.unwrap_or(retry_defaults.attempts),
+                base_delay: self
+                    .retry_delay_ms
+                    .map_or(retry_defaults.base_delay, Duration::from_millis),
+                ..retry_defaults
+            },
+            ..defaults
+        })
+    }
+}
+
+impl RunCfg {
+    /// The run described by the TOML config file at `path`, see the [module docs](crate::config)
+    pub fn from_config(path: &Path) -> Result<Self, Error> {
+        Config::read(path)?.into_run_cfg()
+    }
+}
+
+/// Deserializes a value from the same text it's given as on the command line.
+/// Numbers are accepted as well, so that `output-threads = 8` doesn't need quotes
+struct FromStrVisitor<T>(PhantomData<T>);
+
+impl<T: FromStr> de::Visitor<'_> for FromStrVisitor<T>
+where
+    T::Err: Display,
+{
+    type Value = T;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "a string or a number")
+    }
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<T, E> {
+        v.parse().map_err(E::custom)
+    }
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<T, E> {
+        self.visit_str(&v.to_string())
+    }
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<T, E> {
+        self.visit_str(&v.to_string())
+    }
+    fn visit_f64<E: de::Error>(self, v: f64) -> Result<T, E> {
+        self.visit_str(&v.to_string())
+    }
+}
+
+macro_rules! deserialize_from_str {
+    ($($t:ty),*) => {$(
+        impl<'de> Deserialize<'de> for $t {
+            fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+                d.deserialize_any(FromStrVisitor(PhantomData))
+            }
+        }
+    )*};
+}
+
+deserialize_from_str!(
+    Threads,
+    TrailingLinePolicy,
+    GzipErrorPolicy,
+    EnvRename,
+    EmptyComponentPolicy,
+    GzipMtime,
+    DeflateStrategy,
+    ThreadAssignment,
+    StripeCount,
+    ReserializeMode,
+    InvalidLineLimit,
+    FilterTerm,
+    JsonPath
+);
+#[cfg(unix)]
+deserialize_from_str!(UnixMode);
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use tempdir::TempDir;
+
+    use crate::{
+        file_pool::ExistingFilePolicy,
+        input::TrailingLinePolicy,
+        invalid_lines::InvalidLineLimit,
+        output::GzipMtime,
+        stripes::{KeyStripes, StripeCount},
+        ErrorKind, OutputTarget, RunCfg, Threads,
+    };
+
+    use super::{Config, DEFAULT_INDEX_INTERVAL};
+
+    #[test]
+    fn test_from_config() {
+        let tmp = TempDir::new("logsplitter2").unwrap();
+        let path = tmp.path().join("config.toml");
+        std::fs::write(
+            &path,
+            r#"
+            input = ["a.json.gz", "b.json.gz"]
+            output = "out"
+            output-threads = 3
+            gzip-mtime = "key-date"
+            trailing-line = "reject"
+            max-invalid-lines = "0.5%"
+            filter = ["env=prod", "service=a"]
+            append = true
+            write-attempts = 2
+            hash-seed = 42
+            max-memory = 268435456
+            stripes = 4
+            stripe-keys = ["api_prod_2024-10-20"]
+            "#,
+        )
+        .unwrap();
+
+        let cfg = RunCfg::from_config(&path).unwrap();
+        assert_eq!(
+            cfg.input_files,
+            [PathBuf::from("a.json.gz"), PathBuf::from("b.json.gz")]
+        );
+        assert!(matches!(cfg.output, OutputTarget::Dir(dir) if dir.as_path() == Path::new("out")));
+        assert_eq!(cfg.output_threads, Threads::Fixed(3));
+        assert_eq!(cfg.gzip_mtime, GzipMtime::KeyDate);
+        assert_eq!(cfg.trailing_line, TrailingLinePolicy::Reject);
+        assert_eq!(cfg.max_invalid_lines, InvalidLineLimit::Percent(0.5));
+        assert_eq!(cfg.existing_files, Some(ExistingFilePolicy::Append));
+        assert_eq!(cfg.retry.attempts, 2);
+        assert_eq!(cfg.hash_seed, 42);
+        assert_eq!(cfg.max_memory, Some(256 << 20));
+        assert_eq!(
+            cfg.stripes,
+            Some(KeyStripes {
+                keys: vec!["api_prod_2024-10-20".to_string()],
+                count: StripeCount::Fixed(4),
+            })
+        );
+        // Left to their defaults
+        assert_eq!(cfg.index_interval, None);
+        assert!(cfg.on_file_complete.is_none());
+        assert_eq!(cfg.retry.base_delay, RunCfg::default().retry.base_delay);
+
+        let err = |toml: &str| {
+            std::fs::write(&path, toml).unwrap();
+            match *RunCfg::from_config(&path).err().unwrap().kind {
+                ErrorKind::InvalidConfig(detail) => detail,
+                other => panic!("Unexpected error {other:?}"),
+            }
+        };
+        assert!(err("output-thread = 3").contains("unknown field"));
+        assert!(err(r#"output-threads = "many""#).contains("`many`"));
+        assert!(err(r#"output = "out""#).contains("`input` or `input-list` is required"));
+        assert!(err(r#"
+            input = ["a.json.gz"]
+            output = "out"
+            append = true
+            write-index = true
+            "#)
+        .contains("`append` and `write-index`"));
+        assert!(err(r#"
+            input = ["a.json.gz"]
+            output = "out"
+            normalize-keys = true
+            hash-shards = 4
+            "#)
+        .contains("`normalize-keys` and `hash-shards`"));
+        assert!(err(r#"
+            input = ["a.json.gz"]
+            output = "out"
+            env-map = ["staging=nonprod"]
+            hash-shards = 4
+            "#)
+        .contains("`env-map` and `hash-shards`"));
+        assert!(err(r#"env-map = ["staging"]"#).contains("`FROM=TO`"));
+        assert!(err(r#"
+            input = ["a.json.gz"]
+            output = "out"
+            empty-component-policy = "substitute"
+            hash-shards = 4
+            "#)
+        .contains("`empty-component-policy` and `hash-shards`"));
+        assert!(err(r#"empty-component-policy = "drop""#).contains("`substitute`"));
+        assert!(err(r#"
+            input = ["a.json.gz"]
+            output = "out"
+            single-output = "out.json.gz"
+            "#)
+        .contains("`output` and `single-output`"));
+
+        std::fs::write(
+            &path,
+            r#"
+            input = ["a.json.gz"]
+            single-output = "out.json.gz"
+            "#,
+        )
+        .unwrap();
+        let cfg = RunCfg::from_config(&path).unwrap();
+        assert!(
+            matches!(cfg.output, OutputTarget::File { path, .. } if path.as_path() == Path::new("out.json.gz"))
+        );
+        assert!(err(r#"
+            input = ["a.json.gz"]
+            output = "out"
+            hash-shards = 0
+            "#)
+        .contains("`hash-shards` must be positive"));
+        assert!(err(r#"
+            input = ["a.json.gz"]
+            single-output = "out.json.gz"
+            on-file-complete-cmd = "upload"
+            "#)
+        .contains("`single-output` and `on-file-complete-cmd`"));
+        assert!(err(r#"
+            input = ["a.json.gz"]
+            output = "out"
+            max-memory = 0
+            "#)
+        .contains("`max-memory` must be positive"));
+        assert!(err(r#"
+            input = ["a.json.gz"]
+            output = "out"
+            stripes = 4
+            "#)
+        .contains("`stripes` needs `stripe-keys`"));
+        assert!(err(r#"
+            input = ["a.json.gz"]
+            output = "out"
+            stripe-keys = ["api_prod_2024-10-20"]
+            "#)
+        .contains("`stripe-keys` needs `stripes`"));
+        assert!(err(r#"
+            input = ["a.json.gz"]
+            output = "out"
+            stripes = 1
+            "#)
+        .contains("at least 2"));
+        assert!(err(r#"
+            input = ["a.json.gz", "b.json.gz"]
+            output = "out"
+            parallel-inputs = 2
+            stripes = "auto"
+            "#)
+        .contains("`stripes` and `parallel-inputs` can't be used together"));
+        assert!(err(r#"
+            input = ["a.json.gz", "b.json.gz"]
+            output = "out"
+            parallel-inputs = 0
+            "#)
+        .contains("`parallel-inputs` must be positive"));
+        assert!(err(r#"
+            input = ["a.json.gz", "b.json.gz"]
+            output = "out"
+            parallel-inputs = 2
+            tail = true
+            "#)
+        .contains("`tail` and `parallel-inputs` can't be used together"));
+        assert!(err(r#"
+            input = ["a.json.gz"]
+            output = "out"
+            on-file-complete-cmd = "upload"
+            on-file-complete-jobs = 0
+            "#)
+        .contains("`on-file-complete-jobs` must be positive"));
+    }
+
+    #[test]
+    fn test_config_precedence() {
+        let file = Config {
+            input_list: Some("inputs.txt".into()),
+            output: Some("out".into()),
+            output_threads: Some(Threads::Fixed(3)),
+            append: Some(true),
+            ..Default::default()
+        };
+        let flags = Config {
+            input: Some(vec!["a.json.gz".into()]),
+            output_threads: Some(Threads::Auto),
+            truncate: Some(true),
+            ..Default::default()
+        };
+
+        let merged = flags.or(file);
+        assert_eq!(merged.input, Some(vec!["a.json.gz".into()]));
+        // Replaced by `input`, instead of conflicting with it
+        assert_eq!(merged.input_list, None);
+        assert_eq!(merged.output.as_deref(), Some("out"));
+        assert_eq!(merged.output_threads, Some(Threads::Auto));
+        assert_eq!((merged.append, merged.truncate), (None, Some(true)));
+
+        // Options which conflict with one given on the command line are dropped from the file
+        let file = Config {
+            input: Some(vec!["a.json.gz".into()]),
+            output: Some("out".into()),
+            append: Some(true),
+            hash_shards: Some(4),
+            output_threads: Some(Threads::Fixed(3)),
+            tail: Some(true),
+            ..Default::default()
+        };
+        let flags = Config {
+            write_index: Some(true),
+            normalize_keys: Some(true),
+            parallel_inputs: Some(2),
+            ..Default::default()
+        };
+        let merged = flags.or(file.clone());
+        assert_eq!(merged.append, None);
+        assert_eq!(merged.hash_shards, None);
+        assert_eq!(merged.tail, None);
+        // Only what conflicts is dropped
+        assert_eq!(merged.output_threads, Some(Threads::Fixed(3)));
+        let cfg = merged.into_run_cfg().unwrap();
+        assert_eq!(cfg.existing_files, None);
+        assert_eq!(cfg.index_interval, Some(DEFAULT_INDEX_INTERVAL));
+        assert_eq!(cfg.parallel_inputs, Some(2));
+        assert!(cfg.tail.is_none());
+
+        // Flags which don't rule anything out, like `--write-index false`, leave the file's options alone
+        let flags = Config {
+            write_index: Some(false),
+            ..Default::default()
+        };
+        let cfg = flags.or(file.clone()).into_run_cfg().unwrap();
+        assert_eq!(cfg.existing_files, Some(ExistingFilePolicy::Append));
+
+        // Conflicts within one source are still errors
+        let flags = Config {
+            single_output: Some("out.json.gz".into()),
+            max_memory: Some(1 << 20),
+            ..Default::default()
+        };
+        let err = flags.or(file).into_run_cfg().err().unwrap();
+        assert!(err
+            .to_string()
+            .contains("`single-output` and `max-memory` can't be used together"));
+    }
+}
@@ -0,0 +1,249 @@
+//! A gzip encoder which can use any of the deflate strategies, which [`flate2`] doesn't expose
+
+use std::{io::Write, str::FromStr};
+
+use flate2::{Compression, Crc};
+use miniz_oxide::deflate::core::{
+    compress, create_comp_flags_from_zip_params, CompressionStrategy, CompressorOxide, TDEFLFlush,
+    TDEFLStatus,
+};
+
+/// How deflate looks for repeated strings, which trades compression ratio for speed differently depending on the data
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DeflateStrategy {
+    /// Matches of any length (`default`)
+    #[default]
+    Default,
+    /// Only matches of at least 5 bytes, which suits data with short repeats but mostly random bytes (`filtered`)
+    Filtered,
+    /// No matches at all, only huffman coding of single bytes, which is fastest but only helps with skewed byte frequencies (`huffman-only`)
+    HuffmanOnly,
+    /// Only matches of the previous byte, for long runs of the same byte (`rle`)
+    Rle,
+    /// Like `default`, but with the fixed huffman codes of the deflate spec instead of ones fit to the data (`fixed`)
+    Fixed,
+}
+
+impl FromStr for DeflateStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "default" => Ok(Self::Default),
+            "filtered" => Ok(Self::Filtered),
+            "huffman-only" => Ok(Self::HuffmanOnly),
+            "rle" => Ok(Self::Rle),
+            "fixed" => Ok(Self::Fixed),
+            _ => Err(format!(
+                "Unknown deflate strategy `{s}`, expected `default`, `filtered`, `huffman-only`, `rle` or `fixed`"
+            )),
+        }
+    }
+}
+
+impl DeflateStrategy {
+    fn to_miniz(self) -> CompressionStrategy {
+        match self {
+            Self::Default => CompressionStrategy::Default,
+            Self::Filtered => CompressionStrategy::Filtered,
+            Self::HuffmanOnly => CompressionStrategy::HuffmanOnly,
+            Self::Rle => CompressionStrategy::RLE,
+            Self::Fixed => CompressionStrategy::Fixed,
+        }
+    }
+}
+
+/// How much compressed output is collected before it's written on
+const OUT_BUF_SIZE: usize = 32 * 1024;
+
+/// Writes a single gzip member into `w`, like [`flate2::write::GzEncoder`], but compressed with any [`DeflateStrategy`].
+///
+/// The header has the same fields as one of [`flate2::GzBuilder`] with a file name and mtime,
+/// so with [`DeflateStrategy::Default`] the output is byte-for-byte the same.
+/// Must be [finished](StrategyGzEncoder::try_finish), otherwise the member is cut off
+pub struct StrategyGzEncoder<W: Write> {
+    w: W,
+    /// Written along with the first compressed bytes, like flate2 does
+    header: Vec<u8>,
+    compressor: Box<CompressorOxide>,
+    crc: Crc,
+    out_buf: Vec<u8>,
+    finished: bool,
+}
+
+impl<W: Write> StrategyGzEncoder<W> {
+    pub fn new(
+        w: W,
+        filename: &str,
+        mtime: u32,
+        level: Compression,
+        strategy: DeflateStrategy,
+    ) -> Self {
+        // Negative window bits mean a raw deflate stream, which the gzip header and trailer wrap
+        let flags = create_comp_flags_from_zip_params(
+            level.level() as i32,
+            -15,
+            strategy.to_miniz() as i32,
+        );
+        let xfl = match level.level() {
+            9.. => 2,
+            0 | 1 => 4,
+            _ => 0,
+        };
+        // Magic, deflate, and the flag for a file name, followed by the little-endian mtime,
+        // the extra flags, and an unknown OS
+        let mut header = vec![0x1f, 0x8b, 8, 0x08];
+        header.extend_from_slice(&mtime.to_le_bytes());
+        header.extend_from_slice(&[xfl, 255]);
+        header.extend_from_slice(filename.as_bytes());
+        header.push(0);
+
+        Self {
+            w,
+            header,
+            compressor: Box::new(CompressorOxide::new(flags)),
+            crc: Crc::new(),
+            out_buf: vec![0; OUT_BUF_SIZE],
+            finished: false,
+        }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.w
+    }
+
+    fn write_header(&mut self) -> std::io::Result<()> {
+        if !self.header.is_empty() {
+            self.w.write_all(&std::mem::take(&mut self.header))?;
+        }
+        Ok(())
+    }
+
+    /// Compresses all of `input`, writing everything which is ready
+    fn compress(&mut self, mut input: &[u8], flush: TDEFLFlush) -> std::io::Result<()> {
+        loop {
+            let (status, consumed, produced) =
+                compress(&mut self.compressor, input, &mut self.out_buf, flush);
+            self.w.write_all(&self.out_buf[..produced])?;
+            input = &input[consumed..];
+            match status {
+                TDEFLStatus::Done => return Ok(()),
+                // A full output buffer may have left more output behind
+                TDEFLStatus::Okay if input.is_empty() && produced < self.out_buf.len() => {
+                    if flush == TDEFLFlush::None {
+                        return Ok(());
+                    }
+                }
+                TDEFLStatus::Okay => {}
+                TDEFLStatus::BadParam | TDEFLStatus::PutBufFailed => {
+                    return Err(std::io::Error::other(format!(
+                        "Deflate failed with {status:?}"
+                    )))
+                }
+            }
+        }
+    }
+
+    /// Writes the end of the compressed stream and the gzip trailer, after which nothing more can be written
+    pub fn try_finish(&mut self) -> std::io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.write_header()?;
+        self.compress(&[], TDEFLFlush::Finish)?;
+        let mut trailer = self.crc.sum().to_le_bytes().to_vec();
+        trailer.extend_from_slice(&self.crc.amount().to_le_bytes());
+        self.w.write_all(&trailer)?;
+        self.finished = true;
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for StrategyGzEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        assert!(!self.finished, "Cannot write to a finished gzip member");
+        self.write_header()?;
+        self.crc.update(buf);
+        self.compress(buf, TDEFLFlush::None)?;
+        Ok(buf.len())
+    }
+
+    /// Only flushes what was already compressed, since a sync flush would make the rest compress worse
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.w.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+
+    use flate2::{read::GzDecoder, Compression, GzBuilder};
+
+    use super::{DeflateStrategy, StrategyGzEncoder};
+
+    fn input() -> Vec<u8> {
+        (0..5_000)
+            .map(|i| {
+                format!(
+                    r#"{{"message":"request {i} handled","n":{}}}"#,
+                    i * 7919 % 1000
+                ) + "\n"
+            })
+            .collect::<String>()
+            .into_bytes()
+    }
+
+    fn encode(input: &[u8], level: Compression, strategy: DeflateStrategy) -> Vec<u8> {
+        let mut enc = StrategyGzEncoder::new(vec![], "a.json", 1234, level, strategy);
+        // In pieces, like lines are written
+        for chunk in input.chunks(100) {
+            enc.write_all(chunk).unwrap();
+        }
+        enc.try_finish().unwrap();
+        enc.w
+    }
+
+    #[test]
+    fn test_same_as_flate2() {
+        let input = input();
+        for level in [
+            Compression::fast(),
+            Compression::default(),
+            Compression::best(),
+        ] {
+            let mut enc = GzBuilder::new()
+                .filename("a.json")
+                .mtime(1234)
+                .write(vec![], level);
+            enc.write_all(&input).unwrap();
+            assert_eq!(
+                encode(&input, level, DeflateStrategy::Default),
+                enc.finish().unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_strategies_round_trip() {
+        let input = input();
+        let mut sizes = vec![];
+        for strategy in ["default", "filtered", "huffman-only", "rle", "fixed"] {
+            let strategy = strategy.parse().unwrap();
+            let encoded = encode(&input, Compression::default(), strategy);
+            let mut dec = GzDecoder::new(&encoded[..]);
+            let mut decoded = vec![];
+            dec.read_to_end(&mut decoded).unwrap();
+            assert_eq!(decoded, input, "{strategy:?}");
+            let header = dec.header().unwrap();
+            assert_eq!(header.filename(), Some(&b"a.json"[..]));
+            assert_eq!(header.mtime(), 1234);
+            sizes.push(encoded.len());
+        }
+        // Without matches, repeated lines barely compress
+        assert!(sizes[2] > 3 * sizes[0], "{sizes:?}");
+        // Even an empty member is valid
+        assert!(encode(&[], Compression::default(), DeflateStrategy::Rle).len() > 10);
+        assert!("lz4".parse::<DeflateStrategy>().is_err());
+    }
+}
@@ -0,0 +1,183 @@
+//! Acting on each output file as soon as it's finished, rather than once the whole run is, see [`OnFileComplete`].
+//!
+//! A file counts as finished once its encoder is finished and the file is synced and closed,
+//! which is when its output thread calls the callback. Files which are left incomplete
+//! (such as because the disk filled up) are never reported
+
+use std::{
+    path::{Path, PathBuf},
+    process::{Child, Command},
+    sync::{Arc, Mutex},
+};
+
+use crate::output::panic_message;
+
+/// An output file which was finished, see [`OnFileComplete`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileCompleteEvent {
+    /// The key whose lines the file holds
+    pub key: String,
+    pub path: PathBuf,
+    pub lines: u64,
+    /// The size of the file, like in its [manifest entry](crate::manifest::ManifestEntry)
+    pub bytes: u64,
+}
+
+/// Called by an output thread with every file it finishes, see the [module docs](self).
+///
+/// The callback runs on the output thread, so a slow one holds up the thread's other files.
+/// A callback which panics is reported, rather than taking down the output thread
+#[derive(Clone)]
+pub struct OnFileComplete(Arc<dyn Fn(FileCompleteEvent) + Send + Sync>);
+
+impl OnFileComplete {
+    pub fn new(f: impl Fn(FileCompleteEvent) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(f))
+    }
+
+    /// Runs `program` with the path of every finished file as its only argument.
+    ///
+    /// At most `max_running` of them run at once; finishing another file waits for one of them to exit.
+    /// Once the last clone of the callback is dropped (which is when the run finishes), every program still running is waited for.
+    /// Programs which can't be started, or which fail, are reported without failing the run
+    pub fn command(program: PathBuf, max_running: usize) -> Self {
+        assert!(max_running > 0, "Must allow at least one running program");
+        let cmd = CompleteCmd {
+            program,
+            max_running,
+            running: Mutex::new(vec![]),
+        };
+        Self::new(move |event| cmd.spawn(&event.path))
+    }
+
+    /// Calls the callback, reporting a panic instead of unwinding into the caller
+    pub(crate) fn call(&self, event: FileCompleteEvent) {
+        let path = event.path.clone();
+        let called = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| (self.0)(event)));
+        if let Err(payload) = called {
+            eprintln!(
+                "Warning: the file complete callback panicked for {}: {}",
+                path.display(),
+                panic_message(payload.as_ref())
+            );
+        }
+    }
+}
+
+impl std::fmt::Debug for OnFileComplete {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("OnFileComplete(..)")
+    }
+}
+
+/// The programs started by [`OnFileComplete::command`]
+struct CompleteCmd {
+    program: PathBuf,
+    max_running: usize,
+    /// Every program which may still be running, with the file it was started for
+    running: Mutex<Vec<(PathBuf, Child)>>,
+}
+
+impl CompleteCmd {
+    fn spawn(&self, path: &Path) {
+        let mut running = self.running.lock().unwrap();
+        running.retain_mut(|(done, child)| match child.try_wait() {
+            Ok(None) => true,
+            Ok(Some(status)) => {
+                self.check(done, Ok(status));
+                false
+            }
+            Err(e) => {
+                self.check(done, Err(e));
+                false
+            }
+        });
+        // The oldest program is the likeliest to be done soon
+        if running.len() >= self.max_running {
+            let (done, mut child) = running.remove(0);
+            self.check(&done, child.wait());
+        }
+        match Command::new(&self.program).arg(path).spawn() {
+            Ok(child) => running.push((path.to_path_buf(), child)),
+            Err(e) => eprintln!(
+                "Warning: could not run {} for {}: {e}",
+                self.program.display(),
+                path.display()
+            ),
+        }
+    }
+
+    /// Reports the program run for `path` if it failed
+    fn check(&self, path: &Path, status: std::io::Result<std::process::ExitStatus>) {
+        match status {
+            Ok(status) if status.success() => {}
+            Ok(status) => eprintln!(
+                "Warning: {} failed for {} ({status})",
+                self.program.display(),
+                path.display()
+            ),
+            Err(e) => eprintln!(
+                "Warning: could not wait for {} to finish for {}: {e}",
+                self.program.display(),
+                path.display()
+            ),
+        }
+    }
+}
+
+impl Drop for CompleteCmd {
+    fn drop(&mut self) {
+        let running = std::mem::take(self.running.get_mut().unwrap());
+        for (path, mut child) in running {
+            self.check(&path, child.wait());
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use tempdir::TempDir;
+
+    use super::{FileCompleteEvent, OnFileComplete};
+
+    #[test]
+    fn test_command() {
+        let tmp = TempDir::new("logsplitter2").unwrap();
+        let script = tmp.path().join("done.sh");
+        // Slow enough that finishing files has to wait for earlier programs
+        std::fs::write(&script, "#!/bin/sh\nsleep 0.2\ntouch \"$1.done\"\n").unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let paths = (0..5)
+            .map(|i| tmp.path().join(format!("{i}.json.gz")))
+            .collect::<Vec<_>>();
+        let on_file_complete = OnFileComplete::command(script, 2);
+        for path in &paths {
+            on_file_complete.call(FileCompleteEvent {
+                key: "a".to_string(),
+                path: path.clone(),
+                lines: 1,
+                bytes: 1,
+            });
+        }
+        // Dropping the last clone waits for the programs which are still running
+        drop(on_file_complete);
+        for path in &paths {
+            assert!(
+                path.with_extension("gz.done").exists(),
+                "{}",
+                path.display()
+            );
+        }
+
+        // A program which can't be started is only reported
+        OnFileComplete::command(tmp.path().join("missing"), 1).call(FileCompleteEvent {
+            key: "a".to_string(),
+            path: paths[0].clone(),
+            lines: 1,
+            bytes: 1,
+        });
+    }
+}
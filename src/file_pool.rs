@@ -1,35 +1,261 @@
 use std::{
-    collections::{HashMap, HashSet, VecDeque},
-    path::PathBuf,
+    cell::Cell,
+    collections::VecDeque,
+    future::Future,
+    io,
+    path::{Path, PathBuf},
+    rc::Rc,
+    time::{Duration, Instant},
 };
+#[cfg(unix)]
+use std::{fmt::Display, str::FromStr};
 
 use tokio_uring::fs::{File, OpenOptions};
 
-use crate::data::{MsgKey, MsgKeyMap, MsgKeySet};
+use crate::data::{hash_builder, MsgKey, MsgKeyMap, MsgKeySet};
+
+/// Whether `e` means the disk is full, which a run can recover from by stopping early
+/// instead of failing outright
+pub fn is_storage_full(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::StorageFull | io::ErrorKind::WriteZero
+    )
+}
+
+/// A Unix permission mode such as `640`, which is always written in octal
+#[cfg(unix)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnixMode(pub u32);
+
+#[cfg(unix)]
+impl UnixMode {
+    /// Sets the permissions of `path` to exactly this mode, regardless of the umask it was created with
+    pub fn apply(self, path: &Path) -> io::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(self.0))
+    }
+}
+
+#[cfg(unix)]
+impl FromStr for UnixMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match u32::from_str_radix(s.strip_prefix("0o").unwrap_or(s), 8) {
+            Ok(mode) if mode <= 0o7777 => Ok(Self(mode)),
+            _ => Err(format!(
+                "Expected an octal permission mode such as `640`, got `{s}`"
+            )),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Display for UnixMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:03o}", self.0)
+    }
+}
+
+/// How writes and opens which fail with transient errors are retried, such as the hiccups of network filesystems
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// How many times an operation is attempted in total, so `1` disables retrying
+    pub attempts: u32,
+    /// The delay before the first retry, which doubles for every retry after it
+    pub base_delay: Duration,
+    /// The error kinds which are worth retrying. Anything else fails immediately
+    pub transient_kinds: Vec<io::ErrorKind>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 4,
+            base_delay: Duration::from_millis(10),
+            transient_kinds: vec![
+                io::ErrorKind::Interrupted,
+                io::ErrorKind::WouldBlock,
+                io::ErrorKind::TimedOut,
+            ],
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Whether `e` is worth retrying. Running out of space never is, since it won't resolve itself
+    pub fn is_transient(&self, e: &io::Error) -> bool {
+        !is_storage_full(e) && self.transient_kinds.contains(&e.kind())
+    }
+}
+
+/// A [`RetryPolicy`], along with how many retries happened under it
+#[derive(Debug, Default)]
+struct Retries {
+    policy: RetryPolicy,
+    count: Cell<u64>,
+}
+
+impl Retries {
+    /// Waits before retrying an operation which failed with `e` on its `attempt`th try (counting from `1`).
+    ///
+    /// Returns `false` without waiting if the operation shouldn't be retried
+    async fn backoff(&self, e: &io::Error, attempt: u32) -> bool {
+        if attempt >= self.policy.attempts || !self.policy.is_transient(e) {
+            return false;
+        }
+        self.count.set(self.count.get() + 1);
+        tokio::time::sleep(self.policy.base_delay * 2u32.pow(attempt - 1)).await;
+        true
+    }
+}
+
+/// The file operations which a [`FilePool`] is built on.
+///
+/// Real output goes through [`UringBackend`], but tests can swap in a backend which never touches the disk
+pub trait FileBackend: Clone + 'static {
+    type File: 'static;
+
+    /// Creates the file at `path`, truncating it if it already exists
+    fn create(&self, path: &Path) -> impl Future<Output = io::Result<Self::File>>;
+    /// Opens the file at `path` for writing without truncating it, creating it if it doesn't exist
+    fn open(&self, path: &Path) -> impl Future<Output = io::Result<Self::File>>;
+    /// The length of the file at `path`, or `0` if it doesn't exist
+    fn file_len(&self, path: &Path) -> impl Future<Output = io::Result<u64>>;
+    /// Removes the file at `path`, doing nothing if it doesn't exist
+    fn remove(&self, path: &Path) -> impl Future<Output = io::Result<()>>;
+    /// Writes (part of) `buf` at `pos`, returning how many bytes were written along with the buffer
+    fn write_at(
+        &self,
+        file: &Self::File,
+        buf: Vec<u8>,
+        pos: u64,
+    ) -> impl Future<Output = (io::Result<usize>, Vec<u8>)>;
+    /// Makes sure everything written to `file` has reached the disk
+    fn sync(&self, file: &Self::File) -> impl Future<Output = io::Result<()>>;
+    fn close(&self, file: Self::File) -> impl Future<Output = io::Result<()>>;
+}
+
+/// Files opened with `io_uring`, through [`tokio_uring`]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UringBackend {
+    /// If set, the permissions of every file this backend creates, instead of leaving them to the umask
+    #[cfg(unix)]
+    pub file_mode: Option<UnixMode>,
+}
+
+impl FileBackend for UringBackend {
+    type File = File;
+
+    async fn create(&self, path: &Path) -> io::Result<File> {
+        let file = File::create(path).await?;
+        #[cfg(unix)]
+        if let Some(mode) = self.file_mode {
+            mode.apply(path)?;
+        }
+        Ok(file)
+    }
+    async fn open(&self, path: &Path) -> io::Result<File> {
+        #[cfg(unix)]
+        let created = !path.exists();
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .open(path)
+            .await?;
+        // Existing files keep their permissions
+        #[cfg(unix)]
+        if let (Some(mode), true) = (self.file_mode, created) {
+            mode.apply(path)?;
+        }
+        Ok(file)
+    }
+    async fn file_len(&self, path: &Path) -> io::Result<u64> {
+        match std::fs::metadata(path) {
+            Ok(meta) => Ok(meta.len()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(0),
+            Err(e) => Err(e),
+        }
+    }
+    async fn remove(&self, path: &Path) -> io::Result<()> {
+        match tokio_uring::fs::remove_file(path).await {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+    async fn write_at(&self, file: &File, buf: Vec<u8>, pos: u64) -> (io::Result<usize>, Vec<u8>) {
+        file.write_at(buf, pos).await
+    }
+    async fn sync(&self, file: &File) -> io::Result<()> {
+        // NOTE: dropping a `tokio_uring` file does not ensure all data is written to disk!
+        file.sync_all().await
+    }
+    async fn close(&self, file: File) -> io::Result<()> {
+        file.close().await
+    }
+}
 
 /// A `FilePool` file that is open.
 /// Any `FilePoolEntry` items should be returned to their `FilePool` instead of being dropped
-pub struct FilePoolEntry {
+pub struct FilePoolEntry<B: FileBackend = UringBackend> {
     pub cursor: usize,
-    pub file: File,
+    pub file: B::File,
+    backend: B,
+    retries: Rc<Retries>,
+    /// Shared with the pool, see [`FilePool::bytes_written`]
+    written: Rc<Cell<u64>>,
+    /// The epoch of the pool when this entry was last given back, see [`FilePool::idle_files_queue`]
+    given_at: u64,
 }
 
-impl FilePoolEntry {
+impl<B: FileBackend> FilePoolEntry<B> {
+    /// Writes all of `to_write` at the cursor, retrying transient errors according to the pool's [`RetryPolicy`]
     pub async fn write_all(&mut self, mut to_write: Vec<u8>) -> Result<(), std::io::Error> {
+        let mut attempt = 1;
         loop {
-            if to_write.len() == 0 {
+            if to_write.is_empty() {
                 break;
             }
-            let (written, mut same_buf) = self.file.write_at(to_write, self.cursor as u64).await;
-            let written = written?;
+            let (written, mut same_buf) = self
+                .backend
+                .write_at(&self.file, to_write, self.cursor as u64)
+                .await;
+            let written = match written {
+                Ok(written) => written,
+                Err(e) if self.retries.backoff(&e, attempt).await => {
+                    attempt += 1;
+                    to_write = same_buf;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            if written == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
 
             self.cursor += written;
+            self.written.set(self.written.get() + written as u64);
             to_write = same_buf.split_off(written);
         }
         Ok(())
     }
 }
 
+/// What to do when an output file already exists before this pool first takes it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ExistingFilePolicy {
+    /// Truncate the existing file, losing its content
+    #[default]
+    Truncate,
+    /// Keep the existing content, and write after it.
+    ///
+    /// Since every key's encoder is finished at the end of a run,
+    /// the lines of this run become a new gzip member following the existing ones
+    Append,
+}
+
 struct FilePoolEntryInactive {
     cursor: usize,
     closing_task: tokio::task::JoinHandle<std::io::Result<()>>,
@@ -39,32 +265,157 @@ struct FilePoolEntryInactive {
 ///
 /// The only way to obtain is a file is to [`take()`](FilePool::take),
 /// which will return a [`FilePoolEntry`](FilePoolEntry)
-pub struct FilePool {
+pub struct FilePool<B: FileBackend = UringBackend> {
+    backend: B,
     max_open_files: usize,
     root: PathBuf,
+    existing_files: ExistingFilePolicy,
+    /// The extension of every file in this pool
+    extension: &'static str,
     /// This is a FIFO queue representing how recently a given file has been used.
-    /// The elements in this queue are the same as the keys in `idle_files`
     ///
-    /// When a file is given back to this file pool, it will be pushed to the back of this queue
+    /// When a file is given back to this file pool, it will be pushed to the back of this queue along with the current `epoch`,
+    /// which is also stored in its entry. Taking an idle file leaves its element in the queue,
+    /// so an element is stale unless its key is idle and was given back at that same epoch.
+    /// This keeps both taking and giving O(1), instead of searching the queue on every take
     ///
     /// When a file must be temporarily closed to stay under the `max_open_files`,
-    /// the idle file at the front of this queue will be chosen
-    idle_files_queue: VecDeque<MsgKey>,
-    idle_files: MsgKeyMap<FilePoolEntry>,
+    /// the first element of this queue which isn't stale will be chosen
+    idle_files_queue: VecDeque<(u64, MsgKey)>,
+    /// Incremented on every give
+    epoch: u64,
+    idle_files: MsgKeyMap<FilePoolEntry<B>>,
     taken_files: MsgKeySet,
     inactive_files: MsgKeyMap<FilePoolEntryInactive>,
+    /// Shared with every entry of this pool
+    retries: Rc<Retries>,
+    written: Rc<Cell<u64>>,
+    /// See [`open_close_time`](FilePool::open_close_time)
+    open_close_time: Duration,
+    /// See [`with_sync_every`](FilePool::with_sync_every)
+    sync_every: Option<usize>,
+    gives_since_sync: usize,
+    /// Files given back since the last sync
+    unsynced: MsgKeySet,
 }
 
 impl FilePool {
     /// Creates a file pool which will not open more than the specified number of files at once
-    pub fn new(max_open_files: usize, root: PathBuf) -> Self {
+    pub fn new(max_open_files: usize, root: PathBuf, existing_files: ExistingFilePolicy) -> Self {
+        Self::with_backend(
+            max_open_files,
+            root,
+            existing_files,
+            UringBackend::default(),
+        )
+    }
+}
+
+impl<B: FileBackend> FilePool<B> {
+    /// Like [`new`](FilePool::new), but all file operations go through `backend`
+    pub fn with_backend(
+        max_open_files: usize,
+        root: PathBuf,
+        existing_files: ExistingFilePolicy,
+        backend: B,
+    ) -> Self {
         Self {
+            backend,
             max_open_files,
             root,
+            existing_files,
+            extension: "json.gz",
             idle_files_queue: Default::default(),
+            epoch: 0,
             idle_files: Default::default(),
             taken_files: Default::default(),
             inactive_files: Default::default(),
+            retries: Default::default(),
+            written: Default::default(),
+            open_close_time: Duration::ZERO,
+            sync_every: None,
+            gives_since_sync: 0,
+            unsynced: Default::default(),
+        }
+    }
+
+    /// Seeds the hasher of this pool's maps, which is [`DEFAULT_HASH_SEED`](crate::data::DEFAULT_HASH_SEED) by default.
+    /// Only takes effect before any file is taken
+    pub fn with_hash_seed(mut self, seed: u64) -> Self {
+        self.idle_files = MsgKeyMap::with_hasher(hash_builder(seed));
+        self.taken_files = MsgKeySet::with_hasher(hash_builder(seed));
+        self.inactive_files = MsgKeyMap::with_hasher(hash_builder(seed));
+        self.unsynced = MsgKeySet::with_hasher(hash_builder(seed));
+        self
+    }
+
+    /// Sets the extension of the files in this pool, which is `json.gz` by default
+    pub fn with_extension(mut self, extension: &'static str) -> Self {
+        self.extension = extension;
+        self
+    }
+
+    /// Sets how transient errors are retried, which is [`RetryPolicy::default`] otherwise
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retries = Rc::new(Retries {
+            policy,
+            count: Cell::new(0),
+        });
+        self
+    }
+
+    /// Syncs the files given back to this pool every `n` [gives](FilePool::give),
+    /// instead of only when they're evicted or the pool is finished.
+    ///
+    /// This bounds how much output is lost if the process is killed, at a cost in throughput:
+    /// every sync waits until the data is on the disk, which takes far longer than writing into the page cache.
+    /// Syncing on every give (`n = 1`) can easily dominate the run time, especially on spinning disks or network filesystems
+    pub fn with_sync_every(mut self, n: usize) -> Self {
+        assert!(n > 0, "Cannot sync every 0 gives");
+        self.sync_every = Some(n);
+        self
+    }
+
+    /// How many times an operation of this pool was retried after a transient error
+    pub fn retries(&self) -> u64 {
+        self.retries.count.get()
+    }
+
+    /// How many bytes were written to the files of this pool (including [unpooled](FilePool::write_unpooled) ones),
+    /// not counting bytes which were already in them
+    pub fn bytes_written(&self) -> u64 {
+        self.written.get()
+    }
+
+    /// How long taking files has spent opening them, including closing idle files to make room for them.
+    /// Taking a file which is already open isn't counted
+    pub fn open_close_time(&self) -> Duration {
+        self.open_close_time
+    }
+
+    /// Opens (or creates, truncating it) the file at `path`, retrying transient errors
+    async fn open_retrying(&self, path: &Path, create: bool) -> io::Result<B::File> {
+        let mut attempt = 1;
+        loop {
+            let result = match create {
+                true => self.backend.create(path).await,
+                false => self.backend.open(path).await,
+            };
+            match result {
+                Err(e) if self.retries.backoff(&e, attempt).await => attempt += 1,
+                result => return result,
+            }
+        }
+    }
+
+    fn entry(&self, cursor: usize, file: B::File) -> FilePoolEntry<B> {
+        FilePoolEntry {
+            cursor,
+            file,
+            backend: self.backend.clone(),
+            retries: self.retries.clone(),
+            written: self.written.clone(),
+            given_at: 0,
         }
     }
 
@@ -72,31 +423,60 @@ impl FilePool {
     ///
     /// Before dropping this pool, this should return `true`
     pub fn has_no_file_handles(&self) -> bool {
-        self.idle_files.len() == 0
+        self.idle_files.is_empty()
+    }
+
+    /// The most files this pool holds open at once
+    pub fn max_open_files(&self) -> usize {
+        self.max_open_files
     }
 
-    fn open_files(&self) -> usize {
+    /// How many files this pool currently holds open, whether taken or idle
+    pub fn open_files(&self) -> usize {
         self.idle_files.len() + self.taken_files.len()
     }
 
-    /// Pops the top of `self.idle_files_queue`, and flushes that file.
+    /// How many open files are waiting in this pool to be taken again
+    pub fn idle_files(&self) -> usize {
+        self.idle_files.len()
+    }
+
+    /// How many files were closed to stay under the limit, and are reopened once they're taken again
+    pub fn inactive_files(&self) -> usize {
+        self.inactive_files.len()
+    }
+
+    /// Whether `self.idle_files_queue` still holds the file `key` which was given back at `epoch`
+    fn is_queued_idle(&self, epoch: u64, key: &MsgKey) -> bool {
+        self.idle_files
+            .get(key)
+            .is_some_and(|entry| entry.given_at == epoch)
+    }
+
+    /// Pops the top of `self.idle_files_queue` (skipping stale elements), and flushes that file.
     /// Then, moves that file to `self.inactive_files`
     ///
     /// Panics:
     /// * If there is no file which can be closed
     async fn close_file(&mut self) {
-        let to_close_key = self
-            .idle_files_queue
-            .pop_front()
-            .expect("There was no file to close! (idle_files was empty)");
+        let to_close_key = loop {
+            let (epoch, key) = self
+                .idle_files_queue
+                .pop_front()
+                .expect("There was no file to close! (idle_files was empty)");
+            if self.is_queued_idle(epoch, &key) {
+                break key;
+            }
+        };
         let FilePoolEntry {
             cursor,
             file: to_close,
+            backend,
+            ..
         } = self.idle_files.remove(&to_close_key).expect("unreachable!");
         let h = tokio_uring::spawn(async move {
-            // NOTE: dropping a `tokio_uring` file does not ensure all data is written to disk!
-            to_close.sync_all().await?;
-            to_close.close().await?;
+            backend.sync(&to_close).await?;
+            backend.close(to_close).await?;
             Ok(())
         });
 
@@ -112,10 +492,13 @@ impl FilePool {
     ///
     /// Dropping files must be done via the [`finish`](FilePool::finish) method
     ///
+    /// Fails if the file can't be opened, or if closing it earlier failed (such as when the disk filled up while flushing it).
+    /// In either case, the file is no longer part of this pool
+    ///
     /// Panics:
     /// * If the file is already taken
     /// * If taking this file would mean exceeding the `max_open_files` specified when creating this file pool
-    pub async fn take(&mut self, to_take: MsgKey) -> FilePoolEntry {
+    pub async fn take(&mut self, to_take: MsgKey) -> io::Result<FilePoolEntry<B>> {
         assert!(
             !self.taken_files.contains(&to_take),
             "Tried to take a file that was already taken!"
@@ -128,19 +511,23 @@ impl FilePool {
 
         if self.idle_files.contains_key(&to_take) {
             // This file is already open, just idle (not taken)
-
-            let queue_pos = self
-                .idle_files_queue
-                .iter()
-                .position(|k| k == &to_take)
-                .expect("unreachable!");
-            self.idle_files_queue.remove(queue_pos).unwrap();
+            // Its element of `idle_files_queue` is now stale, and will be skipped or compacted away later
 
             let f = self.idle_files.remove(&to_take).expect("unreachable!");
             assert!(self.taken_files.insert(to_take));
 
-            f
-        } else if self.inactive_files.contains_key(&to_take) {
+            return Ok(f);
+        }
+
+        let start = Instant::now();
+        let result = self.open(to_take).await;
+        self.open_close_time += start.elapsed();
+        result
+    }
+
+    /// Takes a file which isn't open, closing an idle file first if there's no room for it
+    async fn open(&mut self, to_take: MsgKey) -> io::Result<FilePoolEntry<B>> {
+        if self.inactive_files.contains_key(&to_take) {
             // This file needs to be re-opened
 
             if self.open_files() >= self.max_open_files {
@@ -153,13 +540,13 @@ impl FilePool {
             } = self.inactive_files.remove(&to_take).unwrap();
 
             // Make sure the file gets properly flushed before re-opening
-            closing_task.await.unwrap().unwrap();
+            closing_task.await.unwrap()?;
 
-            let path = to_take.path_to(&self.root);
-            let file = OpenOptions::new().write(true).open(path).await.unwrap();
-            let entry = FilePoolEntry { cursor, file };
+            let path = to_take.path_with_extension(&self.root, self.extension);
+            let file = self.open_retrying(&path, false).await?;
+            let entry = self.entry(cursor, file);
             assert!(self.taken_files.insert(to_take));
-            entry
+            Ok(entry)
         } else {
             // A new file must be created
 
@@ -167,34 +554,441 @@ impl FilePool {
                 self.close_file().await;
             }
 
-            let path = to_take.path_to(&self.root);
-            let file = File::create(path).await.unwrap();
-            let entry = FilePoolEntry { cursor: 0, file };
+            let path = to_take.path_with_extension(&self.root, self.extension);
+            let (cursor, file) = match self.existing_files {
+                ExistingFilePolicy::Truncate => (0, self.open_retrying(&path, true).await?),
+                ExistingFilePolicy::Append => {
+                    let cursor = self.backend.file_len(&path).await.map_err(|e| {
+                        io::Error::new(
+                            e.kind(),
+                            format!("Could not read metadata of {}: {e}", path.display()),
+                        )
+                    })?;
+                    (cursor as usize, self.open_retrying(&path, false).await?)
+                }
+            };
+            let entry = self.entry(cursor, file);
             assert!(self.taken_files.insert(to_take));
-            entry
+            Ok(entry)
         }
     }
     /// Gives this `FilePool` back ownership over a file.
     ///
+    /// If this pool [syncs every few gives](FilePool::with_sync_every) and this give is due,
+    /// every file given since the last sync is synced before returning.
+    /// The file is given back even if syncing fails, and the files which weren't synced yet are synced by the next sync that's due
+    ///
     /// Panics if `entry` is not currently taken
-    #[track_caller]
-    pub fn give(&mut self, key: MsgKey, entry: FilePoolEntry) {
+    pub async fn give(&mut self, key: MsgKey, mut entry: FilePoolEntry<B>) -> io::Result<()> {
         assert!(
             self.taken_files.remove(&key),
             "Tried to give file that was not taken!"
         );
 
         // NOTE: this operation will not change `self.open_files()`, since we are removing from `taken` and adding to `idle`
+        self.epoch += 1;
+        entry.given_at = self.epoch;
         assert!(self.idle_files.insert(key.clone(), entry).is_none());
-        self.idle_files_queue.push_back(key);
+        self.idle_files_queue.push_back((self.epoch, key.clone()));
+        // At most `max_open_files` elements aren't stale, so this drops at least half of the queue
+        if self.idle_files_queue.len() > 2 * self.max_open_files {
+            let mut queue = std::mem::take(&mut self.idle_files_queue);
+            queue.retain(|(epoch, key)| self.is_queued_idle(*epoch, key));
+            self.idle_files_queue = queue;
+        }
+
+        let Some(every) = self.sync_every else {
+            return Ok(());
+        };
+        self.unsynced.insert(key);
+        self.gives_since_sync += 1;
+        if self.gives_since_sync < every {
+            return Ok(());
+        }
+
+        self.gives_since_sync = 0;
+        // Each key is only removed once it's synced, so that a failed sync leaves the rest for the next one.
+        // Removed in place, since a new set wouldn't be seeded like the one it replaces
+        let keys = self.unsynced.iter().cloned().collect::<Vec<_>>();
+        for key in keys {
+            // Evicted files were already synced when they were closed
+            if let Some(entry) = self.idle_files.get(&key) {
+                self.backend.sync(&entry.file).await?;
+            }
+            self.unsynced.remove(&key);
+        }
+        Ok(())
+    }
+    /// Syncs and closes the file of `key` if it's open (or waits for it to be done closing),
+    /// so that everything written to it has reached the disk. Afterwards the key is no longer part of this pool,
+    /// and taking it again would create its file anew
+    ///
+    /// Panics if the file is taken
+    pub async fn close(&mut self, key: &MsgKey) -> io::Result<()> {
+        assert!(
+            !self.taken_files.contains(key),
+            "Tried to close a file that was taken!"
+        );
+        self.unsynced.remove(key);
+        // Its element of `idle_files_queue` is now stale
+        if let Some(entry) = self.idle_files.remove(key) {
+            self.backend.sync(&entry.file).await?;
+            return self.backend.close(entry.file).await;
+        }
+        match self.inactive_files.remove(key) {
+            Some(inactive) => inactive.closing_task.await.unwrap(),
+            None => Ok(()),
+        }
     }
-    pub async fn finish(&mut self) {
+    /// Writes `contents` to a new file at `path` which isn't managed by this pool, such as an index sidecar.
+    ///
+    /// The file is only open for the duration of this call, on top of the pool's limit
+    pub async fn write_unpooled(&self, path: &Path, contents: Vec<u8>) -> io::Result<()> {
+        let file = self.open_retrying(path, true).await?;
+        let mut entry = self.entry(0, file);
+        entry.write_all(contents).await?;
+        self.backend.sync(&entry.file).await?;
+        self.backend.close(entry.file).await
+    }
+    /// Removes the file at `path` which isn't managed by this pool, such as the other format's file of a key
+    pub async fn remove_unpooled(&self, path: &Path) -> io::Result<()> {
+        self.backend.remove(path).await
+    }
+    /// Syncs every open file of this pool, and waits for every closed file to be done closing (which syncs it too),
+    /// returning the files which couldn't be synced.
+    /// Unlike [`finish`](FilePool::finish), every file stays part of this pool
+    ///
+    /// Panics if any file is taken
+    pub async fn sync_all(&mut self) -> Vec<(MsgKey, io::Error)> {
+        assert!(
+            self.taken_files.is_empty(),
+            "Tried to sync while files are taken!"
+        );
+        let mut failed = vec![];
+        for (key, entry) in &self.idle_files {
+            if let Err(e) = self.backend.sync(&entry.file).await {
+                failed.push((key.clone(), e));
+            }
+        }
+        for (key, inactive) in &mut self.inactive_files {
+            let closing_task = std::mem::replace(
+                &mut inactive.closing_task,
+                tokio_uring::spawn(async { Ok(()) }),
+            );
+            if let Err(e) = closing_task.await.unwrap() {
+                // Taking the file later still fails with this error
+                let again = io::Error::new(e.kind(), e.to_string());
+                inactive.closing_task = tokio_uring::spawn(async { Err(again) });
+                failed.push((key.clone(), e));
+            }
+        }
+        self.unsynced.clear();
+        self.gives_since_sync = 0;
+        failed
+    }
+    /// Closes every file of this pool, returning the files which couldn't be flushed
+    pub async fn finish(&mut self) -> Vec<(MsgKey, io::Error)> {
         for _i in 0..self.idle_files.len() {
             self.close_file().await;
         }
-        for (_, entry) in self.inactive_files.drain() {
-            entry.closing_task.await.unwrap().unwrap();
+        let mut failed = vec![];
+        for (key, entry) in self.inactive_files.drain() {
+            if let Err(e) = entry.closing_task.await.unwrap() {
+                failed.push((key, e));
+            }
         }
         assert!(self.idle_files.is_empty());
+        failed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        hash::BuildHasher,
+        io::{self, ErrorKind},
+        path::PathBuf,
+        time::Duration,
+    };
+
+    use chrono::NaiveDate;
+
+    use crate::{
+        data::{hash_builder, MsgKey},
+        test_utils::MemBackend,
+    };
+
+    use super::{ExistingFilePolicy, FilePool, RetryPolicy};
+
+    #[test]
+    fn test_transient_classification() {
+        let policy = RetryPolicy::default();
+        for kind in [
+            ErrorKind::Interrupted,
+            ErrorKind::WouldBlock,
+            ErrorKind::TimedOut,
+        ] {
+            assert!(policy.is_transient(&kind.into()), "{kind:?}");
+        }
+        for kind in [
+            ErrorKind::StorageFull,
+            ErrorKind::WriteZero,
+            ErrorKind::PermissionDenied,
+            ErrorKind::NotFound,
+        ] {
+            assert!(!policy.is_transient(&kind.into()), "{kind:?}");
+        }
+
+        // Running out of space is never retried, even if asked to
+        let policy = RetryPolicy {
+            transient_kinds: vec![ErrorKind::StorageFull, ErrorKind::PermissionDenied],
+            ..Default::default()
+        };
+        assert!(!policy.is_transient(&ErrorKind::StorageFull.into()));
+        assert!(policy.is_transient(&ErrorKind::PermissionDenied.into()));
+    }
+
+    #[test]
+    fn test_retry_transient_errors() {
+        let backend = MemBackend::default();
+        let mut pool = FilePool::with_backend(
+            2,
+            PathBuf::from("/out"),
+            ExistingFilePolicy::Truncate,
+            backend.clone(),
+        )
+        .with_retry_policy(RetryPolicy {
+            attempts: 3,
+            base_delay: Duration::from_millis(1),
+            ..Default::default()
+        });
+        let key = MsgKey::new("a", NaiveDate::from_ymd_opt(2024, 10, 20).unwrap());
+        let path = key.path_to("/out".as_ref());
+
+        tokio_uring::start(async {
+            // Creating the file, then writing to it, each fail twice before succeeding
+            backend.fail_next(ErrorKind::Interrupted, 2);
+            let mut f = pool.take(key.clone()).await.unwrap();
+            backend.fail_next(ErrorKind::WouldBlock, 2);
+            f.write_all(b"first".to_vec()).await.unwrap();
+            assert_eq!(pool.retries(), 4);
+
+            // Out of attempts
+            backend.fail_next(ErrorKind::Interrupted, 3);
+            let e: io::Error = f.write_all(b"lost".to_vec()).await.unwrap_err();
+            assert_eq!(e.kind(), ErrorKind::Interrupted);
+            assert_eq!(pool.retries(), 6);
+
+            // Errors which aren't transient fail immediately
+            for kind in [ErrorKind::StorageFull, ErrorKind::PermissionDenied] {
+                backend.fail_next(kind, 1);
+                assert_eq!(
+                    f.write_all(b"lost".to_vec()).await.unwrap_err().kind(),
+                    kind
+                );
+            }
+            assert_eq!(pool.retries(), 6);
+
+            f.write_all(b" second".to_vec()).await.unwrap();
+            pool.give(key.clone(), f).await.unwrap();
+            assert!(pool.finish().await.is_empty());
+        });
+
+        assert_eq!(backend.contents(&path).unwrap(), b"first second");
+    }
+
+    #[test]
+    fn test_sync_every() {
+        let date = NaiveDate::from_ymd_opt(2024, 10, 20).unwrap();
+        let (a, b) = (MsgKey::new("a", date), MsgKey::new("b", date));
+
+        for (every, expected_syncs) in [(None, [0, 0, 0, 0]), (Some(2), [0, 2, 2, 3])] {
+            let backend = MemBackend::default();
+            let mut pool = FilePool::with_backend(
+                2,
+                PathBuf::from("/out"),
+                ExistingFilePolicy::Truncate,
+                backend.clone(),
+            );
+            if let Some(every) = every {
+                pool = pool.with_sync_every(every);
+            }
+
+            tokio_uring::start(async {
+                // Every due give syncs all the files given since the last sync, but only once each
+                for (key, expected) in [&a, &b, &a, &a].into_iter().zip(expected_syncs) {
+                    let mut f = pool.take(key.clone()).await.unwrap();
+                    f.write_all(b"line".to_vec()).await.unwrap();
+                    pool.give(key.clone(), f).await.unwrap();
+                    assert_eq!(backend.syncs(), expected, "{every:?}");
+                }
+                assert!(pool.finish().await.is_empty());
+            });
+            // Finishing always syncs
+            assert_eq!(backend.syncs(), expected_syncs[3] + 2);
+        }
+
+        // A failed sync leaves every file it didn't sync for the next sync that's due
+        let backend = MemBackend::default();
+        let mut pool = FilePool::with_backend(
+            2,
+            PathBuf::from("/out"),
+            ExistingFilePolicy::Truncate,
+            backend.clone(),
+        )
+        .with_sync_every(2);
+        tokio_uring::start(async {
+            let f = pool.take(a.clone()).await.unwrap();
+            pool.give(a.clone(), f).await.unwrap();
+            let f = pool.take(b.clone()).await.unwrap();
+            backend.fail_next(ErrorKind::Other, 1);
+            assert!(pool.give(b.clone(), f).await.is_err());
+            assert_eq!(backend.syncs(), 0);
+            assert_eq!(pool.unsynced.len(), 2);
+
+            let f = pool.take(a.clone()).await.unwrap();
+            pool.give(a.clone(), f).await.unwrap();
+            let f = pool.take(a.clone()).await.unwrap();
+            pool.give(a.clone(), f).await.unwrap();
+            assert_eq!(backend.syncs(), 2);
+            assert!(pool.unsynced.is_empty());
+            assert!(pool.finish().await.is_empty());
+        });
+
+        // The keys given since the last sync stay in a set with the run's seed
+        let mut pool = FilePool::with_backend(
+            2,
+            PathBuf::from("/out"),
+            ExistingFilePolicy::Truncate,
+            MemBackend::default(),
+        )
+        .with_hash_seed(7)
+        .with_sync_every(1);
+        tokio_uring::start(async {
+            let f = pool.take(a.clone()).await.unwrap();
+            pool.give(a.clone(), f).await.unwrap();
+            assert!(pool.unsynced.is_empty());
+            assert_eq!(
+                pool.unsynced.hasher().hash_one(&b),
+                hash_builder(7).hash_one(&b)
+            );
+            assert!(pool.finish().await.is_empty());
+        });
+    }
+
+    #[test]
+    fn test_sync_all() {
+        let date = NaiveDate::from_ymd_opt(2024, 10, 20).unwrap();
+        let [a, b, c] = ["a", "b", "c"].map(|s| MsgKey::new(s, date));
+
+        let backend = MemBackend::default();
+        let mut pool = FilePool::with_backend(
+            2,
+            PathBuf::from("/out"),
+            ExistingFilePolicy::Truncate,
+            backend.clone(),
+        );
+
+        tokio_uring::start(async {
+            for key in [&a, &b, &c] {
+                let mut f = pool.take(key.clone()).await.unwrap();
+                f.write_all(b"line".to_vec()).await.unwrap();
+                pool.give(key.clone(), f).await.unwrap();
+            }
+            // `a` was closed (and synced) to make room for `c`, and the others are synced now
+            assert!(pool.sync_all().await.is_empty());
+            assert_eq!(backend.syncs(), 3);
+            assert!(pool.sync_all().await.is_empty());
+            assert_eq!(backend.syncs(), 5);
+
+            // Every file is still part of the pool
+            let mut f = pool.take(a.clone()).await.unwrap();
+            f.write_all(b" more".to_vec()).await.unwrap();
+            pool.give(a.clone(), f).await.unwrap();
+            assert!(pool.finish().await.is_empty());
+        });
+        assert_eq!(backend.reopens(), 1);
+        assert_eq!(
+            backend.contents(&a.path_to("/out".as_ref())).unwrap(),
+            b"line more"
+        );
+    }
+
+    #[test]
+    fn test_close() {
+        let date = NaiveDate::from_ymd_opt(2024, 10, 20).unwrap();
+        let [a, b, c] = ["a", "b", "c"].map(|s| MsgKey::new(s, date));
+
+        let backend = MemBackend::default();
+        let mut pool = FilePool::with_backend(
+            1,
+            PathBuf::from("/out"),
+            ExistingFilePolicy::Truncate,
+            backend.clone(),
+        );
+
+        tokio_uring::start(async {
+            for key in [&a, &b] {
+                let mut f = pool.take(key.clone()).await.unwrap();
+                f.write_all(b"line".to_vec()).await.unwrap();
+                pool.give(key.clone(), f).await.unwrap();
+            }
+            // `a` was already being closed to make room for `b`, and `b` is synced as it's closed
+            pool.close(&a).await.unwrap();
+            pool.close(&b).await.unwrap();
+            assert_eq!(backend.syncs(), 2);
+            assert_eq!(pool.open_files(), 0);
+            // Keys without a file are left alone
+            pool.close(&c).await.unwrap();
+            assert!(pool.finish().await.is_empty());
+        });
+        assert_eq!(backend.syncs(), 2);
+        for key in [&a, &b] {
+            assert_eq!(
+                backend.contents(&key.path_to("/out".as_ref())).unwrap(),
+                b"line"
+            );
+        }
+    }
+
+    #[test]
+    fn test_evicts_least_recently_given() {
+        let date = NaiveDate::from_ymd_opt(2024, 10, 20).unwrap();
+        let [a, b, c] = ["a", "b", "c"].map(|s| MsgKey::new(s, date));
+
+        let backend = MemBackend::default();
+        let mut pool = FilePool::with_backend(
+            2,
+            PathBuf::from("/out"),
+            ExistingFilePolicy::Truncate,
+            backend.clone(),
+        );
+
+        tokio_uring::start(async {
+            for key in [&a, &b] {
+                let f = pool.take(key.clone()).await.unwrap();
+                pool.give(key.clone(), f).await.unwrap();
+            }
+            // Re-taking `a` many times makes it the most recent, and leaves plenty of stale elements behind
+            for _ in 0..100 {
+                let f = pool.take(a.clone()).await.unwrap();
+                pool.give(a.clone(), f).await.unwrap();
+            }
+            assert!(pool.idle_files_queue.len() <= 4);
+
+            // So `b` is the one closed to make room for `c`
+            let f = pool.take(c.clone()).await.unwrap();
+            pool.give(c.clone(), f).await.unwrap();
+            assert_eq!(backend.reopens(), 0);
+            let f = pool.take(a.clone()).await.unwrap();
+            pool.give(a.clone(), f).await.unwrap();
+            assert_eq!(backend.reopens(), 0);
+            let f = pool.take(b.clone()).await.unwrap();
+            pool.give(b.clone(), f).await.unwrap();
+            assert_eq!(backend.reopens(), 1);
+
+            assert!(pool.finish().await.is_empty());
+            assert!(pool.has_no_file_handles());
+        });
     }
 }
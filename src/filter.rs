@@ -0,0 +1,230 @@
+use std::str::FromStr;
+
+use chrono::NaiveDate;
+use json::JsonValue;
+
+/// A (possibly nested) json field, written dot-separated such as `@meta.user`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JsonPath(Vec<String>);
+
+impl JsonPath {
+    pub fn new(segments: &[&str]) -> Self {
+        Self(segments.iter().map(|s| s.to_string()).collect())
+    }
+
+    /// The value at this path, which is null if there is none
+    pub fn lookup<'a>(&self, info: &'a JsonValue) -> &'a JsonValue {
+        self.0.iter().fold(info, |v, p| &v[p.as_str()])
+    }
+
+    /// Removes the value at this path, returning whether there was one
+    pub fn remove(&self, info: &mut JsonValue) -> bool {
+        let (last, parents) = self.0.split_last().expect("Paths are never empty");
+        let mut v = info;
+        for p in parents {
+            // Indexing mutably would insert the missing fields instead
+            match v {
+                JsonValue::Object(obj) => match obj.get_mut(p) {
+                    Some(child) => v = child,
+                    None => return false,
+                },
+                _ => return false,
+            }
+        }
+        match v {
+            JsonValue::Object(obj) => obj.remove(last).is_some(),
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for JsonPath {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let segments = s.split('.').map(String::from).collect::<Vec<_>>();
+        if segments.iter().any(String::is_empty) {
+            return Err(format!("Json path `{s}` has an empty field name"));
+        }
+        Ok(Self(segments))
+    }
+}
+
+/// The part of a line a [`FilterTerm`] is compared against
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FilterField {
+    /// The `YYYY-MM-DD` date bucket the line's `@timestamp` falls into
+    Date,
+    Path(JsonPath),
+}
+
+/// A single `field=value` condition, as given on the command line with `--filter`
+///
+/// `field` is one of:
+/// * `service` - shorthand for `@meta.service`
+/// * `env` - shorthand for `@meta.env`
+/// * `date` - the `YYYY-MM-DD` date which the line is split by
+/// * Any other dot-separated json path, such as `level` or `@meta.user`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilterTerm {
+    field: FilterField,
+    value: String,
+}
+
+impl FromStr for FilterTerm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (field, value) = s
+            .split_once('=')
+            .ok_or_else(|| format!("Expected a filter of the form `field=value`, got `{s}`"))?;
+
+        let field = match field {
+            "" => return Err(format!("Filter `{s}` has an empty field name")),
+            "date" => {
+                NaiveDate::parse_from_str(value, "%Y-%m-%d").map_err(|e| {
+                    format!("Invalid date `{value}` in filter (expected YYYY-MM-DD): {e}")
+                })?;
+                FilterField::Date
+            }
+            "service" => FilterField::Path(JsonPath::new(&["@meta", "service"])),
+            "env" => FilterField::Path(JsonPath::new(&["@meta", "env"])),
+            path => FilterField::Path(path.parse()?),
+        };
+
+        Ok(Self {
+            field,
+            value: value.to_string(),
+        })
+    }
+}
+
+/// A set of [`FilterTerm`]s which decides which lines are kept.
+/// An empty filter keeps every line
+///
+/// Terms on the same field are OR-ed together, and terms on different fields are AND-ed,
+/// so `env=prod env=stg service=auth` keeps `auth` lines from either `prod` or `stg`
+#[derive(Debug, Clone, Default)]
+pub struct LineFilter {
+    terms: Vec<FilterTerm>,
+}
+
+impl LineFilter {
+    pub fn new(terms: Vec<FilterTerm>) -> Self {
+        Self { terms }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.terms.is_empty()
+    }
+
+    /// Returns `true` iff the line with the parsed json `info`, which falls on `date`, should be kept
+    pub fn matches(&self, info: &JsonValue, date: NaiveDate) -> bool {
+        self.matches_fields(info) && self.matches_date(date)
+    }
+
+    /// Returns `true` iff the line with the parsed json `info` is kept by the terms on its json fields.
+    ///
+    /// Unlike `date` terms, these don't need the line's date, so they can drop a line before its timestamp is parsed
+    pub fn matches_fields(&self, info: &JsonValue) -> bool {
+        self.groups_match(
+            |field| matches!(field, FilterField::Path(_)),
+            |t| match &t.field {
+                FilterField::Path(path) => match path.lookup(info) {
+                    v if v.is_string() => v.as_str() == Some(t.value.as_str()),
+                    v => !v.is_null() && v.dump() == t.value,
+                },
+                FilterField::Date => unreachable!(),
+            },
+        )
+    }
+
+    /// Returns `true` iff a line which falls on `date` is kept by the `date` terms
+    pub fn matches_date(&self, date: NaiveDate) -> bool {
+        if !self.terms.iter().any(|t| t.field == FilterField::Date) {
+            return true;
+        }
+        let date = date.format("%Y-%m-%d").to_string();
+        self.groups_match(|field| field == &FilterField::Date, |t| t.value == date)
+    }
+
+    /// Whether every group of terms on a field picked by `fields` has a term which `keeps` the line
+    fn groups_match(
+        &self,
+        fields: impl Fn(&FilterField) -> bool,
+        keeps: impl Fn(&FilterTerm) -> bool,
+    ) -> bool {
+        self.terms.iter().filter(|t| fields(&t.field)).all(|term| {
+            self.terms
+                .iter()
+                .filter(|t| t.field == term.field)
+                .any(&keeps)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use super::{FilterTerm, JsonPath, LineFilter};
+
+    fn filter(terms: &[&str]) -> LineFilter {
+        LineFilter::new(terms.iter().map(|t| t.parse().unwrap()).collect())
+    }
+
+    #[test]
+    fn test_filter_matching() {
+        let info = json::object! {
+            level: "info",
+            code: 200,
+            "@meta": { service: "auth", env: "prod" },
+        };
+        let day = NaiveDate::from_ymd_opt(2024, 10, 20).unwrap();
+
+        assert!(filter(&[]).matches(&info, day));
+        assert!(filter(&["service=auth", "date=2024-10-20"]).matches(&info, day));
+        assert!(filter(&["env=stg", "env=prod"]).matches(&info, day));
+        assert!(filter(&["level=info", "code=200", "@meta.env=prod"]).matches(&info, day));
+
+        assert!(!filter(&["service=auth", "date=2024-10-21"]).matches(&info, day));
+        assert!(!filter(&["env=stg"]).matches(&info, day));
+        assert!(!filter(&["@meta.user=alice"]).matches(&info, day));
+
+        // Each phase only checks its own terms
+        let mixed = filter(&["service=auth", "date=2024-10-21"]);
+        assert!(mixed.matches_fields(&info));
+        assert!(!mixed.matches_date(day));
+        assert!(!filter(&["service=web", "date=2024-10-20"]).matches_fields(&info));
+        assert!(filter(&["service=web"]).matches_date(day));
+    }
+
+    #[test]
+    fn test_filter_parse_errors() {
+        assert!("service".parse::<FilterTerm>().is_err());
+        assert!("=auth".parse::<FilterTerm>().is_err());
+        assert!("date=yesterday".parse::<FilterTerm>().is_err());
+        assert!("@meta..user=alice".parse::<FilterTerm>().is_err());
+    }
+
+    #[test]
+    fn test_remove_path() {
+        let mut info = json::object! {
+            level: "info",
+            "@meta": { service: "auth", user: "alice" },
+        };
+        let path = |s: &str| s.parse::<JsonPath>().unwrap();
+
+        assert!(path("@meta.user").remove(&mut info));
+        assert!(!path("@meta.user").remove(&mut info));
+        // Missing parents aren't created
+        assert!(!path("@other.user").remove(&mut info));
+        assert!(!path("level.user").remove(&mut info));
+        assert_eq!(
+            info,
+            json::object! { level: "info", "@meta": { service: "auth" } }
+        );
+        assert!(path("@meta").remove(&mut info));
+        assert_eq!(info, json::object! { level: "info" });
+    }
+}
@@ -0,0 +1,229 @@
+//! Line index sidecars, which allow jumping to a line of an output file without decompressing everything before it.
+//!
+//! When indexing is enabled, every `interval` lines of an output file start a fresh gzip member,
+//! and the `<key>.idx` sidecar next to it records where each of those members starts.
+//!
+//! # Format
+//!
+//! All integers are little-endian `u64`s.
+//! * Header: the magic bytes [`INDEX_MAGIC`], followed by the interval
+//! * Entries, one per index point, each made of three integers:
+//!   * The (0-based) number of the first line in the member
+//!   * The offset of that line in the decompressed output
+//!   * The offset in the compressed file at which the member starts
+//!
+//! The compressed offsets are used by [`open_at_line`] to skip decompressing earlier members,
+//! while the uncompressed offsets let [`skip_to_line`] jump ahead in an already decompressed stream
+
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use flate2::read::MultiGzDecoder;
+
+pub const INDEX_MAGIC: [u8; 8] = *b"LSIDX\0\0\x01";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IndexEntry {
+    pub line: u64,
+    pub uncompressed_offset: u64,
+    pub compressed_offset: u64,
+}
+
+/// A parsed index sidecar
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineIndex {
+    pub interval: u64,
+    pub entries: Vec<IndexEntry>,
+}
+
+impl LineIndex {
+    pub fn new(interval: u64) -> Self {
+        Self {
+            interval,
+            entries: vec![],
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut v = Vec::with_capacity(16 + self.entries.len() * 24);
+        v.extend_from_slice(&INDEX_MAGIC);
+        v.extend_from_slice(&self.interval.to_le_bytes());
+        for e in &self.entries {
+            v.extend_from_slice(&e.line.to_le_bytes());
+            v.extend_from_slice(&e.uncompressed_offset.to_le_bytes());
+            v.extend_from_slice(&e.compressed_offset.to_le_bytes());
+        }
+        v
+    }
+
+    pub fn from_bytes(b: &[u8]) -> io::Result<Self> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+
+        if b.len() < 16 || b[..8] != INDEX_MAGIC {
+            return Err(invalid("Not a line index (bad magic bytes)"));
+        }
+        if !(b.len() - 16).is_multiple_of(24) {
+            return Err(invalid("Line index has a truncated entry"));
+        }
+
+        let u64_at = |i: usize| u64::from_le_bytes(b[i..i + 8].try_into().unwrap());
+        Ok(Self {
+            interval: u64_at(8),
+            entries: (16..b.len())
+                .step_by(24)
+                .map(|i| IndexEntry {
+                    line: u64_at(i),
+                    uncompressed_offset: u64_at(i + 8),
+                    compressed_offset: u64_at(i + 16),
+                })
+                .collect(),
+        })
+    }
+
+    pub fn read(path: &Path) -> io::Result<Self> {
+        Self::from_bytes(&std::fs::read(path)?)
+    }
+
+    /// The last index point at or before `line`
+    pub fn entry_for_line(&self, line: u64) -> Option<IndexEntry> {
+        let i = self.entries.partition_point(|e| e.line <= line);
+        i.checked_sub(1).map(|i| self.entries[i])
+    }
+}
+
+/// Opens the output file at `path`, using its sidecar index at `index_path` to skip
+/// directly to the gzip member containing `line` (0-based).
+///
+/// The returned reader yields decompressed data starting at the beginning of `line`
+pub fn open_at_line(path: &Path, index_path: &Path, line: u64) -> io::Result<impl BufRead> {
+    let index = LineIndex::read(index_path)?;
+    let entry = entry_for_line(&index, line)?;
+
+    let mut f = File::open(path)?;
+    f.seek(SeekFrom::Start(entry.compressed_offset))?;
+    let mut r = BufReader::new(MultiGzDecoder::new(f));
+
+    skip_lines(&mut r, line - entry.line)?;
+    Ok(r)
+}
+
+/// Advances `r`, a reader over the decompressed content of an indexed file, to the beginning of `line` (0-based).
+///
+/// Everything up to the last index point before `line` is discarded in bulk, without looking for line breaks
+pub fn skip_to_line<R: BufRead>(mut r: R, index: &LineIndex, line: u64) -> io::Result<R> {
+    let entry = entry_for_line(index, line)?;
+
+    let skipped = io::copy(
+        &mut (&mut r).take(entry.uncompressed_offset),
+        &mut io::sink(),
+    )?;
+    if skipped < entry.uncompressed_offset {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+
+    skip_lines(&mut r, line - entry.line)?;
+    Ok(r)
+}
+
+fn entry_for_line(index: &LineIndex, line: u64) -> io::Result<IndexEntry> {
+    index
+        .entry_for_line(line)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Line index has no entries"))
+}
+
+/// Skips the lines between an index point and the requested line
+fn skip_lines(r: &mut impl BufRead, count: u64) -> io::Result<()> {
+    let mut skipped = vec![];
+    for _ in 0..count {
+        skipped.clear();
+        if r.read_until(b'\n', &mut skipped)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::read_lines;
+
+    use std::io::BufReader;
+
+    use flate2::read::MultiGzDecoder;
+
+    use super::{open_at_line, skip_to_line, IndexEntry, LineIndex};
+
+    #[test]
+    fn test_index_round_trip() {
+        let index = LineIndex {
+            interval: 3,
+            entries: vec![
+                IndexEntry {
+                    line: 0,
+                    uncompressed_offset: 0,
+                    compressed_offset: 0,
+                },
+                IndexEntry {
+                    line: 3,
+                    uncompressed_offset: 100,
+                    compressed_offset: 40,
+                },
+            ],
+        };
+        let parsed = LineIndex::from_bytes(&index.to_bytes()).unwrap();
+        assert_eq!(parsed, index);
+
+        assert_eq!(parsed.entry_for_line(2).unwrap().line, 0);
+        assert_eq!(parsed.entry_for_line(3).unwrap().line, 3);
+        assert_eq!(parsed.entry_for_line(1000).unwrap().line, 3);
+
+        assert!(LineIndex::from_bytes(b"garbage").is_err());
+        assert!(LineIndex::from_bytes(&index.to_bytes()[..30]).is_err());
+    }
+
+    #[test]
+    fn test_open_at_line() {
+        let tmp = tempdir::TempDir::new("logsplitter2").unwrap();
+        let path = tmp.path().join("out.json.gz");
+        let index_path = tmp.path().join("out.idx");
+
+        // Hand-build a file with members starting every 2 lines
+        let lines = (0..7).map(|i| format!("line {i}\n")).collect::<Vec<_>>();
+        let mut file = vec![];
+        let mut index = LineIndex::new(2);
+        let mut uncompressed = 0;
+        for (i, chunk) in lines.chunks(2).enumerate() {
+            index.entries.push(IndexEntry {
+                line: i as u64 * 2,
+                uncompressed_offset: uncompressed,
+                compressed_offset: file.len() as u64,
+            });
+            let mut enc = flate2::write::GzEncoder::new(&mut file, Default::default());
+            for ln in chunk {
+                std::io::Write::write_all(&mut enc, ln.as_bytes()).unwrap();
+                uncompressed += ln.len() as u64;
+            }
+            enc.finish().unwrap();
+        }
+        std::fs::write(&path, &file).unwrap();
+        std::fs::write(&index_path, index.to_bytes()).unwrap();
+
+        for n in 0..7 {
+            let expected = lines[n as usize..]
+                .iter()
+                .map(|l| l.trim_end().to_string())
+                .collect::<Vec<_>>();
+
+            let r = open_at_line(&path, &index_path, n).unwrap();
+            assert_eq!(read_lines(r), expected);
+
+            let decompressed = BufReader::new(MultiGzDecoder::new(&file[..]));
+            let r = skip_to_line(decompressed, &index, n).unwrap();
+            assert_eq!(read_lines(r), expected);
+        }
+        assert!(skip_to_line(&b"line 0\n"[..], &index, 5).is_err());
+    }
+}
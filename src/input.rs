@@ -1,30 +1,497 @@
 use std::{
-    collections::VecDeque,
+    borrow::Cow,
     io::{Read, Write},
+    ops::AddAssign,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::{Duration, Instant},
 };
 
-use flate2::write::MultiGzDecoder;
+use flate2::write::GzDecoder;
+use futures::Stream;
+use json::JsonValue;
 use kanal::{ReceiveError, Receiver, Sender};
+use memmap2::Mmap;
 use tokio_uring::fs::File;
 
-use crate::{byte_channel, data::LineData, ReadError};
+use crate::{
+    byte_channel::{self, BytesRx, TryRecv, WhenFull},
+    data::{default_key, InputName, KeyFn, LineData, MsgKey},
+    filter::LineFilter,
+    metrics::MetricsHandle,
+    Error, ReadError,
+};
+
+/// What happens to the last line of an input file if the file doesn't end with a newline
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TrailingLinePolicy {
+    /// The line is read like any other (`emit`)
+    #[default]
+    Emit,
+    /// The input ends with [`ReadError::MissingNewline`] instead, since the line may have been cut off (`reject`)
+    Reject,
+    /// The line is dropped, and the rest of the input is still read (`ignore`)
+    Ignore,
+}
+
+impl FromStr for TrailingLinePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "emit" => Ok(Self::Emit),
+            "reject" => Ok(Self::Reject),
+            "ignore" => Ok(Self::Ignore),
+            _ => Err(format!(
+                "Unknown trailing line policy `{s}`, expected `emit`, `reject` or `ignore`"
+            )),
+        }
+    }
+}
+
+/// What happens when an input's gzip data turns out to be corrupt
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum GzipErrorPolicy {
+    /// The input ends with [`ReadError::CorruptInput`] (`abort`)
+    #[default]
+    Abort,
+    /// The rest of the corrupt member is skipped, and reading resumes at the next gzip member header after
+    /// the start of it (`skip-to-next-member`), so that the valid members after a bad one are still read.
+    ///
+    /// This is lossy: the corrupt member's lines after the error are lost, and whatever bytes of it happen to look like
+    /// a member header are tried (and usually skipped) as well. Skipped members are counted in [`SkippedGzip`]
+    SkipToNextMember,
+}
+
+impl FromStr for GzipErrorPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "abort" => Ok(Self::Abort),
+            "skip-to-next-member" => Ok(Self::SkipToNextMember),
+            _ => Err(format!(
+                "Unknown gzip error policy `{s}`, expected `abort` or `skip-to-next-member`"
+            )),
+        }
+    }
+}
+
+/// How much corrupt gzip data was skipped with [`GzipErrorPolicy::SkipToNextMember`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SkippedGzip {
+    /// Every attempt at decoding a member which failed, including ones which weren't really member headers
+    pub members: u64,
+    /// The compressed bytes from the start of each failed member to where decoding resumed
+    pub bytes: u64,
+}
+
+impl AddAssign for SkippedGzip {
+    fn add_assign(&mut self, other: Self) {
+        self.members += other.members;
+        self.bytes += other.bytes;
+    }
+}
+
+/// Where the reader thread of a [`JsonLinesRecv`], and the thread iterating over it, spent their time
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct InputTimings {
+    /// Reading compressed bytes from the input files
+    pub read: Duration,
+    /// Decompressing the input, and splitting it into lines
+    pub decode: Duration,
+    /// Waiting for the iterating thread to take lines, while the channel of lines was full
+    pub send_wait: Duration,
+    /// Waiting for lines on the iterating thread, while the channel of lines was empty
+    pub recv_wait: Duration,
+    /// Parsing, filtering, and keying lines on the iterating thread
+    pub parse: Duration,
+}
+
+impl AddAssign for InputTimings {
+    fn add_assign(&mut self, other: Self) {
+        self.read += other.read;
+        self.decode += other.decode;
+        self.send_wait += other.send_wait;
+        self.recv_wait += other.recv_wait;
+        self.parse += other.parse;
+    }
+}
+
+/// [`InputTimings`] which are added to by both threads as lines are read, in nanoseconds.
+///
+/// Only waits and whole reads are timed, along with parsing each line, so this costs little next to the parsing itself
+#[derive(Debug, Default)]
+pub struct InputTimers {
+    read: AtomicU64,
+    /// Including the time spent in `send_wait`, which is only subtracted once it's read
+    decode: AtomicU64,
+    send_wait: AtomicU64,
+    recv_wait: AtomicU64,
+    parse: AtomicU64,
+}
+
+impl InputTimers {
+    fn add(timer: &AtomicU64, since: Instant) {
+        timer.fetch_add(since.elapsed().as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// The time measured so far
+    pub fn get(&self) -> InputTimings {
+        let get = |timer: &AtomicU64| Duration::from_nanos(timer.load(Ordering::Relaxed));
+        InputTimings {
+            read: get(&self.read),
+            decode: get(&self.decode).saturating_sub(get(&self.send_wait)),
+            send_wait: get(&self.send_wait),
+            recv_wait: get(&self.recv_wait),
+            parse: get(&self.parse),
+        }
+    }
+}
+
+/// Where [`read_input`] gets each input file from
+enum InputSource {
+    Opened(std::fs::File),
+    /// Opened only once the files before it are read, so that long lists of inputs don't run out of file descriptors
+    Path(PathBuf),
+    /// Read with blocking reads, rather than with `io_uring`
+    Reader(Box<dyn Read + Send>),
+}
 
 pub struct JsonLinesRecv {
-    rx_raw: Receiver<String>,
+    /// Lines, or an error which ends the input
+    rx_raw: Receiver<RawLine>,
+    filter: LineFilter,
+    key_fn: KeyFn,
+    skipped: Arc<Mutex<SkippedGzip>>,
+    timers: Arc<InputTimers>,
+    metrics: MetricsHandle,
+    stop: InputStop,
+}
+
+/// Stops the reader thread of a [`JsonLinesRecv`] from another thread, see [`JsonLinesRecv::stop_handle`]
+#[derive(Debug, Clone)]
+pub struct InputStop {
+    tx: Weak<Sender<RawLine>>,
+    /// Set by [`end_tail`](InputStop::end_tail)
+    tail_ended: Arc<AtomicBool>,
+}
+
+impl InputStop {
+    /// Closes the channel of lines, dropping any which were read but not received yet.
+    ///
+    /// The reader notices before decoding its next chunk (or right away, if it's waiting for room in the channel),
+    /// drops its decoder, and exits. Does nothing if the reader has already exited
+    pub fn stop(&self) {
+        if let Some(tx) = self.tx.upgrade() {
+            tx.close();
+        }
+    }
+
+    /// Makes a reader started by [`spawn_tail`](JsonLinesRecv::spawn_tail) finish once it next reaches the end of its input,
+    /// instead of waiting for more. Unlike [`stop`](InputStop::stop), every line written so far is still received,
+    /// and the input is checked like one which isn't tailed (so a gzip member which was cut off fails the input)
+    pub fn end_tail(&self) {
+        self.tail_ended.store(true, Ordering::Relaxed);
+    }
 }
 
 impl JsonLinesRecv {
     pub fn spawn_new(input: std::fs::File) -> Self {
-        let (tx, rx) = kanal::bounded::<String>(100);
+        Self::spawn(
+            vec![InputSource::Opened(input)],
+            Default::default(),
+            Default::default(),
+            false,
+            None,
+            MetricsHandle::default(),
+        )
+    }
+
+    /// Reads gzip data from any reader, such as stdin, a socket, or a `Cursor` over a buffer.
+    ///
+    /// The input is decoded on a plain thread with blocking reads, instead of with `io_uring` like files are.
+    /// Corrupt gzip data always ends the input, since the reader can't go back to look for the next member
+    pub fn from_reader<R: Read + Send + 'static>(r: R) -> Self {
+        Self::spawn(
+            vec![InputSource::Reader(Box::new(r))],
+            Default::default(),
+            GzipErrorPolicy::Abort,
+            false,
+            None,
+            MetricsHandle::default(),
+        )
+    }
 
-        std::thread::spawn(move || {
-            tokio_uring::start(async {
-                let input = File::from_std(input);
-                read_input(input, tx).await
+    /// Reads the files at `paths` one after another, as if they were a single input.
+    ///
+    /// A file which can't be opened ends the input with [`ReadError::Io`]
+    pub fn spawn_files(paths: Vec<PathBuf>) -> Self {
+        Self::spawn_files_with(paths, Default::default(), Default::default(), false)
+    }
+
+    /// Like [`spawn_files`](JsonLinesRecv::spawn_files), but the last line of each file is treated according to `trailing_line`
+    /// if the file doesn't end with a newline, and corrupt gzip data according to `gzip_errors`.
+    ///
+    /// With `mmap`, regular files are memory-mapped and decoded straight from memory instead of being read in small chunks,
+    /// which is faster for big local files. Other files (such as pipes) are read as usual
+    pub fn spawn_files_with(
+        paths: Vec<PathBuf>,
+        trailing_line: TrailingLinePolicy,
+        gzip_errors: GzipErrorPolicy,
+        mmap: bool,
+    ) -> Self {
+        Self::spawn_files_counted(
+            paths,
+            trailing_line,
+            gzip_errors,
+            mmap,
+            MetricsHandle::default(),
+        )
+    }
+
+    /// Like [`spawn_files_with`](JsonLinesRecv::spawn_files_with), but the reader adds to `metrics`
+    /// (such as those of a [`Splitter`](crate::splitter::Splitter::metrics)) instead of to metrics of its own
+    pub fn spawn_files_counted(
+        paths: Vec<PathBuf>,
+        trailing_line: TrailingLinePolicy,
+        gzip_errors: GzipErrorPolicy,
+        mmap: bool,
+        metrics: MetricsHandle,
+    ) -> Self {
+        Self::spawn(
+            paths.into_iter().map(InputSource::Path).collect(),
+            trailing_line,
+            gzip_errors,
+            mmap,
+            None,
+            metrics,
+        )
+    }
+
+    /// Like [`spawn_files_with`](JsonLinesRecv::spawn_files_with), but the last file is tailed: once the reader reaches its end,
+    /// it waits for more to be appended to it (checking every [`TAIL_POLL`]) rather than ending the input,
+    /// until [`InputStop::end_tail`] or [`InputStop::stop`] is called from a [`stop_handle`](JsonLinesRecv::stop_handle).
+    ///
+    /// The tailed file is always read rather than memory-mapped, since a mapping doesn't grow with the file.
+    /// Lines are received as soon as they're appended, even from a gzip member which isn't finished yet,
+    /// but ending the tail while the last member is cut off fails the input, so the producer has to write whole members.
+    /// Nothing notices if the file is truncated or replaced (such as by log rotation) while it's tailed.
+    ///
+    /// The lines can be split as they come in by giving this to [`Splitter::process`](crate::splitter::Splitter::process)
+    pub fn spawn_tail(
+        paths: Vec<PathBuf>,
+        trailing_line: TrailingLinePolicy,
+        gzip_errors: GzipErrorPolicy,
+    ) -> Self {
+        Self::spawn_tail_counted(
+            paths,
+            trailing_line,
+            gzip_errors,
+            Default::default(),
+            MetricsHandle::default(),
+        )
+    }
+
+    /// Like [`spawn_tail`](JsonLinesRecv::spawn_tail), but the tail also ends once `tail_ended` is set (such as by a signal handler),
+    /// and the reader adds to `metrics` like [`spawn_files_counted`](JsonLinesRecv::spawn_files_counted)
+    pub fn spawn_tail_counted(
+        paths: Vec<PathBuf>,
+        trailing_line: TrailingLinePolicy,
+        gzip_errors: GzipErrorPolicy,
+        tail_ended: Arc<AtomicBool>,
+        metrics: MetricsHandle,
+    ) -> Self {
+        Self::spawn(
+            paths.into_iter().map(InputSource::Path).collect(),
+            trailing_line,
+            gzip_errors,
+            false,
+            Some(tail_ended),
+            metrics,
+        )
+    }
+
+    /// With `tail_ended`, the last input is tailed until it's set, see [`spawn_tail`](JsonLinesRecv::spawn_tail)
+    fn spawn(
+        inputs: Vec<InputSource>,
+        trailing_line: TrailingLinePolicy,
+        gzip_errors: GzipErrorPolicy,
+        mmap: bool,
+        tail_ended: Option<Arc<AtomicBool>>,
+        metrics: MetricsHandle,
+    ) -> Self {
+        let (tx, rx) = kanal::bounded(100);
+        // Only the reader owns the sender, so that the channel still ends if the reader panics
+        let tx = Arc::new(tx);
+        let stop = InputStop {
+            tx: Arc::downgrade(&tx),
+            tail_ended: tail_ended.clone().unwrap_or_default(),
+        };
+        let skipped = Arc::new(Mutex::new(SkippedGzip::default()));
+        let timers = Arc::new(InputTimers::default());
+
+        let reader_skipped = skipped.clone();
+        let reader_timers = timers.clone();
+        let reader_metrics = metrics.clone();
+        // Readers never await anything, so they don't need a runtime of their own. A tail waits on the runtime's timer
+        let uring =
+            tail_ended.is_some() || inputs.iter().any(|i| !matches!(i, InputSource::Reader(_)));
+        std::thread::Builder::new()
+            .name("input-reader".to_string())
+            .spawn(move || {
+                let read = read_input(
+                    inputs,
+                    tx,
+                    trailing_line,
+                    gzip_errors,
+                    mmap,
+                    tail_ended,
+                    ReaderStats {
+                        skipped: &reader_skipped,
+                        timers: &reader_timers,
+                        metrics: &reader_metrics,
+                    },
+                );
+                match uring {
+                    true => tokio_uring::start(read),
+                    false => futures::executor::block_on(read),
+                }
             })
-        });
+            .expect("Could not spawn the input thread");
 
-        Self { rx_raw: rx }
+        Self {
+            rx_raw: rx,
+            filter: LineFilter::default(),
+            key_fn: Arc::new(default_key),
+            skipped,
+            timers,
+            metrics,
+            stop,
+        }
+    }
+
+    /// Stops reading the input, see [`InputStop::stop`].
+    /// Dropping the receiver stops the reader as well, so this is only needed while it's still around
+    pub fn stop(&self) {
+        self.stop.stop();
+    }
+
+    /// A handle which stops reading the input from elsewhere (such as a signal handler)
+    /// while this receiver is being iterated over
+    pub fn stop_handle(&self) -> InputStop {
+        self.stop.clone()
+    }
+
+    /// The corrupt gzip data which has been skipped so far, which is updated as the input is read.
+    /// Only ever non-zero with [`GzipErrorPolicy::SkipToNextMember`]
+    pub fn skipped_gzip(&self) -> Arc<Mutex<SkippedGzip>> {
+        self.skipped.clone()
+    }
+
+    /// Where the reader thread and the iterating thread have spent their time so far.
+    /// With [`into_stream`](JsonLinesRecv::into_stream), the waits are the time spent awaiting lines
+    pub fn timers(&self) -> Arc<InputTimers> {
+        self.timers.clone()
+    }
+
+    /// The metrics which the reader thread adds to as it reads, such as how much of the input it has read.
+    /// Can be taken before iterating, which consumes this receiver
+    pub fn metrics(&self) -> MetricsHandle {
+        self.metrics.clone()
+    }
+
+    /// Only yields the lines which `filter` keeps
+    pub fn with_filter(mut self, filter: LineFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Keys lines with `key_fn` instead of [`default_key`]
+    pub fn with_key_fn(mut self, key_fn: KeyFn) -> Self {
+        self.key_fn = key_fn;
+        self
+    }
+
+    /// The decoded text of every line, without parsing them, so that reading and decoding can be measured on their own.
+    /// The filter and key function are ignored
+    #[cfg(feature = "bench-internals")]
+    pub fn into_raw_lines(self) -> impl Iterator<Item = Result<String, ReadError>> {
+        self.rx_raw.map(|ln| ln.map(|(ln, _)| ln))
+    }
+
+    /// Yields the same items as iterating over this receiver, but awaits new lines instead of blocking.
+    ///
+    /// Lines are parsed inline when the stream is polled
+    pub fn into_stream(self) -> impl Stream<Item = Result<LineData, ReadError>> {
+        let JsonLinesRecv {
+            rx_raw,
+            filter,
+            key_fn,
+            timers,
+            ..
+        } = self;
+
+        futures::stream::unfold(
+            (rx_raw.to_async(), filter, key_fn, timers),
+            |(rx_raw, filter, key_fn, timers)| async move {
+                loop {
+                    let received = match rx_raw.as_sync().try_recv() {
+                        Ok(Some(ln)) => Ok(ln),
+                        Ok(None) => {
+                            let start = Instant::now();
+                            let received = rx_raw.recv().await;
+                            InputTimers::add(&timers.recv_wait, start);
+                            received
+                        }
+                        Err(e) => Err(e),
+                    };
+                    let (ln, input) = match received.ok()? {
+                        Ok(ln) => ln,
+                        Err(e) => return Some((Err(e), (rx_raw, filter, key_fn, timers))),
+                    };
+
+                    let start = Instant::now();
+                    let data = parse_line(ln, input, &*key_fn, &filter);
+                    InputTimers::add(&timers.parse, start);
+                    match data {
+                        Ok(Some(s)) => return Some((Ok(s), (rx_raw, filter, key_fn, timers))),
+                        // Filtered out
+                        Ok(None) => continue,
+                        Err(ReadError::EndOfInputReached) => return None,
+                        Err(e) => return Some((Err(e), (rx_raw, filter, key_fn, timers))),
+                    }
+                }
+            },
+        )
+    }
+}
+
+/// A decoded line and the input it was read from, or an error which ends the input
+type RawLine = Result<(String, InputName), ReadError>;
+
+/// Parses a line of `input`, tagging the line (or the reason it's rejected) with its input
+fn parse_line(
+    ln: String,
+    input: InputName,
+    key_fn: &dyn Fn(&JsonValue) -> Option<MsgKey>,
+    filter: &LineFilter,
+) -> Result<Option<LineData>, ReadError> {
+    match LineData::parse_with(ln, key_fn, filter) {
+        Ok(data) => Ok(data.map(|data| data.with_source(input))),
+        Err(ReadError::InvalidLine { line, .. }) => Err(ReadError::InvalidLine {
+            line,
+            input: Some(input),
+        }),
+        Err(ReadError::NoKey { line, .. }) => Err(ReadError::NoKey {
+            line,
+            input: Some(input),
+        }),
+        Err(e) => Err(e),
     }
 }
 
@@ -32,89 +499,509 @@ impl Iterator for JsonLinesRecv {
     type Item = Result<LineData, ReadError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let ln = match self.rx_raw.recv() {
-            Ok(s) => s,
-            Err(ReceiveError::Closed) | Err(ReceiveError::SendClosed) => return None,
-        };
-        let data = LineData::parse(&ln);
+        loop {
+            // Only waits are timed, so that lines which are already queued cost nothing extra
+            let received = match self.rx_raw.try_recv() {
+                Ok(Some(ln)) => Ok(ln),
+                Ok(None) => {
+                    let start = Instant::now();
+                    let received = self.rx_raw.recv();
+                    InputTimers::add(&self.timers.recv_wait, start);
+                    received
+                }
+                Err(e) => Err(e),
+            };
+            let (ln, input) = match received {
+                Ok(Ok(ln)) => ln,
+                Ok(Err(e)) => return Some(Err(e)),
+                Err(ReceiveError::Closed) | Err(ReceiveError::SendClosed) => return None,
+            };
+            let start = Instant::now();
+            let data = parse_line(ln, input, &*self.key_fn, &self.filter);
+            InputTimers::add(&self.timers.parse, start);
+
+            match data {
+                Ok(Some(s)) => return Some(Ok(s)),
+                // Filtered out
+                Ok(None) => continue,
+                Err(ReadError::EndOfInputReached) => return None,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
 
-        match data {
-            Ok(s) => Some(Ok(s)),
-            Err(ReadError::EndOfInputReached) => None,
-            Err(e) => Some(Err(e)),
+    /// Every line which is already queued is yielded (as a line or an error), unless it may be filtered out
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match self.filter.is_empty() {
+            true => (self.rx_raw.len(), None),
+            false => (0, None),
         }
     }
 }
 
-struct FileRead {
-    f: File,
-    cursor: u64,
+/// How long a tailed input waits for more to be appended to it, see [`JsonLinesRecv::spawn_tail`]
+pub const TAIL_POLL: Duration = Duration::from_millis(100);
+
+/// How much of an input is decoded at once. Deflate expands by at most about 1000x,
+/// so this can't decode to more than the 8 MiB buffer of [`read_file`]
+const READ_CHUNK: usize = 1024;
+
+/// Where [`read_file`] reads an input from
+enum FileRead<'a> {
+    /// Read a chunk at a time with `io_uring`
+    Uring { f: File, cursor: u64 },
+    /// Decoded straight from the mapped file, see [`map_input`]
+    Mapped { data: &'a [u8], cursor: u64 },
+    /// Read with blocking reads, see [`JsonLinesRecv::from_reader`]
+    Stream {
+        r: Box<dyn Read + Send>,
+        cursor: u64,
+    },
+}
+
+impl<'a> FileRead<'a> {
+    fn cursor(&self) -> u64 {
+        match self {
+            FileRead::Uring { cursor, .. }
+            | FileRead::Mapped { cursor, .. }
+            | FileRead::Stream { cursor, .. } => *cursor,
+        }
+    }
+
+    fn seek(&mut self, to: u64) {
+        match self {
+            FileRead::Uring { cursor, .. }
+            | FileRead::Mapped { cursor, .. }
+            | FileRead::Stream { cursor, .. } => *cursor = to,
+        }
+    }
+
+    /// The next chunk of the input, which is empty at the end of it.
+    /// Mapped chunks are borrowed from the mapping, rather than from `self`
+    pub async fn read_next(&mut self) -> std::io::Result<Cow<'a, [u8]>> {
+        match self {
+            FileRead::Uring { f, cursor } => {
+                let v = vec![0; READ_CHUNK];
+                let (written, mut v) = f.read_at(v, *cursor).await;
+                let written = written?;
+                v.truncate(written);
+                *cursor += written as u64;
+                Ok(Cow::Owned(v))
+            }
+            FileRead::Mapped { data, cursor } => {
+                let data: &'a [u8] = data;
+                let start = (*cursor as usize).min(data.len());
+                let chunk = &data[start..(start + READ_CHUNK).min(data.len())];
+                *cursor += chunk.len() as u64;
+                Ok(Cow::Borrowed(chunk))
+            }
+            FileRead::Stream { r, cursor } => {
+                let mut v = vec![0; READ_CHUNK];
+                let read = loop {
+                    match r.read(&mut v) {
+                        Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                        read => break read?,
+                    }
+                };
+                v.truncate(read);
+                *cursor += read as u64;
+                Ok(Cow::Owned(v))
+            }
+        }
+    }
+
+    /// The offset of the first gzip member header at or after `from`, or the end of the file if there's none.
+    /// Doesn't move the cursor
+    pub async fn find_member(&self, mut from: u64) -> std::io::Result<u64> {
+        // The magic bytes and the deflate compression method, since the magic bytes alone often show up in deflate data
+        const HEADER: [u8; 3] = [GZIP_MAGIC[0], GZIP_MAGIC[1], 8];
+        let f = match self {
+            FileRead::Uring { f, .. } => f,
+            FileRead::Stream { .. } => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    "can't look for the next gzip member of a stream",
+                ))
+            }
+            FileRead::Mapped { data, .. } => {
+                let start = (from as usize).min(data.len());
+                return Ok(
+                    match data[start..]
+                        .windows(HEADER.len())
+                        .position(|w| w == HEADER)
+                    {
+                        Some(i) => (start + i) as u64,
+                        None => data.len() as u64,
+                    },
+                );
+            }
+        };
+        loop {
+            let (read, v) = f.read_at(vec![0; 64 << 10], from).await;
+            let read = read?;
+            if let Some(i) = v[..read].windows(HEADER.len()).position(|w| w == HEADER) {
+                return Ok(from + i as u64);
+            }
+            if read < HEADER.len() {
+                return Ok(from + read as u64);
+            }
+            // A header may be split across reads
+            from += (read - (HEADER.len() - 1)) as u64;
+        }
+    }
 }
 
-impl FileRead {
-    pub async fn read_next(&mut self) -> std::io::Result<Vec<u8>> {
-        let v = vec![0; 1024];
-        let (written, mut v) = self.f.read_at(v, self.cursor).await;
-        let written = written?;
-        v.truncate(written);
-        self.cursor += written as u64;
-        Ok(v)
+/// Maps `f` into memory, or returns `None` if it isn't a regular file (such as a pipe), which can't be mapped
+fn map_input(f: &std::fs::File) -> std::io::Result<Option<Mmap>> {
+    if !f.metadata()?.is_file() {
+        return Ok(None);
     }
+    // SAFETY: the input isn't expected to change while it's split. If it's truncated anyway, reading past its new end
+    // raises `SIGBUS`, which is no worse than splitting a file which is being rewritten
+    let map = unsafe { Mmap::map(f)? };
+    // The input is read from start to end exactly once
+    let _ = map.advise(memmap2::Advice::Sequential);
+    Ok(Some(map))
 }
 
-async fn read_input(input: File, tx: Sender<String>) {
-    let mut input = FileRead {
-        f: input,
-        cursor: 0,
+/// Sends every line of each input in turn, then drops `tx`.
+/// With `tail_ended`, the last input is tailed until it's set, see [`JsonLinesRecv::spawn_tail`]
+async fn read_input(
+    inputs: Vec<InputSource>,
+    tx: Arc<Sender<RawLine>>,
+    trailing_line: TrailingLinePolicy,
+    gzip_errors: GzipErrorPolicy,
+    mmap: bool,
+    tail_ended: Option<Arc<AtomicBool>>,
+    stats: ReaderStats<'_>,
+) {
+    let last = inputs.len().saturating_sub(1);
+    for (i, input) in inputs.into_iter().enumerate() {
+        let tail = tail_ended.as_deref().filter(|_| i == last);
+        // Mapped inputs borrow from the mapping, which has to outlive them
+        let map;
+        let (input, name) = match input {
+            InputSource::Reader(r) => (
+                FileRead::Stream { r, cursor: 0 },
+                InputName::from("The input"),
+            ),
+            InputSource::Opened(_) | InputSource::Path(_) => {
+                let (f, name) = match input {
+                    InputSource::Path(path) => match std::fs::File::open(&path) {
+                        Ok(f) => (f, InputName::from(path.display().to_string())),
+                        Err(e) => {
+                            let e = ReadError::Io(format!("{}: {e}", path.display()));
+                            // Nothing more is read either way
+                            let _ = tx.send(Err(e));
+                            break;
+                        }
+                    },
+                    InputSource::Opened(f) => (f, InputName::from("The input")),
+                    InputSource::Reader(_) => unreachable!(),
+                };
+                map = match (mmap && tail.is_none()).then(|| map_input(&f)).transpose() {
+                    Ok(map) => map.flatten(),
+                    Err(e) => {
+                        let _ = tx.send(Err(ReadError::Io(format!("{name}: {e}"))));
+                        break;
+                    }
+                };
+                let input = match &map {
+                    Some(map) => FileRead::Mapped {
+                        data: map,
+                        cursor: 0,
+                    },
+                    None => FileRead::Uring {
+                        f: File::from_std(f),
+                        cursor: 0,
+                    },
+                };
+                (input, name)
+            }
+        };
+        match read_file(input, &name, &tx, trailing_line, gzip_errors, tail, stats).await {
+            Ok(true) => {}
+            // A stopped or dropped receiver means the run stopped early, so the rest of the input isn't needed
+            Ok(false) => return,
+            Err(e) => {
+                let _ = tx.send(Err(e));
+                break;
+            }
+        }
+    }
+
+    // Dropping the only sender ends the channel once the lines in it are received, unlike closing it,
+    // which would drop them
+    drop(tx);
+}
+
+/// The first two bytes of every gzip member
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// How many bytes of an input which isn't gzip are shown in [`ReadError::NotGzip`]
+const NOT_GZIP_SNIPPET: usize = 16;
+
+/// Sends every line of `input` (which is called `name` in errors), returning `false` if the receiver is gone or stopped.
+///
+/// Fails without sending anything if `input` isn't empty but doesn't start like gzip,
+/// which the decoder would otherwise only notice by failing to decode anything at all.
+/// With [`TrailingLinePolicy::Reject`], also fails after sending every other line if the last one has no newline.
+/// Corrupt gzip data fails, or is skipped and added to `stats`, depending on `gzip_errors`.
+/// Where the time goes and how far the input has gotten are added to `stats` as well.
+///
+/// With `tail`, the end of `input` only ends it once `tail` is set, see [`JsonLinesRecv::spawn_tail`]
+#[allow(clippy::too_many_arguments)]
+async fn read_file(
+    mut input: FileRead<'_>,
+    name: &InputName,
+    tx: &Sender<RawLine>,
+    trailing_line: TrailingLinePolicy,
+    gzip_errors: GzipErrorPolicy,
+    tail: Option<&AtomicBool>,
+    stats: ReaderStats<'_>,
+) -> Result<bool, ReadError> {
+    let timers = stats.timers;
+    let io_error = |e: std::io::Error| ReadError::Io(format!("{name}: {e}"));
+    let corrupt = |compressed_offset, detail| ReadError::CorruptInput {
+        input: name.to_string(),
+        compressed_offset,
+        detail,
     };
-    let (tx_decoded, mut rx_decoded) = byte_channel::bounded(100);
+    // Drained by this same thread after every write, which is big enough for anything a `READ_CHUNK` decodes to
+    let (mut tx_decoded, mut rx_decoded) = byte_channel::bounded_with(8 << 20, WhenFull::Error);
 
-    let mut dec = MultiGzDecoder::new(tx_decoded);
-    let mut curr_line = String::new();
+    // Members are decoded one at a time, rather than by a `MultiGzDecoder`, so that where each one starts is known
+    let mut dec = GzDecoder::new(&mut tx_decoded);
+    // Whether `dec` has been given any bytes, since a member which was started has to be finished
+    let mut fed = false;
+    // Where the member being decoded starts in the file
+    let mut member_start = 0;
+    // Bytes rather than chars, since a multi-byte character may be split across reads
+    let mut curr_line = vec![];
+    // How much of the file is counted as read, which a skip past a corrupt member may go back from
+    let mut counted = 0;
 
-    loop {
-        let to_decode = input.read_next().await.unwrap();
-        dec.write_all(&to_decode).unwrap();
+    'read: loop {
+        // A reader with nothing to send wouldn't notice a stopped or dropped receiver otherwise
+        if tx.is_disconnected() {
+            return Ok(false);
+        }
+        // Checked before reading, so that whatever was appended before the tail was ended is still read
+        let tail_ended = tail.map(|t| t.load(Ordering::Relaxed));
+        let read_start = input.cursor();
+        let start = Instant::now();
+        let to_decode = input.read_next().await.map_err(io_error)?;
+        InputTimers::add(&timers.read, start);
+        if input.cursor() > counted {
+            let read = input.cursor() - counted;
+            stats.metrics.add_bytes_read(read);
+            counted = input.cursor();
+        }
+        let start = Instant::now();
+        // A file shorter than the magic bytes is checked against as much of them as it has
+        if read_start == 0 && !GZIP_MAGIC.starts_with(&to_decode[..to_decode.len().min(2)]) {
+            return Err(ReadError::NotGzip {
+                input: name.to_string(),
+                start: to_decode[..to_decode.len().min(NOT_GZIP_SNIPPET)].to_vec(),
+            });
+        }
 
-        if to_decode.len() == 0 {
-            dec.flush().unwrap();
+        if to_decode.is_empty() && tail_ended == Some(false) {
+            // The decoder holds on to what it decoded last until it's written to again, or flushed
+            if fed {
+                dec.flush()
+                    .map_err(|e| corrupt(read_start, e.to_string()))?;
+            }
+            if !send_lines(&mut rx_decoded, &mut curr_line, name, tx, stats) {
+                return Ok(false);
+            }
+            tokio::time::sleep(TAIL_POLL).await;
+            continue;
+        }
 
-            // Duplicated
-            while let Some(b) = rx_decoded.try_recv() {
-                if b == b'\n' {
-                    tx.send(curr_line).unwrap();
-                    curr_line = String::new();
-                } else {
-                    // Don't include newlines in the json
-                    curr_line.push(b as char);
+        if to_decode.is_empty() {
+            // Finishing checks that the last member is whole, which a file cut off mid-member isn't
+            let flushed = match fed {
+                true => dec.try_finish(),
+                false => Ok(()),
+            };
+            drop(dec);
+            if !send_lines(&mut rx_decoded, &mut curr_line, name, tx, stats) {
+                return Ok(false);
+            }
+            if let Err(e) = flushed {
+                if gzip_errors == GzipErrorPolicy::Abort {
+                    let detail = format!("{e}, so the input may have been cut off");
+                    return Err(corrupt(read_start, detail));
                 }
+                // There's no next member to skip to
+                skip_member(name, &e, member_start, read_start, stats.skipped);
+                return Ok(true);
             }
 
-            if curr_line.len() > 0 {
-                tx.send(curr_line).unwrap();
+            // A last line without a newline doesn't run into the first line of the next input
+            if !curr_line.is_empty() {
+                match trailing_line {
+                    TrailingLinePolicy::Emit => {
+                        curr_line.push(b'\n');
+                        stats.metrics.line_read(curr_line.len());
+                        if tx.send(line_text(curr_line, name)).is_err() {
+                            return Ok(false);
+                        }
+                    }
+                    TrailingLinePolicy::Reject => {
+                        return Err(ReadError::MissingNewline(name.to_string()))
+                    }
+                    TrailingLinePolicy::Ignore => {}
+                }
             }
-            loop {
-                if tx.is_empty() {
-                    tx.close();
-                    return;
+            return Ok(true);
+        }
+
+        let mut pos = 0;
+        while pos < to_decode.len() {
+            // A finished member takes no more bytes, after which its checksum is checked
+            let written = dec.write(&to_decode[pos..]).and_then(|n| {
+                if n == 0 {
+                    dec.try_finish().map(|_| n)
+                } else {
+                    Ok(n)
+                }
+            });
+            match written {
+                Ok(0) => {
+                    drop(dec);
+                    dec = GzDecoder::new(&mut tx_decoded);
+                    fed = false;
+                    member_start = read_start + pos as u64;
+                }
+                Ok(n) => {
+                    pos += n;
+                    fed = true;
+                }
+                Err(e) => {
+                    drop(dec);
+                    // Whole lines decoded before the error are kept, but not the one it was cut off in
+                    if !send_lines(&mut rx_decoded, &mut curr_line, name, tx, stats) {
+                        return Ok(false);
+                    }
+                    if gzip_errors == GzipErrorPolicy::Abort {
+                        return Err(corrupt(read_start + pos as u64, e.to_string()));
+                    }
+                    curr_line.clear();
+                    let next = input
+                        .find_member(member_start + 1)
+                        .await
+                        .map_err(io_error)?;
+                    skip_member(name, &e, member_start, next, stats.skipped);
+                    input.seek(next);
+                    dec = GzDecoder::new(&mut tx_decoded);
+                    fed = false;
+                    member_start = next;
+                    continue 'read;
                 }
             }
         }
 
-        // Duplicated
-        while let Some(b) = rx_decoded.try_recv() {
-            if b == b'\n' {
-                tx.send(curr_line).unwrap();
-                curr_line = String::new();
-            } else {
-                // Don't include newlines in the json
-                curr_line.push(b as char);
+        if !send_lines(&mut rx_decoded, &mut curr_line, name, tx, stats) {
+            return Ok(false);
+        }
+        // Reads which end in an error or the end of the file are left out, since there are few of them
+        InputTimers::add(&timers.decode, start);
+    }
+}
+
+/// What the reader thread records as it goes, which its [`JsonLinesRecv`] reads from
+#[derive(Clone, Copy)]
+struct ReaderStats<'a> {
+    skipped: &'a Mutex<SkippedGzip>,
+    timers: &'a InputTimers,
+    metrics: &'a MetricsHandle,
+}
+
+/// Sends every whole line of `name` which has been decoded so far, leaving the start of the next one in `curr_line`.
+/// Returns `false` if the receiver is gone
+fn send_lines(
+    rx_decoded: &mut BytesRx,
+    curr_line: &mut Vec<u8>,
+    name: &InputName,
+    tx: &Sender<RawLine>,
+    stats: ReaderStats<'_>,
+) -> bool {
+    while let TryRecv::Ready(b) = rx_decoded.try_recv() {
+        curr_line.push(b);
+        if b == b'\n' {
+            stats.metrics.line_read(curr_line.len());
+            // The newline is kept, so that `LineData` can reuse this buffer as-is
+            let mut msg = Some(line_text(std::mem::take(curr_line), name));
+            let sent = match tx.try_send_option(&mut msg) {
+                Ok(false) => {
+                    let start = Instant::now();
+                    let sent = tx.send(msg.take().unwrap());
+                    InputTimers::add(&stats.timers.send_wait, start);
+                    stats.metrics.input_stalled();
+                    sent
+                }
+                sent => sent.map(|_| ()),
+            };
+            if sent.is_err() {
+                return false;
             }
         }
     }
+    true
+}
+
+/// Records that the corrupt member at `start` of `name` was skipped up to `next`, which is where decoding resumes
+fn skip_member(
+    name: &str,
+    e: &std::io::Error,
+    start: u64,
+    next: u64,
+    skipped: &Mutex<SkippedGzip>,
+) {
+    eprintln!(
+        "Warning: {name}: skipped {} bytes of a corrupt gzip member at offset {start} ({e})",
+        next - start
+    );
+    let mut skipped = skipped.lock().unwrap();
+    skipped.members += 1;
+    skipped.bytes += next - start;
+}
+
+/// The text of a line of decoded bytes from `input`, which is an invalid line if it isn't UTF-8
+fn line_text(line: Vec<u8>, input: &InputName) -> RawLine {
+    match String::from_utf8(line) {
+        Ok(line) => Ok((line, input.clone())),
+        Err(e) => Err(ReadError::InvalidLine {
+            line: String::from_utf8_lossy(e.as_bytes()).trim_end().to_string(),
+            input: Some(input.clone()),
+        }),
+    }
+}
+
+/// Reads a list of input paths, one per line, like the `--input-list` of a batch job.
+///
+/// Blank lines and lines starting with `#` are skipped. Relative paths are relative to the working directory
+pub fn read_input_list(list: &Path) -> Result<Vec<PathBuf>, Error> {
+    let text = std::fs::read_to_string(list).map_err(|e| Error::io(list, e))?;
+    let paths = text
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(PathBuf::from)
+        .collect::<Vec<_>>();
+    if paths.is_empty() {
+        return Err(Error::io(
+            list,
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "Lists no input files"),
+        ));
+    }
+    Ok(paths)
 }
 
 /// Sends every line of the input `.json.gz` file until all lines have been read from `tx`, then `tx` is closed
+#[allow(dead_code, unused_variables)]
 async fn reading_input(input: File, tx: Sender<String>) {
     todo!()
     // // Sender for the raw file data
@@ -196,3 +1083,492 @@ async fn reading_input(input: File, tx: Sender<String>) {
     //     }
     // }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Cursor, Write},
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    use flate2::{write::GzEncoder, Compression};
+    use futures::StreamExt;
+    use tempdir::TempDir;
+
+    use crate::{
+        data::LineData,
+        test_utils::{gz_reader, line, write_input},
+        testdata_gen::{generate_testdata, TestdataCfg},
+        ErrorKind, ReadError,
+    };
+
+    use super::{
+        read_input_list, GzipErrorPolicy, InputTimers, JsonLinesRecv, SkippedGzip,
+        TrailingLinePolicy, TAIL_POLL,
+    };
+
+    #[test]
+    fn test_stop() {
+        let tmp = TempDir::new("logsplitter2").unwrap();
+        let path = tmp.path().join("input.json.gz");
+        // Many more lines than fit into the channel, so that the reader has to wait for room
+        let lines = (0..2000)
+            .map(|i| line("a", &i.to_string()))
+            .collect::<Vec<_>>();
+        write_input(&path, &lines);
+
+        // The reader thread holds on to the timers until it exits, as does the receiver (if it's `alive`)
+        let reader_exits = |timers: &Arc<InputTimers>, alive: bool| {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while Arc::strong_count(timers) > 1 + alive as usize {
+                assert!(Instant::now() < deadline, "The reader thread didn't exit");
+                std::thread::sleep(Duration::from_millis(5));
+            }
+        };
+
+        // Stopped while the reader waits for room in the channel, from another handle
+        let mut recv = JsonLinesRecv::spawn_files(vec![path.clone()]);
+        let timers = recv.timers();
+        assert_eq!(recv.by_ref().take(5).count(), 5);
+        recv.stop_handle().stop();
+        reader_exits(&timers, true);
+        assert_eq!(recv.count(), 0);
+
+        // Stopped by the receiver itself
+        let mut recv = JsonLinesRecv::spawn_files(vec![path.clone()]);
+        let timers = recv.timers();
+        assert_eq!(recv.by_ref().take(5).count(), 5);
+        recv.stop();
+        reader_exits(&timers, true);
+        assert_eq!(recv.count(), 0);
+
+        // Dropped without stopping
+        let mut recv = JsonLinesRecv::spawn_files(vec![path]);
+        let timers = recv.timers();
+        assert_eq!(recv.by_ref().take(5).count(), 5);
+        drop(recv);
+        reader_exits(&timers, false);
+
+        // Finished with every line still in the channel, which the reader doesn't wait to be received
+        let short = tmp.path().join("short.json.gz");
+        write_input(&short, &lines[..50]);
+        let recv = JsonLinesRecv::spawn_files(vec![short]);
+        let timers = recv.timers();
+        reader_exits(&timers, true);
+        assert_eq!(recv.count(), 50);
+    }
+
+    #[test]
+    fn test_tail() {
+        let tmp = TempDir::new("logsplitter2").unwrap();
+        let path = tmp.path().join("input.json.gz");
+        let lines = (0..30)
+            .map(|i| line("a", &i.to_string()))
+            .collect::<Vec<_>>();
+        let member = |lines: &[String]| gz_reader(lines).into_inner();
+        let append = |data: &[u8]| {
+            std::fs::OpenOptions::new()
+                .append(true)
+                .open(&path)
+                .unwrap()
+                .write_all(data)
+                .unwrap()
+        };
+        let text =
+            |l: Result<LineData, ReadError>| l.unwrap().original_line_text().trim_end().to_string();
+        write_input(&path, &lines[..10]);
+
+        let mut recv =
+            JsonLinesRecv::spawn_tail(vec![path.clone()], Default::default(), Default::default());
+        let handle = recv.stop_handle();
+        let got = recv.by_ref().take(10).map(text).collect::<Vec<_>>();
+        assert_eq!(got, lines[..10]);
+
+        // Whatever is appended is read, including the start of a member which isn't finished yet
+        let last = member(&lines[20..]);
+        append(&member(&lines[10..20]));
+        append(&last[..last.len() / 2]);
+        let got = recv.by_ref().take(10).map(text).collect::<Vec<_>>();
+        assert_eq!(got, lines[10..20]);
+        std::thread::sleep(TAIL_POLL * 2);
+
+        // Ending the tail still reads what was appended before it
+        append(&last[last.len() / 2..]);
+        handle.end_tail();
+        let got = recv.map(text).collect::<Vec<_>>();
+        assert_eq!(got, lines[20..]);
+
+        // A member which is still cut off when the tail ends fails the input
+        write_input(&path, &lines[..10]);
+        append(&last[..last.len() / 2]);
+        let recv = JsonLinesRecv::spawn_tail(vec![path], Default::default(), Default::default());
+        recv.stop_handle().end_tail();
+        let mut got = recv.collect::<Vec<_>>();
+        assert!(matches!(
+            got.pop(),
+            Some(Err(ReadError::CorruptInput { .. }))
+        ));
+        assert_eq!(got.into_iter().map(text).collect::<Vec<_>>(), lines[..10]);
+    }
+
+    #[test]
+    fn test_into_stream() {
+        let tmp = TempDir::new("logsplitter2").unwrap();
+        let path = tmp.path().join("input.json.gz");
+        let lines = (0..500)
+            .map(|i| line(&format!("s{}", i % 7), &i.to_string()))
+            .chain(["not json".to_string()])
+            .collect::<Vec<_>>();
+        write_input(&path, &lines);
+
+        let recv = JsonLinesRecv::spawn_new(std::fs::File::open(&path).unwrap());
+        let received = tokio_uring::start(recv.into_stream().collect::<Vec<_>>());
+
+        assert_eq!(received.len(), lines.len());
+        for (expected, got) in lines.iter().zip(&received[..500]) {
+            assert_eq!(
+                got.as_ref().unwrap().original_line_text(),
+                format!("{expected}\n")
+            );
+        }
+        assert!(received[500].is_err());
+    }
+
+    #[test]
+    fn test_spawn_files() {
+        let tmp = TempDir::new("logsplitter2").unwrap();
+        let paths = (0..3)
+            .map(|i| tmp.path().join(format!("input{i}.json.gz")))
+            .collect::<Vec<_>>();
+        for (i, path) in paths.iter().enumerate() {
+            write_input(
+                path,
+                &[line("a", &format!("{i}.0")), line("b", &format!("{i}.1"))],
+            );
+        }
+
+        let received = JsonLinesRecv::spawn_files(paths.clone())
+            .map(|l| l.unwrap().original_line_text().to_string())
+            .collect::<Vec<_>>();
+        let expected = (0..3)
+            .flat_map(|i| [line("a", &format!("{i}.0")), line("b", &format!("{i}.1"))])
+            .map(|l| format!("{l}\n"))
+            .collect::<Vec<_>>();
+        assert_eq!(received, expected);
+
+        // Inputs which disappear before they're read end the input with an error
+        std::fs::remove_file(&paths[1]).unwrap();
+        let received = JsonLinesRecv::spawn_files(paths).collect::<Vec<_>>();
+        assert_eq!(received.len(), 3);
+        match &received[2] {
+            Err(ReadError::Io(e)) => assert!(e.contains("input1.json.gz"), "{e}"),
+            other => panic!("Unexpected {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_from_reader() {
+        let read = |r: Cursor<Vec<u8>>| {
+            JsonLinesRecv::from_reader(r)
+                .map(|l| l.map(|l| l.original_line_text().trim_end().to_string()))
+                .collect::<Vec<_>>()
+        };
+        let lines = (0..300)
+            .map(|i| line(&format!("s{}", i % 3), &i.to_string()))
+            .collect::<Vec<_>>();
+
+        // Several members in a row, as if the reader was a file
+        let mut data = gz_reader(&lines[..100]).into_inner();
+        data.extend(gz_reader(&lines[100..]).into_inner());
+        let received = read(Cursor::new(data.clone()))
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(received, lines);
+
+        assert!(read(Cursor::new(vec![])).is_empty());
+        let received = read(Cursor::new(b"{\"not\": \"gzip\"}\n".to_vec()));
+        assert!(
+            matches!(&received[..], [Err(ReadError::NotGzip { .. })]),
+            "{received:?}"
+        );
+
+        // Corrupt data ends the input after the lines before it
+        let second_member = gz_reader(&lines[..100]).into_inner().len();
+        data[second_member + 10] = 0xff;
+        let received = read(Cursor::new(data));
+        assert_eq!(received.len(), 101);
+        assert!(matches!(received[100], Err(ReadError::CorruptInput { .. })));
+    }
+
+    #[test]
+    fn test_corrupt_input() {
+        let tmp = TempDir::new("logsplitter2").unwrap();
+        let mut cfg = TestdataCfg {
+            lines: 2000,
+            seed: Some(5),
+            ..Default::default()
+        };
+        cfg.set_unique_dates(1)
+            .set_services(3, 3..6)
+            .set_envs(1, 3..6);
+        let mut data = vec![];
+        generate_testdata(cfg, &mut data, None).unwrap();
+        let all = JsonLinesRecv::from_reader(Cursor::new(data.clone()))
+            .map(|l| l.unwrap().original_line_text().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(all.len(), 2000);
+
+        let truncated = data[..data.len() / 2].to_vec();
+        let mut flipped = data.clone();
+        flipped[data.len() / 2] ^= 0xff;
+        for (name, corrupt) in [("truncated", truncated), ("flipped", flipped)] {
+            let path = tmp.path().join(format!("{name}.json.gz"));
+            std::fs::write(&path, &corrupt).unwrap();
+            let mut received = JsonLinesRecv::spawn_files(vec![path.clone()]).collect::<Vec<_>>();
+
+            let Some(Err(ReadError::CorruptInput {
+                input,
+                compressed_offset,
+                ..
+            })) = received.pop()
+            else {
+                panic!("{name}: the input didn't end with corrupt input");
+            };
+            assert_eq!(input, path.display().to_string());
+            assert!(compressed_offset <= corrupt.len() as u64, "{name}");
+            if name == "truncated" {
+                assert_eq!(compressed_offset, corrupt.len() as u64);
+            }
+
+            // The lines before the corruption are kept, up to the one it cut off
+            let salvaged = received
+                .into_iter()
+                .map_while(Result::ok)
+                .map(|l| l.original_line_text().to_string())
+                .collect::<Vec<_>>();
+            assert!(!salvaged.is_empty() && salvaged.len() < all.len(), "{name}");
+            assert_eq!(salvaged, all[..salvaged.len()], "{name}");
+        }
+    }
+
+    #[test]
+    fn test_progress() {
+        let tmp = TempDir::new("logsplitter2").unwrap();
+        let path = tmp.path().join("input.json.gz");
+        let mut cfg = TestdataCfg {
+            lines: 2000,
+            gzip_members: 3,
+            seed: Some(11),
+            ..Default::default()
+        };
+        cfg.set_unique_dates(1)
+            .set_services(3, 3..6)
+            .set_envs(1, 3..6);
+        let stats =
+            generate_testdata(cfg, &mut std::fs::File::create(&path).unwrap(), None).unwrap();
+
+        let mut recv = JsonLinesRecv::spawn_files(vec![path]);
+        let metrics = recv.metrics();
+        // Lines are queued up while nothing is received
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while recv.size_hint().0 == 0 {
+            assert!(Instant::now() < deadline, "No lines were queued");
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        let mut last = metrics.snapshot();
+        let mut received = 0usize;
+        while recv.next().is_some() {
+            received += 1;
+            let now = metrics.snapshot();
+            assert!(now.bytes_read >= last.bytes_read);
+            assert!(now.bytes_decoded >= last.bytes_decoded);
+            assert!(now.lines_read >= last.lines_read && now.lines_read >= received as u64);
+            last = now;
+        }
+        assert_eq!(received, stats.lines);
+        let done = metrics.snapshot();
+        assert_eq!(
+            (done.bytes_read, done.bytes_decoded, done.lines_read),
+            (
+                stats.bytes_compressed,
+                stats.bytes_plain,
+                stats.lines as u64
+            )
+        );
+    }
+
+    #[test]
+    fn test_input_mmap() {
+        let tmp = TempDir::new("logsplitter2").unwrap();
+        let path = tmp.path().join("input.json.gz");
+        let mut cfg = TestdataCfg {
+            lines: 2000,
+            gzip_members: 3,
+            seed: Some(7),
+            ..Default::default()
+        };
+        cfg.set_unique_dates(1)
+            .set_services(3, 3..6)
+            .set_envs(1, 3..6);
+        generate_testdata(cfg, &mut std::fs::File::create(&path).unwrap(), None).unwrap();
+        let empty = tmp.path().join("empty.json.gz");
+        std::fs::write(&empty, []).unwrap();
+
+        // Mapped inputs are decoded to exactly the same lines, empty ones included
+        let read = |mmap| {
+            let paths = vec![path.clone(), empty.clone(), path.clone()];
+            JsonLinesRecv::spawn_files_with(paths, Default::default(), Default::default(), mmap)
+                .map(|l| l.unwrap().original_line_text().to_string())
+                .collect::<Vec<_>>()
+        };
+        let mapped = read(true);
+        assert_eq!(mapped.len(), 4000);
+        assert_eq!(mapped, read(false));
+    }
+
+    #[test]
+    fn test_trailing_line_policy() {
+        let tmp = TempDir::new("logsplitter2").unwrap();
+        let paths = (0..2)
+            .map(|i| tmp.path().join(format!("input{i}.json.gz")))
+            .collect::<Vec<_>>();
+        // Only the first input is missing its last newline
+        let write_gz = |path, text: String| {
+            let mut enc =
+                GzEncoder::new(std::fs::File::create(path).unwrap(), Compression::default());
+            enc.write_all(text.as_bytes()).unwrap();
+            enc.finish().unwrap();
+        };
+        write_gz(&paths[0], format!("{}\n{}", line("a", "0"), line("a", "1")));
+        write_gz(&paths[1], format!("{}\n", line("b", "2")));
+
+        for mmap in [false, true] {
+            // The lines which were read, and the error which ended the input
+            let read = |policy| {
+                let mut lines = vec![];
+                for l in
+                    JsonLinesRecv::spawn_files_with(paths.clone(), policy, Default::default(), mmap)
+                {
+                    match l {
+                        Ok(l) => lines.push(l.original_line_text().trim_end().to_string()),
+                        Err(e) => return (lines, Some(e)),
+                    }
+                }
+                (lines, None)
+            };
+            let lines = [line("a", "0"), line("a", "1"), line("b", "2")];
+
+            let (emitted, e) = read(TrailingLinePolicy::Emit);
+            assert_eq!(emitted, lines);
+            assert!(e.is_none());
+            let (ignored, e) = read(TrailingLinePolicy::Ignore);
+            assert_eq!(ignored, [lines[0].clone(), lines[2].clone()]);
+            assert!(e.is_none());
+            // The lines before it are still read, but nothing after it
+            let (rejected, e) = read(TrailingLinePolicy::Reject);
+            assert_eq!(rejected, [lines[0].clone()]);
+            match e {
+                Some(ReadError::MissingNewline(name)) => {
+                    assert_eq!(name, paths[0].display().to_string())
+                }
+                other => panic!("Unexpected {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_gzip_error_policy() {
+        let tmp = TempDir::new("logsplitter2").unwrap();
+        let path = tmp.path().join("input.json.gz");
+        let members = (0..3)
+            .map(|m| {
+                (0..200)
+                    .map(|i| line(&format!("s{m}"), &format!("{i}")))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        let mut data = vec![];
+        let mut starts = vec![];
+        for lines in &members {
+            starts.push(data.len());
+            let mut enc = GzEncoder::new(vec![], Compression::default());
+            for l in lines {
+                writeln!(enc, "{l}").unwrap();
+            }
+            data.extend(enc.finish().unwrap());
+        }
+        // The middle member's first deflate block has the reserved block type, right after its 10 byte header
+        let middle_len = (starts[2] - starts[1]) as u64;
+        data[starts[1] + 10] = 0xff;
+        std::fs::write(&path, data).unwrap();
+
+        let read = |policy, mmap| {
+            let recv = JsonLinesRecv::spawn_files_with(
+                vec![path.clone()],
+                Default::default(),
+                policy,
+                mmap,
+            );
+            let skipped = recv.skipped_gzip();
+            let mut lines = vec![];
+            for l in recv {
+                match l {
+                    Ok(l) => lines.push(l.original_line_text().trim_end().to_string()),
+                    Err(e) => return (lines, Some(e), *skipped.lock().unwrap()),
+                }
+            }
+            let skipped = *skipped.lock().unwrap();
+            (lines, None, skipped)
+        };
+
+        for mmap in [false, true] {
+            // Nothing after the corrupt member is read
+            let (lines, e, skipped) = read(GzipErrorPolicy::Abort, mmap);
+            assert_eq!(lines, members[0]);
+            assert!(matches!(e, Some(ReadError::CorruptInput { .. })), "{e:?}");
+            assert_eq!(skipped, SkippedGzip::default());
+
+            // The members on either side of it are read
+            let (lines, e, skipped) = read(GzipErrorPolicy::SkipToNextMember, mmap);
+            assert_eq!(lines, [members[0].clone(), members[2].clone()].concat());
+            assert!(e.is_none(), "{e:?}");
+            assert_eq!(
+                skipped,
+                SkippedGzip {
+                    members: 1,
+                    bytes: middle_len
+                }
+            );
+        }
+
+        assert_eq!(
+            "skip-to-next-member".parse(),
+            Ok(GzipErrorPolicy::SkipToNextMember)
+        );
+        assert!("skip".parse::<GzipErrorPolicy>().is_err());
+    }
+
+    #[test]
+    fn test_read_input_list() {
+        let tmp = TempDir::new("logsplitter2").unwrap();
+        let list = tmp.path().join("filelist.txt");
+        std::fs::write(
+            &list,
+            "# Monday\n/logs/a.json.gz\n\n  /logs/b.json.gz  \n#/logs/skipped.json.gz\nrelative.json.gz\n",
+        )
+        .unwrap();
+        assert_eq!(
+            read_input_list(&list).unwrap(),
+            ["/logs/a.json.gz", "/logs/b.json.gz", "relative.json.gz"]
+                .map(std::path::PathBuf::from)
+        );
+
+        std::fs::write(&list, "# Nothing today\n\n").unwrap();
+        let e = read_input_list(&list).unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::Io { path: Some(p), .. } if p == &list));
+        assert!(read_input_list(&tmp.path().join("missing.txt")).is_err());
+    }
+}
@@ -0,0 +1,210 @@
+//! Decides when a run has seen too many invalid lines, such as when it's given a file which isn't json lines at all
+
+use std::str::FromStr;
+
+use crate::{data::LineData, Error, ErrorKind, ReadError};
+
+/// How many invalid lines (including lines with no key) a run tolerates before aborting
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InvalidLineLimit {
+    /// At most this many invalid lines
+    Count(u64),
+    /// At most this percentage of lines may be invalid.
+    ///
+    /// This is only checked once [`WARM_UP_LINES`](InvalidLineLimit::WARM_UP_LINES) lines have been read
+    /// (and again at the end of the input), so that a bad first line doesn't immediately abort the run
+    Percent(f64),
+}
+
+impl Default for InvalidLineLimit {
+    /// No invalid lines at all
+    fn default() -> Self {
+        Self::Count(0)
+    }
+}
+
+impl InvalidLineLimit {
+    pub const WARM_UP_LINES: u64 = 1000;
+
+    /// Whether `invalid` out of `read` lines crosses this limit
+    fn exceeded(&self, invalid: u64, read: u64, end_of_input: bool) -> bool {
+        match *self {
+            Self::Count(max) => invalid > max,
+            Self::Percent(max) => {
+                (end_of_input || read >= Self::WARM_UP_LINES)
+                    && invalid as f64 * 100.0 > max * read as f64
+            }
+        }
+    }
+}
+
+impl FromStr for InvalidLineLimit {
+    type Err = String;
+
+    /// Either a number of lines (`100`) or a percentage (`0.1%`)
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_suffix('%') {
+            Some(pct) => match pct.parse::<f64>() {
+                Ok(pct) if (0.0..=100.0).contains(&pct) => Ok(Self::Percent(pct)),
+                _ => Err(format!(
+                    "Expected a percentage between 0% and 100%, got `{s}`"
+                )),
+            },
+            None => s
+                .parse()
+                .map(Self::Count)
+                .map_err(|_| format!("Expected a number of lines or a percentage, got `{s}`")),
+        }
+    }
+}
+
+/// Counts the lines of a run against an [`InvalidLineLimit`]
+#[derive(Debug)]
+pub struct InvalidLines {
+    limit: InvalidLineLimit,
+    /// Lines which weren't filtered out, valid or not
+    read: u64,
+    invalid: u64,
+    /// The first few invalid lines, to show what went wrong
+    examples: Vec<String>,
+}
+
+impl InvalidLines {
+    /// How many invalid lines are reported once the limit is crossed
+    const MAX_EXAMPLES: usize = 5;
+
+    pub fn new(limit: InvalidLineLimit) -> Self {
+        Self {
+            limit,
+            read: 0,
+            invalid: 0,
+            examples: vec![],
+        }
+    }
+
+    /// Passes valid lines through, and counts invalid ones, which are skipped with `Ok(None)`.
+    ///
+    /// Fails once there are too many invalid lines, or if the input itself can't be read
+    pub fn check(&mut self, line: Result<LineData, ReadError>) -> Result<Option<LineData>, Error> {
+        self.read += 1;
+        let line = match line {
+            Ok(line) => Some(line),
+            Err(ReadError::InvalidLine { line, input } | ReadError::NoKey { line, input }) => {
+                self.invalid += 1;
+                if self.examples.len() < Self::MAX_EXAMPLES {
+                    self.examples.push(match input {
+                        Some(input) => format!("{input}: {line}"),
+                        None => line,
+                    });
+                }
+                None
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        // Valid lines are checked too, since a percentage can be crossed by reaching the end of the warm-up
+        if self.limit.exceeded(self.invalid, self.read, false) {
+            return Err(self.error());
+        }
+        Ok(line)
+    }
+
+    /// Checks the limit once more at the end of the input, which percentages need if the input was shorter than the warm-up
+    pub fn finish(&self) -> Result<(), Error> {
+        match self.limit.exceeded(self.invalid, self.read, true) {
+            true => Err(self.error()),
+            false => Ok(()),
+        }
+    }
+
+    fn error(&self) -> Error {
+        Error {
+            kind: Box::new(ErrorKind::TooManyInvalidLines {
+                invalid: self.invalid,
+                read: self.read,
+                examples: self.examples.clone(),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{data::LineData, test_utils::line, ErrorKind, ReadError};
+
+    use super::{InvalidLineLimit, InvalidLines};
+
+    fn valid() -> Result<LineData, ReadError> {
+        Ok(LineData::parse(line("a", "ok")).unwrap())
+    }
+
+    fn invalid(i: u64) -> Result<LineData, ReadError> {
+        Err(ReadError::InvalidLine {
+            line: format!("bad {i}"),
+            input: None,
+        })
+    }
+
+    #[test]
+    fn test_parse_limit() {
+        assert_eq!("10".parse(), Ok(InvalidLineLimit::Count(10)));
+        assert_eq!("0.1%".parse(), Ok(InvalidLineLimit::Percent(0.1)));
+        for bad in ["-1", "ten", "101%", "%"] {
+            assert!(bad.parse::<InvalidLineLimit>().is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn test_count_limit() {
+        let mut lines = InvalidLines::new(InvalidLineLimit::Count(2));
+        for i in 0..2 {
+            assert!(lines.check(valid()).unwrap().is_some());
+            assert!(lines.check(invalid(i)).unwrap().is_none());
+        }
+        lines.finish().unwrap();
+
+        let e = lines.check(invalid(2)).unwrap_err();
+        match e.kind() {
+            ErrorKind::TooManyInvalidLines {
+                invalid,
+                read,
+                examples,
+            } => {
+                assert_eq!((*invalid, *read), (3, 5));
+                assert_eq!(examples, &["bad 0", "bad 1", "bad 2"]);
+            }
+            other => panic!("Unexpected error {other:?}"),
+        }
+
+        // Errors reading the input aren't invalid lines, and always fail
+        let mut lines = InvalidLines::new(InvalidLineLimit::Count(100));
+        let e = lines.check(Err(ReadError::Io("corrupt".to_string())));
+        assert!(matches!(e.unwrap_err().kind(), ErrorKind::ReadErr(_)));
+    }
+
+    #[test]
+    fn test_percent_limit() {
+        // A bad first line doesn't trip the limit during the warm-up...
+        let mut lines = InvalidLines::new(InvalidLineLimit::Percent(0.05));
+        assert!(lines.check(invalid(0)).unwrap().is_none());
+        for _ in 1..InvalidLineLimit::WARM_UP_LINES - 1 {
+            lines.check(valid()).unwrap();
+        }
+        // ...but does once there are enough lines to judge by
+        assert!(lines.check(valid()).is_err());
+
+        let mut lines = InvalidLines::new(InvalidLineLimit::Percent(0.1));
+        lines.check(invalid(0)).unwrap();
+        for _ in 1..2 * InvalidLineLimit::WARM_UP_LINES {
+            lines.check(valid()).unwrap();
+        }
+        lines.finish().unwrap();
+
+        // Short inputs are still judged once they end
+        let mut lines = InvalidLines::new(InvalidLineLimit::Percent(10.0));
+        for i in 0..5 {
+            lines.check(invalid(i)).unwrap();
+        }
+        assert!(lines.finish().is_err());
+    }
+}
@@ -0,0 +1,2931 @@
+use std::{
+    collections::HashSet,
+    fmt::Display,
+    io::Write,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{atomic::AtomicBool, Arc},
+};
+
+use chrono::NaiveDate;
+use data::{InputName, KeyFn, MsgKey, MsgKeyMap, TransformFn};
+use deflate::DeflateStrategy;
+use file_complete::OnFileComplete;
+#[cfg(unix)]
+use file_pool::UnixMode;
+use file_pool::{ExistingFilePolicy, RetryPolicy};
+use filter::{JsonPath, LineFilter};
+use flate2::Compression;
+use input::{GzipErrorPolicy, JsonLinesRecv, TrailingLinePolicy};
+use invalid_lines::InvalidLineLimit;
+use lock::{DirLock, LockError};
+use manifest::Manifest;
+use metrics::MetricsHandle;
+use output::{CompressionFn, GzipMtime, OutputFormat, ReserializeMode, ThreadAssignment};
+use splitter::{FinishOnDrop, RunStats, Splitter, StopOnDrop};
+use stripes::KeyStripes;
+
+pub mod byte_channel;
+pub mod config;
+pub mod data;
+pub mod deflate;
+pub mod file_complete;
+pub mod file_pool;
+pub mod filter;
+pub mod index;
+pub mod input;
+pub mod invalid_lines;
+pub mod lock;
+pub mod manifest;
+pub mod math_utils;
+pub mod memory;
+pub mod merge;
+pub mod metrics;
+pub mod output;
+#[cfg(feature = "parquet")]
+pub mod parquet_output;
+pub mod splitter;
+pub mod stripes;
+pub mod testdata_gen;
+pub mod verify;
+
+#[cfg(test)]
+mod test_utils;
+
+#[derive(Debug, Clone)]
+pub enum ReadError {
+    EndOfInputReached,
+    InvalidLine {
+        line: String,
+        /// The input file the line was read from, if it's known
+        input: Option<InputName>,
+    },
+    /// The line is valid json, but the key function gave it no key
+    NoKey {
+        line: String,
+        input: Option<InputName>,
+    },
+    /// The input itself couldn't be read
+    Io(String),
+    /// The named input doesn't start like a gzip file, such as a plain `.json` file given by mistake
+    NotGzip {
+        input: String,
+        /// The first few bytes of the input
+        start: Vec<u8>,
+    },
+    /// The named input's gzip data is corrupt or cut off, see [`GzipErrorPolicy::Abort`](input::GzipErrorPolicy::Abort).
+    /// Every whole line before it has been read
+    CorruptInput {
+        input: String,
+        /// How far into the compressed input the decoder got before failing
+        compressed_offset: u64,
+        detail: String,
+    },
+    /// The named input's last line has no newline, see [`TrailingLinePolicy::Reject`]
+    MissingNewline(String),
+}
+
+/// Shortens `line` for error messages, since lines of the wrong file type can be arbitrarily long
+struct Snippet<'a>(&'a str);
+
+impl Display for Snippet<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        const MAX_CHARS: usize = 200;
+        match self.0.char_indices().nth(MAX_CHARS) {
+            Some((end, _)) => write!(f, "{}...", &self.0[..end]),
+            None => write!(f, "{}", self.0),
+        }
+    }
+}
+
+/// Where a line came from in error messages, if it's known
+struct InInput<'a>(&'a Option<InputName>);
+
+impl Display for InInput<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0 {
+            Some(input) => write!(f, " in {input}"),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Display for ReadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReadError::EndOfInputReached => write!(f, "The end of the input was reached"),
+            ReadError::InvalidLine { line, input } => {
+                write!(f, "Invalid json line{}: {}", InInput(input), Snippet(line))
+            }
+            ReadError::NoKey { line, input } => {
+                write!(f, "Line has no key{}: {}", InInput(input), Snippet(line))
+            }
+            ReadError::Io(e) => write!(f, "Could not read the input: {e}"),
+            ReadError::NotGzip { input, start } => write!(
+                f,
+                "{input} does not appear to be gzip (starts with {:?}). Only gzip compressed (`.json.gz`) input can be split",
+                String::from_utf8_lossy(start)
+            ),
+            ReadError::CorruptInput {
+                input,
+                compressed_offset,
+                detail,
+            } => write!(
+                f,
+                "{input} is corrupt at byte {compressed_offset} of its compressed data: {detail}"
+            ),
+            ReadError::MissingNewline(input) => write!(
+                f,
+                "{input} does not end with a newline, so its last line may have been cut off"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ReadError {}
+
+#[derive(Debug, Clone)]
+pub enum ErrorKind {
+    ReadErr(ReadError),
+    /// The output directory could not be locked for this run
+    Lock(LockError),
+    /// The input file is inside of the output directory, so outputs could overwrite (or be mistaken for) it
+    InputInsideOutput {
+        input: PathBuf,
+        output_dir: PathBuf,
+    },
+    /// The input file is also the [output file](OutputTarget::File), which would be truncated before it's read
+    InputIsOutput {
+        file: PathBuf,
+    },
+    /// Files can't be created in the output directory
+    OutputNotWritable {
+        output_dir: PathBuf,
+        detail: String,
+    },
+    /// The run was aborted because more lines were invalid than [`RunCfg::max_invalid_lines`] allows
+    TooManyInvalidLines {
+        invalid: u64,
+        /// How many lines were read (and not filtered out) before aborting
+        read: u64,
+        /// The first few invalid lines
+        examples: Vec<String>,
+    },
+    /// The disk filled up, so the run stopped early.
+    /// Its manifest (if there was room for it) is marked as [partial](manifest::Manifest::partial)
+    StorageFull {
+        /// How many files were fully written
+        complete: usize,
+        /// The files which may be truncated
+        suspect: Vec<String>,
+    },
+    /// Every key was split across many dates into tiny files, see [`RunCfg::strict`].
+    /// The output itself is complete
+    TinyFiles {
+        files: usize,
+        /// How many keys there are once their dates are left out, such as one per service and env
+        keys: usize,
+        median_bytes: u64,
+    },
+    /// A [config file](config) couldn't be parsed, or its options (along with the command line's) can't be combined
+    InvalidConfig(String),
+    /// Output threads panicked, so the keys they wrote are incomplete.
+    /// `panics` has the name of each thread which did along with its panic message
+    OutputThreadPanicked {
+        panics: Vec<(String, String)>,
+    },
+    /// A file couldn't be opened, read, or written. `path` is `None` for streams such as stdout.
+    /// `source` is shared so that errors can be cloned, which [`std::io::Error`] can't
+    Io {
+        path: Option<PathBuf>,
+        source: Arc<std::io::Error>,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct Error {
+    kind: Box<ErrorKind>,
+}
+
+impl Error {
+    pub fn kind(&self) -> &ErrorKind {
+        &self.kind
+    }
+
+    /// The message followed by those of its [sources](std::error::Error::source), such as to print it for a user
+    pub fn full_message(&self) -> String {
+        let mut msg = self.to_string();
+        let mut source = std::error::Error::source(self);
+        while let Some(e) = source {
+            msg += &format!(": {e}");
+            source = e.source();
+        }
+        msg
+    }
+
+    /// An [`ErrorKind::Io`] error for the file at `path`
+    pub fn io(path: impl Into<PathBuf>, source: std::io::Error) -> Self {
+        Self {
+            kind: Box::new(ErrorKind::Io {
+                path: Some(path.into()),
+                source: Arc::new(source),
+            }),
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.kind)
+    }
+}
+
+/// The [`source`](std::error::Error::source) is the read, lock or io error which this wraps, if any.
+/// The message doesn't repeat it, so print the whole chain (such as with `anyhow`'s `{:#}`) to see what went wrong
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match &*self.kind {
+            ErrorKind::ReadErr(e) => Some(e),
+            ErrorKind::Lock(e) => Some(e),
+            ErrorKind::Io { source, .. } => Some(&**source),
+            _ => None,
+        }
+    }
+}
+
+impl Display for ErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ErrorKind::ReadErr(_) => write!(f, "Could not split the input"),
+            ErrorKind::Lock(_) => write!(f, "The output directory can't be used by this run"),
+            ErrorKind::InputInsideOutput { input, output_dir } => write!(
+                f,
+                "The input file {} is inside of the output directory {}",
+                input.display(),
+                output_dir.display()
+            ),
+            ErrorKind::InputIsOutput { file } => {
+                write!(f, "The input file {} is also the output file", file.display())
+            }
+            ErrorKind::OutputNotWritable { output_dir, detail } => write!(
+                f,
+                "Cannot write to the output directory {}: {detail}",
+                output_dir.display()
+            ),
+            ErrorKind::TooManyInvalidLines {
+                invalid,
+                read,
+                examples,
+            } => {
+                write!(
+                    f,
+                    "Aborted after {invalid} of {read} lines were invalid. The first invalid lines were:"
+                )?;
+                for line in examples {
+                    write!(f, "\n  {}", Snippet(line))?;
+                }
+                Ok(())
+            }
+            ErrorKind::StorageFull { complete, suspect } => {
+                write!(
+                    f,
+                    "The output directory ran out of space, so only part of the input was split. \
+                    {complete} files are complete, and {} may be truncated",
+                    suspect.len()
+                )?;
+                for file in suspect {
+                    write!(f, "\n  {file}")?;
+                }
+                Ok(())
+            }
+            ErrorKind::TinyFiles {
+                files,
+                keys,
+                median_bytes,
+            } => write!(
+                f,
+                "{keys} keys were split into {files} files, with a median size of only {median_bytes} bytes. \
+                Grouping dates into coarser periods (such as with a key function which maps each date to the start of its month) \
+                would give fewer, bigger files"
+            ),
+            ErrorKind::InvalidConfig(detail) => write!(f, "Invalid configuration: {detail}"),
+            ErrorKind::OutputThreadPanicked { panics } => {
+                write!(
+                    f,
+                    "{} output threads panicked, so the output is incomplete:",
+                    panics.len()
+                )?;
+                for (name, msg) in panics {
+                    write!(f, "\n  thread '{name}' panicked: {msg}")?;
+                }
+                Ok(())
+            }
+            ErrorKind::Io {
+                path: Some(path), ..
+            } => write!(f, "Could not read or write {}", path.display()),
+            ErrorKind::Io { path: None, .. } => write!(f, "Could not read or write a stream"),
+        }
+    }
+}
+
+impl From<ReadError> for Error {
+    fn from(value: ReadError) -> Self {
+        Self {
+            kind: Box::new(ErrorKind::ReadErr(value)),
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(source: std::io::Error) -> Self {
+        Self {
+            kind: Box::new(ErrorKind::Io {
+                path: None,
+                source: Arc::new(source),
+            }),
+        }
+    }
+}
+
+impl From<LockError> for Error {
+    fn from(value: LockError) -> Self {
+        Self {
+            kind: Box::new(ErrorKind::Lock(value)),
+        }
+    }
+}
+
+/// Where the lines of a run end up
+#[derive(Debug, Clone)]
+pub enum OutputTarget {
+    /// One `.json.gz` file per key, inside of the given directory
+    Dir(PathBuf),
+    /// Every line is streamed to stdout, gzip-compressed if a compression level is given.
+    ///
+    /// Lines with different keys are interleaved in input order,
+    /// so this is usually combined with a filter that narrows the input down to one key
+    Stdout { compression: Option<Compression> },
+    /// Every line is written to one gzip file at `path`, in input order.
+    ///
+    /// Like [`OutputTarget::Stdout`], this skips the output threads and their open files,
+    /// which are all overhead when the input only holds one key anyway
+    File {
+        path: PathBuf,
+        compression: Compression,
+    },
+}
+
+/// How many output threads a run uses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Threads {
+    Fixed(usize),
+    /// Chosen from the size of the input file and the available parallelism, see [`Threads::resolve`]
+    Auto,
+}
+
+impl Threads {
+    /// How many bytes of (compressed) input justify one more output thread for [`Threads::Auto`]
+    pub const AUTO_BYTES_PER_THREAD: u64 = 64 * 1024 * 1024;
+
+    /// The number of threads to use for an input of `input_size` bytes.
+    ///
+    /// [`Threads::Auto`] uses one thread per [`AUTO_BYTES_PER_THREAD`](Threads::AUTO_BYTES_PER_THREAD) of input,
+    /// clamped between 1 and [`max_auto`](Threads::max_auto),
+    /// since small inputs are dominated by thread startup and big ones by compression
+    pub fn resolve(self, input_size: u64) -> usize {
+        match self {
+            Threads::Fixed(n) => n,
+            Threads::Auto => {
+                let wanted = input_size.div_ceil(Self::AUTO_BYTES_PER_THREAD);
+                wanted.clamp(1, Self::max_auto() as u64) as usize
+            }
+        }
+    }
+
+    /// The most output threads [`Threads::Auto`] uses.
+    ///
+    /// This is the available parallelism minus the cores taken by the input thread (which decompresses)
+    /// and the main thread (which parses and routes lines), but always at least 1
+    pub fn max_auto() -> usize {
+        let cores = std::thread::available_parallelism().map_or(1, |n| n.get());
+        cores.saturating_sub(2).max(1)
+    }
+}
+
+impl FromStr for Threads {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" | "0" => Ok(Threads::Auto),
+            _ => match s.parse() {
+                Ok(n) if n > 0 => Ok(Threads::Fixed(n)),
+                _ => Err(format!("Expected `auto` or a number of threads, got `{s}`")),
+            },
+        }
+    }
+}
+
+pub struct RunCfg {
+    /// The `.json.gz` files to split, read one after another as if they were a single input
+    pub input_files: Vec<PathBuf>,
+    /// What happens to the last line of an input file which doesn't end with a newline
+    pub trailing_line: TrailingLinePolicy,
+    /// What happens when an input's gzip data is corrupt, see [`GzipErrorPolicy`]
+    pub gzip_error_policy: GzipErrorPolicy,
+    /// Memory-map input files which are regular files, instead of reading them in small chunks.
+    /// Faster for big local inputs, but a file which is truncated while it's being split crashes the run
+    pub input_mmap: bool,
+    /// If set, up to this many of the input files are read and parsed at once, each by a thread of its own,
+    /// instead of one after another. Helps with many inputs, when reading and parsing keeps up with the output threads.
+    ///
+    /// The lines of each input are still written in order, but a key's lines from different inputs are interleaved
+    /// in no particular order. Keys go to output threads by a hash of their name, as with [`ThreadAssignment::HashMod`],
+    /// unless [`balance_threads`](RunCfg::balance_threads) assigned them. Only used with [`OutputTarget::Dir`],
+    /// and not with [`stripes`](RunCfg::stripes) or [`tail`](RunCfg::tail), see [`Splitter::process_files_parallel`](splitter::Splitter::process_files_parallel)
+    pub parallel_inputs: Option<usize>,
+    /// If set, the last input file is tailed: once it's read to its end, the run waits for more to be appended to it
+    /// rather than finishing, until this is set. Everything appended before then is still split, see [`JsonLinesRecv::spawn_tail`].
+    /// The input files are read one after another, even with [`parallel_inputs`](RunCfg::parallel_inputs)
+    pub tail: Option<Arc<AtomicBool>>,
+    pub output: OutputTarget,
+    pub output_threads: Threads,
+    /// How many output files are kept open at once, shared between the output threads.
+    /// Raised to the number of output threads if it's below it, since each thread needs at least one
+    pub max_active_files: usize,
+    /// Only lines kept by this filter are written
+    pub filter: LineFilter,
+    /// How output files left behind by a previous run are treated.
+    /// If not given, they are truncated, with a warning if the output directory isn't empty
+    pub existing_files: Option<ExistingFilePolicy>,
+    pub gzip_mtime: GzipMtime,
+    /// The gzip compression level of each output file, see [`OutputCfg::compression`](output::OutputCfg::compression)
+    pub compression: CompressionFn,
+    /// The deflate strategy of each output file, see [`DeflateStrategy`]
+    pub deflate_strategy: DeflateStrategy,
+    /// Only used with [`OutputTarget::Dir`]
+    pub format: OutputFormat,
+    /// If set, a line index sidecar is written for every output file, with an entry every this many lines
+    pub index_interval: Option<u64>,
+    /// If set, keys with fewer than this many bytes of lines are written uncompressed, see [`OutputCfg::plain_below`](output::OutputCfg::plain_below)
+    pub plain_below: Option<usize>,
+    /// Take over the output directory's lock if it's held by a PID which no longer exists
+    pub force_lock: bool,
+    /// Decides which key (and so which output file) each line belongs to
+    pub key_fn: KeyFn,
+    /// How output threads retry transient write errors
+    pub retry: RetryPolicy,
+    /// Invalid lines (and lines with no key) are skipped until there are more than this many of them,
+    /// at which point the run finishes what it has written so far and fails
+    pub max_invalid_lines: InvalidLineLimit,
+    /// Sync recently written output files every this many writes, see [`OutputCfg::sync_every`](output::OutputCfg::sync_every).
+    /// By default, files are only synced when they're closed
+    pub sync_every: Option<usize>,
+    /// Whether lines are written as-is, or parsed and written again, see [`ReserializeMode`]
+    pub reserialize: ReserializeMode,
+    /// Fields removed from every line before it's written, see [`OutputCfg::redact_fields`](output::OutputCfg::redact_fields)
+    pub redact_fields: Vec<JsonPath>,
+    /// If set, the run stops after writing this many lines, and finishes its output as usual.
+    /// Lines which are filtered out or invalid don't count
+    pub max_lines: Option<usize>,
+    /// If set, the run stops taking input once the output threads have written this many bytes across every output file,
+    /// and finishes its output as usual. This is a soft limit: lines which were already handed to the output threads
+    /// are still written, so the output ends up somewhat bigger. Only used with [`OutputTarget::Dir`]
+    pub max_output_bytes: Option<u64>,
+    /// If set, the run stops taking input once this is set (such as by a signal handler), and finishes its output
+    /// as usual, like after [`max_lines`](RunCfg::max_lines), which also unlocks the output directory.
+    /// It's only noticed as lines come in, so a [tailed](RunCfg::tail) input which is waiting for more should be ended instead
+    pub stop: Option<Arc<AtomicBool>>,
+    /// Fail with [`ErrorKind::TinyFiles`] instead of warning when the output is split into many tiny files
+    pub strict: bool,
+    /// Applied to every kept line before it's written, returning `None` to drop the line
+    /// or the (possibly rewritten) line to write instead.
+    ///
+    /// A transform may return a line with a different key than it was given, such as to rewrite its env,
+    /// in which case the line is written to the new key's file.
+    /// Dropped lines don't count towards [`max_lines`](RunCfg::max_lines)
+    pub transform: Option<TransformFn>,
+    /// Read the input twice: first to measure how many bytes of lines each key has,
+    /// then to split it with keys assigned to output threads so that their loads are as even as possible
+    /// (see [`math_utils::partition_items`]), rather than round-robin.
+    ///
+    /// This helps when a few keys have most of the lines. Keys are measured before [`transform`](RunCfg::transform),
+    /// so lines it moves to other keys are assigned round-robin. Only used with [`OutputTarget::Dir`]
+    pub balance_threads: bool,
+    /// How keys are routed to output threads (other than those assigned by [`balance_threads`](RunCfg::balance_threads)).
+    /// [`ThreadAssignment::HashMod`] keeps memory from growing with the number of distinct keys,
+    /// which round-robin can't, since it has to remember the thread of every key. Only used with [`OutputTarget::Dir`]
+    pub thread_assignment: ThreadAssignment,
+    /// If set, the lines of hot keys are spread over several files, which are written by different output threads.
+    /// Only used with [`OutputTarget::Dir`], see [`stripes`] for how to read them back
+    pub stripes: Option<KeyStripes>,
+    /// Seeds the hasher of every key map and of [`ThreadAssignment::HashMod`], see [`data::hash_builder`].
+    /// Only changes which thread each key is written by, never the output
+    pub hash_seed: u64,
+    /// If set, called with every output file as soon as it's finished, such as to start uploading it.
+    /// Only used with [`OutputTarget::Dir`], see [`file_complete`]
+    pub on_file_complete: Option<OnFileComplete>,
+    /// If set, roughly how many bytes the output threads' writers may use at once.
+    /// Once a thread's share of it is nearly used up, the gzip members of its least recently written keys are finished
+    /// and their compressors dropped, and their next lines start new members. Only used with [`OutputTarget::Dir`], see [`memory`].
+    /// Must be at least the [minimum](memory::min_limit) for the output threads
+    pub max_memory: Option<u64>,
+    /// Added to by every stage of the run as it goes. Keep a clone to sample the run while it's going,
+    /// or take one from [`Splitter::metrics`](splitter::Splitter::metrics), see [`metrics`]
+    pub metrics: MetricsHandle,
+    /// If set, the permissions of every file the run creates (including sidecars and the manifest),
+    /// instead of leaving them to the umask. Files which are appended to keep their permissions
+    #[cfg(unix)]
+    pub file_mode: Option<UnixMode>,
+    /// If set, the permissions of the output directory (and any parents of it) if the run creates it
+    #[cfg(unix)]
+    pub dir_mode: Option<UnixMode>,
+}
+
+impl Default for RunCfg {
+    fn default() -> Self {
+        Self {
+            input_files: vec![],
+            trailing_line: Default::default(),
+            gzip_error_policy: Default::default(),
+            input_mmap: false,
+            parallel_inputs: None,
+            tail: None,
+            output: OutputTarget::Dir(PathBuf::new()),
+            output_threads: Threads::Fixed(8),
+            max_active_files: 64,
+            filter: Default::default(),
+            existing_files: Default::default(),
+            gzip_mtime: Default::default(),
+            compression: Arc::new(output::default_compression),
+            deflate_strategy: Default::default(),
+            format: Default::default(),
+            index_interval: None,
+            plain_below: None,
+            retry: Default::default(),
+            force_lock: false,
+            key_fn: Arc::new(data::default_key),
+            max_invalid_lines: Default::default(),
+            sync_every: None,
+            reserialize: Default::default(),
+            redact_fields: vec![],
+            max_lines: None,
+            max_output_bytes: None,
+            stop: None,
+            strict: false,
+            transform: None,
+            balance_threads: false,
+            thread_assignment: Default::default(),
+            stripes: None,
+            hash_seed: data::DEFAULT_HASH_SEED,
+            on_file_complete: None,
+            max_memory: None,
+            metrics: Default::default(),
+            #[cfg(unix)]
+            file_mode: None,
+            #[cfg(unix)]
+            dir_mode: None,
+        }
+    }
+}
+
+/// The file which [`lock_output_dir`] creates to check that the output directory is writable
+const PROBE_FILE_NAME: &str = ".logsplitter2.probe";
+
+/// Creates `output_dir` if needed (with `dir_mode`, see [`RunCfg::dir_mode`]), locks it (see [`lock`]),
+/// and makes sure files can be created in it.
+///
+/// Checking this up front means a read-only output fails before any input is read,
+/// rather than as a panic inside of an output thread
+fn lock_output_dir(
+    output_dir: &Path,
+    force_lock: bool,
+    #[cfg(unix)] dir_mode: Option<UnixMode>,
+) -> Result<DirLock, Error> {
+    let not_writable = |e: std::io::Error| Error {
+        kind: Box::new(ErrorKind::OutputNotWritable {
+            output_dir: output_dir.to_path_buf(),
+            detail: e.to_string(),
+        }),
+    };
+
+    #[cfg(unix)]
+    if let Some(mode) = dir_mode {
+        let missing = output_dir
+            .ancestors()
+            .take_while(|dir| !dir.as_os_str().is_empty() && !dir.exists())
+            .collect::<Vec<_>>();
+        std::fs::create_dir_all(output_dir).map_err(not_writable)?;
+        for dir in missing {
+            mode.apply(dir).map_err(not_writable)?;
+        }
+    }
+    std::fs::create_dir_all(output_dir).map_err(not_writable)?;
+
+    // Creating the lock file is the first write, so failing to is most likely a read-only directory
+    let lock = match DirLock::acquire(output_dir, force_lock) {
+        Ok(lock) => lock,
+        Err(LockError::Io(detail)) => {
+            return Err(Error {
+                kind: Box::new(ErrorKind::OutputNotWritable {
+                    output_dir: output_dir.to_path_buf(),
+                    detail,
+                }),
+            })
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    // The lock file may have been left behind writable, so a new file is still tried.
+    // Only the holder of the lock probes, so a probe left behind by a killed run is simply overwritten
+    let probe = output_dir.join(PROBE_FILE_NAME);
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&probe)
+        .and_then(|mut f| f.write_all(b"probe"))
+        .map_err(not_writable)?;
+    std::fs::remove_file(&probe).map_err(not_writable)?;
+    Ok(lock)
+}
+
+/// The number of bytes available to unprivileged users on the filesystem containing `path`
+#[cfg(unix)]
+fn available_space(path: &Path) -> std::io::Result<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: `path` is a valid C string, and `stat` is only read after `statvfs` succeeds
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// Elsewhere the space isn't known, so [`warn_on_low_space`] skips its check
+#[cfg(not(unix))]
+fn available_space(_path: &Path) -> std::io::Result<u64> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+/// Warns if the output directory has less space than the input files take up,
+/// which is a rough upper bound for the size of the output since it's compressed the same way
+fn warn_on_low_space(input_size: u64, output_dir: &Path) {
+    let Ok(available) = available_space(output_dir) else {
+        return;
+    };
+    if available < input_size {
+        eprintln!(
+            "Warning: only {available} bytes are available in {}, but the input is {input_size} bytes. \
+            The run may fail if the disk fills up",
+            output_dir.display(),
+        );
+    }
+}
+
+/// The median output file must be smaller than this for [`check_file_sizes`] to complain
+const TINY_FILE_BYTES: u64 = 4 * 1024;
+/// How many output files there must be for [`check_file_sizes`] to complain
+const TINY_FILES_MIN_COUNT: usize = 500;
+
+/// Detects output which was split into many tiny files because each key's lines are spread over many dates,
+/// which is [`ErrorKind::TinyFiles`]. If most keys only span a date or two, splitting more coarsely wouldn't help,
+/// so it's not reported
+fn check_file_sizes(manifest: &Manifest) -> Option<ErrorKind> {
+    let files = manifest.files.len();
+    if files < TINY_FILES_MIN_COUNT {
+        return None;
+    }
+    let mut bytes = manifest.files.iter().map(|e| e.bytes).collect::<Vec<_>>();
+    let (_, &mut median_bytes, _) = bytes.select_nth_unstable(files / 2);
+    let keys = manifest
+        .files
+        .iter()
+        .map(|e| undated_key(&e.key))
+        .collect::<HashSet<_>>()
+        .len();
+    if median_bytes >= TINY_FILE_BYTES || files < 2 * keys {
+        return None;
+    }
+    Some(ErrorKind::TinyFiles {
+        files,
+        keys,
+        median_bytes,
+    })
+}
+
+/// The name of a key without the `_YYYY-MM-DD` date which the names of [`default_key`](data::default_key) end with,
+/// so that the files a key has on each date are counted as one key. Other names are kept whole
+fn undated_key(key: &str) -> &str {
+    match key.rsplit_once('_') {
+        Some((undated, date)) if NaiveDate::parse_from_str(date, "%Y-%m-%d").is_ok() => undated,
+        _ => key,
+    }
+}
+
+/// The first pass of [`RunCfg::balance_threads`], which assigns the keys of `lines` to `threads` output threads
+/// by how many bytes of lines each has. Invalid lines are left for the second pass to report
+fn balance_keys(lines: JsonLinesRecv, threads: usize) -> Vec<Vec<MsgKey>> {
+    let mut sizes = MsgKeyMap::<u64>::default();
+    for line in lines.flatten() {
+        *sizes.entry(line.key().clone()).or_default() += line.original_line_text().len() as u64;
+    }
+    let mut items = sizes.into_iter().collect::<Vec<_>>();
+    // So that keys of equal sizes are always assigned the same way
+    items.sort_by(|(a, _), (b, _)| a.name().cmp(b.name()));
+
+    let assignments = math_utils::partition_items(&items, threads);
+    let size_of = items.into_iter().collect::<MsgKeyMap<_>>();
+    let loads = assignments
+        .iter()
+        .map(|keys| keys.iter().map(|k| size_of[k]).sum())
+        .collect::<Vec<_>>();
+    eprintln!(
+        "Assigned {} keys to {threads} threads, the busiest of which has {:.2}x the average load",
+        size_of.len(),
+        math_utils::imbalance_ratio(&loads)
+    );
+    assignments
+}
+
+/// Fails if any of `inputs` is inside of `output_dir` (after resolving symlinks),
+/// and warns about files being overwritten if `output_dir` isn't empty and no `existing_files` policy was given
+fn check_output_dir(
+    inputs: &[PathBuf],
+    output_dir: &Path,
+    existing_files: Option<ExistingFilePolicy>,
+) -> Result<(), Error> {
+    if let Ok(output_dir) = output_dir.canonicalize() {
+        for input in inputs {
+            // Inputs which can't be resolved don't exist, which opening them will report
+            let Ok(input) = input.canonicalize() else {
+                continue;
+            };
+            if input.starts_with(&output_dir) {
+                return Err(Error {
+                    kind: Box::new(ErrorKind::InputInsideOutput {
+                        input,
+                        output_dir: output_dir.clone(),
+                    }),
+                });
+            }
+        }
+    }
+
+    let is_empty = std::fs::read_dir(output_dir)
+        .map_err(|e| Error::io(output_dir, e))?
+        .all(|e| e.is_ok_and(|e| e.file_name() == lock::LOCK_FILE_NAME));
+    if existing_files.is_none() && !is_empty {
+        eprintln!(
+            "Warning: {} is not empty, and existing output files will be overwritten. \
+            Pass `--truncate` or `--append` to choose explicitly",
+            output_dir.display()
+        );
+    }
+
+    Ok(())
+}
+
+/// Fails if `output` is one of `inputs`, and warns if it already exists without a [`RunCfg::existing_files`] policy
+fn check_output_file(
+    inputs: &[PathBuf],
+    output: &Path,
+    existing_files: Option<ExistingFilePolicy>,
+) -> Result<(), Error> {
+    let Ok(output) = output.canonicalize() else {
+        return Ok(());
+    };
+    if inputs
+        .iter()
+        .any(|input| input.canonicalize().is_ok_and(|input| input == output))
+    {
+        return Err(Error {
+            kind: Box::new(ErrorKind::InputIsOutput { file: output }),
+        });
+    }
+    if existing_files.is_none() {
+        eprintln!(
+            "Warning: {} already exists, and will be overwritten. \
+            Pass `--truncate` or `--append` to choose explicitly",
+            output.display()
+        );
+    }
+    Ok(())
+}
+
+/// The total size of `inputs`, failing if any of them doesn't exist or isn't a file.
+///
+/// Only checked up front, since inputs are opened one at a time as they're read
+fn check_inputs(inputs: &[PathBuf]) -> Result<u64, Error> {
+    let mut total = 0;
+    for input in inputs {
+        let meta = std::fs::metadata(input).map_err(|e| Error::io(input, e))?;
+        if !meta.is_file() {
+            let e = std::io::Error::new(std::io::ErrorKind::InvalidInput, "Not a file");
+            return Err(Error::io(input, e));
+        }
+        total += meta.len();
+    }
+    Ok(total)
+}
+
+/// Splits the input files according to `cfg`.
+///
+/// Progress and timing information is written to stderr, so that stdout stays clean for [`OutputTarget::Stdout`]
+///
+/// Fails if another run is writing into the same output directory
+pub fn run(cfg: RunCfg) -> Result<(), Error> {
+    futures::executor::block_on(run_async(cfg))?;
+    Ok(())
+}
+
+/// Like [`run`], but awaits the input and the output threads instead of blocking, so that it can be awaited
+/// from any async runtime. Reading the input and writing the output still happen on threads of their own,
+/// and [`Splitter::new`] still blocks while setting up the output (reading all of the input with [`RunCfg::balance_threads`]).
+///
+/// Dropping the returned future stops reading the input, and finishes whatever was written so far,
+/// blocking until the output directory is unlocked again.
+/// With [`RunCfg::parallel_inputs`], the inputs are split on a thread of their own, which dropping the future stops
+/// by setting [`RunCfg::stop`] (which is set up if it isn't given)
+pub async fn run_async(mut cfg: RunCfg) -> Result<RunStats, Error> {
+    let input_files = cfg.input_files.clone();
+    if let Some(parallel) = cfg.parallel_inputs {
+        let stop = cfg.stop.get_or_insert_with(Default::default).clone();
+        let splitter = Splitter::new(cfg)?;
+        return run_parallel_async(splitter, input_files, parallel, stop).await;
+    }
+    let mut splitter = FinishOnDrop(Some(Splitter::new(cfg)?));
+    let processed = splitter
+        .0
+        .as_mut()
+        .unwrap()
+        .process_files_async(input_files)
+        .await;
+    if let Err(e) = processed {
+        // The inputs were rejected before anything was split, which leaves the output as it was, like `run`
+        drop(splitter.0.take());
+        return Err(e);
+    }
+    splitter.0.take().unwrap().finish_async().await
+}
+
+/// Splits `input_files` with [`process_files_parallel`](Splitter::process_files_parallel) and finishes the output
+/// on a thread of its own, which blocks on the threads reading the inputs, and awaits it.
+/// Dropping the returned future sets `stop` and blocks until the thread has finished the output
+async fn run_parallel_async(
+    mut splitter: Splitter,
+    input_files: Vec<PathBuf>,
+    parallel: usize,
+    stop: Arc<AtomicBool>,
+) -> Result<RunStats, Error> {
+    let (tx, rx) = futures::channel::oneshot::channel();
+    let thread = std::thread::Builder::new()
+        .name("splitter-parallel".to_string())
+        .spawn(move || {
+            // Inputs which are rejected before anything was split leave the output as it was, like `run`
+            let result = splitter
+                .process_files_parallel(input_files, parallel)
+                .and_then(|()| splitter.finish());
+            let _ = tx.send(result);
+        })
+        .expect("Could not spawn the splitting thread");
+    let mut cancel = StopOnDrop {
+        stop,
+        thread: Some(thread),
+    };
+    // The sender is only dropped without sending if splitting panicked, which has already been printed
+    let result = rx.await.expect("Splitting the inputs panicked");
+    cancel.thread = None;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        error::Error as _,
+        io::{Read, Write},
+        path::{Path, PathBuf},
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    };
+
+    use chrono::{NaiveDate, TimeDelta};
+    use flate2::{
+        read::{GzDecoder, MultiGzDecoder},
+        write::GzEncoder,
+        Compression,
+    };
+    use tempdir::TempDir;
+
+    use crate::{
+        available_space, balance_keys, check_file_sizes, check_output_dir,
+        data::{env_mapped_key, normalized_key, EnvMap, LineData, MsgKey},
+        file_complete::{FileCompleteEvent, OnFileComplete},
+        file_pool::{ExistingFilePolicy, UnixMode},
+        index::{open_at_line, LineIndex},
+        input::{JsonLinesRecv, TAIL_POLL},
+        invalid_lines::InvalidLineLimit,
+        lock::{DirLock, LOCK_FILE_NAME},
+        manifest::{FileFormat, Manifest, ManifestEntry, MANIFEST_FILE_NAME},
+        math_utils,
+        memory::ENCODER_MEMORY,
+        metrics::{MetricsHandle, MetricsSnapshot},
+        output::{GzipMtime, ThreadAssignment},
+        run, run_async,
+        stripes::{KeyStripes, StripeCount, AUTO_WARM_UP_LINES},
+        test_utils::{line, output_file, read_lines, write_input},
+        testdata_gen::{
+            generate_testdata, generate_testdata_files, KeyDistribution, MessageCharset,
+            MessageLength, MessageSpec, TestdataCfg,
+        },
+        ErrorKind, OutputTarget, ReadError, RunCfg, Threads, PROBE_FILE_NAME, TINY_FILES_MIN_COUNT,
+        TINY_FILE_BYTES,
+    };
+
+    #[test]
+    fn test_append_runs_as_new_members() {
+        let tmp = TempDir::new("logsplitter2").unwrap();
+        let out = tmp.path().join("out");
+        let input = tmp.path().join("input.json.gz");
+
+        let run1 = vec![line("a", "a1"), line("b", "b1"), line("a", "a2")];
+        let run2 = vec![line("a", "a3"), line("c", "c1")];
+
+        for (lines, existing_files) in [
+            (&run1, Some(ExistingFilePolicy::Truncate)),
+            (&run2, Some(ExistingFilePolicy::Append)),
+        ] {
+            write_input(&input, lines);
+            run(RunCfg {
+                input_files: vec![input.clone()],
+                output: OutputTarget::Dir(out.clone()),
+                output_threads: Threads::Fixed(2),
+                existing_files,
+                ..Default::default()
+            })
+            .unwrap();
+        }
+
+        let open = |service| std::fs::File::open(output_file(&out, service)).unwrap();
+
+        assert_eq!(
+            read_lines(MultiGzDecoder::new(open("a"))),
+            [line("a", "a1"), line("a", "a2"), line("a", "a3")]
+        );
+        assert_eq!(
+            read_lines(MultiGzDecoder::new(open("b"))),
+            [line("b", "b1")]
+        );
+        assert_eq!(
+            read_lines(MultiGzDecoder::new(open("c"))),
+            [line("c", "c1")]
+        );
+
+        // The first member only contains the first run's lines
+        assert_eq!(
+            read_lines(GzDecoder::new(open("a"))),
+            [line("a", "a1"), line("a", "a2")]
+        );
+
+        // Line counts of appended files cover both runs
+        let lines = Manifest::read(&out)
+            .unwrap()
+            .files
+            .iter()
+            .map(|e| (e.key.clone(), e.lines))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            [
+                ("a_prod_2024-10-20".to_string(), 3),
+                ("b_prod_2024-10-20".to_string(), 1),
+                ("c_prod_2024-10-20".to_string(), 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_single_output_file() {
+        let tmp = TempDir::new("logsplitter2").unwrap();
+        let out = tmp.path().join("out.json.gz");
+        let input = tmp.path().join("input.json.gz");
+
+        let run1 = vec![line("a", "a1"), line("b", "b1"), line("a", "a2")];
+        let run2 = vec![line("a", "a3")];
+
+        for (lines, existing_files) in [(&run1, None), (&run2, Some(ExistingFilePolicy::Append))] {
+            write_input(&input, lines);
+            let stats = futures::executor::block_on(run_async(RunCfg {
+                input_files: vec![input.clone()],
+                output: OutputTarget::File {
+                    path: out.clone(),
+                    compression: Compression::fast(),
+                },
+                existing_files,
+                ..Default::default()
+            }))
+            .unwrap();
+            assert_eq!(stats.lines_written, lines.len());
+            assert!(stats.manifest.is_none());
+        }
+
+        // Lines are written in input order regardless of their key, with the second run as a new member
+        let open = || std::fs::File::open(&out).unwrap();
+        assert_eq!(
+            read_lines(MultiGzDecoder::new(open())),
+            [run1.clone(), run2].concat()
+        );
+        assert_eq!(read_lines(GzDecoder::new(open())), run1);
+        assert_eq!(std::fs::read_dir(tmp.path()).unwrap().count(), 2);
+
+        let err = run(RunCfg {
+            input_files: vec![input.clone()],
+            output: OutputTarget::File {
+                path: input.clone(),
+                compression: Compression::fast(),
+            },
+            ..Default::default()
+        })
+        .unwrap_err();
+        assert!(matches!(*err.kind, ErrorKind::InputIsOutput { .. }));
+    }
+
+    #[test]
+    fn test_random_hash_seed() {
+        use std::hash::{BuildHasher, RandomState};
+
+        let tmp = TempDir::new("logsplitter2").unwrap();
+        let input = tmp.path().join("input.json.gz");
+        let services = ["a", "b", "c", "d", "e", "f", "g"];
+        let lines = (0..200)
+            .map(|i| line(services[i % services.len()], &i.to_string()))
+            .collect::<Vec<_>>();
+        write_input(&input, &lines);
+
+        // Which output thread finished each key's file
+        let split = |thread_assignment, hash_seed, out: &Path| {
+            let threads = Arc::new(std::sync::Mutex::new(std::collections::BTreeMap::new()));
+            let finished = threads.clone();
+            run(RunCfg {
+                input_files: vec![input.clone()],
+                output: OutputTarget::Dir(out.to_path_buf()),
+                output_threads: Threads::Fixed(3),
+                max_active_files: 3,
+                thread_assignment,
+                hash_seed,
+                on_file_complete: Some(OnFileComplete::new(move |event: FileCompleteEvent| {
+                    let thread = std::thread::current().name().unwrap().to_string();
+                    finished.lock().unwrap().insert(event.key, thread);
+                })),
+                ..Default::default()
+            })
+            .unwrap();
+            let threads = threads.lock().unwrap().clone();
+            threads
+        };
+
+        // The seed changes which thread `HashMod` routes keys to, but not what's written
+        let unseeded = split(ThreadAssignment::HashMod, 0, &tmp.path().join("out_0"));
+        let seeded = split(ThreadAssignment::HashMod, 1, &tmp.path().join("out_1"));
+        assert_eq!(unseeded.len(), services.len());
+        assert_ne!(unseeded, seeded);
+        for service in services {
+            let read = |out: &str| {
+                let file = std::fs::File::open(output_file(&tmp.path().join(out), service));
+                read_lines(MultiGzDecoder::new(file.unwrap()))
+            };
+            assert_eq!(read("out_0"), read("out_1"));
+        }
+
+        let seed = RandomState::new().hash_one(0);
+        for thread_assignment in [ThreadAssignment::RoundRobin, ThreadAssignment::HashMod] {
+            let out = tmp.path().join(format!("out_{thread_assignment:?}"));
+            split(thread_assignment, seed, &out);
+
+            // Every key's lines still end up together, in input order
+            for service in services {
+                let file = std::fs::File::open(output_file(&out, service)).unwrap();
+                let expected = lines
+                    .iter()
+                    .filter(|l| l.contains(&format!("\"service\":\"{service}\"")))
+                    .cloned()
+                    .collect::<Vec<_>>();
+                assert_eq!(
+                    read_lines(MultiGzDecoder::new(file)),
+                    expected,
+                    "seed {seed}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_on_file_complete() {
+        let tmp = TempDir::new("logsplitter2").unwrap();
+        let out = tmp.path().join("out");
+        let input = tmp.path().join("input.json.gz");
+        let lines = [
+            line("a", "a1"),
+            line("b", "b1"),
+            line("a", "a2"),
+            line("c", "c1"),
+        ];
+        write_input(&input, &lines);
+
+        // Each file is already whole when it's reported
+        let events = Arc::new(std::sync::Mutex::new(vec![]));
+        let reported = events.clone();
+        let on_file_complete = OnFileComplete::new(move |event: FileCompleteEvent| {
+            let read = read_lines(GzDecoder::new(std::fs::File::open(&event.path).unwrap()));
+            assert_eq!(read.len() as u64, event.lines);
+            reported.lock().unwrap().push(event);
+        });
+        run(RunCfg {
+            input_files: vec![input.clone()],
+            output: OutputTarget::Dir(out.clone()),
+            output_threads: Threads::Fixed(2),
+            on_file_complete: Some(on_file_complete),
+            ..Default::default()
+        })
+        .unwrap();
+
+        let mut events = events.lock().unwrap().clone();
+        events.sort_by(|a, b| a.key.cmp(&b.key));
+        let manifest = Manifest::read(&out).unwrap();
+        assert_eq!(events.len(), manifest.files.len());
+        for (event, entry) in events.iter().zip(&manifest.files) {
+            assert_eq!(event.key, entry.key);
+            assert_eq!(event.path, out.join(&entry.file));
+            assert_eq!(event.lines, entry.lines);
+            assert_eq!(event.bytes, std::fs::metadata(&event.path).unwrap().len());
+        }
+        assert_eq!(events[0].path, output_file(&out, "a"));
+        assert_eq!(events[0].lines, 2);
+
+        // Each file is reported as soon as it's finished, while the thread's other files are still being finished
+        let whole = |path: &Path| {
+            let mut text = String::new();
+            std::fs::File::open(path).is_ok_and(|f| {
+                MultiGzDecoder::new(f).read_to_string(&mut text).is_ok() && !text.is_empty()
+            })
+        };
+        let finished = Arc::new(std::sync::Mutex::new(vec![]));
+        let reported = finished.clone();
+        let dir = out.clone();
+        let on_file_complete = OnFileComplete::new(move |event: FileCompleteEvent| {
+            let others = ["a", "b", "c"]
+                .map(|s| output_file(&dir, s))
+                .into_iter()
+                .filter(|path| *path != event.path && whole(path))
+                .count();
+            reported.lock().unwrap().push(others);
+        });
+        run(RunCfg {
+            input_files: vec![input.clone()],
+            output: OutputTarget::Dir(out.clone()),
+            output_threads: Threads::Fixed(1),
+            existing_files: Some(ExistingFilePolicy::Truncate),
+            on_file_complete: Some(on_file_complete),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(*finished.lock().unwrap(), [0, 1, 2]);
+
+        // A callback which panics doesn't take the run down with it
+        run(RunCfg {
+            input_files: vec![input],
+            output: OutputTarget::Dir(out.clone()),
+            output_threads: Threads::Fixed(2),
+            existing_files: Some(ExistingFilePolicy::Truncate),
+            on_file_complete: Some(OnFileComplete::new(|_| panic!("callback failed"))),
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(Manifest::read(&out)
+            .unwrap()
+            .files
+            .iter()
+            .all(|e| e.complete));
+    }
+
+    #[test]
+    fn test_max_memory() {
+        let tmp = TempDir::new("logsplitter2").unwrap();
+        let out = tmp.path().join("out");
+        let input = tmp.path().join("input.json.gz");
+        // Far more keys than the budget has room for encoders, each of them written again and again
+        let keys = 400;
+        let lines = (0..4)
+            .flat_map(|round| (0..keys).map(move |k| line(&format!("s{k}"), &format!("m{round}"))))
+            .collect::<Vec<_>>();
+        write_input(&input, &lines);
+
+        let limit = 16 * ENCODER_MEMORY as u64;
+        let stats = futures::executor::block_on(run_async(RunCfg {
+            input_files: vec![input],
+            output: OutputTarget::Dir(out.clone()),
+            output_threads: Threads::Fixed(2),
+            max_memory: Some(limit),
+            ..Default::default()
+        }))
+        .unwrap();
+        let memory = stats.memory.unwrap();
+        assert_eq!(memory.limit, limit);
+        assert!(memory.high_water <= limit, "{memory:?}");
+        assert_eq!(memory.used, 0);
+        assert_eq!(stats.lines_written, lines.len());
+
+        // Parked keys carry on in a new gzip member
+        let read = read_lines(MultiGzDecoder::new(
+            std::fs::File::open(output_file(&out, "s7")).unwrap(),
+        ));
+        assert_eq!(
+            read,
+            (0..4)
+                .map(|round| line("s7", &format!("m{round}")))
+                .collect::<Vec<_>>()
+        );
+
+        // A budget without room for a few compressors per thread would park every key after every line
+        let e = crate::Splitter::new(RunCfg {
+            output: OutputTarget::Dir(tmp.path().join("small")),
+            output_threads: Threads::Fixed(8),
+            max_memory: Some(1 << 20),
+            ..Default::default()
+        })
+        .err()
+        .unwrap();
+        assert!(matches!(e.kind(), ErrorKind::InvalidConfig(_)), "{e}");
+        assert!(!tmp.path().join("small").exists());
+    }
+
+    #[test]
+    fn test_auto_stripes() {
+        let tmp = TempDir::new("logsplitter2").unwrap();
+        let out = tmp.path().join("out");
+        let input = tmp.path().join("input.json.gz");
+        let total = AUTO_WARM_UP_LINES as usize + 2000;
+        let lines = (0..total)
+            .map(|i| match i % 5 {
+                0 => line("cold", &i.to_string()),
+                _ => line("hot", &i.to_string()),
+            })
+            .collect::<Vec<_>>();
+        write_input(&input, &lines);
+        run(RunCfg {
+            input_files: vec![input],
+            output: OutputTarget::Dir(out.clone()),
+            output_threads: Threads::Fixed(4),
+            // Keeps the test quick without optimizations
+            compression: Arc::new(|_| Compression::fast()),
+            stripes: Some(KeyStripes {
+                keys: vec![],
+                count: StripeCount::Auto,
+            }),
+            ..Default::default()
+        })
+        .unwrap();
+
+        // The hot key has 4/5 of the lines, so it's split into one stripe per thread once the warm-up is over.
+        // Its lines from before then stay in its own file
+        let files_of = |service: &str| {
+            let mut files = std::fs::read_dir(&out)
+                .unwrap()
+                .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+                .filter(|f| f.starts_with(&format!("{service}_")))
+                .collect::<Vec<_>>();
+            files.sort();
+            files
+        };
+        let hot = files_of("hot");
+        assert_eq!(
+            hot,
+            [
+                "hot_prod_2024-10-20.0.json.gz",
+                "hot_prod_2024-10-20.1.json.gz",
+                "hot_prod_2024-10-20.2.json.gz",
+                "hot_prod_2024-10-20.3.json.gz",
+                "hot_prod_2024-10-20.json.gz",
+            ]
+        );
+        assert_eq!(files_of("cold"), ["cold_prod_2024-10-20.json.gz"]);
+        assert_eq!(
+            read_lines(GzDecoder::new(
+                std::fs::File::open(output_file(&out, "hot")).unwrap()
+            ))
+            .len(),
+            AUTO_WARM_UP_LINES as usize * 4 / 5
+        );
+
+        let mut read = hot
+            .iter()
+            .flat_map(|f| read_lines(GzDecoder::new(std::fs::File::open(out.join(f)).unwrap())))
+            .collect::<Vec<_>>();
+        let mut hot_lines = lines
+            .iter()
+            .filter(|l| l.contains("\"hot\""))
+            .cloned()
+            .collect::<Vec<_>>();
+        read.sort();
+        hot_lines.sort();
+        assert_eq!(read, hot_lines);
+    }
+
+    #[test]
+    fn test_metrics() {
+        let tmp = TempDir::new("logsplitter2").unwrap();
+        let out = tmp.path().join("out");
+        let input = tmp.path().join("input.json.gz");
+        let mut cfg = TestdataCfg {
+            lines: 3000,
+            seed: Some(5),
+            ..Default::default()
+        };
+        cfg.set_unique_dates(1)
+            .set_services(3, 3..6)
+            .set_envs(1, 3..6);
+        let generated =
+            generate_testdata(cfg, &mut std::fs::File::create(&input).unwrap(), None).unwrap();
+
+        let metrics = MetricsHandle::default();
+        let cfg = RunCfg {
+            input_files: vec![input],
+            output: OutputTarget::Dir(out.clone()),
+            output_threads: Threads::Fixed(2),
+            metrics: metrics.clone(),
+            ..Default::default()
+        };
+        let running = std::thread::spawn(move || run(cfg));
+
+        let counters = |m: &MetricsSnapshot| {
+            [
+                m.lines_read,
+                m.bytes_read,
+                m.bytes_decoded,
+                m.lines_parsed,
+                m.lines_rejected,
+                m.lines_written,
+                m.bytes_written,
+                m.keys,
+                m.input_stalls,
+                m.output_stalls,
+                m.retries,
+            ]
+        };
+        let mut last = metrics.snapshot();
+        while !running.is_finished() {
+            let now = metrics.snapshot();
+            for (now, last) in counters(&now).into_iter().zip(counters(&last)) {
+                assert!(now >= last, "{now:?} {last:?}");
+            }
+            last = now;
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        running.join().unwrap().unwrap();
+
+        let manifest = Manifest::read(&out).unwrap();
+        let done = metrics.snapshot();
+        assert_eq!(
+            done,
+            MetricsSnapshot {
+                lines_read: generated.lines as u64,
+                bytes_read: generated.bytes_compressed,
+                bytes_decoded: generated.bytes_plain,
+                lines_parsed: generated.lines as u64,
+                lines_rejected: 0,
+                lines_written: generated.lines as u64,
+                bytes_written: manifest.files.iter().map(|e| e.bytes).sum(),
+                open_files: 0,
+                keys: manifest.files.len() as u64,
+                queue_depths: vec![0, 0],
+                input_stalls: done.input_stalls,
+                output_stalls: done.output_stalls,
+                retries: 0,
+            }
+        );
+        assert!(toml::to_string(&done)
+            .unwrap()
+            .contains(&format!("lines_written = {}", generated.lines)));
+    }
+
+    #[test]
+    fn test_truncate_overwrites() {
+        let tmp = TempDir::new("logsplitter2").unwrap();
+        let out = tmp.path().join("out");
+        let input = tmp.path().join("input.json.gz");
+
+        for lines in [vec![line("a", "a1")], vec![line("a", "a2")]] {
+            write_input(&input, &lines);
+            run(RunCfg {
+                input_files: vec![input.clone()],
+                output: OutputTarget::Dir(out.clone()),
+                output_threads: Threads::Fixed(1),
+                ..Default::default()
+            })
+            .unwrap();
+        }
+
+        let f = std::fs::File::open(output_file(&out, "a")).unwrap();
+        assert_eq!(read_lines(MultiGzDecoder::new(f)), [line("a", "a2")]);
+    }
+
+    #[test]
+    fn test_gzip_header() {
+        let tmp = TempDir::new("logsplitter2").unwrap();
+        let out = tmp.path().join("out");
+        let input = tmp.path().join("input.json.gz");
+        write_input(&input, &[line("a", "a1")]);
+
+        for gzip_mtime in [GzipMtime::RunStart, GzipMtime::KeyDate] {
+            let before = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+            run(RunCfg {
+                input_files: vec![input.clone()],
+                output: OutputTarget::Dir(out.clone()),
+                output_threads: Threads::Fixed(1),
+                gzip_mtime,
+                ..Default::default()
+            })
+            .unwrap();
+            let after = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+
+            let mut dec = GzDecoder::new(std::fs::File::open(output_file(&out, "a")).unwrap());
+            // The header is only parsed once reading starts
+            assert_eq!(read_lines(&mut dec), [line("a", "a1")]);
+            let header = dec.header().unwrap();
+
+            assert_eq!(header.filename(), Some(&b"a_prod_2024-10-20.json"[..]));
+            match gzip_mtime {
+                GzipMtime::RunStart => {
+                    assert!((before..=after).contains(&(header.mtime() as u64)))
+                }
+                // 2024-10-20T00:00:00Z
+                GzipMtime::KeyDate => assert_eq!(header.mtime(), 1729382400),
+            }
+        }
+    }
+
+    #[test]
+    fn test_write_index() {
+        let tmp = TempDir::new("logsplitter2").unwrap();
+        let out = tmp.path().join("out");
+        let input = tmp.path().join("input.json.gz");
+
+        let lines = (0..10)
+            .map(|i| line("a", &format!("a{i}")))
+            .collect::<Vec<_>>();
+        write_input(&input, &lines);
+        run(RunCfg {
+            input_files: vec![input.clone()],
+            output: OutputTarget::Dir(out.clone()),
+            output_threads: Threads::Fixed(1),
+            index_interval: Some(3),
+            ..Default::default()
+        })
+        .unwrap();
+
+        let path = output_file(&out, "a");
+        let index_path = out.join("a_prod_2024-10-20.idx");
+
+        let index = LineIndex::read(&index_path).unwrap();
+        assert_eq!(index.interval, 3);
+        assert_eq!(
+            index.entries.iter().map(|e| e.line).collect::<Vec<_>>(),
+            [0, 3, 6, 9]
+        );
+        let line_len = lines[0].len() as u64 + 1;
+        assert_eq!(index.entries[2].uncompressed_offset, 6 * line_len);
+
+        // Index points split the file into members, which still decode as a whole
+        let f = std::fs::File::open(&path).unwrap();
+        assert_eq!(read_lines(MultiGzDecoder::new(f)), lines);
+        let f = std::fs::File::open(&path).unwrap();
+        assert_eq!(read_lines(GzDecoder::new(f)), &lines[..3]);
+
+        for n in [0, 2, 3, 7, 9] {
+            let r = open_at_line(&path, &index_path, n).unwrap();
+            assert_eq!(read_lines(r), &lines[n as usize..]);
+        }
+    }
+
+    #[test]
+    fn test_plain_below() {
+        let tmp = TempDir::new("logsplitter2").unwrap();
+        let out = tmp.path().join("out");
+        let input = tmp.path().join("input.json.gz");
+
+        let small = vec![line("a", "a0"), line("a", "a1")];
+        let big = (0..100)
+            .map(|i| line("b", &format!("b{i}")))
+            .collect::<Vec<_>>();
+        write_input(&input, &[small.clone(), big.clone()].concat());
+        run(RunCfg {
+            input_files: vec![input],
+            output: OutputTarget::Dir(out.clone()),
+            output_threads: Threads::Fixed(1),
+            plain_below: Some(4096),
+            ..Default::default()
+        })
+        .unwrap();
+
+        let plain_path = out.join("a_prod_2024-10-20.json");
+        assert!(!output_file(&out, "a").exists());
+        assert_eq!(read_lines(std::fs::File::open(&plain_path).unwrap()), small);
+        let f = std::fs::File::open(output_file(&out, "b")).unwrap();
+        assert_eq!(read_lines(MultiGzDecoder::new(f)), big);
+
+        let manifest = Manifest::read(&out).unwrap();
+        let summary = manifest
+            .files
+            .iter()
+            .map(|e| (e.file.as_str(), e.format, e.lines))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            [
+                ("a_prod_2024-10-20.json", FileFormat::Plain, 2),
+                ("b_prod_2024-10-20.json.gz", FileFormat::Gzip, 100),
+            ]
+        );
+        for e in &manifest.files {
+            assert_eq!(std::fs::metadata(out.join(&e.file)).unwrap().len(), e.bytes);
+        }
+    }
+
+    #[test]
+    fn test_locked_output_dir() {
+        let tmp = TempDir::new("logsplitter2").unwrap();
+        let out = tmp.path().join("out");
+        let input = tmp.path().join("input.json.gz");
+        write_input(&input, &[line("a", "a1")]);
+
+        let cfg = || RunCfg {
+            input_files: vec![input.clone()],
+            output: OutputTarget::Dir(out.clone()),
+            output_threads: Threads::Fixed(1),
+            ..Default::default()
+        };
+
+        std::fs::create_dir_all(&out).unwrap();
+        let other_run = DirLock::acquire(&out, false).unwrap();
+        // As if the other run was checking that it can write, which a locked out run mustn't get in the way of
+        let probe = out.join(PROBE_FILE_NAME);
+        std::fs::write(&probe, "probe").unwrap();
+        let err = run(cfg()).unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::Lock(_)));
+        assert!(err
+            .full_message()
+            .contains(&format!("({} ", std::process::id())));
+        assert!(!output_file(&out, "a").exists());
+        assert!(probe.exists());
+
+        // The probe is now left behind by a run which no longer holds the lock
+        drop(other_run);
+        run(cfg()).unwrap();
+        assert!(output_file(&out, "a").exists());
+        assert!(!out.join(LOCK_FILE_NAME).exists());
+        assert!(!probe.exists());
+    }
+
+    #[test]
+    fn test_concurrent_runs() {
+        let tmp = TempDir::new("logsplitter2").unwrap();
+        let out = tmp.path().join("out");
+        let (first, second) = (tmp.path().join("a.json.gz"), tmp.path().join("b.json.gz"));
+        write_input(&first, &[line("a", "a1")]);
+        write_input(&second, &[line("b", "b1")]);
+        let cfg = |input: &PathBuf| RunCfg {
+            input_files: vec![input.clone()],
+            output: OutputTarget::Dir(out.clone()),
+            output_threads: Threads::Fixed(1),
+            ..Default::default()
+        };
+
+        // Tailing keeps the first run going for as long as the second one needs
+        let tail_ended = Arc::new(AtomicBool::new(false));
+        let first_run = {
+            let cfg = RunCfg {
+                tail: Some(tail_ended.clone()),
+                ..cfg(&first)
+            };
+            std::thread::spawn(move || run(cfg))
+        };
+        // The lock file is only written to once it's locked
+        while std::fs::read_to_string(out.join(LOCK_FILE_NAME)).map_or(true, |s| s.is_empty()) {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let err = run(cfg(&second)).unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::Lock(_)), "{err}");
+        assert!(!output_file(&out, "b").exists());
+
+        tail_ended.store(true, Ordering::Relaxed);
+        first_run.join().unwrap().unwrap();
+        assert!(output_file(&out, "a").exists());
+        assert!(!out.join(LOCK_FILE_NAME).exists());
+
+        // Stopping early (such as on a signal) still finishes the output and unlocks the directory
+        let stop = Arc::new(AtomicBool::new(true));
+        run(RunCfg {
+            stop: Some(stop),
+            existing_files: Some(ExistingFilePolicy::Append),
+            ..cfg(&second)
+        })
+        .unwrap();
+        assert!(!out.join(LOCK_FILE_NAME).exists());
+        assert!(!output_file(&out, "b").exists());
+        run(cfg(&second)).unwrap();
+        assert!(output_file(&out, "b").exists());
+    }
+
+    #[test]
+    fn test_redact_fields() {
+        let tmp = TempDir::new("logsplitter2").unwrap();
+        let input = tmp.path().join("input.json.gz");
+        let out = tmp.path().join("out");
+        let with_user = json::object! {
+            message: "login",
+            "@timestamp": "2024-10-20T12:00:00Z",
+            "@meta": { service: "auth", env: "prod", user: "alice" },
+        };
+        write_input(&input, &[with_user.dump(), line("auth", "no user")]);
+
+        run(RunCfg {
+            input_files: vec![input],
+            output: OutputTarget::Dir(out.clone()),
+            redact_fields: vec!["@meta.user".parse().unwrap()],
+            ..Default::default()
+        })
+        .unwrap();
+
+        let written = read_lines(GzDecoder::new(
+            std::fs::File::open(output_file(&out, "auth")).unwrap(),
+        ));
+        assert_eq!(written.len(), 2);
+        let redacted = json::parse(&written[0]).unwrap();
+        assert_eq!(redacted["@meta"]["service"], "auth");
+        assert!(!redacted["@meta"].has_key("user"));
+        assert!(!written[0].contains("alice"));
+        // Lines without the field are written as they were
+        assert_eq!(written[1], line("auth", "no user"));
+    }
+
+    #[test]
+    fn test_transform() {
+        let tmp = TempDir::new("logsplitter2").unwrap();
+        let input = tmp.path().join("input.json.gz");
+        let out = tmp.path().join("out");
+        write_input(
+            &input,
+            &[
+                line("a", "1"),
+                line("b", "2"),
+                line("c", "3"),
+                line("a", "4"),
+            ],
+        );
+
+        run(RunCfg {
+            input_files: vec![input],
+            output: OutputTarget::Dir(out.clone()),
+            max_lines: Some(3),
+            // Drops `b`, and moves `c` into `a`'s file
+            transform: Some(Box::new(|ln: LineData| match ln.key().name() {
+                "b_prod_2024-10-20" => None,
+                "c_prod_2024-10-20" => {
+                    let key = MsgKey::new("a_prod_2024-10-20", ln.key().date());
+                    Some(LineData::new(ln.original_line_text().to_string(), key))
+                }
+                _ => Some(ln),
+            })),
+            ..Default::default()
+        })
+        .unwrap();
+
+        let manifest = Manifest::read(&out).unwrap();
+        assert_eq!(manifest.files.len(), 1);
+        // The dropped line doesn't count towards `max_lines`
+        assert_eq!(
+            read_lines(GzDecoder::new(
+                std::fs::File::open(output_file(&out, "a")).unwrap(),
+            )),
+            [line("a", "1"), line("c", "3"), line("a", "4")]
+        );
+    }
+
+    #[test]
+    fn test_balance_threads() {
+        let tmp = TempDir::new("logsplitter2").unwrap();
+        let input = tmp.path().join("input.json.gz");
+        let out = tmp.path().join("out");
+        // `a` has most of the lines
+        let mut lines = (0..50)
+            .map(|i| line("a", &i.to_string()))
+            .collect::<Vec<_>>();
+        lines.extend(["b", "c", "d"].map(|s| line(s, "")));
+        lines.push("not json".to_string());
+        write_input(&input, &lines);
+
+        run(RunCfg {
+            input_files: vec![input.clone()],
+            output: OutputTarget::Dir(out.clone()),
+            output_threads: Threads::Fixed(2),
+            balance_threads: true,
+            max_invalid_lines: InvalidLineLimit::Count(1),
+            ..Default::default()
+        })
+        .unwrap();
+
+        let manifest = Manifest::read(&out).unwrap();
+        assert_eq!(manifest.files.len(), 4);
+        assert_eq!(
+            read_lines(GzDecoder::new(
+                std::fs::File::open(output_file(&out, "a")).unwrap(),
+            )),
+            lines[..50]
+        );
+
+        // With many services, a few of which have most of the lines like in real logs, every thread gets about as much
+        let out = tmp.path().join("out_skewed");
+        let mut cfg = TestdataCfg {
+            lines: 5_000,
+            seed: Some(8),
+            key_distribution: KeyDistribution::Zipf { exponent: 1.0 },
+            ..Default::default()
+        };
+        cfg.set_unique_dates(1)
+            .set_services(30, 3..6)
+            .set_envs(1, 3..6);
+        generate_testdata(cfg, &mut std::fs::File::create(&input).unwrap(), None).unwrap();
+        let threads = 4;
+
+        let stats = futures::executor::block_on(run_async(RunCfg {
+            input_files: vec![input.clone()],
+            output: OutputTarget::Dir(out.clone()),
+            output_threads: Threads::Fixed(threads),
+            balance_threads: true,
+            ..Default::default()
+        }))
+        .unwrap();
+        assert_eq!(stats.lines_written, 5_000);
+
+        // Each thread's load is the size of the lines of the keys the run's first pass assigned to it
+        let manifest = Manifest::read(&out).unwrap();
+        assert_eq!(manifest.files.iter().map(|e| e.lines).sum::<u64>(), 5_000);
+        let size_of = manifest
+            .files
+            .iter()
+            .map(|e| (e.key.as_str(), e.uncompressed_bytes.unwrap()))
+            .collect::<HashMap<_, _>>();
+        let loads = |assignments: &[Vec<MsgKey>]| {
+            assignments
+                .iter()
+                .map(|keys| keys.iter().map(|k| size_of[k.name()]).sum())
+                .collect::<Vec<u64>>()
+        };
+        let assignments = balance_keys(JsonLinesRecv::spawn_files(vec![input]), threads);
+        assert_eq!(assignments.len(), threads);
+        assert_eq!(
+            assignments.iter().map(Vec::len).sum::<usize>(),
+            manifest.files.len()
+        );
+        let balanced = math_utils::imbalance_ratio(&loads(&assignments));
+        assert!(balanced < 1.1, "{balanced}");
+
+        // Dealing the same keys out in turn leaves the threads with the biggest keys far busier
+        let mut keys = assignments.into_iter().flatten().collect::<Vec<_>>();
+        keys.sort_by(|a, b| a.name().cmp(b.name()));
+        let mut dealt = vec![vec![]; threads];
+        for (i, key) in keys.into_iter().enumerate() {
+            dealt[i % threads].push(key);
+        }
+        let dealt = math_utils::imbalance_ratio(&loads(&dealt));
+        assert!(dealt > balanced + 0.2, "{dealt} vs {balanced}");
+    }
+
+    #[test]
+    fn test_normalized_keys_independent_of_order() {
+        let tmp = TempDir::new("logsplitter2").unwrap();
+        let input = tmp.path().join("input.json.gz");
+        let env_line = |env: &str| {
+            json::object! {
+                "@timestamp": "2024-10-20T12:00:00Z",
+                "@meta": { service: "Auth ", env: env },
+            }
+            .dump()
+        };
+
+        let mut names = vec![];
+        for (i, envs) in [["Prod", "prod"], ["prod", "Prod"]].iter().enumerate() {
+            let out = tmp.path().join(format!("out{i}"));
+            write_input(&input, &envs.map(env_line));
+            run(RunCfg {
+                input_files: vec![input.clone()],
+                output: OutputTarget::Dir(out.clone()),
+                key_fn: Arc::new(normalized_key),
+                ..Default::default()
+            })
+            .unwrap();
+            let manifest = Manifest::read(&out).unwrap();
+            names.push(
+                manifest
+                    .files
+                    .into_iter()
+                    .map(|e| e.file)
+                    .collect::<Vec<_>>(),
+            );
+        }
+        assert_eq!(names[0], ["auth_prod_2024-10-20.json.gz"]);
+        assert_eq!(names[0], names[1]);
+    }
+
+    #[test]
+    fn test_env_mapped_keys() {
+        let tmp = TempDir::new("logsplitter2").unwrap();
+        let input = tmp.path().join("input.json.gz");
+        let out = tmp.path().join("out");
+        let mut lines = vec![];
+        for service in ["a", "b"] {
+            for date in ["2024-10-20", "2024-10-21"] {
+                for env in ["prod", "staging", "dev", "qa"] {
+                    lines.push(
+                        json::object! {
+                            "@timestamp": format!("{date}T12:00:00Z"),
+                            "@meta": { service: service, env: env },
+                        }
+                        .dump(),
+                    );
+                }
+            }
+        }
+        write_input(&input, &lines);
+
+        let env_map = EnvMap {
+            envs: [("prod", "prod")]
+                .map(|(from, to)| (from.to_string(), to.to_string()))
+                .into(),
+            default: Some("nonprod".to_string()),
+        };
+        run(RunCfg {
+            input_files: vec![input],
+            output: OutputTarget::Dir(out.clone()),
+            key_fn: env_mapped_key(env_map, false),
+            ..Default::default()
+        })
+        .unwrap();
+        let mut names = Manifest::read(&out)
+            .unwrap()
+            .files
+            .into_iter()
+            .map(|e| (e.file, e.lines))
+            .collect::<Vec<_>>();
+        names.sort();
+        // Two files for each service and date, with the lines of three envs in the `nonprod` one
+        let mut expected = vec![];
+        for service in ["a", "b"] {
+            for date in ["2024-10-20", "2024-10-21"] {
+                expected.push((format!("{service}_nonprod_{date}.json.gz"), 3));
+                expected.push((format!("{service}_prod_{date}.json.gz"), 1));
+            }
+        }
+        expected.sort();
+        assert_eq!(names, expected);
+    }
+
+    #[test]
+    fn test_custom_key_fn_across_dates() {
+        let tmp = TempDir::new("logsplitter2").unwrap();
+        let input = tmp.path().join("input.json.gz");
+        let out = tmp.path().join("out");
+        let lines = ["2024-10-20", "2024-10-21", "2024-10-20", "2024-10-22"].map(|date| {
+            json::object! { level: "warn", "@timestamp": format!("{date}T12:00:00Z") }.dump()
+        });
+        write_input(&input, &lines);
+
+        // Every line has the same name, whatever its date
+        let by_level = |info: &json::JsonValue| {
+            Some(MsgKey::new(
+                &format!("level_{}", info["level"].as_str()?),
+                crate::data::line_date(info)?,
+            ))
+        };
+        run(RunCfg {
+            input_files: vec![input],
+            output: OutputTarget::Dir(out.clone()),
+            output_threads: Threads::Fixed(2),
+            key_fn: Arc::new(by_level),
+            ..Default::default()
+        })
+        .unwrap();
+
+        let f = std::fs::File::open(out.join("level_warn.json.gz")).unwrap();
+        assert_eq!(read_lines(MultiGzDecoder::new(f)), lines);
+        let manifest = Manifest::read(&out).unwrap();
+        assert_eq!(manifest.files.len(), 1);
+        assert_eq!(manifest.files[0].lines, 4);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_create_modes() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp = TempDir::new("logsplitter2").unwrap();
+        let input = tmp.path().join("input.json.gz");
+        let out = tmp.path().join("new").join("out");
+        write_input(&input, &[line("a", "1"), line("b", "2")]);
+
+        run(RunCfg {
+            input_files: vec![input],
+            output: OutputTarget::Dir(out.clone()),
+            output_threads: Threads::Fixed(1),
+            file_mode: Some("640".parse().unwrap()),
+            dir_mode: Some("0o750".parse().unwrap()),
+            ..Default::default()
+        })
+        .unwrap();
+
+        let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o7777;
+        for path in [
+            output_file(&out, "a"),
+            output_file(&out, "b"),
+            out.join(MANIFEST_FILE_NAME),
+        ] {
+            assert_eq!(mode(&path), 0o640, "{}", path.display());
+        }
+        assert_eq!(mode(&out), 0o750);
+        assert_eq!(mode(&tmp.path().join("new")), 0o750);
+        // Directories which already existed are left alone
+        assert_ne!(mode(tmp.path()), 0o750);
+
+        assert!("8".parse::<UnixMode>().is_err());
+        assert!("17777".parse::<UnixMode>().is_err());
+        assert_eq!("0644".parse::<UnixMode>().unwrap().to_string(), "644");
+    }
+
+    #[test]
+    fn test_missing_input() {
+        let tmp = TempDir::new("logsplitter2").unwrap();
+        let input = tmp.path().join("input.json.gz");
+        let missing = tmp.path().join("missing.json.gz");
+        let out = tmp.path().join("out");
+        write_input(&input, &[line("a", "1")]);
+
+        // Checked before any input is read, or anything is written
+        let e = run(RunCfg {
+            input_files: vec![input, missing.clone()],
+            output: OutputTarget::Dir(out.clone()),
+            ..Default::default()
+        })
+        .unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::Io { path: Some(p), .. } if p == &missing));
+        assert!(!out.exists());
+    }
+
+    #[test]
+    fn test_plain_input() {
+        let tmp = TempDir::new("logsplitter2").unwrap();
+        let input = tmp.path().join("input.json");
+        let out = tmp.path().join("out");
+        std::fs::write(&input, line("a", "1") + "\n").unwrap();
+
+        // Fails instead of succeeding with no output
+        let e = run(RunCfg {
+            input_files: vec![input.clone()],
+            output: OutputTarget::Dir(out.clone()),
+            output_threads: Threads::Fixed(1),
+            ..Default::default()
+        })
+        .unwrap_err();
+        match e.kind() {
+            ErrorKind::ReadErr(ReadError::NotGzip { input: name, start }) => {
+                assert_eq!(name, &input.display().to_string());
+                assert_eq!(start, &line("a", "1").as_bytes()[..16]);
+            }
+            other => panic!("Unexpected error {other:?}"),
+        }
+        assert!(
+            e.source()
+                .unwrap()
+                .to_string()
+                .contains(r#"does not appear to be gzip (starts with "{\"message\":\"1\",\"")"#),
+            "{e}"
+        );
+        assert!(!output_file(&out, "a").exists());
+
+        // An empty input is still just an input without lines
+        std::fs::write(&input, "").unwrap();
+        run(RunCfg {
+            input_files: vec![input],
+            output: OutputTarget::Dir(out),
+            output_threads: Threads::Fixed(1),
+            existing_files: Some(ExistingFilePolicy::Truncate),
+            ..Default::default()
+        })
+        .unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_input_inside_output() {
+        let tmp = TempDir::new("logsplitter2").unwrap();
+        let out = tmp.path().join("out");
+        std::fs::create_dir_all(out.join("sub")).unwrap();
+        let input = out.join("sub").join("input.json.gz");
+        write_input(&input, &[line("a", "a1")]);
+
+        // Both the input and the output directory are reached through symlinks
+        let input_link = tmp.path().join("input_link");
+        let out_link = tmp.path().join("out_link");
+        std::os::unix::fs::symlink(out.join("sub"), &input_link).unwrap();
+        std::os::unix::fs::symlink(&out, &out_link).unwrap();
+
+        for (input, output) in [
+            (input.clone(), out.clone()),
+            (input_link.join("input.json.gz"), out.clone()),
+            (input.clone(), out_link.clone()),
+            (out_link.join("sub/../sub/input.json.gz"), out_link.clone()),
+        ] {
+            let err = run(RunCfg {
+                input_files: vec![input],
+                output: OutputTarget::Dir(output),
+                output_threads: Threads::Fixed(1),
+                ..Default::default()
+            })
+            .unwrap_err();
+            match err.kind() {
+                ErrorKind::InputInsideOutput { input, output_dir } => {
+                    assert_eq!(
+                        input,
+                        &out.canonicalize().unwrap().join("sub/input.json.gz")
+                    );
+                    assert_eq!(output_dir, &out.canonicalize().unwrap());
+                }
+                other => panic!("Unexpected error {other:?}"),
+            }
+            assert!(!output_file(&out, "a").exists());
+        }
+
+        // A sibling of the output directory is fine, even if its name shares a prefix
+        let sibling = tmp.path().join("out2");
+        run(RunCfg {
+            input_files: vec![input_link.join("input.json.gz")],
+            output: OutputTarget::Dir(sibling.clone()),
+            output_threads: Threads::Fixed(1),
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(output_file(&sibling, "a").exists());
+    }
+
+    #[test]
+    fn test_output_dir_unreadable() {
+        // As if the directory was deleted after being created
+        let tmp = TempDir::new("logsplitter2").unwrap();
+        let out = tmp.path().join("out");
+        let err = check_output_dir(&[], &out, None).unwrap_err();
+        match err.kind() {
+            ErrorKind::Io { path, source } => {
+                assert_eq!(path.as_deref(), Some(out.as_path()));
+                assert_eq!(source.kind(), std::io::ErrorKind::NotFound);
+            }
+            other => panic!("Unexpected error {other:?}"),
+        }
+    }
+    #[test]
+    #[cfg(unix)]
+    fn test_read_only_output() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp = TempDir::new("logsplitter2").unwrap();
+        let input = tmp.path().join("input.json.gz");
+        write_input(&input, &[line("a", "a1")]);
+
+        let read_only = tmp.path().join("read_only");
+        std::fs::create_dir(&read_only).unwrap();
+        std::fs::set_permissions(&read_only, std::fs::Permissions::from_mode(0o555)).unwrap();
+
+        // Permissions don't apply to root, but nobody can create files in procfs
+        let permissions_apply = std::fs::File::create(read_only.join("f")).is_err();
+        let outputs = if permissions_apply {
+            vec![read_only.clone(), read_only.join("nested")]
+        } else {
+            vec![PathBuf::from("/proc/self")]
+        };
+
+        for out in outputs {
+            let err = run(RunCfg {
+                input_files: vec![input.clone()],
+                output: OutputTarget::Dir(out.clone()),
+                output_threads: Threads::Fixed(1),
+                ..Default::default()
+            })
+            .unwrap_err();
+            match err.kind() {
+                ErrorKind::OutputNotWritable { output_dir, .. } => assert_eq!(output_dir, &out),
+                other => panic!("Unexpected error {other:?}"),
+            }
+        }
+
+        std::fs::set_permissions(&read_only, std::fs::Permissions::from_mode(0o755)).unwrap();
+        assert!(available_space(tmp.path()).unwrap() > 0);
+    }
+
+    #[test]
+    fn test_threads() {
+        assert_eq!("auto".parse(), Ok(Threads::Auto));
+        assert_eq!("3".parse(), Ok(Threads::Fixed(3)));
+        assert_eq!("0".parse(), Ok(Threads::Auto));
+        assert!("-1".parse::<Threads>().is_err());
+        assert!("many".parse::<Threads>().is_err());
+
+        assert_eq!(Threads::Fixed(3).resolve(u64::MAX), 3);
+        assert_eq!(Threads::Auto.resolve(0), 1);
+        assert_eq!(Threads::Auto.resolve(1), 1);
+        assert_eq!(
+            Threads::Auto.resolve(u64::MAX),
+            Threads::max_auto(),
+            "Auto never uses more threads than spare cores"
+        );
+        assert!(Threads::max_auto() >= 1);
+        if Threads::max_auto() >= 2 {
+            assert_eq!(Threads::Auto.resolve(Threads::AUTO_BYTES_PER_THREAD + 1), 2);
+        }
+    }
+
+    #[test]
+    fn test_check_file_sizes() {
+        let manifest = |keys: usize, dates: usize, bytes: u64| Manifest {
+            partial: false,
+            files: (0..keys)
+                .flat_map(|k| {
+                    (0..dates).map(move |d| ManifestEntry {
+                        key: format!(
+                            "s{k}_prod_{}",
+                            NaiveDate::default() + TimeDelta::days(d as i64)
+                        ),
+                        file: format!("s{k}_prod_{d}.json.gz"),
+                        format: FileFormat::Gzip,
+                        lines: 1,
+                        bytes,
+                        uncompressed_bytes: None,
+                        complete: true,
+                    })
+                })
+                .collect(),
+        };
+
+        assert!(matches!(
+            check_file_sizes(&manifest(10, 100, 200)),
+            Some(ErrorKind::TinyFiles {
+                files: 1000,
+                keys: 10,
+                median_bytes: 200
+            })
+        ));
+        // Big enough files
+        assert!(check_file_sizes(&manifest(10, 100, TINY_FILE_BYTES)).is_none());
+        // Too few files to matter
+        assert!(check_file_sizes(&manifest(1, TINY_FILES_MIN_COUNT - 1, 200)).is_none());
+        // Many keys with a single date each, which coarser dates wouldn't help with
+        assert!(check_file_sizes(&manifest(1000, 1, 200)).is_none());
+    }
+
+    #[test]
+    fn test_strict_tiny_files() {
+        let tmp = TempDir::new("logsplitter2").unwrap();
+        let input = tmp.path().join("input.json.gz");
+        let mut cfg = TestdataCfg {
+            lines: 10_000,
+            seed: Some(5),
+            ..Default::default()
+        };
+        // A few lines for each of two services on every date
+        cfg.set_unique_dates(400)
+            .set_start_date(2024, 1, 1)
+            .set_date_delta(1)
+            .set_services(2, 3..6)
+            .set_envs(1, 3..6);
+        generate_testdata(cfg, &mut std::fs::File::create(&input).unwrap(), None).unwrap();
+
+        let split = |out: &str, strict: bool| {
+            run(RunCfg {
+                input_files: vec![input.clone()],
+                output: OutputTarget::Dir(tmp.path().join(out)),
+                strict,
+                ..Default::default()
+            })
+        };
+        let e = split("strict", true).unwrap_err();
+        let ErrorKind::TinyFiles { files, keys, .. } = *e.kind() else {
+            panic!("Unexpected error: {e}");
+        };
+        assert_eq!(keys, 2);
+        assert!(files >= TINY_FILES_MIN_COUNT, "{files}");
+        // Only a warning otherwise
+        split("lenient", false).unwrap();
+    }
+
+    #[test]
+    fn test_tail() {
+        let tmp = TempDir::new("logsplitter2").unwrap();
+        let input = tmp.path().join("input.json.gz");
+        let out = tmp.path().join("out");
+        let lines = (0..20)
+            .map(|i| line("a", &i.to_string()))
+            .collect::<Vec<_>>();
+        write_input(&input, &lines[..10]);
+
+        let tail_ended = Arc::new(AtomicBool::new(false));
+        let cfg = RunCfg {
+            input_files: vec![input.clone()],
+            output: OutputTarget::Dir(out.clone()),
+            output_threads: Threads::Fixed(1),
+            tail: Some(tail_ended.clone()),
+            ..Default::default()
+        };
+        let h = std::thread::spawn(move || run(cfg));
+        std::thread::sleep(TAIL_POLL * 3);
+        assert!(!h.is_finished());
+
+        // Appended while the run waits for more, as a member of its own
+        let mut enc = GzEncoder::new(vec![], Compression::default());
+        for l in &lines[10..] {
+            writeln!(enc, "{l}").unwrap();
+        }
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&input)
+            .unwrap()
+            .write_all(&enc.finish().unwrap())
+            .unwrap();
+        tail_ended.store(true, Ordering::Relaxed);
+        h.join().unwrap().unwrap();
+
+        let got = read_lines(MultiGzDecoder::new(
+            std::fs::File::open(output_file(&out, "a")).unwrap(),
+        ));
+        assert_eq!(got, lines);
+    }
+
+    #[test]
+    fn test_max_lines() {
+        let tmp = TempDir::new("logsplitter2").unwrap();
+        let input = tmp.path().join("input.json.gz");
+        let out = tmp.path().join("out");
+
+        let mut expected = vec![];
+        generate_testdata(
+            TestdataCfg {
+                lines: 1000,
+                ..Default::default()
+            },
+            &mut std::fs::File::create(&input).unwrap(),
+            Some(&mut expected),
+        )
+        .unwrap();
+
+        // Returning at all means the reader thread didn't deadlock on the dropped receiver
+        run(RunCfg {
+            input_files: vec![input],
+            output: OutputTarget::Dir(out.clone()),
+            max_lines: Some(10),
+            ..Default::default()
+        })
+        .unwrap();
+
+        let manifest = Manifest::read(&out).unwrap();
+        assert_eq!(manifest.files.iter().map(|e| e.lines).sum::<u64>(), 10);
+        let mut got = manifest
+            .files
+            .iter()
+            .flat_map(|e| {
+                read_lines(GzDecoder::new(
+                    std::fs::File::open(out.join(&e.file)).unwrap(),
+                ))
+            })
+            .collect::<Vec<_>>();
+        let mut expected = read_lines(&expected[..])[..10].to_vec();
+        got.sort();
+        expected.sort();
+        assert_eq!(got, expected);
+    }
+
+    #[test]
+    fn test_corrupt_second_input() {
+        let tmp = TempDir::new("logsplitter2").unwrap();
+        let out = tmp.path().join("out");
+        let inputs = [
+            tmp.path().join("first.json.gz"),
+            tmp.path().join("second.json.gz"),
+        ];
+        let mut plain = [vec![], vec![]];
+        for (seed, (input, plain)) in inputs.iter().zip(&mut plain).enumerate() {
+            let mut cfg = TestdataCfg {
+                lines: 1000,
+                seed: Some(seed as u64),
+                ..Default::default()
+            };
+            cfg.set_unique_dates(1)
+                .set_services(3, 3..6)
+                .set_envs(1, 3..6);
+            generate_testdata(cfg, &mut std::fs::File::create(input).unwrap(), Some(plain))
+                .unwrap();
+        }
+        let first = read_lines(&plain[0][..]);
+        let run_inputs = || RunCfg {
+            input_files: inputs.to_vec(),
+            output: OutputTarget::Dir(out.clone()),
+            existing_files: Some(ExistingFilePolicy::Truncate),
+            ..Default::default()
+        };
+        let written = || {
+            let mut lines = Manifest::read(&out)
+                .unwrap()
+                .files
+                .iter()
+                .flat_map(|e| {
+                    read_lines(MultiGzDecoder::new(
+                        std::fs::File::open(out.join(&e.file)).unwrap(),
+                    ))
+                })
+                .collect::<Vec<_>>();
+            lines.sort();
+            lines
+        };
+
+        // Every line is counted towards the input it came from
+        let stats = futures::executor::block_on(run_async(run_inputs())).unwrap();
+        let names = inputs
+            .iter()
+            .map(|p| p.display().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            stats
+                .input_lines
+                .iter()
+                .map(|(input, lines)| (input.to_string(), *lines))
+                .collect::<Vec<_>>(),
+            [(names[0].clone(), 1000), (names[1].clone(), 1000)]
+        );
+
+        let second = std::fs::read(&inputs[1]).unwrap();
+        std::fs::write(&inputs[1], &second[..second.len() / 2]).unwrap();
+        let err = run(run_inputs()).unwrap_err();
+        let ErrorKind::ReadErr(ReadError::CorruptInput { input, .. }) = err.kind() else {
+            panic!("unexpected error {err}");
+        };
+        assert_eq!(input, &names[1]);
+        let source = err.source().unwrap().to_string();
+        assert!(source.contains(&names[1]), "{source}");
+
+        // Every line of the first input was written before the second one's corruption was found
+        let written = written();
+        assert!(written.len() > first.len() && written.len() < 2000);
+        assert!(first.iter().all(|l| written.binary_search(l).is_ok()));
+    }
+
+    #[test]
+    fn test_max_output_bytes() {
+        let tmp = TempDir::new("logsplitter2").unwrap();
+        let input = tmp.path().join("input.json.gz");
+        let out = tmp.path().join("out");
+        let mut cfg = TestdataCfg {
+            lines: 50_000,
+            seed: Some(3),
+            ..Default::default()
+        };
+        cfg.set_unique_dates(1)
+            .set_services(4, 3..6)
+            .set_envs(1, 3..6);
+        generate_testdata(cfg, &mut std::fs::File::create(&input).unwrap(), None).unwrap();
+
+        const MAX: u64 = 64 << 10;
+        run(RunCfg {
+            input_files: vec![input],
+            output: OutputTarget::Dir(out.clone()),
+            output_threads: Threads::Fixed(2),
+            max_output_bytes: Some(MAX),
+            ..Default::default()
+        })
+        .unwrap();
+
+        // The run stopped well before the end of the input, with every file still complete
+        let manifest = Manifest::read(&out).unwrap();
+        let lines = manifest.files.iter().map(|e| e.lines).sum::<u64>();
+        let bytes = manifest.files.iter().map(|e| e.bytes).sum::<u64>();
+        assert!(bytes >= MAX, "{bytes}");
+        assert!(lines < 50_000, "{lines}");
+        for e in &manifest.files {
+            assert!(e.complete);
+            let got = read_lines(MultiGzDecoder::new(
+                std::fs::File::open(out.join(&e.file)).unwrap(),
+            ));
+            assert_eq!(got.len() as u64, e.lines, "{}", e.file);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_run_async() {
+        let tmp = TempDir::new("logsplitter2").unwrap();
+        let out = tmp.path().join("out");
+        let input = tmp.path().join("input.json.gz");
+        write_input(&input, &[line("a", "1"), line("b", "2"), line("a", "3")]);
+
+        // Spawning it checks that the future can be moved across the runtime's threads
+        let stats = tokio::spawn(run_async(RunCfg {
+            input_files: vec![input],
+            output: OutputTarget::Dir(out.clone()),
+            output_threads: Threads::Fixed(2),
+            ..Default::default()
+        }))
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(stats.lines_written, 3);
+        assert_eq!(stats.manifest.unwrap().files.len(), 2);
+        let read = |service| {
+            read_lines(MultiGzDecoder::new(
+                std::fs::File::open(output_file(&out, service)).unwrap(),
+            ))
+        };
+        assert_eq!(read("a"), [line("a", "1"), line("a", "3")]);
+        assert_eq!(read("b"), [line("b", "2")]);
+        assert!(!out.join(LOCK_FILE_NAME).exists());
+    }
+
+    #[tokio::test]
+    async fn test_run_async_cancelled() {
+        let tmp = TempDir::new("logsplitter2").unwrap();
+        let input = tmp.path().join("input.json.gz");
+        let out = tmp.path().join("out");
+        let mut cfg = TestdataCfg {
+            lines: 20_000,
+            seed: Some(4),
+            ..Default::default()
+        };
+        cfg.set_unique_dates(1)
+            .set_services(4, 3..6)
+            .set_envs(1, 3..6);
+        generate_testdata(cfg, &mut std::fs::File::create(&input).unwrap(), None).unwrap();
+
+        let run = run_async(RunCfg {
+            input_files: vec![input],
+            output: OutputTarget::Dir(out.clone()),
+            output_threads: Threads::Fixed(2),
+            ..Default::default()
+        });
+        let cancelled = tokio::time::timeout(std::time::Duration::from_millis(20), run).await;
+        assert!(
+            cancelled.is_err(),
+            "The run finished before being cancelled"
+        );
+
+        // What was written before the run was cancelled is finished, and the output directory is unlocked
+        assert!(!out.join(LOCK_FILE_NAME).exists());
+        let manifest = Manifest::read(&out).unwrap();
+        let lines = manifest.files.iter().map(|e| e.lines).sum::<u64>();
+        assert!(lines < 20_000, "{lines}");
+        for e in &manifest.files {
+            assert!(e.complete);
+            let got = read_lines(MultiGzDecoder::new(
+                std::fs::File::open(out.join(&e.file)).unwrap(),
+            ));
+            assert_eq!(got.len() as u64, e.lines, "{}", e.file);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_run_async_parallel_inputs() {
+        let tmp = TempDir::new("logsplitter2").unwrap();
+        let mut cfg = TestdataCfg {
+            lines: 40_000,
+            seed: Some(6),
+            output_files: 4,
+            ..Default::default()
+        };
+        cfg.set_unique_dates(1)
+            .set_services(4, 3..6)
+            .set_envs(1, 3..6);
+        let (inputs, _) = generate_testdata_files(cfg, tmp.path(), None).unwrap();
+        let run_cfg = |out: &Path| RunCfg {
+            input_files: inputs.clone(),
+            output: OutputTarget::Dir(out.to_path_buf()),
+            output_threads: Threads::Fixed(2),
+            parallel_inputs: Some(4),
+            ..Default::default()
+        };
+
+        let out = tmp.path().join("out");
+        let stats = run_async(run_cfg(&out)).await.unwrap();
+        assert_eq!(stats.lines_written, 40_000);
+        assert_eq!(stats.input_lines.len(), 4);
+        assert!(!out.join(LOCK_FILE_NAME).exists());
+
+        // The inputs are split off of this runtime's only thread, so the timeout fires while they are
+        let out = tmp.path().join("cancelled");
+        let run = run_async(run_cfg(&out));
+        let cancelled = tokio::time::timeout(std::time::Duration::from_millis(20), run).await;
+        assert!(
+            cancelled.is_err(),
+            "The run finished before being cancelled"
+        );
+
+        // What was written before the run was cancelled is finished, and the output directory is unlocked
+        assert!(!out.join(LOCK_FILE_NAME).exists());
+        let manifest = Manifest::read(&out).unwrap();
+        let lines = manifest.files.iter().map(|e| e.lines).sum::<u64>();
+        assert!(lines < 40_000, "{lines}");
+        for e in &manifest.files {
+            assert!(e.complete);
+            let got = read_lines(MultiGzDecoder::new(
+                std::fs::File::open(out.join(&e.file)).unwrap(),
+            ));
+            assert_eq!(got.len() as u64, e.lines, "{}", e.file);
+        }
+    }
+
+    #[test]
+    fn test_generated_files_and_members() {
+        let tmp = TempDir::new("logsplitter2").unwrap();
+        let cfg = TestdataCfg {
+            lines: 1_000,
+            seed: Some(9),
+            gzip_members: 3,
+            ..Default::default()
+        };
+
+        let mut expected = vec![];
+        let (paths, stats) = generate_testdata_files(
+            TestdataCfg {
+                output_files: 4,
+                ..cfg.clone()
+            },
+            tmp.path(),
+            Some(&mut expected),
+        )
+        .unwrap();
+        assert_eq!(paths.len(), 4);
+        assert!(paths[3].ends_with("input-003.json.gz"));
+        let mut all_lines = vec![];
+        for path in &paths {
+            let lines = read_lines(MultiGzDecoder::new(std::fs::File::open(path).unwrap()));
+            assert_eq!(lines.len(), 250);
+            // A decoder which stops after the first member only sees a third of them
+            let first_member = read_lines(GzDecoder::new(std::fs::File::open(path).unwrap()));
+            assert_eq!(first_member.len(), 84);
+            all_lines.extend(lines);
+        }
+        assert_eq!(all_lines, read_lines(&expected[..]));
+        let total_size = paths
+            .iter()
+            .map(|p| p.metadata().unwrap().len())
+            .sum::<u64>();
+        assert_eq!(stats.bytes_compressed, total_size);
+
+        // As several inputs, and as a single input made of several members
+        let single = tmp.path().join("single.json.gz");
+        generate_testdata(cfg, &mut std::fs::File::create(&single).unwrap(), None).unwrap();
+        for (i, input_files) in [paths, vec![single]].into_iter().enumerate() {
+            let out = tmp.path().join(format!("out{i}"));
+            run(RunCfg {
+                input_files,
+                output: OutputTarget::Dir(out.clone()),
+                output_threads: Threads::Fixed(2),
+                ..Default::default()
+            })
+            .unwrap();
+            let manifest = Manifest::read(&out).unwrap();
+            assert_eq!(manifest.files.iter().map(|e| e.lines).sum::<u64>(), 1_000);
+        }
+    }
+
+    #[test]
+    fn test_generated_key_lines() {
+        let tmp = TempDir::new("logsplitter2").unwrap();
+        let input = tmp.path().join("input.json.gz");
+        let out = tmp.path().join("out");
+        let mut cfg = TestdataCfg {
+            lines: 3_000,
+            omit_service_rate: 0.02,
+            null_timestamp_rate: 0.02,
+            seed: Some(4),
+            ..Default::default()
+        };
+        cfg.set_unique_dates(6).set_services(8, 3..6);
+        let stats =
+            generate_testdata(cfg, &mut std::fs::File::create(&input).unwrap(), None).unwrap();
+        assert_eq!(
+            stats.key_lines.values().sum::<u64>(),
+            (stats.lines - stats.keyless) as u64
+        );
+
+        run(RunCfg {
+            input_files: vec![input],
+            output: OutputTarget::Dir(out.clone()),
+            output_threads: Threads::Fixed(2),
+            max_invalid_lines: InvalidLineLimit::Count(stats.keyless as u64),
+            ..Default::default()
+        })
+        .unwrap();
+
+        // Exactly one file per expected key, with exactly as many lines as were generated for it
+        let mut files = std::fs::read_dir(&out)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name != MANIFEST_FILE_NAME && name != LOCK_FILE_NAME)
+            .collect::<Vec<_>>();
+        files.sort();
+        let expected_files = stats
+            .key_lines
+            .keys()
+            .map(|key| format!("{key}.json.gz"))
+            .collect::<Vec<_>>();
+        assert_eq!(files, expected_files);
+        for (key, &lines) in &stats.key_lines {
+            let f = std::fs::File::open(out.join(format!("{key}.json.gz"))).unwrap();
+            assert_eq!(
+                read_lines(MultiGzDecoder::new(f)).len() as u64,
+                lines,
+                "{key}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_long_unicode_lines() {
+        let tmp = TempDir::new("logsplitter2").unwrap();
+        let input = tmp.path().join("input.json.gz");
+        let out = tmp.path().join("out");
+
+        // Many short lines, then a single line of several megabytes, as two gzip members
+        let (mut enc, mut expected) = (vec![], vec![]);
+        for (lines, length, charset) in [
+            (500, MessageLength::Uniform(0..300), MessageCharset::Unicode),
+            (500, MessageLength::Uniform(0..300), MessageCharset::LogLike),
+            (
+                1,
+                MessageLength::OneOf(vec![2_000_000]),
+                MessageCharset::Unicode,
+            ),
+        ] {
+            let mut cfg = TestdataCfg {
+                lines,
+                seed: Some(lines as u64),
+                message: MessageSpec {
+                    length,
+                    charset,
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            cfg.set_unique_dates(3).set_services(5, 3..6);
+            generate_testdata(cfg, &mut enc, Some(&mut expected)).unwrap();
+        }
+        std::fs::write(&input, enc).unwrap();
+        assert!(expected.len() > 4_000_000);
+
+        run(RunCfg {
+            input_files: vec![input],
+            output: OutputTarget::Dir(out.clone()),
+            output_threads: Threads::Fixed(2),
+            ..Default::default()
+        })
+        .unwrap();
+
+        // Byte for byte, so compared as bytes rather than as parsed json
+        let mut got = vec![];
+        for e in Manifest::read(&out).unwrap().files {
+            let mut f = MultiGzDecoder::new(std::fs::File::open(out.join(&e.file)).unwrap());
+            std::io::Read::read_to_end(&mut f, &mut got).unwrap();
+        }
+        let split_lines = |bytes: &[u8]| {
+            let mut lines = bytes
+                .split_inclusive(|&b| b == b'\n')
+                .map(<[u8]>::to_vec)
+                .collect::<Vec<_>>();
+            lines.sort();
+            lines
+        };
+        assert_eq!(split_lines(&got), split_lines(&expected));
+    }
+
+    #[test]
+    fn test_single_thread_run() {
+        let tmp = TempDir::new("logsplitter2").unwrap();
+        let input = tmp.path().join("input.json.gz");
+        let out = tmp.path().join("out");
+
+        let mut expected = vec![];
+        generate_testdata(
+            TestdataCfg {
+                lines: 300,
+                unique_dates: 5,
+                ..Default::default()
+            },
+            &mut std::fs::File::create(&input).unwrap(),
+            Some(&mut expected),
+        )
+        .unwrap();
+
+        run(RunCfg {
+            input_files: vec![input],
+            output: OutputTarget::Dir(out.clone()),
+            output_threads: Threads::Fixed(1),
+            ..Default::default()
+        })
+        .unwrap();
+
+        let manifest = Manifest::read(&out).unwrap();
+        let mut got = manifest
+            .files
+            .iter()
+            .flat_map(|e| {
+                read_lines(MultiGzDecoder::new(
+                    std::fs::File::open(out.join(&e.file)).unwrap(),
+                ))
+            })
+            .collect::<Vec<_>>();
+        let mut expected = read_lines(&expected[..]);
+        got.sort();
+        expected.sort();
+        assert_eq!(got.len(), 300);
+        assert_eq!(got, expected);
+    }
+
+    #[test]
+    fn test_max_invalid_lines() {
+        let tmp = TempDir::new("logsplitter2").unwrap();
+        let input = tmp.path().join("input.json.gz");
+        let out = tmp.path().join("out");
+        write_input(
+            &input,
+            &[
+                line("a", "a1"),
+                "not,json".to_string(),
+                line("a", "a2"),
+                "also,not,json".to_string(),
+                line("a", "a3"),
+            ],
+        );
+
+        let run_with = |limit| {
+            run(RunCfg {
+                input_files: vec![input.clone()],
+                output: OutputTarget::Dir(out.clone()),
+                output_threads: Threads::Fixed(1),
+                max_invalid_lines: limit,
+                ..Default::default()
+            })
+        };
+
+        run_with(InvalidLineLimit::Count(2)).unwrap();
+        let lines = read_lines(MultiGzDecoder::new(
+            std::fs::File::open(output_file(&out, "a")).unwrap(),
+        ));
+        assert_eq!(lines, [line("a", "a1"), line("a", "a2"), line("a", "a3")]);
+
+        // Aborting still finishes what was written before the limit was crossed
+        let e = run_with(InvalidLineLimit::Count(1)).unwrap_err();
+        assert!(matches!(
+            e.kind(),
+            ErrorKind::TooManyInvalidLines { invalid: 2, read: 4, examples }
+                if examples == &[
+                    format!("{}: not,json", input.display()),
+                    format!("{}: also,not,json", input.display()),
+                ]
+        ));
+        let lines = read_lines(MultiGzDecoder::new(
+            std::fs::File::open(output_file(&out, "a")).unwrap(),
+        ));
+        assert_eq!(lines, [line("a", "a1"), line("a", "a2")]);
+        assert_eq!(Manifest::read(&out).unwrap().files[0].lines, 2);
+
+        // The input is too short for the warm-up, so the percentage is only checked at the end
+        assert!(run_with(InvalidLineLimit::Percent(40.0)).is_ok());
+        assert!(run_with(InvalidLineLimit::Percent(39.0)).is_err());
+    }
+
+    #[test]
+    fn test_mostly_invalid_input_aborts_early() {
+        let tmp = TempDir::new("logsplitter2").unwrap();
+        let out = tmp.path().join("out");
+        let input = tmp.path().join("input.json.gz");
+        // Only every tenth line is json, like a file in the wrong format with a few lines that happen to parse
+        let lines = (0..5_000)
+            .map(|i| match i % 10 {
+                0 => line("a", &i.to_string()),
+                _ => format!("2024-10-20 12:00:00 INFO request {i} served"),
+            })
+            .collect::<Vec<_>>();
+        write_input(&input, &lines);
+
+        let err = run(RunCfg {
+            input_files: vec![input],
+            output: OutputTarget::Dir(out.clone()),
+            output_threads: Threads::Fixed(1),
+            max_invalid_lines: InvalidLineLimit::Percent(5.0),
+            ..Default::default()
+        })
+        .unwrap_err();
+
+        // The run stops as soon as the warm-up is over, rather than reading the rest of the input
+        let warm_up = InvalidLineLimit::WARM_UP_LINES;
+        let ErrorKind::TooManyInvalidLines {
+            invalid,
+            read,
+            examples,
+        } = err.kind()
+        else {
+            panic!("unexpected error {err}");
+        };
+        assert_eq!((*invalid, *read), (warm_up * 9 / 10, warm_up));
+        assert_eq!(
+            examples[0],
+            format!(
+                "{}: {}",
+                tmp.path().join("input.json.gz").display(),
+                lines[1]
+            )
+        );
+
+        // What was written before then is finished as usual
+        let manifest = Manifest::read(&out).unwrap();
+        assert_eq!(manifest.files[0].lines, warm_up / 10);
+        let written = read_lines(MultiGzDecoder::new(
+            std::fs::File::open(output_file(&out, "a")).unwrap(),
+        ));
+        assert_eq!(
+            written,
+            lines
+                .iter()
+                .step_by(10)
+                .take(written.len())
+                .cloned()
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_error_messages() {
+        let long = "x".repeat(1000);
+        let msg = ReadError::InvalidLine {
+            line: long,
+            input: None,
+        }
+        .to_string();
+        assert!(msg.starts_with("Invalid json line: xxx") && msg.ends_with("..."));
+        assert!(msg.len() < 300);
+        assert_eq!(
+            ReadError::NoKey {
+                line: "{}".to_string(),
+                input: Some("b.json.gz".into()),
+            }
+            .to_string(),
+            "Line has no key in b.json.gz: {}"
+        );
+
+        let tmp = TempDir::new("logsplitter2").unwrap();
+        let input = tmp.path().join("missing.json.gz");
+        let e = run(RunCfg {
+            input_files: vec![input.clone()],
+            output: OutputTarget::Dir(tmp.path().join("out")),
+            ..Default::default()
+        })
+        .unwrap_err();
+        let ErrorKind::Io {
+            path: Some(path),
+            source,
+        } = e.kind()
+        else {
+            panic!("unexpected error {e}");
+        };
+        assert_eq!(path, &input);
+        assert_eq!(source.kind(), std::io::ErrorKind::NotFound);
+        // The io error is the source rather than part of the message, so that chains don't repeat it
+        assert_eq!(
+            e.to_string(),
+            format!("Could not read or write {}", input.display())
+        );
+        assert_eq!(e.source().unwrap().to_string(), source.to_string());
+        assert_eq!(e.clone().to_string(), e.to_string());
+
+        // Errors can be passed on with `?` by callers which return boxed (or `anyhow`) errors
+        fn boxed(e: crate::Error) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+            Err(e)?
+        }
+        let boxed = boxed(e).unwrap_err();
+        assert!(boxed.source().unwrap().is::<std::io::Error>());
+        assert!(boxed.downcast_ref::<crate::Error>().is_some());
+    }
+}
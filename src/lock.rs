@@ -0,0 +1,211 @@
+//! Prevents concurrent runs from writing into the same output directory.
+//!
+//! The lock is an advisory `flock` on [`LOCK_FILE_NAME`] inside of the output directory,
+//! which also records the PID and start time of its holder.
+//! Since the kernel releases the `flock` when its holder exits, however that happens,
+//! a lock file left behind by a killed run doesn't keep later runs out.
+//!
+//! Telling whether the holder still exists (for `--force`) and whether the lock file was replaced
+//! are only supported on unix. Elsewhere a held lock is never taken over
+
+use std::{
+    fmt::Display,
+    fs::{File, OpenOptions, TryLockError},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+pub const LOCK_FILE_NAME: &str = ".logsplitter2.lock";
+
+#[derive(Debug, Clone)]
+pub enum LockError {
+    /// Another run holds the lock. `holder` is the content of its lock file
+    Held {
+        path: PathBuf,
+        holder: String,
+    },
+    Io(String),
+}
+
+impl Display for LockError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LockError::Held { path, holder } => write!(
+                f,
+                "The output directory is locked by another run ({holder}). \
+                If that process no longer exists, remove {} or pass `--force`",
+                path.display()
+            ),
+            LockError::Io(e) => write!(f, "Could not lock the output directory: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for LockError {}
+
+impl From<io::Error> for LockError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e.to_string())
+    }
+}
+
+/// Holds the lock of an output directory until dropped, which also removes the lock file
+#[derive(Debug)]
+pub struct DirLock {
+    path: PathBuf,
+    file: File,
+}
+
+impl DirLock {
+    /// Locks `dir`, failing immediately if another run holds its lock.
+    ///
+    /// With `force`, a held lock is taken over anyway if the PID recorded in it no longer exists
+    pub fn acquire(dir: &Path, force: bool) -> Result<Self, LockError> {
+        let path = dir.join(LOCK_FILE_NAME);
+
+        loop {
+            let mut file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&path)?;
+
+            match file.try_lock() {
+                Ok(()) => {}
+                Err(TryLockError::WouldBlock) => {
+                    let holder = std::fs::read_to_string(&path).unwrap_or_default();
+                    let holder_pid = holder
+                        .split_whitespace()
+                        .next()
+                        .and_then(|p| p.parse().ok());
+                    if force && holder_pid.is_some_and(|pid: u32| !pid_exists(pid)) {
+                        // Unlinking the file means the next attempt locks a fresh one
+                        std::fs::remove_file(&path)?;
+                        continue;
+                    }
+                    return Err(LockError::Held {
+                        path,
+                        holder: holder.trim().to_string(),
+                    });
+                }
+                Err(TryLockError::Error(e)) => return Err(e.into()),
+            }
+
+            // The previous holder may have removed the file after it was opened here,
+            // in which case the lock is on a file nobody else will see
+            if !is_same_file(&path, &file) {
+                continue;
+            }
+
+            file.set_len(0)?;
+            writeln!(
+                file,
+                "{} {}",
+                std::process::id(),
+                chrono::Utc::now().to_rfc3339()
+            )?;
+            return Ok(Self { path, file });
+        }
+    }
+}
+
+impl Drop for DirLock {
+    fn drop(&mut self) {
+        // Removed while still locked, so nobody can lock the file in between.
+        // If this lock was taken over with `force`, the file at `path` belongs to the new holder
+        if is_same_file(&self.path, &self.file) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+        let _ = self.file.unlock();
+    }
+}
+
+/// Whether `path` still refers to the opened `file`
+#[cfg(unix)]
+fn is_same_file(path: &Path, file: &File) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    match (std::fs::metadata(path), file.metadata()) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+/// Elsewhere files can't be told apart, so the lock file is assumed to be the one which was opened
+#[cfg(not(unix))]
+fn is_same_file(path: &Path, _file: &File) -> bool {
+    path.exists()
+}
+
+#[cfg(unix)]
+fn pid_exists(pid: u32) -> bool {
+    // Other pids than positive ones name groups of processes rather than a process
+    let Some(pid) = libc::pid_t::try_from(pid).ok().filter(|&pid| pid > 0) else {
+        return false;
+    };
+    // SAFETY: signal 0 is never sent, it only checks whether the process exists
+    let found = unsafe { libc::kill(pid, 0) } == 0;
+    // A process of another user exists as well, even though it can't be signalled
+    found || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// Elsewhere processes can't be looked up, so the holder is assumed to still exist
+#[cfg(not(unix))]
+fn pid_exists(_pid: u32) -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use tempdir::TempDir;
+
+    use super::{DirLock, LockError, LOCK_FILE_NAME};
+
+    #[test]
+    fn test_dir_lock() {
+        let tmp = TempDir::new("logsplitter2").unwrap();
+        let path = tmp.path().join(LOCK_FILE_NAME);
+
+        let lock = DirLock::acquire(tmp.path(), false).unwrap();
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert!(content.starts_with(&format!("{} ", std::process::id())));
+
+        // The holder is alive, so even `force` doesn't take the lock over
+        for force in [false, true] {
+            match DirLock::acquire(tmp.path(), force) {
+                Err(LockError::Held { holder, .. }) => assert_eq!(holder, content.trim()),
+                other => panic!("Expected the lock to be held, got {other:?}"),
+            }
+        }
+
+        drop(lock);
+        assert!(!path.exists());
+        drop(DirLock::acquire(tmp.path(), false).unwrap());
+
+        // A lock file left behind by a killed run isn't locked anymore
+        std::fs::write(&path, "4294967295 2024-10-20T00:00:00+00:00\n").unwrap();
+        drop(DirLock::acquire(tmp.path(), false).unwrap());
+    }
+
+    #[test]
+    fn test_force_steals_from_missing_pid() {
+        let tmp = TempDir::new("logsplitter2").unwrap();
+        let path = tmp.path().join(LOCK_FILE_NAME);
+
+        let stale = DirLock::acquire(tmp.path(), false).unwrap();
+        // Pretend the holder is a process which no longer exists
+        std::fs::write(&path, "4294967295 2024-10-20T00:00:00+00:00\n").unwrap();
+
+        assert!(DirLock::acquire(tmp.path(), false).is_err());
+        let lock = DirLock::acquire(tmp.path(), true).unwrap();
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert!(content.starts_with(&format!("{} ", std::process::id())));
+
+        // The old holder finishing doesn't release the new holder's lock
+        drop(stale);
+        assert!(path.exists());
+        drop(lock);
+        assert!(!path.exists());
+    }
+}
@@ -1,144 +1,521 @@
 use std::{
     fs::File,
-    io::{stdout, Write},
-    path::{Path, PathBuf},
-    time::Instant,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
 };
 
-use input::JsonLinesRecv;
-use output::OutputFiles;
-use tempdir::TempDir;
-use testdata_gen::{generate_testdata, TestdataCfg};
-
-mod byte_channel;
-mod data;
-mod file_pool;
-mod input;
-mod math_utils;
-mod output;
-mod testdata_gen;
-
-#[derive(Debug, Clone)]
-pub enum ReadError {
-    EndOfInputReached,
-    InvalidLine(String),
-}
+use clap::{parser::ValueSource, ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand};
+#[cfg(feature = "parquet")]
+use logsplitter2::config::DEFAULT_PARQUET_BATCH_SIZE;
+#[cfg(unix)]
+use logsplitter2::file_pool::UnixMode;
+use logsplitter2::{
+    config::{Config, DEFAULT_INDEX_INTERVAL, DEFAULT_ON_FILE_COMPLETE_JOBS},
+    data::{EmptyComponentPolicy, EnvRename},
+    deflate::DeflateStrategy,
+    filter::{FilterTerm, JsonPath},
+    input::{GzipErrorPolicy, TrailingLinePolicy},
+    invalid_lines::InvalidLineLimit,
+    output::{GzipMtime, ReserializeMode, ThreadAssignment},
+    run,
+    stripes::StripeCount,
+    testdata_gen::{generate_testdata, TestdataCfg},
+    verify::verify,
+    Error, ErrorKind, OutputTarget, RunCfg, Threads,
+};
 
-#[derive(Debug, Clone)]
-pub enum ErrorKind {
-    ReadErr(ReadError),
-}
+/// Splits a `.json.gz` log file into one `.json.gz` file per service, env, and date
+#[derive(Parser)]
+#[command(args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
 
-#[derive(Debug, Clone)]
-pub struct Error {
-    #[allow(unused)]
-    kind: Box<ErrorKind>,
-}
+    /// A TOML file of options, named like their flags (such as `output-threads = 4`).
+    /// Flags and `LOGSPLITTER_*` environment variables override it
+    #[arg(long, value_name = "FILE", env = "LOGSPLITTER_CONFIG")]
+    config: Option<PathBuf>,
 
-impl From<ReadError> for Error {
-    fn from(value: ReadError) -> Self {
-        Self {
-            kind: Box::new(ErrorKind::ReadErr(value)),
-        }
-    }
+    /// The `.json.gz` file to split
+    #[arg(long, env = "LOGSPLITTER_INPUT")]
+    input: Option<PathBuf>,
+    /// A file listing `.json.gz` files to split as one input, one path per line.
+    /// Blank lines and lines starting with `#` are ignored
+    #[arg(long, value_name = "FILE", env = "LOGSPLITTER_INPUT_LIST")]
+    input_list: Option<PathBuf>,
+    /// What happens to the last line of an input which doesn't end with a newline:
+    /// `emit` it like any other line, `reject` the input, or `ignore` the line
+    #[arg(long, default_value = "emit", env = "LOGSPLITTER_TRAILING_LINE")]
+    trailing_line: TrailingLinePolicy,
+    /// What happens when an input's gzip data is corrupt: `abort` the run, or `skip-to-next-member` to skip
+    /// the rest of the corrupt member and keep reading from the next one, losing its lines
+    #[arg(long, default_value = "abort", env = "LOGSPLITTER_GZIP_ERROR_POLICY")]
+    gzip_error_policy: GzipErrorPolicy,
+    /// Memory-map input files instead of reading them in small chunks, which is faster for big local files.
+    /// Inputs which aren't regular files (such as pipes) are read as usual
+    #[arg(long, env = "LOGSPLITTER_INPUT_MMAP")]
+    input_mmap: bool,
+    /// Read and parse up to this many files of `--input-list` at once, instead of one after another.
+    /// Lines from each file stay in order, but a key's lines from different files are interleaved
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+        env = "LOGSPLITTER_PARALLEL_INPUTS"
+    )]
+    parallel_inputs: Option<usize>,
+    /// Keep reading the last input as more is appended to it, until Ctrl-C (or SIGTERM).
+    /// Everything appended until then is split, and the output is finished as usual
+    #[arg(long, env = "LOGSPLITTER_TAIL")]
+    tail: bool,
+    /// The directory to write split files to, or `-` to stream every kept line to stdout
+    #[arg(long, env = "LOGSPLITTER_OUTPUT")]
+    output: Option<String>,
+    /// Write every kept line to this one gzip file instead of splitting them, when the input only holds one key anyway.
+    /// Skips the output threads entirely, so it's faster than `--output` with a single key
+    #[arg(long, env = "LOGSPLITTER_SINGLE_OUTPUT")]
+    single_output: Option<PathBuf>,
+    /// Only keep lines where `FIELD` equals `VALUE`.
+    /// `FIELD` is `service`, `env`, `date` (YYYY-MM-DD), or a dot-separated json path such as `@meta.user`.
+    ///
+    /// Filters on the same field are OR-ed, filters on different fields are AND-ed
+    #[arg(long = "filter", value_name = "FIELD=VALUE")]
+    filters: Vec<FilterTerm>,
+    /// Trim and lowercase each line's service and env before splitting,
+    /// so that spellings such as `Prod` and `prod` share one output file
+    #[arg(long, env = "LOGSPLITTER_NORMALIZE_KEYS")]
+    normalize_keys: bool,
+    /// Split lines into `N` evenly sized files named `shard_0` to `shard_<N-1>`, by a hash of each line's content,
+    /// instead of by service, env, and date. For parallel processing of the output, rather than for grouping related lines
+    #[arg(long, value_name = "N", env = "LOGSPLITTER_HASH_SHARDS")]
+    hash_shards: Option<usize>,
+    /// Key lines of env `FROM` as if their env was `TO`, such as `staging=nonprod`, so that several envs share output files.
+    /// With `--normalize-keys`, envs are normalized before they're mapped
+    #[arg(long = "env-map", value_name = "FROM=TO")]
+    env_map: Vec<EnvRename>,
+    /// Key lines of every env which isn't given to `--env-map` as if their env was `NAME`
+    #[arg(long, value_name = "NAME", env = "LOGSPLITTER_ENV_MAP_DEFAULT")]
+    env_map_default: Option<String>,
+    /// What happens to lines whose service or env is empty: `keep` it (such as `_prod_2024-10-20`),
+    /// `substitute` it with `empty`, or `reject` the line as invalid
+    #[arg(
+        long,
+        default_value = "keep",
+        env = "LOGSPLITTER_EMPTY_COMPONENT_POLICY"
+    )]
+    empty_component_policy: EmptyComponentPolicy,
+    /// Fail (after writing all of the output) if it was split into many tiny files, instead of only warning about it
+    #[arg(long, env = "LOGSPLITTER_STRICT")]
+    strict: bool,
+    /// Stop after writing this many lines, such as to quickly see how a sample of the input is split
+    #[arg(long, value_name = "N", env = "LOGSPLITTER_MAX_LINES")]
+    max_lines: Option<usize>,
+    /// Stop taking input once this many bytes have been written across every output file.
+    /// Lines which are already on their way to the output are still written, so the output ends up somewhat bigger
+    #[arg(long, value_name = "BYTES", env = "LOGSPLITTER_MAX_OUTPUT_BYTES")]
+    max_output_bytes: Option<u64>,
+    /// Remove the dot-separated json path `FIELD` (such as `@meta.user`) from every written line.
+    /// Lines are still split by their original fields, and lines which had the field are re-serialized
+    #[arg(long = "redact", value_name = "FIELD")]
+    redact_fields: Vec<JsonPath>,
+    /// Gzip-compress the stream when writing to stdout (`--output -`)
+    #[arg(long, env = "LOGSPLITTER_GZIP")]
+    gzip: bool,
+    /// Append to output files left behind by a previous run (as new gzip members), instead of overwriting them
+    #[arg(long, env = "LOGSPLITTER_APPEND")]
+    append: bool,
+    /// Overwrite output files left behind by a previous run. This is the default, but without it a warning is shown
+    #[arg(long, env = "LOGSPLITTER_TRUNCATE")]
+    truncate: bool,
+    /// What the MTIME field of each output file's gzip header is set to: `run-start` or `key-date`
+    #[arg(long, default_value = "run-start", env = "LOGSPLITTER_GZIP_MTIME")]
+    gzip_mtime: GzipMtime,
+    /// How deflate looks for repeated strings in each output file: `default`, `filtered`, `huffman-only`, `rle`, or `fixed`.
+    /// Other strategies than `default` can compress faster or better, depending on how repetitive the lines are
+    #[arg(long, default_value = "default", env = "LOGSPLITTER_DEFLATE_STRATEGY")]
+    deflate_strategy: DeflateStrategy,
+    /// Write each line as-is (`off`), or parse it and write it again `compact` or `pretty`-printed.
+    /// Pretty-printed output isn't json lines anymore
+    #[arg(long, default_value = "off", env = "LOGSPLITTER_RESERIALIZE")]
+    reserialize: ReserializeMode,
+    /// Write a `<name>.idx` line index next to every output file, for random access by line number
+    #[arg(long, env = "LOGSPLITTER_WRITE_INDEX")]
+    write_index: bool,
+    /// How many lines apart the entries of `--write-index` are.
+    /// Each entry starts a new gzip member, so smaller intervals compress worse
+    #[arg(long, default_value_t = DEFAULT_INDEX_INTERVAL, value_parser = clap::value_parser!(u64).range(1..), env = "LOGSPLITTER_INDEX_INTERVAL")]
+    index_interval: u64,
+    /// Write one `.parquet` file per key instead of `.json.gz`
+    #[cfg(feature = "parquet")]
+    #[arg(long, env = "LOGSPLITTER_PARQUET")]
+    parquet: bool,
+    /// How many lines each row group of `--parquet` output holds
+    #[cfg(feature = "parquet")]
+    #[arg(long, default_value_t = DEFAULT_PARQUET_BATCH_SIZE, value_parser = clap::value_parser!(u64).range(1..), env = "LOGSPLITTER_PARQUET_BATCH_SIZE")]
+    parquet_batch_size: u64,
+    /// Write keys with fewer than this many bytes of lines as uncompressed `.json` files
+    #[arg(long, value_name = "BYTES", env = "LOGSPLITTER_PLAIN_BELOW")]
+    plain_below: Option<usize>,
+    /// Take over the output directory's lock if the run holding it no longer exists
+    #[arg(long, env = "LOGSPLITTER_FORCE")]
+    force: bool,
+    /// A number of threads, or `auto` (or `0`) to pick one from the input size and number of cores
+    #[arg(long, default_value = "8", env = "LOGSPLITTER_OUTPUT_THREADS")]
+    output_threads: Threads,
+    /// Read the input twice, first to measure each key, so that keys can be spread evenly over the output threads.
+    /// Helps when a few keys have most of the lines
+    #[arg(long, env = "LOGSPLITTER_BALANCE_THREADS")]
+    balance_threads: bool,
+    /// How keys are routed to output threads: `round-robin`, or `hash-mod` to route each key by a hash of its name,
+    /// which doesn't need memory for every key, for inputs with a huge number of keys
+    #[arg(
+        long,
+        default_value = "round-robin",
+        env = "LOGSPLITTER_THREAD_ASSIGNMENT"
+    )]
+    thread_assignment: ThreadAssignment,
+    /// Spread the lines of each `--stripe-key` over this many files (`NAME.0.json.gz` and so on), written by different threads.
+    /// With `auto`, also stripes any key found to have more than one thread's share of the first lines.
+    /// Readers should take a key's lines from `NAME.json.gz` and every `NAME.*.json.gz`
+    #[arg(long, value_name = "N|auto", env = "LOGSPLITTER_STRIPES")]
+    stripes: Option<StripeCount>,
+    /// The name of a key (such as `api_prod_2024-10-20`) to stripe from its first line, see `--stripes`
+    #[arg(long = "stripe-key", value_name = "KEY")]
+    stripe_keys: Vec<String>,
+    /// Seeds the hash of every key map and of `--thread-assignment hash-mod`.
+    /// Only changes which output thread writes each key, never what's written
+    #[arg(long, env = "LOGSPLITTER_HASH_SEED")]
+    hash_seed: Option<u64>,
+    /// How many times a write which fails with a transient error (such as EINTR or EAGAIN) is attempted
+    #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(u32).range(1..), env = "LOGSPLITTER_WRITE_ATTEMPTS")]
+    write_attempts: u32,
+    /// The delay before retrying a transient error, which doubles with every retry
+    #[arg(
+        long,
+        value_name = "MS",
+        default_value_t = 10,
+        env = "LOGSPLITTER_RETRY_DELAY_MS"
+    )]
+    retry_delay_ms: u64,
+    /// Skip up to this many invalid lines (or percentage of lines, like `0.1%`) before aborting the run
+    #[arg(
+        long,
+        value_name = "N|PERCENT",
+        default_value = "0",
+        env = "LOGSPLITTER_MAX_INVALID_LINES"
+    )]
+    max_invalid_lines: InvalidLineLimit,
+    /// Sync recently written output files to disk every this many writes,
+    /// so less is lost if the run is killed. Syncing often is much slower
+    #[arg(long, value_name = "WRITES", value_parser = clap::value_parser!(u64).range(1..), env = "LOGSPLITTER_SYNC_EVERY")]
+    sync_every: Option<u64>,
+    /// A program to run with the path of every output file as soon as it's finished, such as to upload it.
+    /// Its failures are reported without failing the run
+    #[arg(long, value_name = "PROGRAM", env = "LOGSPLITTER_ON_FILE_COMPLETE_CMD")]
+    on_file_complete_cmd: Option<PathBuf>,
+    /// How many `--on-file-complete-cmd` programs may run at once. Finishing more files waits for them
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_ON_FILE_COMPLETE_JOBS, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..), env = "LOGSPLITTER_ON_FILE_COMPLETE_JOBS")]
+    on_file_complete_jobs: usize,
+    /// Roughly how many bytes the output may use for compressing, however many distinct keys there are.
+    /// Once it's nearly used up, the gzip members of the least recently written keys are finished early, which costs some compression.
+    /// Must leave each output thread room for two compressors, of about 350 KiB each
+    #[arg(
+        long,
+        value_name = "BYTES",
+        value_parser = clap::builder::RangedU64ValueParser::<u64>::new().range(1..),
+        env = "LOGSPLITTER_MAX_MEMORY"
+    )]
+    max_memory: Option<u64>,
+    /// The permissions of every file the run creates, in octal such as `640`, instead of leaving them to the umask
+    #[cfg(unix)]
+    #[arg(long, value_name = "MODE", env = "LOGSPLITTER_FILE_MODE")]
+    file_mode: Option<UnixMode>,
+    /// The permissions of the output directory (and its parents) if the run creates it, in octal such as `750`
+    #[cfg(unix)]
+    #[arg(long, value_name = "MODE", env = "LOGSPLITTER_DIR_MODE")]
+    dir_mode: Option<UnixMode>,
 }
 
-pub struct RunCfg {
-    input_file: PathBuf,
-    output_dir: PathBuf,
-    output_threads: usize,
+#[derive(Subcommand)]
+enum Command {
+    /// Generates random test data into `./example_sets/rand/`, then splits it
+    Generate {
+        #[arg(long, default_value_t = 6_000)]
+        lines: usize,
+        /// Generate the same data as every other run with this seed, instead of random data
+        #[arg(long)]
+        seed: Option<u64>,
+        /// How many distinct services to spread lines over
+        #[arg(long, default_value_t = TestdataCfg::default().services, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+        services: usize,
+        /// How many distinct envs to spread lines over
+        #[arg(long, default_value_t = TestdataCfg::default().envs, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+        envs: usize,
+        /// How many distinct users to spread lines over
+        #[arg(long, default_value_t = TestdataCfg::default().users, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+        users: usize,
+        /// Only write the compressed `input.json.gz`, without its human readable copy `input.json`
+        #[arg(long)]
+        no_plain: bool,
+        /// How many threads generate and compress the data. The lines are the same for any number of threads
+        #[arg(long, default_value_t = 1, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+        threads: usize,
+    },
+    /// Checks that the files of an output directory match its manifest
+    Verify {
+        /// The output directory of a previous run
+        #[arg(long)]
+        dir: PathBuf,
+    },
 }
 
-pub fn run(cfg: RunCfg) {
-    std::fs::create_dir_all(&cfg.output_dir).unwrap();
+fn run_generated(cfg: TestdataCfg, plain: bool) -> Result<(), Error> {
+    let path_input = PathBuf::from("./example_sets/rand/input.json.gz");
+    let path_input_dbg = PathBuf::from("./example_sets/rand/input.json");
+    let path_output = PathBuf::from("./example_sets/rand/out/");
 
-    let start = Instant::now();
+    std::fs::create_dir_all(&path_output).unwrap();
 
-    let input = std::fs::File::open(cfg.input_file).unwrap();
-    let lines = JsonLinesRecv::spawn_new(input);
-
-    let mut output = OutputFiles::new(cfg.output_threads, 64, cfg.output_dir);
-
-    for line in lines {
-        // println!("LINE");
-        let line = match line {
-            Ok(l) => l,
-            Err(ReadError::EndOfInputReached) => {
-                unreachable!()
-            }
-            Err(e) => panic!("Error encountered: {e:?}"),
-        };
-
-        output.write_line(line);
-    }
-
-    stdout().flush().unwrap();
-    println!("ELAPSED: {:?}", start.elapsed());
-    stdout().flush().unwrap();
-
-    std::mem::drop(output);
+    let mut dbg = plain.then(|| File::create(&path_input_dbg).unwrap());
+    let stats = generate_testdata(
+        cfg,
+        &mut File::create(&path_input).unwrap(),
+        dbg.as_mut().map(|f| f as &mut dyn std::io::Write),
+    )
+    .unwrap();
 
-    stdout().flush().unwrap();
-    println!("ELAPSED (total): {:?}", start.elapsed());
-    stdout().flush().unwrap();
-}
+    eprintln!(
+        "Testdata generated! {} lines, {} bytes compressed to {}",
+        stats.lines, stats.bytes_plain, stats.bytes_compressed
+    );
 
-fn run_input1() {
     run(RunCfg {
-        input_file: "./example_sets/input1.json.gz".try_into().unwrap(),
-        output_dir: "./example_sets/out/".try_into().unwrap(),
-        output_threads: 8,
+        input_files: vec![path_input],
+        output: OutputTarget::Dir(path_output),
+        ..Default::default()
     })
 }
 
-fn run_ryan1() {
-    run(RunCfg {
-        input_file: "./example_sets/ryan1.json.gz".try_into().unwrap(),
-        output_dir: "./example_sets/out_ryan1/".try_into().unwrap(),
-        output_threads: 8,
-    })
-}
+/// The exit code of a run which stopped early because the disk filled up,
+/// so that scripts can tell it apart from other failures
+const EXIT_STORAGE_FULL: i32 = 3;
+/// The exit code of a run which was stopped early by SIGINT or SIGTERM, like that of a shell's interrupted command
+const EXIT_INTERRUPTED: i32 = 130;
 
-fn run_testlines() {
-    let input = std::fs::File::open::<PathBuf>("./example_sets/input1.json.gz".try_into().unwrap())
-        .unwrap();
-    let lines = JsonLinesRecv::spawn_new(input);
-    for line in lines {
-        match line {
-            Ok(ln) => println!("{}:\n==> {ln}", ln.key().path_to(Path::new("~")).display()),
-            Err(e) => println!("ERR: {e:?}"),
-        }
+fn exit_on_err(result: Result<(), Error>) {
+    if let Err(e) = result {
+        eprintln!("Error: {}", e.full_message());
+        let code = match e.kind() {
+            ErrorKind::StorageFull { .. } => EXIT_STORAGE_FULL,
+            _ => 1,
+        };
+        std::process::exit(code);
     }
 }
 
-fn run_generated(cfg: TestdataCfg) {
-    let path_input = Path::new("./example_sets/rand/input.json.gz");
-    let path_input_dbg = Path::new("./example_sets/rand/input.json");
-    let path_output = Path::new("./example_sets/rand/out/");
+/// Prints every mismatch, then exits with a failure if there were any
+fn run_verify(dir: PathBuf) -> Result<(), Error> {
+    let report = verify(&dir)?;
+    for mismatch in &report.mismatches {
+        println!("{mismatch}");
+    }
+    for file in &report.skipped {
+        eprintln!("Skipped `{file}`, which the manifest marks as incomplete");
+    }
+    eprintln!(
+        "Checked {} files, {} mismatches",
+        report.checked,
+        report.mismatches.len()
+    );
+    if !report.is_ok() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
 
-    std::fs::create_dir_all(path_output).unwrap();
+/// Makes the first SIGINT (such as from Ctrl-C) or SIGTERM end the run early: a `--tail`ed input is ended,
+/// and otherwise the run stops taking input. Either way, the output is finished and the output directory unlocked as usual.
+/// Another signal exits right away, leaving a lock file behind, which the next run takes since nobody holds its `flock`.
+///
+/// Returns the flag which is set if a signal stopped a run which wasn't tailing.
+/// The signals are blocked before the run starts any threads, which inherit the mask, so only the waiting thread gets them
+#[cfg(unix)]
+fn stop_on_signal(cfg: &mut RunCfg) -> Arc<AtomicBool> {
+    let stop = cfg.stop.get_or_insert_with(Default::default).clone();
+    let tail_ended = cfg.tail.clone();
+    // SAFETY: `set` is initialized by `sigemptyset` before it's used
+    let set = unsafe {
+        let mut set = std::mem::zeroed::<libc::sigset_t>();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGINT);
+        libc::sigaddset(&mut set, libc::SIGTERM);
+        libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut());
+        set
+    };
+    let run_stop = stop.clone();
+    std::thread::Builder::new()
+        .name("signals".to_string())
+        .spawn(move || {
+            let mut signal = 0;
+            let mut signalled = false;
+            // SAFETY: both pointers are valid for the call
+            while unsafe { libc::sigwait(&set, &mut signal) } == 0 {
+                if std::mem::replace(&mut signalled, true) {
+                    std::process::exit(128 + signal);
+                }
+                match &tail_ended {
+                    Some(tail_ended) => {
+                        eprintln!("Finishing the tailed input. Interrupt again to exit right away");
+                        tail_ended.store(true, Ordering::Relaxed);
+                    }
+                    None => {
+                        eprintln!(
+                            "Stopping and finishing the output. Interrupt again to exit right away"
+                        );
+                        run_stop.store(true, Ordering::Relaxed);
+                    }
+                }
+            }
+        })
+        .expect("Could not spawn the signal thread");
+    stop
+}
 
-    generate_testdata(
-        cfg,
-        &mut File::create(path_input).unwrap(),
-        &mut File::create(path_input_dbg).unwrap(),
-    )
-    .unwrap();
+/// Elsewhere signals are left to their default, which ends the run without finishing the output
+#[cfg(not(unix))]
+fn stop_on_signal(cfg: &mut RunCfg) -> Arc<AtomicBool> {
+    cfg.stop.get_or_insert_with(Default::default).clone()
+}
 
-    println!("Testdata generated!");
+/// `value` if the option `id` was given in `source` (the command line or its environment variable),
+/// rather than elsewhere or being left to its default
+fn given<T>(matches: &ArgMatches, source: ValueSource, id: &str, value: T) -> Option<T> {
+    (matches.value_source(id) == Some(source)).then_some(value)
+}
 
-    run(RunCfg {
-        input_file: path_input.into(),
-        output_dir: path_output.into(),
-        output_threads: 8,
-    })
+/// The options given in `source`, which is either the command line or the `LOGSPLITTER_*` environment variables.
+/// Each is its own layer of [`Config`], so that an option given on the command line overrides the environment's options
+/// which it can't be used with, like it does the config file's
+fn layer(matches: &ArgMatches, source: ValueSource) -> Config {
+    let cli = Cli::from_arg_matches(matches).unwrap_or_else(|e| e.exit());
+    let m = matches;
+    Config {
+        input: given(m, source, "input", cli.input.map(|input| vec![input])).flatten(),
+        input_list: given(m, source, "input_list", cli.input_list).flatten(),
+        trailing_line: given(m, source, "trailing_line", cli.trailing_line),
+        gzip_error_policy: given(m, source, "gzip_error_policy", cli.gzip_error_policy),
+        input_mmap: given(m, source, "input_mmap", cli.input_mmap),
+        parallel_inputs: given(m, source, "parallel_inputs", cli.parallel_inputs).flatten(),
+        tail: given(m, source, "tail", cli.tail),
+        output: given(m, source, "output", cli.output).flatten(),
+        single_output: given(m, source, "single_output", cli.single_output).flatten(),
+        filter: given(m, source, "filters", cli.filters),
+        normalize_keys: given(m, source, "normalize_keys", cli.normalize_keys),
+        hash_shards: given(m, source, "hash_shards", cli.hash_shards).flatten(),
+        env_map: given(m, source, "env_map", cli.env_map),
+        env_map_default: given(m, source, "env_map_default", cli.env_map_default).flatten(),
+        empty_component_policy: given(
+            m,
+            source,
+            "empty_component_policy",
+            cli.empty_component_policy,
+        ),
+        max_lines: given(m, source, "max_lines", cli.max_lines).flatten(),
+        max_output_bytes: given(m, source, "max_output_bytes", cli.max_output_bytes).flatten(),
+        strict: given(m, source, "strict", cli.strict),
+        redact: given(m, source, "redact_fields", cli.redact_fields),
+        gzip: given(m, source, "gzip", cli.gzip),
+        append: given(m, source, "append", cli.append),
+        truncate: given(m, source, "truncate", cli.truncate),
+        gzip_mtime: given(m, source, "gzip_mtime", cli.gzip_mtime),
+        deflate_strategy: given(m, source, "deflate_strategy", cli.deflate_strategy),
+        reserialize: given(m, source, "reserialize", cli.reserialize),
+        write_index: given(m, source, "write_index", cli.write_index),
+        index_interval: given(m, source, "index_interval", cli.index_interval),
+        #[cfg(feature = "parquet")]
+        parquet: given(m, source, "parquet", cli.parquet),
+        #[cfg(feature = "parquet")]
+        parquet_batch_size: given(m, source, "parquet_batch_size", cli.parquet_batch_size),
+        plain_below: given(m, source, "plain_below", cli.plain_below).flatten(),
+        force: given(m, source, "force", cli.force),
+        output_threads: given(m, source, "output_threads", cli.output_threads),
+        balance_threads: given(m, source, "balance_threads", cli.balance_threads),
+        thread_assignment: given(m, source, "thread_assignment", cli.thread_assignment),
+        stripes: given(m, source, "stripes", cli.stripes).flatten(),
+        stripe_keys: given(m, source, "stripe_keys", cli.stripe_keys),
+        hash_seed: given(m, source, "hash_seed", cli.hash_seed).flatten(),
+        write_attempts: given(m, source, "write_attempts", cli.write_attempts),
+        retry_delay_ms: given(m, source, "retry_delay_ms", cli.retry_delay_ms),
+        max_invalid_lines: given(m, source, "max_invalid_lines", cli.max_invalid_lines),
+        sync_every: given(m, source, "sync_every", cli.sync_every).flatten(),
+        on_file_complete_cmd: given(m, source, "on_file_complete_cmd", cli.on_file_complete_cmd)
+            .flatten(),
+        on_file_complete_jobs: given(
+            m,
+            source,
+            "on_file_complete_jobs",
+            cli.on_file_complete_jobs,
+        ),
+        max_memory: given(m, source, "max_memory", cli.max_memory).flatten(),
+        #[cfg(unix)]
+        file_mode: given(m, source, "file_mode", cli.file_mode).flatten(),
+        #[cfg(unix)]
+        dir_mode: given(m, source, "dir_mode", cli.dir_mode).flatten(),
+    }
 }
 
 fn main() {
-    // run_input1();
-    // run_ryan1();
-    run_generated(TestdataCfg {
-        lines: 6_000,
-        ..Default::default()
-    })
+    let matches = Cli::command().get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+
+    match cli.command {
+        Some(Command::Generate {
+            lines,
+            seed,
+            services,
+            envs,
+            users,
+            no_plain,
+            threads,
+        }) => {
+            exit_on_err(run_generated(
+                TestdataCfg {
+                    lines,
+                    seed,
+                    services,
+                    envs,
+                    users,
+                    gen_threads: threads,
+                    ..Default::default()
+                },
+                !no_plain,
+            ));
+            return;
+        }
+        Some(Command::Verify { dir }) => {
+            exit_on_err(run_verify(dir));
+            return;
+        }
+        None => {}
+    }
+
+    // Flags override environment variables, which override the config file
+    let flags = layer(&matches, ValueSource::CommandLine);
+    let env = layer(&matches, ValueSource::EnvVariable);
+    let cfg = match cli.config {
+        Some(path) => Config::read(&path).map(|file| flags.or(env.or(file))),
+        None => Ok(flags.or(env)),
+    };
+    exit_on_err(cfg.and_then(Config::into_run_cfg).and_then(|mut cfg| {
+        let stopped = stop_on_signal(&mut cfg);
+        run(cfg)?;
+        if stopped.load(Ordering::Relaxed) {
+            eprintln!("Stopped by a signal, so only part of the input was split");
+            std::process::exit(EXIT_INTERRUPTED);
+        }
+        Ok(())
+    }))
 }
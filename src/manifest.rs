@@ -0,0 +1,273 @@
+//! `manifest.json`, which lists every file of an output directory.
+//!
+//! ```json
+//! {
+//!   "partial": false,
+//!   "files": [
+//!     {
+//!       "key": "auth_prod_2024-10-20", "file": "auth_prod_2024-10-20.json.gz", "format": "gzip",
+//!       "lines": 3, "bytes": 120, "uncompressed_bytes": 300, "compression_ratio": 2.5, "complete": true
+//!     }
+//!   ]
+//! }
+//! ```
+//!
+//! `file` is relative to the output directory, and `bytes` is the size of the file on disk.
+//! `uncompressed_bytes` is the size of its lines as json text, and `compression_ratio` is how many times smaller than that the file is.
+//! Manifests from before these were recorded don't have them, and neither do files which were appended to by such a run.
+//!
+//! A run during which the disk filled up is `partial`: it stops splitting as soon as that happens, so later lines of its input are missing.
+//! Its files which were fully written are still `complete`, and hold exactly `lines` lines.
+//! The others are suspect, and may be truncated or missing; their `lines` and `bytes` are what the run attempted to write.
+//! Manifests from before these fields existed are read as complete
+
+use std::{io, path::Path, str::FromStr};
+
+use json::JsonValue;
+
+pub const MANIFEST_FILE_NAME: &str = "manifest.json";
+
+/// How an output file is stored on disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileFormat {
+    /// Gzip-compressed json lines (`.json.gz`), possibly made of several members
+    Gzip,
+    /// Uncompressed json lines (`.json`)
+    Plain,
+    /// See [`parquet_output`](crate::parquet_output)
+    Parquet,
+}
+
+impl FileFormat {
+    pub fn name(&self) -> &'static str {
+        match self {
+            FileFormat::Gzip => "gzip",
+            FileFormat::Plain => "plain",
+            FileFormat::Parquet => "parquet",
+        }
+    }
+}
+
+impl FromStr for FileFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gzip" => Ok(Self::Gzip),
+            "plain" => Ok(Self::Plain),
+            "parquet" => Ok(Self::Parquet),
+            _ => Err(format!("Unknown file format `{s}`")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManifestEntry {
+    /// The name of the key whose lines are in this file
+    pub key: String,
+    /// The file name, relative to the output directory
+    pub file: String,
+    pub format: FileFormat,
+    pub lines: u64,
+    pub bytes: u64,
+    /// The size of the file's lines as json text, or `None` if it's unknown, see the [module docs](self)
+    pub uncompressed_bytes: Option<u64>,
+    /// Whether this file is known to be intact, see the [module docs](self)
+    pub complete: bool,
+}
+
+impl ManifestEntry {
+    /// How many times smaller the file is than its lines, or `None` if that's unknown (or the file is empty)
+    pub fn compression_ratio(&self) -> Option<f64> {
+        match (self.uncompressed_bytes, self.bytes) {
+            (Some(uncompressed), bytes) if bytes > 0 => Some(uncompressed as f64 / bytes as f64),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Manifest {
+    /// Whether the run stopped before splitting all of its input, see the [module docs](self)
+    pub partial: bool,
+    pub files: Vec<ManifestEntry>,
+}
+
+impl Manifest {
+    /// Sorts the entries by file name, so that the same output always gives the same manifest
+    pub fn sort(&mut self) {
+        self.files.sort_by(|a, b| a.file.cmp(&b.file));
+    }
+
+    /// Folds in the manifest of a previous run whose files this run appended to.
+    ///
+    /// Line counts and uncompressed sizes of files written by both runs are added up,
+    /// while sizes on disk are kept from this run since they already include the previous content.
+    /// A file (or the whole output) which either run left incomplete stays incomplete
+    pub fn merge_previous(&mut self, previous: Manifest) {
+        self.partial |= previous.partial;
+        for prev in previous.files {
+            match self.files.iter_mut().find(|e| e.file == prev.file) {
+                Some(e) => {
+                    e.lines += prev.lines;
+                    e.uncompressed_bytes = e
+                        .uncompressed_bytes
+                        .zip(prev.uncompressed_bytes)
+                        .map(|(a, b)| a + b);
+                    e.complete &= prev.complete;
+                }
+                None => self.files.push(prev),
+            }
+        }
+        self.sort();
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        let files = self
+            .files
+            .iter()
+            .map(|e| {
+                let mut entry = json::object! {
+                    key: e.key.as_str(),
+                    file: e.file.as_str(),
+                    format: e.format.name(),
+                    lines: e.lines,
+                    bytes: e.bytes,
+                    complete: e.complete,
+                };
+                if let Some(uncompressed) = e.uncompressed_bytes {
+                    entry["uncompressed_bytes"] = uncompressed.into();
+                }
+                if let Some(ratio) = e.compression_ratio() {
+                    // Rounded, since it's only meant for reading
+                    entry["compression_ratio"] = ((ratio * 1000.0).round() / 1000.0).into();
+                }
+                entry
+            })
+            .collect::<Vec<_>>();
+        json::object! { partial: self.partial, files: files }
+    }
+
+    pub fn from_json(v: &JsonValue) -> Result<Self, String> {
+        let string = |e: &JsonValue, field: &str| {
+            e[field]
+                .as_str()
+                .map(String::from)
+                .ok_or_else(|| format!("Manifest entry is missing `{field}`"))
+        };
+        let number = |e: &JsonValue, field: &str| {
+            e[field]
+                .as_u64()
+                .ok_or_else(|| format!("Manifest entry is missing `{field}`"))
+        };
+
+        if !v["files"].is_array() {
+            return Err("Manifest has no `files` list".to_string());
+        }
+        let files = v["files"]
+            .members()
+            .map(|e| {
+                Ok(ManifestEntry {
+                    key: string(e, "key")?,
+                    file: string(e, "file")?,
+                    format: string(e, "format")?.parse()?,
+                    lines: number(e, "lines")?,
+                    bytes: number(e, "bytes")?,
+                    uncompressed_bytes: e["uncompressed_bytes"].as_u64(),
+                    complete: e["complete"].as_bool().unwrap_or(true),
+                })
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(Self {
+            partial: v["partial"].as_bool().unwrap_or(false),
+            files,
+        })
+    }
+
+    /// Reads the manifest of the output directory `dir`
+    pub fn read(dir: &Path) -> io::Result<Self> {
+        let text = std::fs::read_to_string(dir.join(MANIFEST_FILE_NAME))?;
+        let v = json::parse(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Self::from_json(&v).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Writes this manifest into the output directory `dir`
+    pub fn write(&self, dir: &Path) -> io::Result<()> {
+        std::fs::write(dir.join(MANIFEST_FILE_NAME), self.to_json().pretty(2))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FileFormat, Manifest, ManifestEntry};
+
+    fn entry(file: &str, format: FileFormat, lines: u64, bytes: u64) -> ManifestEntry {
+        ManifestEntry {
+            key: file.split('.').next().unwrap().to_string(),
+            file: file.to_string(),
+            format,
+            lines,
+            bytes,
+            uncompressed_bytes: Some(lines * 100),
+            complete: true,
+        }
+    }
+
+    #[test]
+    fn test_manifest_round_trip() {
+        let mut manifest = Manifest {
+            partial: false,
+            files: vec![
+                entry("a_prod_2024-10-20.json", FileFormat::Plain, 2, 100),
+                entry("b_prod_2024-10-20.json.gz", FileFormat::Gzip, 5000, 2000),
+            ],
+        };
+        assert_eq!(
+            Manifest::from_json(&manifest.to_json()),
+            Ok(manifest.clone())
+        );
+
+        manifest.partial = true;
+        manifest.files[1].complete = false;
+        assert_eq!(
+            Manifest::from_json(&manifest.to_json()),
+            Ok(manifest.clone())
+        );
+
+        // Manifests without the completeness fields are from complete runs
+        let mut old = manifest.to_json();
+        old.remove("partial");
+        for e in old["files"].members_mut() {
+            e.remove("complete");
+            e.remove("uncompressed_bytes");
+        }
+        let old = Manifest::from_json(&old).unwrap();
+        assert!(!old.partial && old.files.iter().all(|e| e.complete));
+        assert!(old.files.iter().all(|e| e.uncompressed_bytes.is_none()));
+
+        assert!(Manifest::from_json(&json::object! { files: [{ key: "a" }] }).is_err());
+        assert!(Manifest::from_json(&json::object! {}).is_err());
+    }
+
+    #[test]
+    fn test_merge_previous() {
+        let mut manifest = Manifest {
+            partial: false,
+            files: vec![entry("b.json.gz", FileFormat::Gzip, 1, 300)],
+        };
+        manifest.merge_previous(Manifest {
+            partial: false,
+            files: vec![
+                entry("a.json.gz", FileFormat::Gzip, 4, 100),
+                entry("b.json.gz", FileFormat::Gzip, 2, 200),
+            ],
+        });
+        assert_eq!(
+            manifest.files,
+            [
+                entry("a.json.gz", FileFormat::Gzip, 4, 100),
+                entry("b.json.gz", FileFormat::Gzip, 3, 300),
+            ]
+        );
+    }
+}
@@ -1,13 +1,257 @@
+use std::{cmp::Reverse, collections::BinaryHeap, time::Duration};
+
+/// Formats `part` along with how much of `whole` it is, such as `1.5s (25.0%)`.
+/// The share of an empty `whole` is `0%`
+pub fn fmt_share(part: Duration, whole: Duration) -> String {
+    let percent = match whole.is_zero() {
+        true => 0.0,
+        false => part.as_secs_f64() / whole.as_secs_f64() * 100.0,
+    };
+    format!("{part:?} ({percent:.1}%)")
+}
+
 /// Returns a vector of `buckets` elements, which all add up to `sum`
 ///
 /// Each element will be one of two values, which are a distance of `1` apart
+///
+/// Panics if `buckets` is `0`, instead of dividing by zero
 pub fn get_even_partition(buckets: usize, sum: usize) -> Vec<usize> {
+    assert!(buckets > 0, "Cannot partition {sum} into 0 buckets");
     let mut v = vec![sum / buckets; buckets];
-    for i in 0..(sum % buckets) {
-        v[i] += 1;
+    for x in v.iter_mut().take(sum % buckets) {
+        *x += 1;
     }
     assert_eq!(v.iter().sum::<usize>(), sum);
     assert_eq!(v.len(), buckets);
 
     v
 }
+
+/// Returns a vector with one element per weight, which all add up to `sum`
+///
+/// Each element is its share of `sum` in proportion to its weight, rounded down or up.
+/// The elements with the largest remainders are rounded up (the earliest of them on ties),
+/// so a bucket with a bigger weight never gets less than one with a smaller weight,
+/// and a bucket with weight `0` gets nothing.
+/// If every weight is `0`, this is the same as [`get_even_partition`]
+///
+/// Panics if `weights` is empty but `sum` isn't `0`
+pub fn get_weighted_partition(weights: &[u64], sum: usize) -> Vec<usize> {
+    if weights.is_empty() {
+        assert_eq!(sum, 0, "Cannot partition {sum} into 0 buckets");
+        return vec![];
+    }
+    let total = weights.iter().map(|&w| w as u128).sum::<u128>();
+    if total == 0 {
+        return get_even_partition(weights.len(), sum);
+    }
+
+    // `sum * w / total` never exceeds `sum`, so the quotients fit back into a `usize`
+    let shares = weights
+        .iter()
+        .map(|&w| sum as u128 * w as u128)
+        .map(|share| ((share / total) as usize, share % total))
+        .collect::<Vec<_>>();
+    let mut v = shares.iter().map(|&(q, _)| q).collect::<Vec<_>>();
+
+    let left = sum - v.iter().sum::<usize>();
+    let mut by_remainder = (0..v.len()).collect::<Vec<_>>();
+    // Stable, so ties go to the earliest bucket
+    by_remainder.sort_by(|&a, &b| shares[b].1.cmp(&shares[a].1));
+    for &i in by_remainder.iter().take(left) {
+        v[i] += 1;
+    }
+    assert_eq!(v.iter().sum::<usize>(), sum);
+    assert_eq!(v.len(), weights.len());
+
+    v
+}
+
+/// Assigns every item to one of `buckets` buckets, so that the biggest total size of a bucket is as small as possible
+///
+/// This is the greedy longest-processing-time heuristic: items are placed from the biggest to the smallest,
+/// each into the bucket whose total is the smallest so far (the earliest of them on ties).
+/// The biggest bucket is never more than 4/3 of what the best possible assignment would give
+///
+/// Panics if `buckets` is `0`
+pub fn partition_items<K: Clone>(items: &[(K, u64)], buckets: usize) -> Vec<Vec<K>> {
+    assert!(buckets > 0, "Cannot partition items into 0 buckets");
+    let mut order = (0..items.len()).collect::<Vec<_>>();
+    // Stable, so equal items are placed in the order they were given
+    order.sort_by(|&a, &b| items[b].1.cmp(&items[a].1));
+
+    let mut totals = (0..buckets)
+        .map(|bucket| Reverse((0u64, bucket)))
+        .collect::<BinaryHeap<_>>();
+    let mut v = vec![vec![]; buckets];
+    for i in order {
+        let Reverse((total, bucket)) = totals.pop().expect("There is at least one bucket");
+        let (key, size) = &items[i];
+        v[bucket].push(key.clone());
+        totals.push(Reverse((total.saturating_add(*size), bucket)));
+    }
+
+    v
+}
+
+/// How much bigger the biggest of `loads` is than their average, which is `1.0` if they're perfectly balanced
+/// (including when there are none, or they're all `0`)
+pub fn imbalance_ratio(loads: &[u64]) -> f64 {
+    let total = loads.iter().map(|&l| l as f64).sum::<f64>();
+    let max = loads.iter().copied().max().unwrap_or(0);
+    if total == 0.0 {
+        return 1.0;
+    }
+    max as f64 / (total / loads.len() as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::{
+        fmt_share, get_even_partition, get_weighted_partition, imbalance_ratio, partition_items,
+    };
+
+    /// The total size of each bucket of `partition`
+    fn loads(items: &[(usize, u64)], partition: &[Vec<usize>]) -> Vec<u64> {
+        partition
+            .iter()
+            .map(|bucket| bucket.iter().map(|&k| items[k].1).sum())
+            .collect()
+    }
+
+    #[test]
+    fn test_partition_items() {
+        let items = |sizes: &[u64]| sizes.iter().copied().enumerate().collect::<Vec<_>>();
+
+        // One giant key gets a bucket to itself, and everything else is spread over the rest
+        let giant = items(&[1, 1, 1000, 1, 1, 1, 1]);
+        let partition = partition_items(&giant, 3);
+        assert_eq!(partition[0], [2]);
+        assert_eq!(loads(&giant, &partition), [1000, 3, 3]);
+        assert!((imbalance_ratio(&[1000, 3, 3]) - 1000.0 / (1006.0 / 3.0)).abs() < 1e-9);
+
+        // Equal keys are spread evenly, in order
+        let equal = items(&[10; 9]);
+        let partition = partition_items(&equal, 3);
+        assert_eq!(partition, [vec![0, 3, 6], vec![1, 4, 7], vec![2, 5, 8]]);
+        assert_eq!(imbalance_ratio(&loads(&equal, &partition)), 1.0);
+
+        // Where the heuristic isn't optimal: {3, 3} and {2, 2, 2} would be 6 and 6
+        let tricky = items(&[3, 3, 2, 2, 2]);
+        assert_eq!(loads(&tricky, &partition_items(&tricky, 2)), [7, 5]);
+
+        // More buckets than items
+        assert_eq!(
+            partition_items(&items(&[5, 7]), 3),
+            [vec![1], vec![0], vec![]]
+        );
+        assert_eq!(
+            partition_items::<usize>(&[], 2),
+            [Vec::<usize>::new(), vec![]]
+        );
+        assert_eq!(imbalance_ratio(&[]), 1.0);
+        assert_eq!(imbalance_ratio(&[0, 0]), 1.0);
+    }
+
+    /// The smallest possible biggest bucket, by trying every assignment
+    fn best_max_load(sizes: &[u64], loads: &mut [u64]) -> u64 {
+        let Some((&size, rest)) = sizes.split_first() else {
+            return loads.iter().copied().max().unwrap_or(0);
+        };
+        let mut best = u64::MAX;
+        for b in 0..loads.len() {
+            loads[b] += size;
+            best = best.min(best_max_load(rest, loads));
+            loads[b] -= size;
+        }
+        best
+    }
+
+    #[test]
+    fn test_partition_items_bound() {
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..300 {
+            let items = (0..rng.gen_range(0..9))
+                .map(|k| (k, rng.gen_range(0..1000)))
+                .collect::<Vec<_>>();
+            let buckets = rng.gen_range(1..4);
+            let partition = partition_items(&items, buckets);
+
+            let mut placed = partition.concat();
+            placed.sort();
+            assert_eq!(placed, (0..items.len()).collect::<Vec<_>>());
+
+            let sizes = items.iter().map(|&(_, s)| s).collect::<Vec<_>>();
+            let best = best_max_load(&sizes, &mut vec![0; buckets]);
+            let max = loads(&items, &partition).into_iter().max().unwrap();
+            assert!(3 * max <= 4 * best, "{items:?} into {buckets}");
+        }
+    }
+
+    #[test]
+    fn test_weighted_partition() {
+        assert_eq!(get_weighted_partition(&[1, 1, 2], 8), [2, 2, 4]);
+        assert_eq!(get_weighted_partition(&[1, 1, 1], 8), [3, 3, 2]);
+        assert_eq!(get_weighted_partition(&[0, 3, 0, 1], 5), [0, 4, 0, 1]);
+        assert_eq!(get_weighted_partition(&[5, 7], 0), [0, 0]);
+        assert_eq!(
+            get_weighted_partition(&[0, 0, 0], 4),
+            get_even_partition(3, 4)
+        );
+        assert_eq!(get_weighted_partition(&[], 0), Vec::<usize>::new());
+        assert_eq!(
+            get_weighted_partition(&[u64::MAX, u64::MAX], usize::MAX),
+            [usize::MAX / 2 + 1, usize::MAX / 2]
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_even_partition_no_buckets() {
+        get_even_partition(0, 3);
+    }
+
+    #[test]
+    fn test_weighted_partition_properties() {
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..2000 {
+            let weights = (0..rng.gen_range(1..10))
+                .map(|_| match rng.gen_bool(0.2) {
+                    true => 0,
+                    false => rng.gen_range(0..100),
+                })
+                .collect::<Vec<_>>();
+            let sum = rng.gen_range(0..1000);
+            let v = get_weighted_partition(&weights, sum);
+
+            assert_eq!(v.iter().sum::<usize>(), sum, "{weights:?} {sum}");
+            let total = weights.iter().sum::<u64>();
+            for i in 0..weights.len() {
+                if total > 0 {
+                    // Within rounding of the exact share
+                    let exact = sum as f64 * weights[i] as f64 / total as f64;
+                    assert!((v[i] as f64 - exact).abs() < 1.0, "{weights:?} {sum}");
+                }
+                for j in 0..weights.len() {
+                    if weights[i] > weights[j] {
+                        assert!(v[i] >= v[j], "{weights:?} {sum}");
+                    } else if weights[i] == weights[j] {
+                        assert!(v[i].abs_diff(v[j]) <= 1, "{weights:?} {sum}");
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_fmt_share() {
+        let ms = Duration::from_millis;
+        assert_eq!(fmt_share(ms(250), ms(1000)), "250ms (25.0%)");
+        assert_eq!(fmt_share(ms(1000), ms(1000)), "1s (100.0%)");
+        assert_eq!(fmt_share(ms(5), Duration::ZERO), "5ms (0.0%)");
+    }
+}
@@ -0,0 +1,226 @@
+//! Keeping a run's memory under a budget, see [`MemoryBudget`].
+//!
+//! Most of a run's memory is the compressor of each key it's writing (see [`ENCODER_MEMORY`]),
+//! which without a budget grows with the number of distinct keys until the run finishes.
+//! With one, each output thread gets an even [share](MemoryBudget::share) of it, and once its share is
+//! [nearly used up](BudgetShare::is_nearly_full), finishes the current gzip member of the keys it wrote to least recently,
+//! drops their compressors, and starts a new member for each key's next line.
+//! Keys which keep getting lines keep their compressors, so they aren't split into a member per line,
+//! as long as each share has room for a few compressors (see [`min_limit`]).
+//!
+//! Usage is estimated rather than measured: the channels between threads (which are bounded by their number of messages)
+//! and the bookkeeping of each key (a few hundred bytes) aren't counted
+
+use std::{
+    cell::Cell,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+/// Roughly how much memory the compressor of a gzip member takes: its dictionary and hash chains,
+/// its buffers of codes and compressed output, and the output buffer of its writer
+pub const ENCODER_MEMORY: usize = 350 << 10;
+
+/// How much of a budget is used before the output threads start freeing memory, in percent
+const HIGH_WATER_PERCENT: u64 = 90;
+/// How much of a budget the output threads free memory down to once they start, in percent,
+/// so that they don't have to free a little more with every line
+const LOW_WATER_PERCENT: u64 = 75;
+
+/// How many compressors each output thread's share of a budget has room for at least.
+/// With fewer, the key a thread is writing to would be parked along with the others after every line
+const MIN_ENCODERS_PER_THREAD: u64 = 2;
+
+/// The smallest budget which can be split between `threads` output threads, see [`MIN_ENCODERS_PER_THREAD`]
+pub fn min_limit(threads: usize) -> u64 {
+    threads as u64 * MIN_ENCODERS_PER_THREAD * ENCODER_MEMORY as u64
+}
+
+/// The memory a run may use, which the components using it add to and take away from as they go
+#[derive(Debug)]
+pub struct MemoryBudget {
+    limit: u64,
+    used: AtomicU64,
+    high_water: AtomicU64,
+}
+
+/// A reading of a [`MemoryBudget`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryStats {
+    pub limit: u64,
+    /// The estimated memory in use when the reading was taken
+    pub used: u64,
+    /// The most memory which was in use at once
+    pub high_water: u64,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: u64) -> Self {
+        Self {
+            limit,
+            used: AtomicU64::new(0),
+            high_water: AtomicU64::new(0),
+        }
+    }
+
+    pub fn add(&self, bytes: u64) {
+        let used = self.used.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        self.high_water.fetch_max(used, Ordering::Relaxed);
+    }
+
+    pub fn sub(&self, bytes: u64) {
+        self.used.fetch_sub(bytes, Ordering::Relaxed);
+    }
+
+    /// Adds or takes away the difference between what a component used to use and what it uses now
+    pub fn adjust(&self, before: u64, now: u64) {
+        match now.cmp(&before) {
+            std::cmp::Ordering::Greater => self.add(now - before),
+            std::cmp::Ordering::Less => self.sub(before - now),
+            std::cmp::Ordering::Equal => {}
+        }
+    }
+
+    /// Whether memory should be freed
+    pub fn is_nearly_full(&self) -> bool {
+        is_nearly_full(self.used(), self.limit)
+    }
+
+    /// Whether enough memory was freed since [`is_nearly_full`](MemoryBudget::is_nearly_full)
+    pub fn has_room(&self) -> bool {
+        has_room(self.used(), self.limit)
+    }
+
+    /// One of `parts` even shares of this budget, for a single thread to keep its own memory under
+    pub fn share(self: &Arc<Self>, parts: usize) -> BudgetShare {
+        BudgetShare {
+            budget: self.clone(),
+            limit: self.limit / parts.max(1) as u64,
+            used: Cell::new(0),
+        }
+    }
+
+    pub fn used(&self) -> u64 {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    pub fn stats(&self) -> MemoryStats {
+        MemoryStats {
+            limit: self.limit,
+            used: self.used(),
+            high_water: self.high_water.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The part of a [`MemoryBudget`] which a single thread keeps its memory under, see [`MemoryBudget::share`].
+///
+/// Everything added to a share is added to the whole budget as well, so the budget's readings cover every thread
+#[derive(Debug)]
+pub struct BudgetShare {
+    budget: Arc<MemoryBudget>,
+    limit: u64,
+    used: Cell<u64>,
+}
+
+impl BudgetShare {
+    /// Adds or takes away the difference between what a component used to use and what it uses now
+    pub fn adjust(&self, before: u64, now: u64) {
+        self.used.set(self.used.get() + now - before);
+        self.budget.adjust(before, now);
+    }
+
+    pub fn sub(&self, bytes: u64) {
+        self.used.set(self.used.get() - bytes);
+        self.budget.sub(bytes);
+    }
+
+    /// Whether this thread should free memory
+    pub fn is_nearly_full(&self) -> bool {
+        is_nearly_full(self.used(), self.limit)
+    }
+
+    /// Whether this thread freed enough memory since [`is_nearly_full`](BudgetShare::is_nearly_full)
+    pub fn has_room(&self) -> bool {
+        has_room(self.used(), self.limit)
+    }
+
+    pub fn used(&self) -> u64 {
+        self.used.get()
+    }
+}
+
+/// Whether `used` is above the high water mark of `limit`.
+/// Both sides are widened and compared in percent, so that neither overflows however big the limit is
+fn is_nearly_full(used: u64, limit: u64) -> bool {
+    used as u128 * 100 > limit as u128 * HIGH_WATER_PERCENT as u128
+}
+
+/// Whether `used` is back down to the low water mark of `limit`
+fn has_room(used: u64, limit: u64) -> bool {
+    used as u128 * 100 <= limit as u128 * LOW_WATER_PERCENT as u128
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{MemoryBudget, MemoryStats};
+
+    #[test]
+    fn test_budget() {
+        let budget = MemoryBudget::new(1000);
+        budget.add(800);
+        assert!(!budget.is_nearly_full());
+        budget.adjust(800, 950);
+        assert!(budget.is_nearly_full());
+        assert!(!budget.has_room());
+        budget.sub(300);
+        assert!(budget.has_room());
+        budget.adjust(650, 100);
+        assert_eq!(
+            budget.stats(),
+            MemoryStats {
+                limit: 1000,
+                used: 100,
+                high_water: 950,
+            }
+        );
+
+        // Limits near `u64::MAX` don't overflow
+        let budget = MemoryBudget::new(u64::MAX);
+        budget.add(u64::MAX / 2);
+        assert!(!budget.is_nearly_full());
+        assert!(budget.has_room());
+        budget.add(u64::MAX / 2);
+        assert!(budget.is_nearly_full());
+    }
+
+    #[test]
+    fn test_shares() {
+        let budget = Arc::new(MemoryBudget::new(1000));
+        let (a, b) = (budget.share(2), budget.share(2));
+        // A thread which uses most of its share frees memory, even though the whole budget has room
+        a.adjust(0, 460);
+        assert!(a.is_nearly_full());
+        assert!(!budget.is_nearly_full());
+        b.adjust(0, 100);
+        assert!(!b.is_nearly_full());
+        assert_eq!(budget.used(), 560);
+
+        a.adjust(460, 300);
+        assert!(a.has_room());
+        b.sub(100);
+        assert_eq!((a.used(), b.used()), (300, 0));
+        assert_eq!(
+            budget.stats(),
+            MemoryStats {
+                limit: 1000,
+                used: 300,
+                high_water: 560,
+            }
+        );
+    }
+}
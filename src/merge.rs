@@ -0,0 +1,362 @@
+//! Merges split output files back into a single stream of lines, the reverse of splitting
+
+use std::{
+    cell::Cell,
+    cmp::Reverse,
+    collections::{BinaryHeap, VecDeque},
+    fs::File,
+    io::{self, BufRead, BufReader},
+    path::PathBuf,
+};
+
+use chrono::{DateTime, Utc};
+use flate2::read::MultiGzDecoder;
+use json::JsonValue;
+
+use crate::{
+    data::{default_key, parse_timestamp, LineData},
+    filter::LineFilter,
+    ReadError,
+};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MergeOrder {
+    /// Every line of the first file, then every line of the second file, and so on
+    #[default]
+    Concatenate,
+    /// Lines are ordered by their `@timestamp`, with ties kept in file order.
+    /// Since each file is already in timestamp order when it comes from a split, this is a k-way merge
+    ByTimestamp,
+}
+
+/// The lines of a single file, decompressed if its name ends with `.gz`
+struct Source {
+    lines: Box<dyn BufRead>,
+    /// Set after a read error, since the rest of the file can't be trusted
+    failed: bool,
+}
+
+impl Source {
+    fn open(path: &PathBuf) -> io::Result<Self> {
+        let f = File::open(path)?;
+        let lines: Box<dyn BufRead> = match path.extension() {
+            Some(ext) if ext == "gz" => Box::new(BufReader::new(MultiGzDecoder::new(f))),
+            _ => Box::new(BufReader::new(f)),
+        };
+        Ok(Self {
+            lines,
+            failed: false,
+        })
+    }
+
+    /// The next line and its timestamp, or `None` at the end of the file
+    fn next(&mut self) -> Option<Result<(DateTime<Utc>, LineData), ReadError>> {
+        if self.failed {
+            return None;
+        }
+
+        let mut buf = vec![];
+        match self.lines.read_until(b'\n', &mut buf) {
+            Ok(0) => return None,
+            Ok(_) => {}
+            Err(e) => {
+                self.failed = true;
+                return Some(Err(ReadError::Io(e.to_string())));
+            }
+        }
+        let line = match String::from_utf8(buf) {
+            Ok(line) => line,
+            Err(e) => {
+                let line = String::from_utf8_lossy(e.as_bytes());
+                return Some(Err(ReadError::InvalidLine {
+                    line: line.trim_end().to_string(),
+                    input: None,
+                }));
+            }
+        };
+
+        // The timestamp is picked up while keying, to avoid parsing the line twice
+        let timestamp = Cell::new(None);
+        let key_fn = |info: &JsonValue| {
+            timestamp.set(info["@timestamp"].as_str().and_then(parse_timestamp));
+            default_key(info)
+        };
+        let data = match LineData::parse_with(line, &key_fn, &LineFilter::default()) {
+            Ok(data) => data.expect("An empty filter should keep every line"),
+            Err(e) => return Some(Err(e)),
+        };
+        // Lines only have the default key if their timestamp is valid
+        Some(Ok((timestamp.get().unwrap(), data)))
+    }
+}
+
+/// See [`merge`]
+pub struct Merge {
+    sources: Vec<Source>,
+    order: MergeOrder,
+    /// The source currently being read from, for [`MergeOrder::Concatenate`]
+    current: usize,
+    /// For [`MergeOrder::ByTimestamp`], the earliest line of each source which hasn't been yielded yet,
+    /// with its timestamp and source index in the heap
+    heap: BinaryHeap<Reverse<(DateTime<Utc>, usize)>>,
+    pending: Vec<Option<LineData>>,
+    /// Errors encountered while refilling `pending`, which are yielded before any more lines
+    errors: VecDeque<ReadError>,
+    started: bool,
+}
+
+impl Merge {
+    /// Reads lines from `sources[i]` until one can be put into `pending`, or the source ends.
+    /// Invalid lines are skipped (and reported), since the rest of the source is still valid
+    fn refill(&mut self, i: usize) {
+        while let Some(next) = self.sources[i].next() {
+            match next {
+                Ok((timestamp, line)) => {
+                    self.pending[i] = Some(line);
+                    self.heap.push(Reverse((timestamp, i)));
+                    return;
+                }
+                Err(e) => self.errors.push_back(e),
+            }
+        }
+    }
+}
+
+impl Iterator for Merge {
+    type Item = Result<LineData, ReadError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.order {
+            MergeOrder::Concatenate => loop {
+                let source = self.sources.get_mut(self.current)?;
+                match source.next() {
+                    Some(line) => return Some(line.map(|(_, line)| line)),
+                    None => self.current += 1,
+                }
+            },
+            MergeOrder::ByTimestamp => {
+                if !self.started {
+                    self.started = true;
+                    for i in 0..self.sources.len() {
+                        self.refill(i);
+                    }
+                }
+                if let Some(e) = self.errors.pop_front() {
+                    return Some(Err(e));
+                }
+
+                let Reverse((_, i)) = self.heap.pop()?;
+                let line = self.pending[i]
+                    .take()
+                    .expect("Heap entries always have a line");
+                self.refill(i);
+                Some(Ok(line))
+            }
+        }
+    }
+}
+
+/// Reads the lines of several output files (`.json.gz` or plain `.json`) as a single stream.
+///
+/// Every file is opened up front, so missing files are reported immediately.
+/// Lines are keyed with [`default_key`], and lines which can't be parsed are yielded as errors without ending the stream.
+///
+/// Memory use is one decoder and one buffered line per file:
+/// [`MergeOrder::ByTimestamp`] keeps exactly one pending line per file in its heap,
+/// so it doesn't grow with the length of the files, only with how many there are
+pub fn merge(
+    files: &[PathBuf],
+    order: MergeOrder,
+) -> io::Result<impl Iterator<Item = Result<LineData, ReadError>>> {
+    let sources = files
+        .iter()
+        .map(Source::open)
+        .collect::<io::Result<Vec<_>>>()?;
+    Ok(Merge {
+        pending: (0..sources.len()).map(|_| None).collect(),
+        sources,
+        order,
+        current: 0,
+        heap: BinaryHeap::new(),
+        errors: VecDeque::new(),
+        started: false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::{write::GzEncoder, Compression};
+    use proptest::prelude::*;
+    use tempdir::TempDir;
+
+    use crate::{
+        file_pool::ExistingFilePolicy,
+        run,
+        test_utils::{output_file, write_input},
+        testdata_gen::{generate_testdata, DateOrder, TestdataCfg},
+        OutputTarget, ReadError, RunCfg, Threads,
+    };
+
+    use super::{merge, MergeOrder};
+
+    fn line_at(service: &str, hour: u32) -> String {
+        json::object! {
+            message: format!("{service}{hour}"),
+            "@timestamp": format!("2024-10-20T{hour:02}:00:00Z"),
+            "@meta": { service: service, env: "prod" },
+        }
+        .dump()
+    }
+
+    #[test]
+    fn test_merge_round_trip() {
+        let tmp = TempDir::new("logsplitter2").unwrap();
+        let out = tmp.path().join("out");
+        let input = tmp.path().join("input.json.gz");
+
+        let lines = (0..24)
+            .map(|hour| line_at(["a", "b", "c"][hour as usize * 7 % 3], hour))
+            .collect::<Vec<_>>();
+        write_input(&input, &lines);
+        run(RunCfg {
+            input_files: vec![input],
+            output: OutputTarget::Dir(out.clone()),
+            output_threads: Threads::Fixed(2),
+            ..Default::default()
+        })
+        .unwrap();
+
+        let files = ["a", "b", "c"].map(|s| output_file(&out, s));
+        let merged = |order| {
+            merge(&files, order)
+                .unwrap()
+                .map(|l| l.unwrap().original_line_text().trim_end().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(merged(MergeOrder::ByTimestamp), lines);
+
+        let mut by_file = lines.clone();
+        by_file.sort_by_key(|l| {
+            ["a", "b", "c"]
+                .iter()
+                .position(|s| l.contains(&format!("\"{s}\"")))
+        });
+        assert_eq!(merged(MergeOrder::Concatenate), by_file);
+
+        assert!(merge(&[out.join("missing.json.gz")], MergeOrder::ByTimestamp).is_err());
+    }
+
+    proptest! {
+        // Every case is a whole run, so only a few of them
+        #![proptest_config(ProptestConfig::with_cases(24))]
+
+        /// Splitting seeded test data and merging the output gives back exactly the lines of the input, in some order
+        #[test]
+        fn test_split_merge_round_trip(
+            seed: u64,
+            lines in 0..400usize,
+            services in 1..20usize,
+            unique_dates in 1..10usize,
+            date_order in prop_oneof![
+                Just(DateOrder::Sequential),
+                Just(DateOrder::Shuffled),
+                (2..50usize).prop_map(|window| DateOrder::Interleaved { window }),
+            ],
+            threads in 1..4usize,
+            // Multi-byte characters, which must survive decoding byte by byte
+            suffix in prop_oneof![Just(""), Just("é"), Just("日本語 ✓ 🎉")],
+            trailing_newline: bool,
+        ) {
+            let mut cfg = TestdataCfg {
+                lines,
+                seed: Some(seed),
+                date_order,
+                ..Default::default()
+            };
+            cfg.set_unique_dates(unique_dates).set_services(services, 3..6);
+            let mut dbg = vec![];
+            generate_testdata(cfg, &mut std::io::sink(), Some(&mut dbg)).unwrap();
+            let mut input_lines = String::from_utf8(dbg)
+                .unwrap()
+                .lines()
+                .map(|ln| {
+                    let mut j = json::parse(ln).unwrap();
+                    j["message"] = format!("{}{suffix}", j["message"]).into();
+                    j.dump()
+                })
+                .collect::<Vec<_>>();
+
+            let tmp = TempDir::new("logsplitter2").unwrap();
+            let input = tmp.path().join("input.json.gz");
+            let mut enc = GzEncoder::new(std::fs::File::create(&input).unwrap(), Compression::fast());
+            enc.write_all(input_lines.join("\n").as_bytes()).unwrap();
+            if trailing_newline && !input_lines.is_empty() {
+                enc.write_all(b"\n").unwrap();
+            }
+            enc.finish().unwrap();
+
+            let out = tmp.path().join("out");
+            run(RunCfg {
+                input_files: vec![input],
+                output: OutputTarget::Dir(out.clone()),
+                output_threads: Threads::Fixed(threads),
+                existing_files: Some(ExistingFilePolicy::Truncate),
+                ..Default::default()
+            })
+            .unwrap();
+
+            let files = std::fs::read_dir(&out)
+                .unwrap()
+                .map(|e| e.unwrap().path())
+                .filter(|p| p.to_string_lossy().ends_with(".json.gz"))
+                .collect::<Vec<_>>();
+            let mut merged = merge(&files, MergeOrder::Concatenate)
+                .unwrap()
+                .map(|l| l.unwrap().original_line_text().to_string())
+                .collect::<Vec<_>>();
+            // Every line keeps exactly one newline
+            for ln in &merged {
+                prop_assert!(ln.ends_with('\n') && !ln[..ln.len() - 1].contains('\n'), "{ln:?}");
+            }
+            merged.iter_mut().for_each(|ln| { ln.pop(); });
+
+            merged.sort();
+            input_lines.sort();
+            prop_assert_eq!(merged, input_lines);
+        }
+    }
+
+    #[test]
+    fn test_merge_invalid_lines() {
+        let tmp = TempDir::new("logsplitter2").unwrap();
+        let plain = tmp.path().join("a.json");
+        std::fs::write(
+            &plain,
+            format!("{}\nnot json\n{}\n", line_at("a", 1), line_at("a", 5)),
+        )
+        .unwrap();
+        let gz = tmp.path().join("b.json.gz");
+        write_input(&gz, &[line_at("b", 3)]);
+
+        let merged = merge(&[plain, gz], MergeOrder::ByTimestamp)
+            .unwrap()
+            .collect::<Vec<_>>();
+        assert_eq!(merged.len(), 4);
+        // The invalid line is found when refilling after `a1`
+        assert!(
+            matches!(&merged[1], Err(ReadError::InvalidLine { line, .. }) if line == "not json")
+        );
+        let messages = merged
+            .iter()
+            .filter_map(|l| l.as_ref().ok())
+            .map(|l| l.original_line_text().trim_end().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            messages,
+            [line_at("a", 1), line_at("b", 3), line_at("a", 5)]
+        );
+    }
+}
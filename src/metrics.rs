@@ -0,0 +1,215 @@
+//! Counters of a run which can be sampled while it's going, such as to export them to a monitoring system, see [`MetricsHandle`].
+//!
+//! Every stage of a run adds to the same [`MetricsHandle`] as it goes: the input reader, the main thread which
+//! parses and routes lines, and the output threads. Counters are relaxed atomics which are only ever added to
+//! (other than the gauges of open files and queue depths), so sampling them never waits for the run.
+//! Each counter is read on its own, so a [`MetricsSnapshot`] taken mid-run may be a line or two out of step between counters
+
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, RwLock,
+};
+
+use serde::Serialize;
+
+/// Counters which are shared by every stage of a run, see the [module docs](self).
+///
+/// Clones share the same counters. A handle which is given to several runs (or several readers) adds them all up
+#[derive(Debug, Clone, Default)]
+pub struct MetricsHandle(Arc<Metrics>);
+
+#[derive(Debug, Default)]
+struct Metrics {
+    lines_read: AtomicU64,
+    bytes_read: AtomicU64,
+    bytes_decoded: AtomicU64,
+    lines_parsed: AtomicU64,
+    lines_rejected: AtomicU64,
+    lines_written: AtomicU64,
+    bytes_written: AtomicU64,
+    open_files: AtomicU64,
+    keys: AtomicU64,
+    /// One per output thread of the latest run
+    queue_depths: RwLock<Arc<[AtomicU64]>>,
+    input_stalls: AtomicU64,
+    output_stalls: AtomicU64,
+    retries: AtomicU64,
+}
+
+/// The counters of a [`MetricsHandle`] at one point in time
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct MetricsSnapshot {
+    pub lines_read: u64,
+    pub bytes_read: u64,
+    pub bytes_decoded: u64,
+    pub lines_parsed: u64,
+    pub lines_rejected: u64,
+    pub lines_written: u64,
+    pub bytes_written: u64,
+    pub open_files: u64,
+    pub keys: u64,
+    pub queue_depths: Vec<u64>,
+    pub input_stalls: u64,
+    pub output_stalls: u64,
+    pub retries: u64,
+}
+
+fn add(counter: &AtomicU64, n: u64) {
+    counter.fetch_add(n, Ordering::Relaxed);
+}
+
+fn get(counter: &AtomicU64) -> u64 {
+    counter.load(Ordering::Relaxed)
+}
+
+impl MetricsHandle {
+    /// Lines sent by input readers, before filtering. Lines which don't parse count as well
+    pub fn lines_read(&self) -> u64 {
+        get(&self.0.lines_read)
+    }
+
+    /// How much of the (gzip-compressed) input has been read, across every input file
+    pub fn bytes_read(&self) -> u64 {
+        get(&self.0.bytes_read)
+    }
+
+    /// The decoded bytes of every line read, including newlines
+    pub fn bytes_decoded(&self) -> u64 {
+        get(&self.0.bytes_decoded)
+    }
+
+    /// Valid lines which reached the splitter, after filtering
+    pub fn lines_parsed(&self) -> u64 {
+        get(&self.0.lines_parsed)
+    }
+
+    /// Invalid lines which reached the splitter, see [`InvalidLineLimit`](crate::invalid_lines::InvalidLineLimit),
+    /// and lines which an output thread skipped because they couldn't be converted to its format
+    pub fn lines_rejected(&self) -> u64 {
+        get(&self.0.lines_rejected)
+    }
+
+    /// Lines handed to the output, after [transforming](crate::RunCfg::transform) them
+    pub fn lines_written(&self) -> u64 {
+        get(&self.0.lines_written)
+    }
+
+    /// Bytes written to output files by the output threads, which lag behind [`lines_written`](MetricsHandle::lines_written)
+    pub fn bytes_written(&self) -> u64 {
+        get(&self.0.bytes_written)
+    }
+
+    /// Output files which are open right now
+    pub fn open_files(&self) -> u64 {
+        get(&self.0.open_files)
+    }
+
+    /// Distinct keys which the output threads have been given lines of
+    pub fn keys(&self) -> u64 {
+        get(&self.0.keys)
+    }
+
+    /// How many lines are waiting for each output thread right now
+    pub fn queue_depths(&self) -> Vec<u64> {
+        self.0
+            .queue_depths
+            .read()
+            .unwrap()
+            .iter()
+            .map(get)
+            .collect()
+    }
+
+    /// How many times an input reader had to wait for the main thread to take its lines
+    pub fn input_stalls(&self) -> u64 {
+        get(&self.0.input_stalls)
+    }
+
+    /// How many times the main thread had to wait for an output thread to take its lines
+    pub fn output_stalls(&self) -> u64 {
+        get(&self.0.output_stalls)
+    }
+
+    /// Writes and opens of output files which were retried after a transient error, see [`RetryPolicy`](crate::file_pool::RetryPolicy)
+    pub fn retries(&self) -> u64 {
+        get(&self.0.retries)
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            lines_read: self.lines_read(),
+            bytes_read: self.bytes_read(),
+            bytes_decoded: self.bytes_decoded(),
+            lines_parsed: self.lines_parsed(),
+            lines_rejected: self.lines_rejected(),
+            lines_written: self.lines_written(),
+            bytes_written: self.bytes_written(),
+            open_files: self.open_files(),
+            keys: self.keys(),
+            queue_depths: self.queue_depths(),
+            input_stalls: self.input_stalls(),
+            output_stalls: self.output_stalls(),
+            retries: self.retries(),
+        }
+    }
+
+    pub(crate) fn line_read(&self, bytes: usize) {
+        add(&self.0.bytes_decoded, bytes as u64);
+        add(&self.0.lines_read, 1);
+    }
+
+    pub(crate) fn add_bytes_read(&self, bytes: u64) {
+        add(&self.0.bytes_read, bytes);
+    }
+
+    pub(crate) fn line_parsed(&self) {
+        add(&self.0.lines_parsed, 1);
+    }
+
+    pub(crate) fn line_rejected(&self) {
+        add(&self.0.lines_rejected, 1);
+    }
+
+    pub(crate) fn line_written(&self) {
+        add(&self.0.lines_written, 1);
+    }
+
+    pub(crate) fn add_bytes_written(&self, bytes: u64) {
+        add(&self.0.bytes_written, bytes);
+    }
+
+    pub(crate) fn add_keys(&self, keys: u64) {
+        add(&self.0.keys, keys);
+    }
+
+    pub(crate) fn add_retries(&self, retries: u64) {
+        add(&self.0.retries, retries);
+    }
+
+    /// Updates the open files gauge with the difference between what a thread had open and what it has open now
+    pub(crate) fn adjust_open_files(&self, before: u64, now: u64) {
+        if now > before {
+            add(&self.0.open_files, now - before);
+        } else {
+            self.0.open_files.fetch_sub(before - now, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn input_stalled(&self) {
+        add(&self.0.input_stalls, 1);
+    }
+
+    pub(crate) fn output_stalled(&self) {
+        add(&self.0.output_stalls, 1);
+    }
+
+    /// Starts counting the queues of `threads` output threads, replacing those of any earlier run.
+    /// The sender adds to a thread's depth before sending it a line, and the thread takes away once it received it
+    pub(crate) fn output_queues(&self, threads: usize) -> Arc<[AtomicU64]> {
+        let queues = (0..threads)
+            .map(|_| AtomicU64::new(0))
+            .collect::<Arc<[_]>>();
+        *self.0.queue_depths.write().unwrap() = queues.clone();
+        queues
+    }
+}
@@ -1,30 +1,326 @@
 use std::{
-    collections::{HashMap, VecDeque},
-    io::{Read, Write},
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    future::Future,
+    io::{self, BufWriter, Write},
     path::PathBuf,
-    sync::{Arc, Mutex},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
     thread::JoinHandle,
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime},
 };
 
-use flate2::{write::GzEncoder, Compression};
+use flate2::{write::GzEncoder, Compression, GzBuilder};
 use kanal::{Receiver, Sender};
 
 use crate::{
-    byte_channel::{self, BytesRx, BytesTx},
-    data::{LineData, MsgKey, MsgKeyMap},
-    file_pool::FilePool,
+    byte_channel::{self, BytesRx, BytesTx, TryRecv, WhenFull},
+    data::{hash_builder, LineData, MsgKey, MsgKeyMap},
+    deflate::{DeflateStrategy, StrategyGzEncoder},
+    file_complete::{FileCompleteEvent, OnFileComplete},
+    file_pool::{
+        is_storage_full, ExistingFilePolicy, FileBackend, FilePool, RetryPolicy, UringBackend,
+    },
+    filter::JsonPath,
+    index::{IndexEntry, LineIndex},
+    manifest::{FileFormat, Manifest, ManifestEntry},
     math_utils,
+    memory::{BudgetShare, MemoryBudget, ENCODER_MEMORY},
+    metrics::MetricsHandle,
+    stripes::{KeyStripes, Striper},
+    Error, ErrorKind,
 };
 
+#[cfg(unix)]
+use crate::file_pool::UnixMode;
+#[cfg(feature = "parquet")]
+use crate::parquet_output::ParquetKeyWriter;
+
 /// Sent from main thread to output writing thread
 enum OutputThreadMsg {
     Finish,
-    Write { ln: LineData },
+    Write {
+        ln: LineData,
+    },
+    /// Sends on `done` once everything written so far is on the disk, see [`OutputFiles::sync_all`]
+    Sync {
+        done: Sender<()>,
+    },
+    /// Creates the files of `keys`, then sends on `done`, see [`OutputFiles::precreate`]
+    Precreate {
+        keys: Vec<MsgKey>,
+        done: Sender<()>,
+    },
+    /// Sends the thread's current [`OutputStatus`] on `reply`, see [`OutputFiles::status`]
+    Status {
+        reply: Sender<OutputStatus>,
+    },
+}
+
+/// A snapshot of what the output threads are holding, see [`OutputFiles::status`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OutputStatus {
+    /// Files which are currently open
+    pub open_files: usize,
+    /// Open files which aren't being written to right now
+    pub idle_files: usize,
+    /// Files which were closed to stay under [`OutputCfg::max_active_files`], and are reopened on their next line
+    pub inactive_files: usize,
+    /// Keys which lines were written to
+    pub distinct_keys: usize,
+    /// An estimate of the bytes held in memory instead of written to the output files:
+    /// lines of keys which are [held uncompressed](OutputCfg::plain_below), compressed bytes which are yet to be written,
+    /// and buffered parquet rows.
+    /// The working memory of each key's compressor isn't counted
+    pub buffered_bytes: usize,
+}
+
+impl OutputStatus {
+    fn add(self, other: Self) -> Self {
+        Self {
+            open_files: self.open_files + other.open_files,
+            idle_files: self.idle_files + other.idle_files,
+            inactive_files: self.inactive_files + other.inactive_files,
+            distinct_keys: self.distinct_keys + other.distinct_keys,
+            buffered_bytes: self.buffered_bytes + other.buffered_bytes,
+        }
+    }
+}
+
+/// What the MTIME field in the header of each gzip member is set to
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum GzipMtime {
+    /// The time at which the output files were created (`run-start`)
+    #[default]
+    RunStart,
+    /// Midnight UTC of the date bucket which the member's key belongs to (`key-date`)
+    KeyDate,
+}
+
+impl FromStr for GzipMtime {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "run-start" => Ok(Self::RunStart),
+            "key-date" => Ok(Self::KeyDate),
+            _ => Err(format!(
+                "Unknown gzip mtime `{s}`, expected `run-start` or `key-date`"
+            )),
+        }
+    }
+}
+
+/// How output threads are picked for keys which aren't [explicitly assigned](OutputFiles::with_assignments)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ThreadAssignment {
+    /// Each new key goes to the next thread in turn (`round-robin`), which spreads keys evenly
+    /// but has to remember the thread of every key, so memory grows with the number of distinct keys
+    #[default]
+    RoundRobin,
+    /// Each key goes to the thread given by a hash of its name (`hash-mod`), which is computed for every line
+    /// instead of being remembered, so memory doesn't grow with the number of keys.
+    /// Threads can be given somewhat uneven numbers of keys
+    HashMod,
+}
+
+impl FromStr for ThreadAssignment {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "round-robin" => Ok(Self::RoundRobin),
+            "hash-mod" => Ok(Self::HashMod),
+            _ => Err(format!(
+                "Unknown thread assignment `{s}`, expected `round-robin` or `hash-mod`"
+            )),
+        }
+    }
+}
+
+/// How the text of each line is written
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ReserializeMode {
+    /// Exactly as it was in the input (`off`)
+    #[default]
+    Off,
+    /// Parsed and written again without any whitespace (`compact`), so that every line is in a canonical form
+    Compact,
+    /// Parsed and written again indented by 2 spaces (`pretty`).
+    /// Each line then spans several lines, so the output isn't json lines anymore,
+    /// and can't be [merged](crate::merge) or [verified](crate::verify)
+    Pretty,
+}
+
+impl ReserializeMode {
+    /// The text to write for `ln`, which ends with a single newline.
+    ///
+    /// Object keys keep their order from the input, but only the last value of a repeated key is kept,
+    /// and numbers may be written in a different form with the same value (`1.50e1` becomes `15.0`)
+    pub fn apply(self, ln: &LineData) -> Cow<'_, str> {
+        self.apply_redacted(ln, &[])
+    }
+
+    /// Like [`apply`](ReserializeMode::apply), but first removes the fields at `redact`.
+    ///
+    /// Lines which had any of those fields are always re-serialized, compactly if this is [`Off`](ReserializeMode::Off)
+    pub fn apply_redacted<'a>(self, ln: &'a LineData, redact: &[JsonPath]) -> Cow<'a, str> {
+        let text = ln.original_line_text();
+        if self == Self::Off && redact.is_empty() {
+            return Cow::Borrowed(text);
+        }
+        // Only lines which are valid json are ever written
+        let Ok(mut v) = json::parse(text) else {
+            return Cow::Borrowed(text);
+        };
+
+        let mut redacted = false;
+        for path in redact {
+            redacted |= path.remove(&mut v);
+        }
+        let text = match self {
+            Self::Off if !redacted => return Cow::Borrowed(text),
+            Self::Off | Self::Compact => v.dump(),
+            Self::Pretty => v.pretty(2),
+        };
+        Cow::Owned(text + "\n")
+    }
+}
+
+impl FromStr for ReserializeMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Self::Off),
+            "compact" => Ok(Self::Compact),
+            "pretty" => Ok(Self::Pretty),
+            _ => Err(format!(
+                "Unknown reserialize mode `{s}`, expected `off`, `compact`, or `pretty`"
+            )),
+        }
+    }
+}
+
+/// Picks the gzip compression level of each key's output file, see [`OutputCfg::compression`]
+pub type CompressionFn = Arc<dyn Fn(&MsgKey) -> Compression + Send + Sync>;
+
+/// The compression policy which uses [`Compression::default()`] for every key
+pub fn default_compression(_key: &MsgKey) -> Compression {
+    Compression::default()
+}
+
+/// What each key's output file is written as
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Newline-delimited json, gzip-compressed (`.json.gz`)
+    #[default]
+    Gzip,
+    /// Columnar [parquet](crate::parquet_output) files (`.parquet`),
+    /// with a row group every `batch_size` lines
+    #[cfg(feature = "parquet")]
+    Parquet { batch_size: usize },
+}
+
+impl OutputFormat {
+    /// The file extension of output files in this format
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Gzip => "json.gz",
+            #[cfg(feature = "parquet")]
+            OutputFormat::Parquet { .. } => "parquet",
+        }
+    }
+}
+
+/// Settings shared by every output thread
+#[derive(Clone)]
+pub struct OutputCfg {
+    pub root_dir: PathBuf,
+    /// The maximum number of files open at once, across all threads
+    pub max_active_files: usize,
+    pub existing_files: ExistingFilePolicy,
+    pub gzip_mtime: GzipMtime,
+    /// The gzip compression level of each key's output file, see [`default_compression`]
+    pub compression: CompressionFn,
+    /// The deflate strategy of each key's gzip output
+    pub deflate_strategy: DeflateStrategy,
+    pub format: OutputFormat,
+    /// If set, every this many lines of each output file start a new gzip member,
+    /// which is recorded in a [line index](crate::index) sidecar.
+    ///
+    /// Cannot be combined with [`ExistingFilePolicy::Append`], since the existing files' lines aren't indexed
+    pub index_interval: Option<u64>,
+    /// If set, keys whose lines add up to fewer than this many bytes are written as plain `.json` files,
+    /// since compressing them would only waste CPU and often make them bigger.
+    ///
+    /// Only supported for [`OutputFormat::Gzip`], without indexing or appending,
+    /// since whether a key's file is compressed is only known once the key is finished.
+    /// A key's file of the other format, left behind by a previous run, is removed
+    pub plain_below: Option<usize>,
+    /// How each thread retries transient write errors
+    pub retry: RetryPolicy,
+    /// If set, each thread syncs its recently written files every this many writes,
+    /// see [`FilePool::with_sync_every`] for the cost of doing so
+    pub sync_every: Option<usize>,
+    /// How the text of each line is written, see [`ReserializeMode`]
+    pub reserialize: ReserializeMode,
+    /// Fields removed from every line before it's written, see [`ReserializeMode::apply_redacted`].
+    /// Lines are still keyed by their original fields
+    pub redact_fields: Vec<JsonPath>,
+    /// If set, the permissions of every file which is created, see [`UringBackend::file_mode`]
+    #[cfg(unix)]
+    pub file_mode: Option<UnixMode>,
+    /// Seeds every key map and [`ThreadAssignment::HashMod`], see [`hash_builder`]
+    pub hash_seed: u64,
+    /// If set, called by each thread with every file it finishes, see [`OnFileComplete`]
+    pub on_file_complete: Option<OnFileComplete>,
+    /// If set, the memory of each key's writer is counted against this budget, of which each thread gets an even share,
+    /// and threads park the keys they wrote to least recently once their share is nearly used up, see the [memory module](crate::memory)
+    pub memory_budget: Option<Arc<MemoryBudget>>,
+    /// Added to by every thread as it writes, and by [`OutputFiles::write_line`] as it queues lines
+    pub metrics: MetricsHandle,
+}
+
+/// What an output thread leaves behind once it's finished
+struct ThreadOutput {
+    /// The manifest entries of every file the thread wrote
+    entries: Vec<ManifestEntry>,
+    timings: ThreadTimings,
+}
+
+/// Where an output thread spent its time, to tell whether a run is bound by compression, the disk, or its input.
+///
+/// Only measured around waits and writes to files, rather than around every line, so lines are close to free
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ThreadTimings {
+    /// From the thread starting until it finished its last file
+    pub total: Duration,
+    /// Waiting for lines (or other messages) while the thread's channel was empty
+    pub channel_wait: Duration,
+    /// Writing and syncing files, and closing them once the thread is finished
+    pub file_io: Duration,
+    /// Opening files, and closing idle files to make room for them, see [`FilePool::open_close_time`]
+    pub open_close: Duration,
+    /// Everything else, which is mostly compressing lines (and [reserializing](OutputCfg::reserialize) them)
+    pub compress: Duration,
+    /// How many writes or opens were retried after a transient error, whose backoff is part of `file_io`
+    pub retries: u64,
+}
+
+/// Awaits `fut`, adding how long it took to `time`
+async fn timed<T>(time: &mut Duration, fut: impl Future<Output = T>) -> T {
+    let start = Instant::now();
+    let out = fut.await;
+    *time += start.elapsed();
+    out
 }
 
 struct ThreadInfo {
-    h: JoinHandle<()>,
+    h: JoinHandle<ThreadOutput>,
     tx: Sender<OutputThreadMsg>,
 }
 
@@ -33,194 +329,2222 @@ struct ThreadInfo {
 /// Each thread keeps track of their own list of active and inactive files
 pub struct OutputFiles {
     threads: Vec<ThreadInfo>,
-    /// The thread which each `MsgKey` will be routed to
+    /// The thread which each `MsgKey` will be routed to. With [`ThreadAssignment::HashMod`],
+    /// only keys given to [`with_assignments`](OutputFiles::with_assignments) are kept here
     msgkey_assigned: MsgKeyMap<usize>,
+    hash_seed: u64,
+    assignment: ThreadAssignment,
     /// The thread which most recently had a new `MsgKey` assigned to it
     last_thread_with_new_file: usize,
+    /// Set by any thread whose writes fail because the disk is full
+    storage_full: Arc<AtomicBool>,
+    /// Added to by every thread as it writes, see [`OutputCfg::metrics`]
+    metrics: MetricsHandle,
+    /// Added to by every thread as it writes, like [`MetricsHandle::bytes_written`] but only for this run,
+    /// since other runs may add to the same metrics. See [`bytes_written`](OutputFiles::bytes_written)
+    bytes_written: Arc<AtomicU64>,
+    /// How many lines are waiting for each thread, see [`MetricsHandle::queue_depths`]
+    queues: Arc<[AtomicU64]>,
+    /// See [`send_wait`](OutputFiles::send_wait)
+    send_wait: Duration,
+    /// See [`with_stripes`](OutputFiles::with_stripes)
+    striper: Option<Box<Striper>>,
+    /// Set once an output thread panicked, for [`finish`](OutputFiles::finish) to return
+    panicked: Option<Error>,
 }
 
 impl OutputFiles {
-    pub fn new(num_threads: usize, max_active_files: usize, root_dir: PathBuf) -> Self {
+    pub fn new(num_threads: usize, cfg: OutputCfg) -> Self {
         assert!(
-            max_active_files >= num_threads,
+            cfg.max_active_files >= num_threads,
             "Cannot have `max_active_threads` < `num_threads`"
         );
+        assert!(
+            cfg.index_interval.is_none() || cfg.existing_files != ExistingFilePolicy::Append,
+            "Cannot write line indexes when appending to existing files"
+        );
+        assert!(
+            cfg.format == OutputFormat::Gzip
+                || (cfg.index_interval.is_none()
+                    && cfg.existing_files != ExistingFilePolicy::Append),
+            "Only gzip output supports line indexes and appending to existing files"
+        );
+        assert!(
+            cfg.plain_below.is_none()
+                || (cfg.format == OutputFormat::Gzip
+                    && cfg.index_interval.is_none()
+                    && cfg.existing_files != ExistingFilePolicy::Append),
+            "Plain output for small keys is only supported for new gzip files without line indexes"
+        );
+        assert_ne!(
+            cfg.index_interval,
+            Some(0),
+            "Index interval must be positive"
+        );
+
+        let run_start = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as u32;
+        let cfg = Arc::new(cfg);
+        let storage_full = Arc::new(AtomicBool::new(false));
+        let bytes_written = Arc::new(AtomicU64::new(0));
+        let queues = cfg.metrics.output_queues(num_threads);
 
-        let threads = math_utils::get_even_partition(num_threads, max_active_files)
+        let threads = math_utils::get_even_partition(num_threads, cfg.max_active_files)
             .into_iter()
-            .map(|max_files| {
-                let root_dir = root_dir.clone();
+            .enumerate()
+            .map(|(i, max_files)| {
+                let cfg = cfg.clone();
+                let storage_full = storage_full.clone();
+                let bytes_written = bytes_written.clone();
+                let queues = queues.clone();
                 let (tx, rx) = kanal::bounded(256);
-                let h = std::thread::spawn(move || {
-                    let files = FilePool::new(max_files, root_dir);
-                    tokio_uring::start(async move { output_thread(rx, files).await })
-                });
+                let budget = cfg.memory_budget.as_ref().map(|b| b.share(num_threads));
+                let h = std::thread::Builder::new()
+                    .name(format!("output-{i}"))
+                    .spawn(move || {
+                        let backend = UringBackend {
+                            #[cfg(unix)]
+                            file_mode: cfg.file_mode,
+                        };
+                        let mut files = FilePool::with_backend(
+                            max_files,
+                            cfg.root_dir.clone(),
+                            cfg.existing_files,
+                            backend,
+                        )
+                        .with_extension(cfg.format.extension())
+                        .with_retry_policy(cfg.retry.clone())
+                        .with_hash_seed(cfg.hash_seed);
+                        if let Some(n) = cfg.sync_every {
+                            files = files.with_sync_every(n);
+                        }
+                        tokio_uring::start(async move {
+                            output_thread(
+                                rx,
+                                files,
+                                &cfg,
+                                budget,
+                                run_start,
+                                &storage_full,
+                                &bytes_written,
+                                &queues[i],
+                            )
+                            .await
+                        })
+                    })
+                    .expect("Could not spawn an output thread");
                 ThreadInfo { h, tx }
             })
             .collect();
 
         Self {
             threads,
-            msgkey_assigned: Default::default(),
+            msgkey_assigned: MsgKeyMap::with_hasher(hash_builder(cfg.hash_seed)),
+            hash_seed: cfg.hash_seed,
+            assignment: Default::default(),
             last_thread_with_new_file: 0,
+            storage_full,
+            metrics: cfg.metrics.clone(),
+            bytes_written,
+            queues,
+            send_wait: Duration::ZERO,
+            striper: None,
+            panicked: None,
         }
     }
 
-    pub fn write_line(&mut self, ln: LineData) {
-        let thread_idx = *self
-            .msgkey_assigned
-            .entry(ln.key().clone())
-            .or_insert_with(|| {
+    /// Routes every key of `assignments[i]` to output thread `i`, such as from [`math_utils::partition_items`],
+    /// instead of assigning keys to threads round-robin as they first show up.
+    /// Keys which aren't in `assignments` are still assigned by the [thread assignment](OutputFiles::with_thread_assignment)
+    ///
+    /// Panics if there are more assignments than threads
+    pub fn with_assignments(mut self, assignments: &[Vec<MsgKey>]) -> Self {
+        assert!(
+            assignments.len() <= self.threads.len(),
+            "Cannot assign keys to {} threads when there are only {}",
+            assignments.len(),
+            self.threads.len()
+        );
+        for (thread_idx, keys) in assignments.iter().enumerate() {
+            for key in keys {
+                self.msgkey_assigned.insert(key.clone(), thread_idx);
+            }
+        }
+        self
+    }
+
+    /// How keys which aren't in [`with_assignments`](OutputFiles::with_assignments) are routed to threads,
+    /// which is [`ThreadAssignment::RoundRobin`] by default
+    pub fn with_thread_assignment(mut self, assignment: ThreadAssignment) -> Self {
+        self.assignment = assignment;
+        self
+    }
+
+    /// Spreads the lines of hot keys over several files and threads, see [`stripes`](crate::stripes)
+    pub fn with_stripes(mut self, stripes: KeyStripes) -> Self {
+        self.striper = Some(Box::new(Striper::new(stripes, self.hash_seed)));
+        self
+    }
+
+    /// The line to send and the thread to send it to. The line of a [striped](OutputFiles::with_stripes) key
+    /// is moved to its stripe, which goes to the thread that many after the key's own
+    fn route(&mut self, ln: LineData) -> (LineData, usize) {
+        let thread_idx = self.thread_of(ln.key());
+        let threads = self.threads.len();
+        match self.striper.as_mut().and_then(|s| s.stripe(&ln, threads)) {
+            Some((stripe, i)) => (ln.with_key(stripe), (thread_idx + i) % threads),
+            None => (ln, thread_idx),
+        }
+    }
+
+    /// The thread which `key` is routed to, assigning it a thread if it's new
+    fn thread_of(&mut self, key: &MsgKey) -> usize {
+        if let Some(&t) = self.msgkey_assigned.get(key) {
+            return t;
+        }
+        match self.assignment {
+            ThreadAssignment::RoundRobin => {
                 let t = self.last_thread_with_new_file;
                 self.last_thread_with_new_file = (t + 1) % self.threads.len();
+                self.msgkey_assigned.insert(key.clone(), t);
                 t
-            });
+            }
+            ThreadAssignment::HashMod => hash_thread(key, self.hash_seed, self.threads.len()),
+        }
+    }
 
-        self.threads[thread_idx]
-            .tx
-            .send(OutputThreadMsg::Write { ln })
-            .unwrap();
+    /// Whether any keys are [striped](OutputFiles::with_stripes)
+    pub(crate) fn is_striped(&self) -> bool {
+        self.striper.is_some()
     }
 
-    fn finish(&mut self) {
-        println!("Started finishing output files...");
+    /// `n` senders which route lines to the output threads from `n` threads at once, see [`LineSender`]
+    ///
+    /// Panics if keys are [striped](OutputFiles::with_stripes), since striping has to see every line
+    pub(crate) fn senders(&self, n: usize) -> Vec<LineSender> {
+        assert!(
+            self.striper.is_none(),
+            "Striped keys can only be routed from one thread"
+        );
+        let assigned = Arc::new(self.msgkey_assigned.clone());
+        (0..n)
+            .map(|_| LineSender {
+                txs: self.threads.iter().map(|t| t.tx.clone()).collect(),
+                queues: self.queues.clone(),
+                metrics: self.metrics.clone(),
+                assigned: assigned.clone(),
+                hash_seed: self.hash_seed,
+                new_keys: (self.assignment == ThreadAssignment::RoundRobin)
+                    .then(|| MsgKeyMap::with_hasher(hash_builder(self.hash_seed))),
+                send_wait: Duration::ZERO,
+            })
+            .collect()
+    }
+
+    /// Takes back a sender from [`senders`](OutputFiles::senders) once it's done, so that the keys it assigned
+    /// stay on their threads and the time it waited counts towards [`send_wait`](OutputFiles::send_wait)
+    pub(crate) fn merge_sender(&mut self, sender: LineSender) {
+        self.send_wait += sender.send_wait;
+        if let Some(new_keys) = sender.new_keys {
+            self.msgkey_assigned.extend(new_keys);
+        }
+    }
+
+    /// Finishes every thread once one of them stopped early (such as after a [`LineSender`] couldn't send to it),
+    /// keeping the panic which stopped it for [`finish`](OutputFiles::finish) to return
+    pub(crate) fn thread_stopped(&mut self) {
+        match self.finish_threads() {
+            Err(e) => self.panicked = Some(e),
+            Ok(_) => unreachable!("An output thread stopped without panicking"),
+        }
+    }
+
+    /// Whether an output thread panicked. Every line written after that is dropped,
+    /// and [`finish`](OutputFiles::finish) returns the panic
+    pub fn panicked(&self) -> bool {
+        self.panicked.is_some()
+    }
+
+    pub fn write_line(&mut self, ln: LineData) {
+        if self.panicked() {
+            return;
+        }
+        let (ln, thread_idx) = self.route(ln);
+
+        let tx = &self.threads[thread_idx].tx;
+        self.queues[thread_idx].fetch_add(1, Ordering::Relaxed);
+        // Only waits are timed, so that sending to a thread which keeps up costs nothing extra
+        let mut msg = Some(OutputThreadMsg::Write { ln });
+        let sent = match tx.try_send_option(&mut msg) {
+            Ok(false) => {
+                self.metrics.output_stalled();
+                let start = Instant::now();
+                let sent = tx.send(msg.take().unwrap());
+                self.send_wait += start.elapsed();
+                sent
+            }
+            sent => sent.map(|_| ()),
+        };
+        // The receiver is only dropped early if the thread panicked, so joining it tells why
+        if sent.is_err() {
+            self.thread_stopped();
+        }
+    }
+
+    /// Like [`write_line`](OutputFiles::write_line), but awaits room in the thread's channel instead of blocking.
+    ///
+    /// If this is cancelled while waiting, `ln` is dropped without being written
+    pub async fn write_line_async(&mut self, ln: LineData) {
+        if self.panicked() {
+            return;
+        }
+        let (ln, thread_idx) = self.route(ln);
+
+        let tx = self.threads[thread_idx].tx.as_async();
+        self.queues[thread_idx].fetch_add(1, Ordering::Relaxed);
+        let mut msg = Some(OutputThreadMsg::Write { ln });
+        let sent = match tx.as_sync().try_send_option(&mut msg) {
+            Ok(false) => {
+                self.metrics.output_stalled();
+                let start = Instant::now();
+                let sent = tx.send(msg.take().unwrap()).await;
+                self.send_wait += start.elapsed();
+                sent
+            }
+            sent => sent.map(|_| ()),
+        };
+        if sent.is_err() {
+            self.thread_stopped();
+        }
+    }
+
+    /// How long [`write_line`](OutputFiles::write_line) has waited for output threads whose channels were full
+    pub fn send_wait(&self) -> Duration {
+        self.send_wait
+    }
+
+    /// Whether the disk has filled up, after which lines written to some keys are dropped.
+    /// The caller should stop writing lines and [`finish`](OutputFiles::finish)
+    pub fn storage_full(&self) -> bool {
+        self.storage_full.load(Ordering::Relaxed)
+    }
+
+    /// How many bytes the output threads have written to the output files so far.
+    ///
+    /// This lags behind the lines given to [`write_line`](OutputFiles::write_line), since lines wait in each thread's queue,
+    /// and compressors hold on to their output until they have a block's worth of it
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written.load(Ordering::Relaxed)
+    }
+
+    /// Waits until every line written so far is on the disk, as complete gzip members,
+    /// so that the output files stay valid up to this point even if the process is killed right after.
+    /// Unlike [`finish`](OutputFiles::finish), writing can continue afterwards.
+    ///
+    /// Every key's current gzip member is finished, and the next line of the key starts a new one.
+    /// Lines of keys which are still held in memory (see [`OutputCfg::plain_below`]), and parquet files,
+    /// are only written once they're finished.
+    /// If the disk fills up while syncing, [`storage_full`](OutputFiles::storage_full) is set
+    ///
+    /// Does nothing once an output thread has [panicked](OutputFiles::panicked)
+    pub fn sync_all(&mut self) {
+        self.broadcast(|_, done| OutputThreadMsg::Sync { done });
+    }
+
+    /// Creates the output files of `keys` ahead of their first line, so that the first write of each key
+    /// doesn't wait for its file to be created. Returns once every thread has created its files.
+    ///
+    /// Keys are assigned to threads like they would be by their first line. Each thread only creates
+    /// as many files as it can keep open (its share of [`OutputCfg::max_active_files`]), since creating more
+    /// would close the ones it just created. The rest of the keys are only assigned to their thread,
+    /// and their files are created by their first line as usual. Nothing is created with [`OutputCfg::plain_below`],
+    /// since whether a key's file is compressed is only known once the key is finished.
+    ///
+    /// Created files are idle until their first line, so they're the first to be closed when a thread
+    /// needs room for another key's file, after which their first line reopens them.
+    /// Keys whose files were created are finished even if they never get a line,
+    /// leaving valid empty files (and manifest entries) behind
+    ///
+    /// Does nothing once an output thread has [panicked](OutputFiles::panicked)
+    pub fn precreate(&mut self, keys: &[MsgKey]) {
+        let mut thread_keys = vec![vec![]; self.threads.len()];
+        for key in keys {
+            thread_keys[self.thread_of(key)].push(key.clone());
+        }
+        self.broadcast(|i, done| OutputThreadMsg::Precreate {
+            keys: std::mem::take(&mut thread_keys[i]),
+            done,
+        });
+    }
+
+    /// How many files and keys the output threads hold, and roughly how much memory they use,
+    /// summed across every thread. Lines which are still queued for a thread aren't counted
+    ///
+    /// Does nothing once an output thread has [panicked](OutputFiles::panicked)
+    pub fn status(&mut self) -> OutputStatus {
+        self.broadcast(|_, reply| OutputThreadMsg::Status { reply })
+            .into_iter()
+            .fold(OutputStatus::default(), OutputStatus::add)
+    }
+
+    /// Sends the message `msg` makes for each thread (given the thread's index and where to reply),
+    /// and waits for every thread's reply
+    ///
+    /// Does nothing once an output thread has [panicked](OutputFiles::panicked)
+    fn broadcast<T>(&mut self, mut msg: impl FnMut(usize, Sender<T>) -> OutputThreadMsg) -> Vec<T> {
+        if self.panicked() {
+            return vec![];
+        }
+        let (reply_tx, reply_rx) = kanal::bounded(self.threads.len());
+        for (i, t) in self.threads.iter().enumerate() {
+            if t.tx.send(msg(i, reply_tx.clone())).is_err() {
+                self.thread_stopped();
+                return vec![];
+            }
+        }
+        drop(reply_tx);
+        let mut replies = Vec::with_capacity(self.threads.len());
+        for _ in 0..self.threads.len() {
+            // The senders are only dropped without sending if a thread panicked
+            match reply_rx.recv() {
+                Ok(reply) => replies.push(reply),
+                Err(_) => {
+                    self.thread_stopped();
+                    return vec![];
+                }
+            }
+        }
+        replies
+    }
+
+    /// Finishes every output file, returning the manifest of everything that was written
+    ///
+    /// Fails with [`ErrorKind::OutputThreadPanicked`] if any output thread panicked,
+    /// naming every thread which did along with its panic message
+    pub fn finish(self) -> Result<Manifest, Error> {
+        Ok(self.finish_with_timings()?.0)
+    }
+
+    /// Like [`finish`](Self::finish), but also returns where each thread spent its time
+    pub fn finish_with_timings(mut self) -> Result<(Manifest, Vec<ThreadTimings>), Error> {
+        match self.panicked.take() {
+            Some(e) => Err(e),
+            None => self.finish_threads(),
+        }
+    }
+
+    fn finish_threads(&mut self) -> Result<(Manifest, Vec<ThreadTimings>), Error> {
+        eprintln!("Started finishing output files...");
 
         let threads = self.threads.drain(..).collect::<Vec<_>>();
 
-        threads
+        // A thread which panicked can't receive `Finish`, and is reported once it's joined
+        threads.iter().for_each(|t| {
+            let _ = t.tx.send(OutputThreadMsg::Finish);
+        });
+
+        // `Finish` is the last message of each channel, and threads only return after handling it,
+        // so joining them is enough to know every line was written
+        eprintln!("Joining threads...");
+        let mut outputs = vec![];
+        let mut panics = vec![];
+        for t in threads {
+            let name = t.h.thread().name().unwrap_or("<unnamed>").to_string();
+            match t.h.join() {
+                Ok(output) => outputs.push(output),
+                Err(payload) => panics.push((name, panic_message(payload.as_ref()).to_string())),
+            }
+        }
+        if !panics.is_empty() {
+            return Err(Error {
+                kind: Box::new(ErrorKind::OutputThreadPanicked { panics }),
+            });
+        }
+
+        let retries = outputs
             .iter()
-            .for_each(|t| t.tx.send(OutputThreadMsg::Finish).unwrap());
+            .map(|o| o.timings.retries)
+            .collect::<Vec<_>>();
+        if retries.iter().any(|&r| r > 0) {
+            eprintln!("Retried transient errors (per thread): {retries:?}");
+        }
+        let timings = outputs.iter().map(|o| o.timings).collect::<Vec<_>>();
+        for (i, t) in timings.iter().enumerate() {
+            let share = |d| math_utils::fmt_share(d, t.total);
+            eprintln!(
+                "Output thread {i}: {:?} total, {} compressing, {} writing files, {} opening and closing files, {} waiting for lines",
+                t.total,
+                share(t.compress),
+                share(t.file_io),
+                share(t.open_close),
+                share(t.channel_wait)
+            );
+        }
 
-        println!("Waiting for thread channels to flush...");
+        let mut manifest = Manifest {
+            partial: false,
+            files: outputs.into_iter().flat_map(|o| o.entries).collect(),
+        };
+        manifest.sort();
+        // Checked after joining, since flushing the last output can also fill the disk
+        manifest.partial = self.storage_full();
+        if manifest.partial {
+            eprintln!("Output files finished, but the disk filled up!");
+        } else {
+            eprintln!("Output files finished successfully!");
+        }
+        Ok((manifest, timings))
+    }
+}
 
-        threads.iter().for_each(|t| {
-            let start = Instant::now();
-            loop {
-                if t.tx.is_empty() {
-                    t.tx.close();
-                    break;
-                }
-                const TIMEOUT: Duration = Duration::from_millis(10_000);
-                if start.elapsed() > TIMEOUT {
-                    // panic!("Timeout elapsed when trying to `finish` a thread! {TIMEOUT:?}")
+/// The thread of `key` with [`ThreadAssignment::HashMod`]
+fn hash_thread(key: &MsgKey, hash_seed: u64, threads: usize) -> usize {
+    let hash = xxhash_rust::xxh3::xxh3_64_with_seed(key.name().as_bytes(), hash_seed);
+    (hash % threads as u64) as usize
+}
+
+/// Routes lines to the output threads of an [`OutputFiles`] like [`write_line`](OutputFiles::write_line),
+/// but without borrowing it, so that several threads can each write with a sender of their own.
+///
+/// Keys which were assigned a thread before the senders were made keep it. New keys go to the thread
+/// given by a hash of their name, whatever the [thread assignment](OutputFiles::with_thread_assignment),
+/// so that every sender routes a key to the same thread, and the lines each sender writes to a key stay in order
+pub(crate) struct LineSender {
+    txs: Vec<Sender<OutputThreadMsg>>,
+    queues: Arc<[AtomicU64]>,
+    metrics: MetricsHandle,
+    assigned: Arc<MsgKeyMap<usize>>,
+    hash_seed: u64,
+    /// The keys this sender assigned, which round-robin has to remember, see [`OutputFiles::merge_sender`]
+    new_keys: Option<MsgKeyMap<usize>>,
+    send_wait: Duration,
+}
+
+impl LineSender {
+    /// Sends `ln` to its key's thread, blocking while the thread's channel is full.
+    /// Fails with the index of the thread if it has stopped, see [`OutputFiles::thread_stopped`]
+    pub(crate) fn write_line(&mut self, ln: LineData) -> Result<(), usize> {
+        let key = ln.key();
+        let thread_idx = match self.assigned.get(key) {
+            Some(&t) => t,
+            None => {
+                let t = hash_thread(key, self.hash_seed, self.txs.len());
+                if let Some(new_keys) = &mut self.new_keys {
+                    if !new_keys.contains_key(key) {
+                        new_keys.insert(key.clone(), t);
+                    }
                 }
+                t
             }
-        });
+        };
 
-        println!("Joining threads...");
-        threads.into_iter().for_each(|t| {
-            t.h.join().unwrap();
-        });
-        println!("Output files finished successfully!")
+        let tx = &self.txs[thread_idx];
+        self.queues[thread_idx].fetch_add(1, Ordering::Relaxed);
+        let mut msg = Some(OutputThreadMsg::Write { ln });
+        let sent = match tx.try_send_option(&mut msg) {
+            Ok(false) => {
+                self.metrics.output_stalled();
+                let start = Instant::now();
+                let sent = tx.send(msg.take().unwrap());
+                self.send_wait += start.elapsed();
+                sent
+            }
+            sent => sent.map(|_| ()),
+        };
+        sent.map_err(|_| thread_idx)
+    }
+}
+
+/// The message of a panic, if it was raised with one
+pub(crate) fn panic_message(payload: &(dyn std::any::Any + Send)) -> &str {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s
+    } else {
+        "(no message)"
     }
 }
 
 impl Drop for OutputFiles {
     fn drop(&mut self) {
-        self.finish();
+        if !self.threads.is_empty() {
+            // Nothing is left to return a panic to, so it's only printed
+            if let Err(e) = self.finish_threads() {
+                eprintln!("{e}");
+            }
+        }
     }
 }
 
-/// The `files` parameter here should be empty
-async fn output_thread(rx: Receiver<OutputThreadMsg>, mut files: FilePool) {
-    let rx = rx.as_async();
-    let mut encoders: HashMap<MsgKey, (flate2::write::GzEncoder<BytesTx>, BytesRx)> =
-        HashMap::new();
+enum StreamWriter<W: Write> {
+    Plain(BufWriter<W>),
+    Gz(GzEncoder<BufWriter<W>>),
+}
 
-    loop {
-        match rx.recv().await.expect(
-            "Main thread closed unexpectedly! /
-            `Finish` should have been sent",
-        ) {
-            OutputThreadMsg::Finish => {
-                for (key, mut enc) in encoders {
-                    enc.0.flush().unwrap();
+/// A single output stream which every line is written to, regardless of its key.
+/// This bypasses [`FilePool`] and the output threads entirely
+///
+/// Must be [`finish`](OutputStream::finish)ed, otherwise buffered lines may be lost
+pub struct OutputStream<W: Write> {
+    w: StreamWriter<W>,
+    reserialize: ReserializeMode,
+    redact_fields: Vec<JsonPath>,
+}
 
-                    let mut to_write = vec![];
-                    while let Some(b) = enc.1.try_recv() {
-                        to_write.push(b);
-                    }
+impl<W: Write> OutputStream<W> {
+    /// Lines are gzip-compressed if `compression` is given, and written as-is otherwise
+    pub fn new(w: W, compression: Option<Compression>) -> Self {
+        let w = BufWriter::new(w);
+        Self {
+            w: match compression {
+                Some(level) => StreamWriter::Gz(GzEncoder::new(w, level)),
+                None => StreamWriter::Plain(w),
+            },
+            reserialize: ReserializeMode::Off,
+            redact_fields: vec![],
+        }
+    }
 
-                    let mut f = files.take(key.clone()).await;
-                    f.write_all(to_write).await.unwrap();
-                    files.give(key, f);
-                }
+    /// Writes lines according to `reserialize` instead of as-is
+    pub fn with_reserialize(mut self, reserialize: ReserializeMode) -> Self {
+        self.reserialize = reserialize;
+        self
+    }
 
-                files.finish().await;
+    /// Removes `redact_fields` from every line before writing it
+    pub fn with_redact_fields(mut self, redact_fields: Vec<JsonPath>) -> Self {
+        self.redact_fields = redact_fields;
+        self
+    }
 
-                assert!(files.has_no_file_handles());
-                rx.close();
-                return;
+    pub fn write_line(&mut self, ln: LineData) -> std::io::Result<()> {
+        let text = self.reserialize.apply_redacted(&ln, &self.redact_fields);
+        let buf = text.as_bytes();
+        match &mut self.w {
+            StreamWriter::Plain(w) => w.write_all(buf),
+            StreamWriter::Gz(enc) => enc.write_all(buf),
+        }
+    }
+
+    /// Writes the gzip trailer (if compressing) and flushes the underlying writer
+    pub fn finish(self) -> std::io::Result<()> {
+        let mut w = match self.w {
+            StreamWriter::Plain(w) => w,
+            StreamWriter::Gz(enc) => enc.finish()?,
+        };
+        w.flush()
+    }
+}
+
+/// How many bytes an encoder may produce from a single write, before it's drained
+const ENCODER_BUDGET: usize = 8 << 20;
+/// How much of a line is written to an encoder at once. Even stored without compression,
+/// a chunk (along with what the encoder buffers) stays well within [`ENCODER_BUDGET`]
+const ENCODER_CHUNK: usize = ENCODER_BUDGET / 4;
+
+/// The encoder of a key's current gzip member
+enum MemberEncoder {
+    Flate2(GzEncoder<BytesTx>),
+    /// Only for strategies other than [`DeflateStrategy::Default`], which flate2 can't use
+    Strategy(StrategyGzEncoder<BytesTx>),
+}
+
+impl MemberEncoder {
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        match self {
+            Self::Flate2(enc) => enc.write_all(buf),
+            Self::Strategy(enc) => enc.write_all(buf),
+        }
+    }
+
+    fn try_finish(&mut self) -> io::Result<()> {
+        match self {
+            Self::Flate2(enc) => enc.try_finish(),
+            Self::Strategy(enc) => enc.try_finish(),
+        }
+    }
+
+    fn get_ref(&self) -> &BytesTx {
+        match self {
+            Self::Flate2(enc) => enc.get_ref(),
+            Self::Strategy(enc) => enc.get_ref(),
+        }
+    }
+}
+
+/// Starts a new gzip member for `key`, with its header filled in according to `cfg`
+fn new_encoder(key: &MsgKey, cfg: &OutputCfg, run_start: u32) -> (MemberEncoder, BytesRx) {
+    let mtime = match cfg.gzip_mtime {
+        GzipMtime::RunStart => run_start,
+        GzipMtime::KeyDate => key
+            .date()
+            .and_time(Default::default())
+            .and_utc()
+            .timestamp() as u32,
+    };
+
+    // The same thread drains `rx` after every write, so a full channel would never empty
+    let (tx, rx) = byte_channel::bounded_with(ENCODER_BUDGET, WhenFull::Error);
+    let filename = format!("{}.json", key.name());
+    let level = (cfg.compression)(key);
+    let enc = match cfg.deflate_strategy {
+        DeflateStrategy::Default => MemberEncoder::Flate2(
+            GzBuilder::new()
+                .filename(filename)
+                .mtime(mtime)
+                .write(tx, level),
+        ),
+        strategy => MemberEncoder::Strategy(StrategyGzEncoder::new(
+            tx, &filename, mtime, level, strategy,
+        )),
+    };
+    (enc, rx)
+}
+
+/// Appends everything an encoder has produced so far to `to_write`
+fn drain(rx: &mut BytesRx, to_write: &mut Vec<u8>) {
+    // The encoder holds the sender, so the channel is only closed once the encoder is gone
+    while let TryRecv::Ready(chunk) = rx.try_recv_chunk() {
+        to_write.extend_from_slice(chunk);
+    }
+}
+
+/// Turns the lines of a single key into the bytes of its output file, according to [`OutputFormat`]
+enum KeyWriter {
+    /// Lines are held uncompressed until the key is finished, or until they reach [`OutputCfg::plain_below`]
+    Plain(String),
+    Gzip {
+        enc: MemberEncoder,
+        rx: BytesRx,
+    },
+    #[cfg(feature = "parquet")]
+    Parquet(Box<ParquetKeyWriter>),
+    /// The key's last gzip member was finished to free the memory of its encoder,
+    /// and a new one is started by its next line
+    Parked,
+}
+
+impl KeyWriter {
+    /// Roughly how many bytes this writer holds which aren't written to the key's file yet
+    fn buffered_bytes(&self) -> usize {
+        match self {
+            Self::Plain(s) => s.len(),
+            Self::Gzip { enc, .. } => enc.get_ref().in_flight(),
+            #[cfg(feature = "parquet")]
+            Self::Parquet(w) => w.buffered_bytes(),
+            Self::Parked => 0,
+        }
+    }
+
+    /// Roughly how much memory this writer takes, which is what's counted against [`OutputCfg::memory_budget`]
+    fn memory(&self) -> usize {
+        match self {
+            Self::Plain(s) => s.capacity(),
+            Self::Gzip { enc, .. } => ENCODER_MEMORY + enc.get_ref().in_flight(),
+            #[cfg(feature = "parquet")]
+            Self::Parquet(w) => w.buffered_bytes(),
+            Self::Parked => 0,
+        }
+    }
+
+    fn new(key: &MsgKey, cfg: &OutputCfg, run_start: u32) -> Self {
+        match cfg.format {
+            OutputFormat::Gzip => {
+                let (enc, rx) = new_encoder(key, cfg, run_start);
+                Self::Gzip { enc, rx }
             }
-            OutputThreadMsg::Write { ln } => {
-                let key = ln.key().clone();
-                let mut f = files.take(key.clone()).await;
-                let enc = encoders.entry(key.clone()).or_insert_with(|| {
-                    let (tx, rx) = byte_channel::bounded(16);
-                    (GzEncoder::new(tx, Compression::default()), rx)
-                });
-                enc.0.write_all(ln.original_line_text().as_bytes()).unwrap();
+            #[cfg(feature = "parquet")]
+            OutputFormat::Parquet { batch_size } => {
+                Self::Parquet(Box::new(ParquetKeyWriter::new(batch_size)))
+            }
+        }
+    }
 
+    /// Returns any output which became ready to be written.
+    /// Only fails with [`io::ErrorKind::InvalidData`], for lines which parquet output can't parse
+    fn write_line(&mut self, text: &str) -> io::Result<Vec<u8>> {
+        match self {
+            Self::Plain(buf) => {
+                buf.push_str(text);
+                Ok(vec![])
+            }
+            Self::Gzip { enc, rx } => {
+                // Drained after every chunk, so that a huge line can't overflow the encoder's channel
                 let mut to_write = vec![];
-                while let Some(b) = enc.1.try_recv() {
-                    to_write.push(b);
-                }
-                if to_write.len() > 0 {
-                    f.write_all(to_write).await.unwrap();
+                for chunk in text.as_bytes().chunks(ENCODER_CHUNK) {
+                    enc.write_all(chunk).unwrap();
+                    drain(rx, &mut to_write);
                 }
+                Ok(to_write)
+            }
+            #[cfg(feature = "parquet")]
+            Self::Parquet(w) => w.write_line(text),
+            Self::Parked => unreachable!("Parked writers are replaced before they're written to"),
+        }
+    }
+
+    /// Finishes the current gzip member (or parquet file), returning the rest of its output
+    fn finish(&mut self) -> Vec<u8> {
+        match self {
+            Self::Plain(buf) => std::mem::take(buf).into_bytes(),
+            Self::Gzip { enc, rx } => {
+                enc.try_finish().unwrap();
+                let mut to_write = vec![];
+                drain(rx, &mut to_write);
+                to_write
+            }
+            #[cfg(feature = "parquet")]
+            Self::Parquet(w) => w.finish(),
+            Self::Parked => vec![],
+        }
+    }
+
+    fn file_format(&self) -> FileFormat {
+        match self {
+            Self::Plain(_) => FileFormat::Plain,
+            Self::Gzip { .. } | Self::Parked => FileFormat::Gzip,
+            #[cfg(feature = "parquet")]
+            Self::Parquet(_) => FileFormat::Parquet,
+        }
+    }
+}
+
+/// Everything an output thread keeps track of for a single key
+struct KeyState {
+    writer: KeyWriter,
+    lines: u64,
+    uncompressed_bytes: u64,
+    /// Only kept when indexing is enabled
+    index: Option<LineIndex>,
+    /// The size of the key's file after its last successful write
+    bytes: usize,
+    /// Set once a write for this key fails because the disk is full,
+    /// after which its file may be truncated in the middle of a gzip member
+    suspect: bool,
+    /// Whether lines were written since the key was last [synced](OutputFiles::sync_all)
+    unsynced: bool,
+    /// The memory of `writer` as of the last time it was [accounted](KeyState::account)
+    accounted: usize,
+    /// When the key was last written to, in lines sent to its thread
+    last_written: u64,
+}
+
+impl KeyState {
+    fn new(key: &MsgKey, cfg: &OutputCfg, run_start: u32) -> Self {
+        let writer = match cfg.plain_below {
+            Some(_) => KeyWriter::Plain(String::new()),
+            None => KeyWriter::new(key, cfg, run_start),
+        };
+        Self {
+            writer,
+            lines: 0,
+            uncompressed_bytes: 0,
+            index: cfg.index_interval.map(LineIndex::new),
+            bytes: 0,
+            suspect: false,
+            unsynced: false,
+            accounted: 0,
+            last_written: 0,
+        }
+    }
+
+    /// Updates `budget` with how much memory the writer takes now
+    fn account(&mut self, budget: Option<&BudgetShare>) {
+        if let Some(budget) = budget {
+            let now = self.writer.memory();
+            budget.adjust(self.accounted as u64, now as u64);
+            self.accounted = now;
+        }
+    }
+}
+
+/// Writes `to_write` to the end of `key`'s pooled file, returning the file's new size.
+/// The time it takes to write is added to `io_time`
+async fn write_to<B: FileBackend>(
+    files: &mut FilePool<B>,
+    key: &MsgKey,
+    to_write: Vec<u8>,
+    io_time: &mut Duration,
+) -> io::Result<usize> {
+    // Taking the file is timed by the pool, as opening and closing files
+    let mut f = files.take(key.clone()).await?;
+    timed(io_time, async {
+        let result = f.write_all(to_write).await;
+        let bytes = f.cursor;
+        let synced = files.give(key.clone(), f).await;
+        result.and(synced).map(|()| bytes)
+    })
+    .await
+}
+
+/// Passes `result` through if it succeeded or failed because the disk is full, and panics otherwise
+fn storage_full_or_panic<T>(result: io::Result<T>, key: &MsgKey) -> io::Result<T> {
+    match result {
+        Err(e) if !is_storage_full(&e) => {
+            panic!("Could not write the output of {}: {e}", key.name())
+        }
+        result => result,
+    }
+}
+
+/// Writes a single line of `key`, along with its index entry and anything the writer had buffered.
+/// Only the time spent writing to the file is added to `io_time`, since most lines are only compressed
+async fn write_key_line<B: FileBackend>(
+    files: &mut FilePool<B>,
+    state: &mut KeyState,
+    key: &MsgKey,
+    text: &str,
+    cfg: &OutputCfg,
+    run_start: u32,
+    io_time: &mut Duration,
+) -> io::Result<()> {
+    if let KeyWriter::Plain(buf) = &mut state.writer {
+        buf.push_str(text);
+        state.lines += 1;
+        state.uncompressed_bytes += text.len() as u64;
+        if buf.len() < cfg.plain_below.unwrap() {
+            return Ok(());
+        }
 
-                files.give(key, f);
+        // Too big to stay plain, so everything buffered so far goes through the encoder
+        let buffered = std::mem::take(buf);
+        state.writer = KeyWriter::new(key, cfg, run_start);
+        let to_write = state.writer.write_line(&buffered)?;
+        state.bytes = write_to(files, key, to_write, io_time).await?;
+        return Ok(());
+    }
+
+    let mut f = files.take(key.clone()).await?;
+    let result = async {
+        if cfg
+            .index_interval
+            .is_some_and(|k| state.lines.is_multiple_of(k))
+        {
+            // Index points always start a new member, so that they can be decoded from directly
+            if state.lines > 0 {
+                let to_write = state.writer.finish();
+                timed(io_time, f.write_all(to_write)).await?;
+                state.writer = KeyWriter::new(key, cfg, run_start);
             }
+            state.index.as_mut().unwrap().entries.push(IndexEntry {
+                line: state.lines,
+                uncompressed_offset: state.uncompressed_bytes,
+                compressed_offset: f.cursor as u64,
+            });
+        }
+
+        if let KeyWriter::Parked = state.writer {
+            state.writer = KeyWriter::new(key, cfg, run_start);
+        }
+        let to_write = state.writer.write_line(text)?;
+        state.lines += 1;
+        state.uncompressed_bytes += text.len() as u64;
+
+        if !to_write.is_empty() {
+            timed(io_time, f.write_all(to_write)).await?;
+        }
+        Ok(())
+    }
+    .await;
+    if result.is_ok() {
+        state.bytes = f.cursor;
+    }
+    let synced = files.give(key.clone(), f).await;
+    result.and(synced)
+}
+
+/// Finishes the current gzip member of every key written since the last sync, then syncs every file.
+///
+/// Returns whether the disk filled up, in which case the keys which couldn't be synced are marked suspect
+async fn sync_keys<B: FileBackend>(
+    files: &mut FilePool<B>,
+    encoders: &mut HashMap<MsgKey, KeyState>,
+    cfg: &OutputCfg,
+    budget: Option<&BudgetShare>,
+    run_start: u32,
+    io_time: &mut Duration,
+) -> bool {
+    let mut full = false;
+    for (key, state) in encoders.iter_mut() {
+        if !state.unsynced || state.suspect || !matches!(state.writer, KeyWriter::Gzip { .. }) {
+            continue;
+        }
+        let to_write = state.writer.finish();
+        state.writer = KeyWriter::new(key, cfg, run_start);
+        // The finished encoder had buffered output of its own, which the fresh one doesn't
+        state.account(budget);
+        state.unsynced = false;
+        match storage_full_or_panic(write_to(files, key, to_write, io_time).await, key) {
+            Ok(bytes) => state.bytes = bytes,
+            Err(_) => {
+                state.suspect = true;
+                full = true;
+            }
+        }
+    }
+    for (key, e) in timed(io_time, files.sync_all()).await {
+        if !is_storage_full(&e) {
+            panic!("Could not sync the output of {}: {e}", key.name());
+        }
+        if let Some(state) = encoders.get_mut(&key) {
+            state.suspect = true;
+        }
+        full = true;
+    }
+    full
+}
+
+/// Finishes the current gzip member of the keys in `active` which were written to least recently, and drops their encoders,
+/// until `budget` [has room](BudgetShare::has_room) again (or every key is parked).
+/// Keys which are still getting lines are parked last, so that they aren't split into many small members
+///
+/// Returns whether the disk filled up, in which case the keys which couldn't be written are marked suspect
+async fn park_keys<B: FileBackend>(
+    files: &mut FilePool<B>,
+    encoders: &mut HashMap<MsgKey, KeyState>,
+    active: &mut HashSet<MsgKey>,
+    budget: &BudgetShare,
+    io_time: &mut Duration,
+) -> bool {
+    let mut oldest = active
+        .iter()
+        .map(|key| (encoders[key].last_written, key.clone()))
+        .collect::<Vec<_>>();
+    oldest.sort_unstable_by_key(|&(last_written, _)| last_written);
+    let mut full = false;
+    for (_, key) in oldest {
+        if budget.has_room() {
+            break;
+        }
+        active.remove(&key);
+        let state = encoders.get_mut(&key).unwrap();
+        let to_write = state.writer.finish();
+        state.writer = KeyWriter::Parked;
+        state.account(Some(budget));
+        // Suspect keys aren't written to anymore, but their encoders are still freed
+        if state.suspect {
+            continue;
+        }
+        match storage_full_or_panic(write_to(files, &key, to_write, io_time).await, &key) {
+            Ok(bytes) => state.bytes = bytes,
+            Err(_) => {
+                state.suspect = true;
+                full = true;
+            }
+        }
+    }
+    full
+}
+
+/// Finishes the file of `key`, returning its manifest entry.
+///
+/// Keys whose writes already failed are left as they are, since the rest of their output can't make them valid again
+async fn finish_key<B: FileBackend>(
+    files: &mut FilePool<B>,
+    key: &MsgKey,
+    mut state: KeyState,
+    cfg: &OutputCfg,
+    io_time: &mut Duration,
+) -> ManifestEntry {
+    let format = state.writer.file_format();
+    // Small keys never had a file in the pool, and are written in one go
+    let path = match format {
+        FileFormat::Plain => key.path_with_extension(&cfg.root_dir, "json"),
+        _ => key.path_with_extension(&cfg.root_dir, cfg.format.extension()),
+    };
+
+    if !state.suspect {
+        // Writes the gzip trailer, so that every run leaves behind complete gzip members.
+        // This is what allows appending to an existing file with a fresh encoder
+        let to_write = state.writer.finish();
+        let result = match format {
+            FileFormat::Plain => {
+                let bytes = to_write.len();
+                timed(io_time, files.write_unpooled(&path, to_write))
+                    .await
+                    .map(|()| bytes)
+            }
+            _ => write_to(files, key, to_write, io_time).await,
+        };
+        match storage_full_or_panic(result, key) {
+            Ok(bytes) => state.bytes = bytes,
+            Err(_) => state.suspect = true,
+        }
+    }
+
+    // A key can be plain in one run and compressed in the next, and truncating means only this run's file is left
+    if let (Some(_), ExistingFilePolicy::Truncate, false) =
+        (cfg.plain_below, cfg.existing_files, state.suspect)
+    {
+        let other = match format {
+            FileFormat::Plain => key.path_with_extension(&cfg.root_dir, cfg.format.extension()),
+            _ => key.path_with_extension(&cfg.root_dir, "json"),
+        };
+        let result = timed(io_time, files.remove_unpooled(&other)).await;
+        if let Err(e) = result {
+            panic!("Could not remove {}: {e}", other.display());
+        }
+    }
+
+    if let (Some(index), false) = (state.index, state.suspect) {
+        let index_path = key.index_path_to(&cfg.root_dir);
+        let result = timed(io_time, files.write_unpooled(&index_path, index.to_bytes())).await;
+        state.suspect = storage_full_or_panic(result, key).is_err();
+    }
+
+    ManifestEntry {
+        key: key.name().to_string(),
+        file: path.file_name().unwrap().to_string_lossy().into_owned(),
+        format,
+        lines: state.lines,
+        bytes: state.bytes as u64,
+        uncompressed_bytes: Some(state.uncompressed_bytes),
+        complete: !state.suspect,
+    }
+}
+
+/// What an output thread has added to [`OutputCfg::metrics`] (and its run's bytes written) so far
+#[derive(Default)]
+struct Published {
+    bytes: u64,
+    open_files: u64,
+    keys: u64,
+    retries: u64,
+}
+
+impl Published {
+    /// Adds whatever changed since the last call
+    fn publish<B: FileBackend>(
+        &mut self,
+        metrics: &MetricsHandle,
+        bytes_written: &AtomicU64,
+        files: &FilePool<B>,
+        keys: usize,
+    ) {
+        let bytes = files.bytes_written();
+        if bytes != self.bytes {
+            metrics.add_bytes_written(bytes - self.bytes);
+            bytes_written.fetch_add(bytes - self.bytes, Ordering::Relaxed);
+            self.bytes = bytes;
+        }
+        let open_files = files.open_files() as u64;
+        if open_files != self.open_files {
+            metrics.adjust_open_files(self.open_files, open_files);
+            self.open_files = open_files;
+        }
+        if keys as u64 > self.keys {
+            metrics.add_keys(keys as u64 - self.keys);
+            self.keys = keys as u64;
+        }
+        let retries = files.retries();
+        if retries > self.retries {
+            metrics.add_retries(retries - self.retries);
+            self.retries = retries;
         }
     }
 }
 
 /// The `files` parameter here should be empty
-async fn output_thread_old(rx: Receiver<OutputThreadMsg>, mut files: FilePool) {
+///
+/// `run_start` is the unix time at which the output files were created
+///
+/// Once a write fails because the disk is full, `storage_full` is set, and every line this thread receives after that is dropped.
+/// Every other key is still finished if the disk allows it, and keys whose writes failed are marked incomplete.
+/// Other IO errors panic, once retrying them according to [`OutputCfg::retry`] has failed.
+///
+/// What this thread has written, how many files it has open and how many keys it has are added to [`OutputCfg::metrics`]
+/// after every message, and what it has written to `bytes_written` as well, which only counts this run.
+/// `queue` is this thread's [queue depth](MetricsHandle::queue_depths), which it takes lines away from.
+/// `budget` is this thread's share of [`OutputCfg::memory_budget`]
+#[allow(clippy::too_many_arguments)]
+async fn output_thread<B: FileBackend>(
+    rx: Receiver<OutputThreadMsg>,
+    mut files: FilePool<B>,
+    cfg: &OutputCfg,
+    budget: Option<BudgetShare>,
+    run_start: u32,
+    storage_full: &AtomicBool,
+    bytes_written: &AtomicU64,
+    queue: &AtomicU64,
+) -> ThreadOutput {
     let rx = rx.as_async();
-    let mut encoders: HashMap<MsgKey, GzEncoder<VecDeque<u8>>> = HashMap::new();
+    let mut encoders: HashMap<MsgKey, KeyState> = HashMap::new();
+    let budget = budget.as_ref();
+    // Keys with a gzip encoder, which are the ones a memory budget can park
+    let mut active = HashSet::new();
+    // Counts the lines this thread was sent, which tells how recently each key was written to
+    let mut written = 0;
+    let mut full = false;
+    let started = Instant::now();
+    let mut timings = ThreadTimings::default();
+    let mut published = Published::default();
 
     loop {
-        match rx.recv().await.expect(
+        published.publish(&cfg.metrics, bytes_written, &files, encoders.len());
+        // Only waits are timed, so that lines which are already queued cost nothing extra
+        let msg = match rx.try_recv() {
+            Ok(Some(msg)) => Ok(msg),
+            Ok(None) => timed(&mut timings.channel_wait, rx.recv()).await,
+            Err(e) => Err(e),
+        };
+        match msg.expect(
             "Main thread closed unexpectedly! /
             `Finish` should have been sent",
         ) {
             OutputThreadMsg::Finish => {
-                for (key, mut enc) in encoders {
-                    enc.flush().unwrap();
-
-                    let mut to_write = vec![];
-                    let written = enc.read_to_end(&mut to_write).unwrap();
-                    to_write.truncate(written);
-
-                    let mut f = files.take(key.clone()).await;
-                    f.write_all(to_write).await.unwrap();
-                    files.give(key, f);
+                let mut manifest = vec![];
+                for (key, state) in encoders {
+                    if let Some(budget) = budget {
+                        budget.sub(state.accounted as u64);
+                    }
+                    let mut entry =
+                        finish_key(&mut files, &key, state, cfg, &mut timings.file_io).await;
+                    // Each file is closed as soon as it's finished, so that it can be acted on while the others are still being finished
+                    match timed(&mut timings.file_io, files.close(&key)).await {
+                        Ok(()) => {}
+                        Err(e) if is_storage_full(&e) => entry.complete = false,
+                        Err(e) => panic!("Could not close the output of {}: {e}", key.name()),
+                    }
+                    if let (Some(on_file_complete), true) = (&cfg.on_file_complete, entry.complete)
+                    {
+                        on_file_complete.call(FileCompleteEvent {
+                            key: entry.key.clone(),
+                            path: cfg.root_dir.join(&entry.file),
+                            lines: entry.lines,
+                            bytes: entry.bytes,
+                        });
+                    }
+                    manifest.push(entry);
                 }
 
-                files.finish().await;
+                // Data can still fail to reach the disk while being flushed
+                for (key, e) in timed(&mut timings.file_io, files.finish()).await {
+                    if !is_storage_full(&e) {
+                        panic!("Could not close the output of {}: {e}", key.name());
+                    }
+                    if let Some(entry) = manifest.iter_mut().find(|e| e.key == key.name()) {
+                        entry.complete = false;
+                    }
+                }
+                if manifest.iter().any(|e| !e.complete) {
+                    storage_full.store(true, Ordering::Relaxed);
+                }
 
                 assert!(files.has_no_file_handles());
+                published.publish(&cfg.metrics, bytes_written, &files, manifest.len());
                 rx.close();
-                return;
+                timings.total = started.elapsed();
+                timings.open_close = files.open_close_time();
+                timings.compress = timings
+                    .total
+                    .saturating_sub(timings.channel_wait + timings.file_io + timings.open_close);
+                timings.retries = files.retries();
+                return ThreadOutput {
+                    entries: manifest,
+                    timings,
+                };
             }
             OutputThreadMsg::Write { ln } => {
+                queue.fetch_sub(1, Ordering::Relaxed);
                 let key = ln.key().clone();
-                let mut f = files.take(key.clone()).await;
-                let enc = encoders
+                if full {
+                    continue;
+                }
+
+                // Keys always start with a fresh encoder, even when appending to an existing file,
+                // so that this run's lines become their own gzip member
+                let state = encoders
                     .entry(key.clone())
-                    .or_insert_with(|| GzEncoder::new(Default::default(), Compression::default()));
-                enc.write_all(ln.original_line_text().as_bytes()).unwrap();
-
-                let mut to_write = vec![0; 1024];
-                let written = enc.read(&mut to_write).unwrap();
-                to_write.truncate(written);
-                if to_write.len() > 0 {
-                    f.write_all(to_write).await.unwrap();
+                    .or_insert_with(|| KeyState::new(&key, cfg, run_start));
+
+                let text = cfg.reserialize.apply_redacted(&ln, &cfg.redact_fields);
+                state.unsynced = true;
+                written += 1;
+                state.last_written = written;
+                let result = write_key_line(
+                    &mut files,
+                    state,
+                    &key,
+                    &text,
+                    cfg,
+                    run_start,
+                    &mut timings.file_io,
+                )
+                .await;
+                let result = match result {
+                    // Nothing of the line was written, so the key carries on without it
+                    Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                        eprintln!(
+                            "Skipped a line of {} which could not be written: {e}",
+                            key.name()
+                        );
+                        cfg.metrics.line_rejected();
+                        Ok(())
+                    }
+                    result => result,
+                };
+                // The failed write may have been partial, so the key's file is left as it is
+                if storage_full_or_panic(result, &key).is_err() {
+                    eprintln!(
+                        "Ran out of space while writing {}, no more lines will be written",
+                        key.name()
+                    );
+                    state.suspect = true;
+                    full = true;
+                    storage_full.store(true, Ordering::Relaxed);
+                }
+                state.account(budget);
+                if budget.is_some() && matches!(state.writer, KeyWriter::Gzip { .. }) {
+                    active.insert(key);
+                }
+                if let Some(budget) = budget.filter(|b| !full && b.is_nearly_full()) {
+                    if park_keys(
+                        &mut files,
+                        &mut encoders,
+                        &mut active,
+                        budget,
+                        &mut timings.file_io,
+                    )
+                    .await
+                    {
+                        eprintln!(
+                            "Ran out of space while parking keys, no more lines will be written"
+                        );
+                        full = true;
+                        storage_full.store(true, Ordering::Relaxed);
+                    }
+                }
+            }
+            OutputThreadMsg::Sync { done } => {
+                if !full
+                    && sync_keys(
+                        &mut files,
+                        &mut encoders,
+                        cfg,
+                        budget,
+                        run_start,
+                        &mut timings.file_io,
+                    )
+                    .await
+                {
+                    eprintln!("Ran out of space while syncing, no more lines will be written");
+                    full = true;
+                    storage_full.store(true, Ordering::Relaxed);
                 }
+                let _ = done.send(());
+            }
+            OutputThreadMsg::Precreate { keys, done } => {
+                let mut room = match cfg.plain_below {
+                    None => files.max_open_files().saturating_sub(files.open_files()),
+                    Some(_) => 0,
+                };
+                for key in keys {
+                    if room == 0 || full {
+                        break;
+                    }
+                    if encoders.contains_key(&key) {
+                        continue;
+                    }
+                    room -= 1;
+                    let mut state = KeyState::new(&key, cfg, run_start);
+                    state.account(budget);
+                    if budget.is_some() && matches!(state.writer, KeyWriter::Gzip { .. }) {
+                        active.insert(key.clone());
+                    }
+                    let result = write_to(&mut files, &key, vec![], &mut timings.file_io).await;
+                    match storage_full_or_panic(result, &key) {
+                        Ok(bytes) => state.bytes = bytes,
+                        Err(_) => {
+                            eprintln!(
+                                "Ran out of space while creating {}, no more lines will be written",
+                                key.name()
+                            );
+                            state.suspect = true;
+                            full = true;
+                            storage_full.store(true, Ordering::Relaxed);
+                        }
+                    }
+                    encoders.insert(key, state);
+                }
+                let _ = done.send(());
+            }
+            OutputThreadMsg::Status { reply } => {
+                let _ = reply.send(OutputStatus {
+                    open_files: files.open_files(),
+                    idle_files: files.idle_files(),
+                    inactive_files: files.inactive_files(),
+                    distinct_keys: encoders.len(),
+                    buffered_bytes: encoders.values().map(|s| s.writer.buffered_bytes()).sum(),
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        io::Write,
+        path::PathBuf,
+        sync::{
+            atomic::{AtomicBool, AtomicU64},
+            mpsc::RecvTimeoutError,
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use flate2::{
+        read::{GzDecoder, MultiGzDecoder},
+        write::GzEncoder,
+        Compression,
+    };
+
+    use crate::{
+        data::{LineData, MsgKey, DEFAULT_HASH_SEED},
+        deflate::DeflateStrategy,
+        file_pool::{ExistingFilePolicy, FilePool, RetryPolicy},
+        manifest::ManifestEntry,
+        math_utils,
+        memory::{MemoryBudget, ENCODER_MEMORY},
+        metrics::MetricsHandle,
+        stripes::{KeyStripes, StripeCount},
+        test_utils::{line, output_file, read_lines, MemBackend},
+        testdata_gen::{generate_testdata, DateOrder, TestdataCfg},
+        ErrorKind,
+    };
+
+    use super::{
+        default_compression, output_thread, OutputCfg, OutputFiles, OutputStatus, OutputThreadMsg,
+        ReserializeMode, ThreadAssignment, ThreadInfo,
+    };
+
+    fn test_cfg(max_open_files: usize) -> OutputCfg {
+        OutputCfg {
+            root_dir: PathBuf::from("/out"),
+            max_active_files: max_open_files,
+            existing_files: ExistingFilePolicy::Truncate,
+            gzip_mtime: Default::default(),
+            compression: Arc::new(default_compression),
+            deflate_strategy: Default::default(),
+            format: Default::default(),
+            index_interval: None,
+            plain_below: None,
+            retry: Default::default(),
+            sync_every: None,
+            reserialize: Default::default(),
+            redact_fields: vec![],
+            #[cfg(unix)]
+            file_mode: None,
+            hash_seed: DEFAULT_HASH_SEED,
+            on_file_complete: None,
+            memory_budget: None,
+            metrics: Default::default(),
+        }
+    }
+
+    /// Runs a single output thread over `lines` with an in-memory backend, then finishes it
+    fn run_output_thread(lines: &[String], max_open_files: usize) -> MemBackend {
+        run_output_thread_with(lines, test_cfg(max_open_files))
+    }
+
+    fn run_output_thread_with(lines: &[String], cfg: OutputCfg) -> MemBackend {
+        let backend = MemBackend::default();
+        run_output_thread_on(&backend, lines, &cfg);
+        backend
+    }
+
+    /// Returns the thread's manifest entries, and whether it ran out of space
+    fn run_output_thread_on(
+        backend: &MemBackend,
+        lines: &[String],
+        cfg: &OutputCfg,
+    ) -> (Vec<ManifestEntry>, bool) {
+        let files = FilePool::with_backend(
+            cfg.max_active_files,
+            cfg.root_dir.clone(),
+            cfg.existing_files,
+            backend.clone(),
+        )
+        .with_retry_policy(cfg.retry.clone());
+
+        let (tx, rx) = kanal::unbounded();
+        for ln in lines {
+            let ln = LineData::parse(ln.clone()).unwrap();
+            tx.send(OutputThreadMsg::Write { ln }).unwrap();
+        }
+        tx.send(OutputThreadMsg::Finish).unwrap();
+
+        let storage_full = AtomicBool::new(false);
+        let output = tokio_uring::start(output_thread(
+            rx,
+            files,
+            cfg,
+            cfg.memory_budget.as_ref().map(|b| b.share(1)),
+            0,
+            &storage_full,
+            &AtomicU64::new(0),
+            // Lines are sent straight to the thread, without being counted as queued
+            &AtomicU64::new(lines.len() as u64),
+        ));
+        // Every retry the thread reports was published to the metrics as well
+        assert_eq!(output.timings.retries, cfg.metrics.retries());
+        (output.entries, storage_full.into_inner())
+    }
+
+    fn contents(backend: &MemBackend, service: &str) -> Vec<String> {
+        let f = backend
+            .contents(&output_file("/out".as_ref(), service))
+            .unwrap();
+        read_lines(MultiGzDecoder::new(&f[..]))
+    }
+
+    #[test]
+    fn test_output_thread_writes_per_key() {
+        let lines = [line("a", "1"), line("b", "2"), line("a", "3")];
+        let backend = run_output_thread(&lines, 4);
+
+        assert_eq!(backend.paths().len(), 2);
+        assert_eq!(contents(&backend, "a"), [line("a", "1"), line("a", "3")]);
+        assert_eq!(contents(&backend, "b"), [line("b", "2")]);
+        assert_eq!(backend.reopens(), 0);
+    }
+
+    #[test]
+    fn test_deflate_strategy() {
+        let lines = (0..200)
+            .map(|i| line("a", &(i % 7).to_string()))
+            .collect::<Vec<_>>();
+        let mut sizes = vec![];
+        for strategy in [DeflateStrategy::Default, DeflateStrategy::HuffmanOnly] {
+            // Indexing starts several members per file
+            let backend = run_output_thread_with(
+                &lines,
+                OutputCfg {
+                    deflate_strategy: strategy,
+                    index_interval: Some(50),
+                    ..test_cfg(4)
+                },
+            );
+            assert_eq!(contents(&backend, "a"), lines, "{strategy:?}");
+            let f = backend
+                .contents(&output_file("/out".as_ref(), "a"))
+                .unwrap();
+            let header = GzDecoder::new(&f[..]).header().unwrap().clone();
+            assert_eq!(header.filename(), Some(&b"a_prod_2024-10-20.json"[..]));
+            sizes.push(f.len());
+        }
+        assert!(sizes[1] > 2 * sizes[0], "{sizes:?}");
+    }
 
-                files.give(key, f);
+    #[test]
+    fn test_line_over_encoder_budget() {
+        let lines = vec![
+            line("a", &"x".repeat(2 * super::ENCODER_BUDGET)),
+            line("a", "after"),
+        ];
+        for strategy in [DeflateStrategy::Default, DeflateStrategy::HuffmanOnly] {
+            // Stored rather than compressed, so the output of the first line is bigger than the budget
+            let backend = run_output_thread_with(
+                &lines,
+                OutputCfg {
+                    compression: Arc::new(|_| Compression::none()),
+                    deflate_strategy: strategy,
+                    ..test_cfg(1)
+                },
+            );
+            assert!(contents(&backend, "a") == lines, "{strategy:?}");
+        }
+    }
+
+    #[test]
+    fn test_memory_budget_parks_idle_keys() {
+        // Room for 4 compressors, so the hot key and the last couple of cold keys keep theirs
+        let budget = Arc::new(MemoryBudget::new(4 * ENCODER_MEMORY as u64));
+        let cfg = OutputCfg {
+            memory_budget: Some(budget.clone()),
+            ..test_cfg(32)
+        };
+        let mut lines = vec![];
+        for round in 0..2 {
+            for k in 0..10 {
+                lines.push(line(&format!("cold{k}"), &format!("{round}")));
+                lines.push(line("hot", &format!("{round}-{k}")));
             }
         }
+        let backend = MemBackend::default();
+        run_output_thread_on(&backend, &lines, &cfg);
+
+        let first_member = |service: &str| {
+            let f = backend
+                .contents(&output_file("/out".as_ref(), service))
+                .unwrap();
+            read_lines(GzDecoder::new(&f[..]))
+        };
+        // The hot key was written to between every other key, so it was never parked and has a single member
+        let hot = lines.iter().filter(|l| l.contains("hot")).cloned();
+        assert_eq!(first_member("hot"), hot.collect::<Vec<_>>());
+        // The cold keys were parked while idle, so their second line starts a new member
+        for k in 0..10 {
+            let service = format!("cold{k}");
+            assert_eq!(first_member(&service), [line(&service, "0")]);
+            assert_eq!(
+                contents(&backend, &service),
+                [line(&service, "0"), line(&service, "1")]
+            );
+        }
+        assert_eq!(budget.used(), 0);
+    }
+
+    #[test]
+    fn test_reserialize() {
+        let text = r#"{ "z": 1.50e1, "@timestamp": "2024-10-20T12:00:00Z",  "@meta": {"service": "a", "env": "prod"}, "s": "\u00e9" }"#;
+        let ln = LineData::parse(text.to_string()).unwrap();
+
+        assert_eq!(ReserializeMode::Off.apply(&ln), format!("{text}\n"));
+        // Keys keep their order
+        let compact = r#"{"z":15.0,"@timestamp":"2024-10-20T12:00:00Z","@meta":{"service":"a","env":"prod"},"s":"é"}"#;
+        assert_eq!(ReserializeMode::Compact.apply(&ln), format!("{compact}\n"));
+        let pretty = ReserializeMode::Pretty.apply(&ln);
+        assert!(pretty.starts_with("{\n  \"z\": 15.0,\n"), "{pretty}");
+        assert!(pretty.ends_with("}\n") && !pretty.ends_with("\n\n"));
+        assert_eq!(json::parse(&pretty).unwrap(), json::parse(compact).unwrap());
+
+        for (s, mode) in [
+            ("off", ReserializeMode::Off),
+            ("compact", ReserializeMode::Compact),
+            ("pretty", ReserializeMode::Pretty),
+        ] {
+            assert_eq!(s.parse(), Ok(mode));
+        }
+        assert!("loose".parse::<ReserializeMode>().is_err());
+
+        let lines = [text.to_string(), line("a", "2")];
+        let backend = run_output_thread_with(
+            &lines,
+            OutputCfg {
+                reserialize: ReserializeMode::Compact,
+                ..test_cfg(4)
+            },
+        );
+        assert_eq!(
+            contents(&backend, "a"),
+            [compact.to_string(), line("a", "2")]
+        );
+    }
+
+    #[test]
+    fn test_output_thread_eviction() {
+        let services = ["a", "b", "c", "d", "e"];
+        let lines = (0..100)
+            .map(|i| line(services[i % services.len()], &i.to_string()))
+            .collect::<Vec<_>>();
+        let backend = run_output_thread(&lines, 2);
+
+        assert!(
+            backend.reopens() > 0,
+            "Files should have been evicted and reopened"
+        );
+        for (i, service) in services.iter().enumerate() {
+            let expected = lines.iter().skip(i).step_by(services.len()).cloned();
+            assert_eq!(contents(&backend, service), expected.collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn test_shuffled_dates_reopen() {
+        /// Generated lines over 5 services and 20 dates, so 100 keys
+        fn generated(date_order: DateOrder) -> Vec<String> {
+            let mut cfg = TestdataCfg {
+                lines: 2_000,
+                seed: Some(0),
+                date_order,
+                ..Default::default()
+            };
+            cfg.set_unique_dates(20)
+                .set_services(5, 3..6)
+                .set_envs(1, 4..5);
+            let mut dbg = vec![];
+            generate_testdata(cfg, &mut std::io::sink(), Some(&mut dbg)).unwrap();
+            String::from_utf8(dbg)
+                .unwrap()
+                .lines()
+                .map(str::to_string)
+                .collect()
+        }
+
+        // Every date's keys fit in the pool, so files are reopened at most once, to be finished
+        let backend = run_output_thread(&generated(DateOrder::Sequential), 8);
+        assert!(backend.reopens() <= 100, "{}", backend.reopens());
+
+        let lines = generated(DateOrder::Shuffled);
+        let backend = run_output_thread(&lines, 8);
+        assert!(backend.reopens() > 1_000, "{}", backend.reopens());
+        let mut expected = HashMap::<MsgKey, Vec<String>>::new();
+        for ln in &lines {
+            let key = LineData::parse(ln.clone()).unwrap().key().clone();
+            expected.entry(key).or_default().push(ln.clone());
+        }
+        assert_eq!(backend.paths().len(), 100);
+        for (key, lines) in expected {
+            let f = backend.contents(&key.path_to("/out".as_ref())).unwrap();
+            assert_eq!(read_lines(MultiGzDecoder::new(&f[..])), lines);
+        }
+    }
+
+    #[test]
+    fn test_per_key_compression() {
+        // The policy can capture its settings, such as levels by prefix from the command line
+        let levels = [("stored".to_string(), Compression::none())];
+        let policy = move |key: &MsgKey| {
+            levels
+                .iter()
+                .find(|(prefix, _)| key.name().starts_with(prefix.as_str()))
+                .map_or(Compression::best(), |&(_, level)| level)
+        };
+
+        let lines = (0..50)
+            .flat_map(|i| [line("stored", &i.to_string()), line("best", &i.to_string())])
+            .collect::<Vec<_>>();
+        let backend = MemBackend::default();
+        let (mut entries, _) = run_output_thread_on(
+            &backend,
+            &lines,
+            &OutputCfg {
+                compression: Arc::new(policy),
+                ..test_cfg(4)
+            },
+        );
+
+        let size = |service| {
+            backend
+                .contents(&output_file("/out".as_ref(), service))
+                .unwrap()
+                .len()
+        };
+        let raw_size = lines.iter().map(|l| l.len() + 1).sum::<usize>() / 2;
+        assert!(size("stored") > raw_size);
+        assert!(size("best") < raw_size / 4);
+
+        entries.sort_by(|a, b| a.key.cmp(&b.key));
+        let [best, stored] = &entries[..] else {
+            panic!("Expected two entries, got {entries:?}");
+        };
+        for e in [best, stored] {
+            let service = e.key.split('_').next().unwrap();
+            let key_size = lines
+                .iter()
+                .filter(|l| l.contains(service))
+                .map(|l| l.len() + 1);
+            assert_eq!(e.uncompressed_bytes, Some(key_size.sum::<usize>() as u64));
+        }
+        assert!(best.compression_ratio().unwrap() > 4.0);
+        assert!(stored.compression_ratio().unwrap() < 1.0);
+        assert_eq!(contents(&backend, "stored").len(), 50);
+        assert_eq!(contents(&backend, "best").len(), 50);
+    }
+
+    #[test]
+    fn test_storage_full() {
+        /// Every complete entry's file must hold exactly its lines
+        fn check_complete(backend: &MemBackend, entries: &[ManifestEntry], plain: bool) {
+            for e in entries.iter().filter(|e| e.complete) {
+                let f = backend
+                    .contents(&PathBuf::from("/out").join(&e.file))
+                    .unwrap();
+                assert_eq!(f.len() as u64, e.bytes);
+                let lines = match plain {
+                    true => read_lines(&f[..]),
+                    false => read_lines(MultiGzDecoder::new(&f[..])),
+                };
+                assert_eq!(lines.len() as u64, e.lines, "{}", e.file);
+            }
+        }
+
+        let services = ["a", "b", "c", "d", "e"];
+
+        // Plain keys are written in one go when finishing, so exactly the keys which fit are complete
+        let lines = (0..100)
+            .map(|i| line(services[i % services.len()], &format!("{i:03}")))
+            .collect::<Vec<_>>();
+        let key_size = lines.iter().take(20).map(|l| l.len() + 1).sum::<usize>();
+        let backend = MemBackend::with_capacity(key_size * 5 / 2);
+        let cfg = OutputCfg {
+            plain_below: Some(usize::MAX),
+            ..test_cfg(4)
+        };
+        let (entries, full) = run_output_thread_on(&backend, &lines, &cfg);
+        assert!(full);
+        assert_eq!(entries.len(), services.len());
+        assert_eq!(entries.iter().filter(|e| e.complete).count(), 2);
+        check_complete(&backend, &entries, true);
+
+        // Gzip keys fill the disk while lines are still coming in,
+        // after which the thread has to get through the rest of its lines without writing them
+        let (done_tx, done_rx) = std::sync::mpsc::channel();
+        let h = std::thread::spawn(move || {
+            let lines = (0..20_000)
+                .map(|i| line(services[i % services.len()], &format!("{i:x}").repeat(20)))
+                .collect::<Vec<_>>();
+            let backend = MemBackend::with_capacity(64 * 1024);
+            let cfg = OutputCfg {
+                compression: Arc::new(|_| Compression::none()),
+                ..test_cfg(2)
+            };
+            let (entries, full) = run_output_thread_on(&backend, &lines, &cfg);
+            assert!(full);
+            assert!(entries.iter().any(|e| !e.complete));
+            assert!(entries.iter().map(|e| e.lines).sum::<u64>() < lines.len() as u64);
+            check_complete(&backend, &entries, false);
+            done_tx.send(()).unwrap();
+        });
+        if done_rx.recv_timeout(Duration::from_secs(60)) == Err(RecvTimeoutError::Timeout) {
+            panic!("The output thread should finish promptly once the disk is full");
+        }
+        h.join().unwrap();
+    }
+
+    #[test]
+    fn test_append_adds_complete_member() {
+        let path = output_file("/out".as_ref(), "a");
+        let mut enc = GzEncoder::new(vec![], Compression::default());
+        enc.write_all(format!("{}\n", line("a", "1")).as_bytes())
+            .unwrap();
+        let previous = enc.finish().unwrap();
+
+        let backend = MemBackend::default();
+        backend.insert(&path, previous.clone());
+        let cfg = OutputCfg {
+            existing_files: ExistingFilePolicy::Append,
+            ..test_cfg(4)
+        };
+        run_output_thread_on(&backend, &[line("a", "2"), line("a", "3")], &cfg);
+
+        // The previous run's member is left untouched, so damage to either run's bytes stays within its own member
+        let f = backend.contents(&path).unwrap();
+        assert_eq!(f[..previous.len()], previous);
+        assert_eq!(
+            read_lines(GzDecoder::new(&f[previous.len()..])),
+            [line("a", "2"), line("a", "3")]
+        );
+        assert_eq!(
+            read_lines(MultiGzDecoder::new(&f[..])),
+            [line("a", "1"), line("a", "2"), line("a", "3")]
+        );
+    }
+
+    #[test]
+    fn test_truncate_removes_other_format() {
+        let plain_path = |service: &str| output_file("/out".as_ref(), service).with_extension("");
+        let backend = MemBackend::default();
+        // A previous run compressed "a" and left "b" plain, and this run does the opposite
+        backend.insert(&output_file("/out".as_ref(), "a"), b"stale".to_vec());
+        backend.insert(&plain_path("b"), b"stale".to_vec());
+        backend.insert(&plain_path("c"), b"unrelated".to_vec());
+
+        let mut lines = vec![line("a", "1")];
+        lines.extend((0..100).map(|i| line("b", &i.to_string())));
+        let cfg = OutputCfg {
+            plain_below: Some(1024),
+            ..test_cfg(4)
+        };
+        let (entries, _) = run_output_thread_on(&backend, &lines, &cfg);
+        assert!(entries.iter().all(|e| e.complete));
+
+        let mut paths = backend.paths();
+        paths.sort();
+        assert_eq!(
+            paths,
+            [
+                plain_path("a"),
+                output_file("/out".as_ref(), "b"),
+                plain_path("c")
+            ]
+        );
+        assert_eq!(
+            read_lines(&backend.contents(&plain_path("a")).unwrap()[..]),
+            [line("a", "1")]
+        );
+    }
+
+    #[test]
+    fn test_output_thread_panic() {
+        let tmp = tempdir::TempDir::new("logsplitter2").unwrap();
+        let mut files = OutputFiles::new(
+            2,
+            OutputCfg {
+                root_dir: tmp.path().to_path_buf(),
+                ..test_cfg(2)
+            },
+        );
+        // Swaps the second thread for one which panics as soon as it's sent a line
+        let (tx, rx) = kanal::bounded(1);
+        let h = std::thread::Builder::new()
+            .name("output-1".to_string())
+            .spawn(move || {
+                rx.recv().unwrap();
+                panic!("out of cheese");
+            })
+            .unwrap();
+        let old = std::mem::replace(&mut files.threads[1], ThreadInfo { h, tx });
+        old.tx.send(OutputThreadMsg::Finish).unwrap();
+        old.h.join().ok().unwrap();
+
+        for i in 0..10 {
+            files.write_line(LineData::parse(line(&i.to_string(), "")).unwrap());
+        }
+        // Whether or not a line already found the thread stopped, syncing does
+        files.sync_all();
+        assert!(files.panicked());
+        assert!(files.threads.is_empty());
+        let e = files.finish().unwrap_err();
+        let ErrorKind::OutputThreadPanicked { panics } = e.kind() else {
+            panic!("Unexpected error: {e}");
+        };
+        assert_eq!(
+            panics,
+            &[("output-1".to_string(), "out of cheese".to_string())]
+        );
+        assert!(e
+            .to_string()
+            .ends_with("thread 'output-1' panicked: out of cheese"));
+    }
+
+    #[test]
+    fn test_thread_timings() {
+        let tmp = tempdir::TempDir::new("logsplitter2").unwrap();
+        let mut files = OutputFiles::new(
+            2,
+            OutputCfg {
+                root_dir: tmp.path().to_path_buf(),
+                ..test_cfg(4)
+            },
+        );
+        // Both threads are idle for a while before their first line (minus however long they took to start)
+        std::thread::sleep(Duration::from_millis(50));
+        for i in 0..100 {
+            files.write_line(LineData::parse(line(["a", "b", "c"][i % 3], "1")).unwrap());
+        }
+        let (manifest, timings) = files.finish_with_timings().unwrap();
+        assert_eq!(manifest.files.len(), 3);
+        assert_eq!(timings.len(), 2);
+        for t in timings {
+            assert!(t.channel_wait >= Duration::from_millis(25), "{t:?}");
+            // Every thread finished at least one file
+            assert!(t.file_io > Duration::ZERO, "{t:?}");
+            assert_eq!(
+                t.compress + t.file_io + t.open_close + t.channel_wait,
+                t.total
+            );
+        }
+    }
+
+    #[test]
+    fn test_status() {
+        let tmp = tempdir::TempDir::new("logsplitter2").unwrap();
+        let mut files = OutputFiles::new(
+            2,
+            OutputCfg {
+                root_dir: tmp.path().to_path_buf(),
+                ..test_cfg(2)
+            },
+        );
+        assert_eq!(files.status(), OutputStatus::default());
+
+        // Each thread can only keep one file open, so `a` and `b` are closed to make room for `c` and `d`
+        for s in ["a", "b", "c", "d", "c"] {
+            files.write_line(LineData::parse(line(s, "1")).unwrap());
+        }
+        assert_eq!(
+            files.status(),
+            OutputStatus {
+                open_files: 2,
+                idle_files: 2,
+                inactive_files: 2,
+                distinct_keys: 4,
+                buffered_bytes: 0,
+            }
+        );
+        files.finish().unwrap();
+
+        // Lines of small keys are held in memory until they're finished
+        let tmp = tempdir::TempDir::new("logsplitter2").unwrap();
+        let mut files = OutputFiles::new(
+            2,
+            OutputCfg {
+                root_dir: tmp.path().to_path_buf(),
+                plain_below: Some(1 << 20),
+                ..test_cfg(2)
+            },
+        );
+        let lines = ["a", "b", "a"].map(|s| line(s, "1"));
+        for ln in &lines {
+            files.write_line(LineData::parse(ln.clone()).unwrap());
+        }
+        let status = files.status();
+        assert_eq!(status.open_files, 0);
+        assert_eq!(status.distinct_keys, 2);
+        assert_eq!(
+            status.buffered_bytes,
+            lines.iter().map(|l| l.len() + 1).sum::<usize>()
+        );
+        files.finish().unwrap();
+    }
+
+    #[test]
+    fn test_precreate() {
+        let tmp = tempdir::TempDir::new("logsplitter2").unwrap();
+        let mut files = OutputFiles::new(
+            2,
+            OutputCfg {
+                root_dir: tmp.path().to_path_buf(),
+                ..test_cfg(4)
+            },
+        );
+        let key = |s| LineData::parse(line(s, "1")).unwrap().key().clone();
+        // Each thread can only keep two files open, so `e` and `f` are only assigned
+        let keys = ["a", "b", "c", "d", "e", "f"].map(key);
+        files.precreate(&keys);
+        for s in ["a", "b", "c", "d"] {
+            assert!(output_file(tmp.path(), s).exists(), "{s}");
+        }
+        for s in ["e", "f"] {
+            assert!(!output_file(tmp.path(), s).exists(), "{s}");
+        }
+        let status = files.status();
+        assert_eq!((status.open_files, status.distinct_keys), (4, 4));
+        // Keys keep the threads they were assigned
+        assert_eq!(files.msgkey_assigned[&keys[4]], 0);
+        assert_eq!(files.msgkey_assigned[&keys[5]], 1);
+
+        let lines = ["a", "e"].map(|s| line(s, "1"));
+        for ln in &lines {
+            files.write_line(LineData::parse(ln.clone()).unwrap());
+        }
+        let manifest = files.finish().unwrap();
+        assert_eq!(manifest.files.len(), 5);
+        let on_disk = |service| {
+            let f = std::fs::read(output_file(tmp.path(), service)).unwrap();
+            read_lines(MultiGzDecoder::new(&f[..]))
+        };
+        assert_eq!(on_disk("a"), [lines[0].clone()]);
+        assert_eq!(on_disk("e"), [lines[1].clone()]);
+        // Keys which never got a line are valid empty files
+        for s in ["b", "c", "d"] {
+            assert!(on_disk(s).is_empty(), "{s}");
+        }
+        assert!(!output_file(tmp.path(), "f").exists());
+    }
+
+    #[test]
+    fn test_retries_counted() {
+        let backend = MemBackend::default();
+        let cfg = OutputCfg {
+            retry: RetryPolicy {
+                base_delay: Duration::from_millis(1),
+                ..Default::default()
+            },
+            metrics: MetricsHandle::default(),
+            ..test_cfg(4)
+        };
+        // Creating the file fails three times before it succeeds
+        backend.fail_next(std::io::ErrorKind::Interrupted, 3);
+        let (entries, full) = run_output_thread_on(&backend, &[line("a", "1")], &cfg);
+        assert!(!full && entries[0].complete);
+        assert_eq!(contents(&backend, "a"), [line("a", "1")]);
+        assert_eq!(cfg.metrics.retries(), 3);
+        assert_eq!(cfg.metrics.snapshot().retries, 3);
+    }
+
+    #[test]
+    fn test_bytes_written_per_run() {
+        let tmp = tempdir::TempDir::new("logsplitter2").unwrap();
+        let metrics = MetricsHandle::default();
+        let mut runs = ["a", "b"].map(|dir| {
+            std::fs::create_dir(tmp.path().join(dir)).unwrap();
+            OutputFiles::new(
+                1,
+                OutputCfg {
+                    root_dir: tmp.path().join(dir),
+                    metrics: metrics.clone(),
+                    ..test_cfg(1)
+                },
+            )
+        });
+
+        // The bytes of a run sharing the same metrics don't count towards the other's
+        runs[0].write_line(LineData::parse(line("a", "1")).unwrap());
+        runs[0].sync_all();
+        // Handled after the thread published what the sync wrote
+        runs[0].status();
+        let written = runs[0].bytes_written();
+        assert!(written > 0);
+        assert_eq!(runs[1].bytes_written(), 0);
+        assert_eq!(metrics.bytes_written(), written);
+    }
+
+    #[test]
+    fn test_sync_all() {
+        let tmp = tempdir::TempDir::new("logsplitter2").unwrap();
+        let mut files = OutputFiles::new(
+            2,
+            OutputCfg {
+                root_dir: tmp.path().to_path_buf(),
+                ..test_cfg(2)
+            },
+        );
+        let on_disk = |service| {
+            let f = std::fs::read(output_file(tmp.path(), service)).unwrap();
+            read_lines(MultiGzDecoder::new(&f[..]))
+        };
+
+        let lines = ["a", "b", "c", "a"].map(|s| line(s, "1"));
+        for ln in &lines {
+            files.write_line(LineData::parse(ln.clone()).unwrap());
+        }
+        // Every file is complete up to here, including `a` which was closed to make room for `c`
+        files.sync_all();
+        assert_eq!(on_disk("a"), [lines[0].clone(), lines[3].clone()]);
+        assert_eq!(on_disk("b"), [lines[1].clone()]);
+        assert_eq!(on_disk("c"), [lines[2].clone()]);
+        // Syncing again without new lines doesn't add anything
+        let size = std::fs::metadata(output_file(tmp.path(), "a"))
+            .unwrap()
+            .len();
+        files.sync_all();
+        assert_eq!(
+            std::fs::metadata(output_file(tmp.path(), "a"))
+                .unwrap()
+                .len(),
+            size
+        );
+
+        files.write_line(LineData::parse(line("a", "2")).unwrap());
+        let manifest = files.finish().unwrap();
+        assert_eq!(manifest.files.len(), 3);
+        assert_eq!(
+            on_disk("a"),
+            [lines[0].clone(), lines[3].clone(), line("a", "2")]
+        );
+    }
+
+    #[test]
+    fn test_with_assignments() {
+        let tmp = tempdir::TempDir::new("logsplitter2").unwrap();
+        let date = chrono::NaiveDate::from_ymd_opt(2024, 10, 20).unwrap();
+        let [a, b] = ["a", "b"].map(|s| MsgKey::new(&format!("{s}_prod_2024-10-20"), date));
+
+        let mut files = OutputFiles::new(
+            2,
+            OutputCfg {
+                root_dir: tmp.path().to_path_buf(),
+                ..test_cfg(2)
+            },
+        )
+        .with_assignments(&[vec![], vec![a.clone()]]);
+        files.write_line(LineData::parse(line("a", "")).unwrap());
+        files.write_line(LineData::parse(line("b", "")).unwrap());
+        // `a` doesn't take the first thread's turn of the round-robin
+        assert_eq!(files.msgkey_assigned[&a], 1);
+        assert_eq!(files.msgkey_assigned[&b], 0);
+        assert_eq!(files.finish().unwrap().files.len(), 2);
+    }
+
+    #[test]
+    fn test_thread_assignment() {
+        assert_eq!("hash-mod".parse(), Ok(ThreadAssignment::HashMod));
+        assert!("random".parse::<ThreadAssignment>().is_err());
+
+        let keys =
+            ["a", "b", "c", "d", "e"].map(|s| LineData::parse(line(s, "1")).unwrap().key().clone());
+        for assignment in [ThreadAssignment::RoundRobin, ThreadAssignment::HashMod] {
+            let tmp = tempdir::TempDir::new("logsplitter2").unwrap();
+            let mut files = OutputFiles::new(
+                3,
+                OutputCfg {
+                    root_dir: tmp.path().to_path_buf(),
+                    ..test_cfg(3)
+                },
+            )
+            .with_thread_assignment(assignment);
+            let first = keys.each_ref().map(|k| files.thread_of(k));
+            // Keys keep their thread, whichever keys come in between
+            for (k, &t) in keys.iter().zip(&first).rev() {
+                assert!(t < 3, "{assignment:?}");
+                assert_eq!(files.thread_of(k), t, "{assignment:?}");
+            }
+            match assignment {
+                ThreadAssignment::RoundRobin => {
+                    assert_eq!(first, [0, 1, 2, 0, 1]);
+                    assert_eq!(files.msgkey_assigned.len(), keys.len());
+                }
+                // Nothing is remembered, and another instance routes keys the same
+                ThreadAssignment::HashMod => {
+                    assert!(files.msgkey_assigned.is_empty());
+                    let tmp = tempdir::TempDir::new("logsplitter2").unwrap();
+                    let mut other = OutputFiles::new(
+                        3,
+                        OutputCfg {
+                            root_dir: tmp.path().to_path_buf(),
+                            ..test_cfg(3)
+                        },
+                    )
+                    .with_thread_assignment(assignment);
+                    assert_eq!(keys.each_ref().map(|k| other.thread_of(k)), first);
+                    other.finish().unwrap();
+                }
+            }
+            files.finish().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_stripes_balance_threads() {
+        let tmp = tempdir::TempDir::new("logsplitter2").unwrap();
+        let hot = LineData::parse(line("hot", "")).unwrap().key().clone();
+        let mut files = OutputFiles::new(
+            4,
+            OutputCfg {
+                root_dir: tmp.path().to_path_buf(),
+                ..test_cfg(4)
+            },
+        )
+        .with_stripes(KeyStripes {
+            keys: vec![hot.name().to_string()],
+            count: StripeCount::Fixed(4),
+        });
+
+        // Without striping, one thread would have all of the hot key's lines, and 4/5 of all lines
+        let mut loads = [0; 4];
+        for i in 0..5000 {
+            let service = if i % 5 == 0 { "cold" } else { "hot" };
+            let (ln, t) = files.route(LineData::parse(line(service, &i.to_string())).unwrap());
+            if service == "hot" {
+                loads[t] += 1;
+            }
+            files.write_line(ln);
+        }
+        assert!(math_utils::imbalance_ratio(&loads) < 1.15, "{loads:?}");
+
+        let manifest = files.finish().unwrap();
+        let mut stripes = manifest
+            .files
+            .iter()
+            .filter(|e| e.key.starts_with(hot.name()))
+            .map(|e| (e.file.clone(), e.lines))
+            .collect::<Vec<_>>();
+        stripes.sort();
+        assert_eq!(
+            stripes.iter().map(|(f, _)| f.clone()).collect::<Vec<_>>(),
+            (0..4)
+                .map(|i| format!("{}.{i}.json.gz", hot.name()))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(stripes.iter().map(|(_, n)| n).sum::<u64>(), 4000);
+        assert_eq!(manifest.files.len(), 5);
     }
 }
@@ -0,0 +1,310 @@
+//! Columnar output, enabled by the `parquet` cargo feature.
+//!
+//! Each key gets a `<name>.parquet` file with the columns in [`schema`].
+//! Lines are buffered until a batch of them is collected, and each batch is written as its own row group
+
+use std::{
+    io,
+    sync::{Arc, OnceLock},
+};
+
+use arrow_array::{
+    builder::{StringBuilder, TimestampMicrosecondBuilder},
+    ArrayRef, RecordBatch,
+};
+use arrow_schema::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use json::JsonValue;
+use parquet::{arrow::ArrowWriter, basic::Compression, file::properties::WriterProperties};
+
+use crate::data::parse_timestamp;
+
+/// The schema of every parquet output file
+///
+/// * `message`, `level` - the top-level json fields of the same name
+/// * `timestamp` - the line's `@timestamp`, in UTC
+/// * `service`, `env` - from `@meta`
+/// * `extra` - every other field of the line, as a json object (or null if there were none)
+pub fn schema() -> SchemaRef {
+    static SCHEMA: OnceLock<SchemaRef> = OnceLock::new();
+    SCHEMA
+        .get_or_init(|| {
+            let timestamp = DataType::Timestamp(TimeUnit::Microsecond, Some("UTC".into()));
+            Arc::new(Schema::new(vec![
+                Field::new("message", DataType::Utf8, true),
+                Field::new("timestamp", timestamp, true),
+                Field::new("level", DataType::Utf8, true),
+                Field::new("service", DataType::Utf8, true),
+                Field::new("env", DataType::Utf8, true),
+                Field::new("extra", DataType::Utf8, true),
+            ]))
+        })
+        .clone()
+}
+
+/// Strings are kept as-is, other non-null values are kept as their json text
+fn take_string(v: JsonValue) -> Option<String> {
+    match v {
+        JsonValue::Null => None,
+        v if v.is_string() => v.as_str().map(String::from),
+        v => Some(v.dump()),
+    }
+}
+
+/// Converts the lines of a single key into the bytes of its parquet file
+pub(crate) struct ParquetKeyWriter {
+    writer: ArrowWriter<Vec<u8>>,
+    batch_size: usize,
+    buffered: usize,
+    message: StringBuilder,
+    timestamp: TimestampMicrosecondBuilder,
+    level: StringBuilder,
+    service: StringBuilder,
+    env: StringBuilder,
+    extra: StringBuilder,
+}
+
+impl ParquetKeyWriter {
+    pub fn new(batch_size: usize) -> Self {
+        let props = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .set_max_row_group_size(batch_size)
+            .build();
+
+        Self {
+            writer: ArrowWriter::try_new(vec![], schema(), Some(props)).unwrap(),
+            batch_size,
+            buffered: 0,
+            message: StringBuilder::new(),
+            timestamp: TimestampMicrosecondBuilder::new().with_timezone("UTC"),
+            level: StringBuilder::new(),
+            service: StringBuilder::new(),
+            env: StringBuilder::new(),
+            extra: StringBuilder::new(),
+        }
+    }
+
+    /// Buffers `line`, returning any part of the file which became ready to be written.
+    ///
+    /// Fails with [`io::ErrorKind::InvalidData`] if `line` isn't valid json, in which case nothing is buffered
+    pub fn write_line(&mut self, line: &str) -> io::Result<Vec<u8>> {
+        let mut info =
+            json::parse(line).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        self.message
+            .append_option(take_string(info.remove("message")));
+        self.level.append_option(take_string(info.remove("level")));
+
+        let raw_timestamp = info.remove("@timestamp");
+        let timestamp = raw_timestamp.as_str().and_then(parse_timestamp);
+        self.timestamp
+            .append_option(timestamp.map(|t| t.timestamp_micros()));
+        if timestamp.is_none() && !raw_timestamp.is_null() {
+            // Keep timestamps which don't fit the column, instead of losing them
+            info["@timestamp"] = raw_timestamp;
+        }
+
+        let meta = &mut info["@meta"];
+        self.service
+            .append_option(take_string(meta.remove("service")));
+        self.env.append_option(take_string(meta.remove("env")));
+        if meta.is_empty() {
+            info.remove("@meta");
+        }
+
+        self.extra
+            .append_option((!info.is_empty()).then(|| info.dump()));
+
+        self.buffered += 1;
+        if self.buffered >= self.batch_size {
+            self.write_batch();
+        }
+        Ok(self.take_output())
+    }
+
+    /// Writes every buffered line as a new row group
+    fn write_batch(&mut self) {
+        if self.buffered == 0 {
+            return;
+        }
+
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(self.message.finish()),
+            Arc::new(self.timestamp.finish()),
+            Arc::new(self.level.finish()),
+            Arc::new(self.service.finish()),
+            Arc::new(self.env.finish()),
+            Arc::new(self.extra.finish()),
+        ];
+        let batch = RecordBatch::try_new(schema(), columns).unwrap();
+        self.writer.write(&batch).unwrap();
+        self.writer.flush().unwrap();
+        self.buffered = 0;
+    }
+
+    /// Roughly how many bytes of lines are held in memory, either in the current batch
+    /// or encoded but not yet part of a finished row group
+    pub fn buffered_bytes(&self) -> usize {
+        let strings = [
+            &self.message,
+            &self.level,
+            &self.service,
+            &self.env,
+            &self.extra,
+        ];
+        strings
+            .iter()
+            .map(|b| b.values_slice().len())
+            .sum::<usize>()
+            + std::mem::size_of_val(self.timestamp.values_slice())
+            + self.writer.in_progress_size()
+            + self.writer.inner().len()
+    }
+
+    fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(self.writer.inner_mut())
+    }
+
+    /// Writes the remaining lines and the file footer, returning the rest of the file
+    pub fn finish(&mut self) -> Vec<u8> {
+        self.write_batch();
+        self.writer.finish().unwrap();
+        self.take_output()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use arrow_array::{cast::AsArray, types::TimestampMicrosecondType, Array};
+    use flate2::Compression;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use tempdir::TempDir;
+
+    use super::ParquetKeyWriter;
+    use crate::{
+        output::OutputFormat,
+        run,
+        test_utils::{line, write_input},
+        ErrorKind, OutputTarget, RunCfg, Threads,
+    };
+
+    #[test]
+    fn test_parquet_output() {
+        let tmp = TempDir::new("logsplitter2").unwrap();
+        let out = tmp.path().join("out");
+        let input = tmp.path().join("input.json.gz");
+
+        let mut lines = (0..10)
+            .map(|i| line("a", &format!("a{i}")))
+            .collect::<Vec<_>>();
+        lines.push(line("b", "b0"));
+        lines.push(
+            r#"{"message":"a10","@timestamp":"2024-10-20T12:00:00Z","level":"warn","code":500,"@meta":{"service":"a","env":"prod","user":"alice"}}"#
+                .to_string(),
+        );
+        write_input(&input, &lines);
+
+        run(RunCfg {
+            input_files: vec![input],
+            output: OutputTarget::Dir(out.clone()),
+            output_threads: Threads::Fixed(1),
+            format: OutputFormat::Parquet { batch_size: 4 },
+            ..Default::default()
+        })
+        .unwrap();
+
+        let f = std::fs::File::open(out.join("a_prod_2024-10-20.parquet")).unwrap();
+        let builder = ParquetRecordBatchReaderBuilder::try_new(f).unwrap();
+        assert_eq!(builder.metadata().num_row_groups(), 3);
+        let batches = builder
+            .build()
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(batches.iter().map(|b| b.num_rows()).sum::<usize>(), 11);
+
+        let messages = batches
+            .iter()
+            .flat_map(|b| {
+                b.column_by_name("message")
+                    .unwrap()
+                    .as_string::<i32>()
+                    .iter()
+                    .map(|m| m.unwrap().to_string())
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        let expected = (0..=10).map(|i| format!("a{i}")).collect::<Vec<_>>();
+        assert_eq!(messages, expected);
+
+        let last = batches.last().unwrap();
+        let row = last.num_rows() - 1;
+        let column = |name| {
+            last.column_by_name(name)
+                .unwrap()
+                .as_string::<i32>()
+                .value(row)
+                .to_string()
+        };
+        assert_eq!(column("level"), "warn");
+        assert_eq!(column("service"), "a");
+        assert_eq!(column("env"), "prod");
+        assert_eq!(
+            json::parse(&column("extra")).unwrap(),
+            json::object! { code: 500, "@meta": { user: "alice" } }
+        );
+        let timestamps = last
+            .column_by_name("timestamp")
+            .unwrap()
+            .as_primitive::<TimestampMicrosecondType>();
+        // 2024-10-20T12:00:00Z
+        assert_eq!(timestamps.value(row), 1729425600 * 1_000_000);
+        assert!(last.column_by_name("extra").unwrap().is_null(0));
+
+        let f = std::fs::File::open(out.join("b_prod_2024-10-20.parquet")).unwrap();
+        let builder = ParquetRecordBatchReaderBuilder::try_new(f).unwrap();
+        assert_eq!(builder.metadata().file_metadata().num_rows(), 1);
+    }
+
+    #[test]
+    fn test_parquet_stream() {
+        let tmp = TempDir::new("logsplitter2").unwrap();
+        let input = tmp.path().join("input.json.gz");
+        write_input(&input, &[line("a", "a0")]);
+        let out = tmp.path().join("out.json.gz");
+
+        // Only whole directories of keys can be parquet
+        for output in [
+            OutputTarget::Stdout { compression: None },
+            OutputTarget::File {
+                path: out.clone(),
+                compression: Compression::fast(),
+            },
+        ] {
+            let err = run(RunCfg {
+                input_files: vec![input.clone()],
+                output,
+                format: OutputFormat::Parquet { batch_size: 4 },
+                ..Default::default()
+            })
+            .unwrap_err();
+            assert!(matches!(err.kind(), ErrorKind::InvalidConfig(_)), "{err}");
+        }
+        assert!(!out.exists());
+    }
+
+    #[test]
+    fn test_parquet_invalid_line() {
+        let mut w = ParquetKeyWriter::new(4);
+        let err = w.write_line("{\"message\":").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(w.write_line(&line("a", "a0")).unwrap().is_empty());
+
+        // The invalid line left nothing behind
+        let tmp = TempDir::new("logsplitter2").unwrap();
+        let path = tmp.path().join("a.parquet");
+        std::fs::write(&path, w.finish()).unwrap();
+        let f = std::fs::File::open(path).unwrap();
+        let builder = ParquetRecordBatchReaderBuilder::try_new(f).unwrap();
+        assert_eq!(builder.metadata().file_metadata().num_rows(), 1);
+    }
+}
@@ -0,0 +1,1033 @@
+//! Splitting any number of inputs into one output, which [`run`](crate::run) is a thin wrapper around.
+//!
+//! A [`Splitter`] owns the output (and, for [`OutputTarget::Dir`], its output threads, open files, and lock),
+//! so several inputs (or a stream of lines which isn't a file at all) can be split into the same files
+//! by calling [`process`](Splitter::process) or [`process_files`](Splitter::process_files) once per input.
+//! [`process_stream`](Splitter::process_stream) and [`process_files_async`](Splitter::process_files_async)
+//! do the same from within an async runtime, which is what [`run_async`](crate::run_async) uses
+
+use std::{
+    io::{stdout, Write},
+    ops::ControlFlow,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use futures::{Stream, StreamExt};
+
+use crate::{
+    balance_keys, check_file_sizes, check_inputs, check_output_dir, check_output_file,
+    data::{InputName, KeyFn, LineData, TransformFn},
+    file_pool::ExistingFilePolicy,
+    filter::LineFilter,
+    input::{GzipErrorPolicy, InputTimings, JsonLinesRecv, SkippedGzip, TrailingLinePolicy},
+    invalid_lines::InvalidLines,
+    lock::DirLock,
+    lock_output_dir,
+    manifest::{Manifest, MANIFEST_FILE_NAME},
+    math_utils::fmt_share,
+    memory::{self, MemoryBudget, MemoryStats},
+    metrics::MetricsHandle,
+    output::{LineSender, OutputCfg, OutputFiles, OutputFormat, OutputStream, ThreadTimings},
+    warn_on_low_space, Error, ErrorKind, OutputTarget, ReadError, RunCfg,
+};
+
+#[cfg(unix)]
+use crate::file_pool::UnixMode;
+
+enum SplitterOutput {
+    Dir {
+        /// Boxed since it's much bigger than a stream
+        files: Box<OutputFiles>,
+        dir: PathBuf,
+        existing_files: ExistingFilePolicy,
+        /// Applied to the manifest, like the output files
+        #[cfg(unix)]
+        file_mode: Option<UnixMode>,
+        /// Held until the splitter is finished (or dropped)
+        _lock: DirLock,
+    },
+    /// [`OutputTarget::Stdout`] or [`OutputTarget::File`]
+    Stream(OutputStream<Box<dyn Write + Send>>),
+}
+
+/// What a [`Splitter`] did, once it's [finished](Splitter::finish)
+#[derive(Debug, Clone)]
+pub struct RunStats {
+    /// How many lines were written, after filtering and [transforming](RunCfg::transform)
+    pub lines_written: usize,
+    /// How many of [`lines_written`](RunStats::lines_written) came from each input, in the order they were read
+    pub input_lines: Vec<(InputName, usize)>,
+    /// The manifest of the output directory, or `None` for [`OutputTarget::Stdout`] and [`OutputTarget::File`]
+    pub manifest: Option<Manifest>,
+    /// Where each output thread spent its time and how many writes it retried, or nothing for [`OutputTarget::Stdout`] and [`OutputTarget::File`]
+    pub thread_timings: Vec<ThreadTimings>,
+    /// The corrupt gzip data which was skipped, see [`GzipErrorPolicy::SkipToNextMember`]
+    pub skipped_gzip: SkippedGzip,
+    /// Where the reader threads of every input, and the main thread parsing their lines, spent their time
+    pub input_timings: InputTimings,
+    /// How long the main thread waited for output threads which had fallen behind, see [`OutputFiles::send_wait`]
+    pub send_wait: Duration,
+    /// The time spent in [`process`](Splitter::process), which the input and main thread timings are a share of
+    pub split_time: Duration,
+    /// How much of [`max_memory`](RunCfg::max_memory) was used, or `None` without one
+    pub memory: Option<MemoryStats>,
+    pub elapsed: Duration,
+}
+
+/// Splits lines into the output of a [`RunCfg`], see the [module docs](self)
+pub struct Splitter {
+    output: SplitterOutput,
+    filter: LineFilter,
+    key_fn: KeyFn,
+    trailing_line: TrailingLinePolicy,
+    gzip_errors: GzipErrorPolicy,
+    input_mmap: bool,
+    tail: Option<Arc<AtomicBool>>,
+    /// Added up over every call to [`process_files`](Splitter::process_files)
+    skipped_gzip: SkippedGzip,
+    /// Added up over every call to [`process_files`](Splitter::process_files)
+    input_timings: InputTimings,
+    /// Added up over every call to [`process`](Splitter::process)
+    split_time: Duration,
+    transform: Option<TransformFn>,
+    invalid_lines: InvalidLines,
+    max_lines: usize,
+    stop: Option<Arc<AtomicBool>>,
+    max_output_bytes: Option<u64>,
+    written: usize,
+    /// Added up over every call to [`process`](Splitter::process), see [`RunStats::input_lines`]
+    input_lines: Vec<(InputName, usize)>,
+    memory_budget: Option<Arc<MemoryBudget>>,
+    metrics: MetricsHandle,
+    strict: bool,
+    /// Set once no more lines are taken, such as after [`max_lines`](RunCfg::max_lines)
+    stopped: bool,
+    /// Set if the run was aborted, which [`finish`](Splitter::finish) returns after finishing whatever was written
+    aborted: Result<(), Error>,
+    start: Instant,
+}
+
+impl Splitter {
+    /// Creates the output of `cfg`, without reading any input yet.
+    ///
+    /// `cfg.input_files` are checked (including that they aren't inside of the output directory),
+    /// and size the run, such as for [`Threads::Auto`](crate::Threads::Auto) and [`RunCfg::balance_threads`].
+    /// They are only split once they're given to [`process_files`](Splitter::process_files)
+    ///
+    /// Fails if another run is writing into the same output directory,
+    /// or if [`max_memory`](RunCfg::max_memory) is below the [minimum](memory::min_limit) for its output threads
+    pub fn new(cfg: RunCfg) -> Result<Self, Error> {
+        let start = Instant::now();
+        let input_size = check_inputs(&cfg.input_files)?;
+        let output_threads = cfg.output_threads.resolve(input_size);
+        let min_memory = memory::min_limit(output_threads);
+        let memory_budget = match &cfg.output {
+            OutputTarget::Dir(_) => match cfg.max_memory {
+                Some(limit) if limit < min_memory => {
+                    return Err(Error {
+                        kind: Box::new(ErrorKind::InvalidConfig(format!(
+                            "`max-memory` must be at least {min_memory} bytes for {output_threads} output threads, \
+                            so that each thread has room for a few compressors"
+                        ))),
+                    })
+                }
+                limit => limit.map(|limit| Arc::new(MemoryBudget::new(limit))),
+            },
+            OutputTarget::Stdout { .. } | OutputTarget::File { .. } => None,
+        };
+
+        let output = match cfg.output {
+            OutputTarget::Dir(dir) => {
+                let lock = lock_output_dir(
+                    &dir,
+                    cfg.force_lock,
+                    #[cfg(unix)]
+                    cfg.dir_mode,
+                )?;
+                check_output_dir(&cfg.input_files, &dir, cfg.existing_files)?;
+                warn_on_low_space(input_size, &dir);
+
+                let assignments = match cfg.balance_threads {
+                    true => {
+                        let lines = JsonLinesRecv::spawn_files_with(
+                            cfg.input_files.clone(),
+                            cfg.trailing_line,
+                            cfg.gzip_error_policy,
+                            cfg.input_mmap,
+                        )
+                        .with_filter(cfg.filter.clone())
+                        .with_key_fn(cfg.key_fn.clone());
+                        balance_keys(lines, output_threads)
+                    }
+                    false => vec![],
+                };
+                let existing_files = cfg.existing_files.unwrap_or_default();
+                let files = OutputFiles::new(
+                    output_threads,
+                    OutputCfg {
+                        root_dir: dir.clone(),
+                        max_active_files: cfg.max_active_files.max(output_threads),
+                        existing_files,
+                        gzip_mtime: cfg.gzip_mtime,
+                        compression: cfg.compression,
+                        deflate_strategy: cfg.deflate_strategy,
+                        format: cfg.format,
+                        index_interval: cfg.index_interval,
+                        plain_below: cfg.plain_below,
+                        retry: cfg.retry,
+                        sync_every: cfg.sync_every,
+                        reserialize: cfg.reserialize,
+                        redact_fields: cfg.redact_fields,
+                        #[cfg(unix)]
+                        file_mode: cfg.file_mode,
+                        hash_seed: cfg.hash_seed,
+                        on_file_complete: cfg.on_file_complete,
+                        memory_budget: memory_budget.clone(),
+                        metrics: cfg.metrics.clone(),
+                    },
+                )
+                .with_assignments(&assignments)
+                .with_thread_assignment(cfg.thread_assignment);
+                let files = match cfg.stripes {
+                    Some(stripes) => files.with_stripes(stripes),
+                    None => files,
+                };
+
+                SplitterOutput::Dir {
+                    files: Box::new(files),
+                    dir,
+                    existing_files,
+                    #[cfg(unix)]
+                    file_mode: cfg.file_mode,
+                    _lock: lock,
+                }
+            }
+            OutputTarget::Stdout { compression } => {
+                if cfg.format != OutputFormat::Gzip {
+                    return Err(Error {
+                        kind: Box::new(ErrorKind::InvalidConfig(
+                            "Only json lines can be streamed to stdout".to_string(),
+                        )),
+                    });
+                }
+                SplitterOutput::Stream(
+                    OutputStream::new(Box::new(stdout()) as Box<dyn Write + Send>, compression)
+                        .with_reserialize(cfg.reserialize)
+                        .with_redact_fields(cfg.redact_fields),
+                )
+            }
+            OutputTarget::File { path, compression } => {
+                if cfg.format != OutputFormat::Gzip {
+                    return Err(Error {
+                        kind: Box::new(ErrorKind::InvalidConfig(
+                            "Only json lines can be written to a single file".to_string(),
+                        )),
+                    });
+                }
+                check_output_file(&cfg.input_files, &path, cfg.existing_files)?;
+                let append = cfg.existing_files == Some(ExistingFilePolicy::Append);
+                let existed = path.exists();
+                // Appending adds this run's lines as a new gzip member, like appending to a key's file does
+                let file = std::fs::OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .append(append)
+                    .truncate(!append)
+                    .open(&path)
+                    .map_err(|e| Error::io(&path, e))?;
+                #[cfg(unix)]
+                if let Some(mode) = cfg.file_mode.filter(|_| !(append && existed)) {
+                    mode.apply(&path).map_err(|e| Error::io(&path, e))?;
+                }
+                SplitterOutput::Stream(
+                    OutputStream::new(Box::new(file) as Box<dyn Write + Send>, Some(compression))
+                        .with_reserialize(cfg.reserialize)
+                        .with_redact_fields(cfg.redact_fields),
+                )
+            }
+        };
+
+        Ok(Self {
+            output,
+            filter: cfg.filter,
+            key_fn: cfg.key_fn,
+            trailing_line: cfg.trailing_line,
+            gzip_errors: cfg.gzip_error_policy,
+            input_mmap: cfg.input_mmap,
+            tail: cfg.tail,
+            skipped_gzip: Default::default(),
+            input_timings: Default::default(),
+            split_time: Duration::ZERO,
+            transform: cfg.transform,
+            invalid_lines: InvalidLines::new(cfg.max_invalid_lines),
+            max_lines: cfg.max_lines.unwrap_or(usize::MAX),
+            stop: cfg.stop,
+            max_output_bytes: cfg.max_output_bytes,
+            written: 0,
+            input_lines: Vec::new(),
+            memory_budget,
+            metrics: cfg.metrics,
+            strict: cfg.strict,
+            stopped: false,
+            aborted: Ok(()),
+            start,
+        })
+    }
+
+    /// Whether this splitter takes no more lines, because it was aborted, hit [`max_lines`](RunCfg::max_lines)
+    /// or [`max_output_bytes`](RunCfg::max_output_bytes), or filled up the disk. [`process`](Splitter::process) skips every line from then on
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
+    /// The metrics of the run, which every stage adds to as it goes. The same as [`RunCfg::metrics`]
+    pub fn metrics(&self) -> MetricsHandle {
+        self.metrics.clone()
+    }
+
+    /// Splits the json lines of the files at `paths`, read one after another with the filter and key function of the run
+    pub fn process_files(&mut self, paths: Vec<PathBuf>) -> Result<(), Error> {
+        let lines = self.spawn_files(paths)?;
+        let skipped = lines.skipped_gzip();
+        let timers = lines.timers();
+        let res = self.process(lines);
+        self.skipped_gzip += *skipped.lock().unwrap();
+        self.input_timings += timers.get();
+        res
+    }
+
+    /// Like [`process_files`](Splitter::process_files), but awaits lines and room in the output threads' channels
+    /// instead of blocking, see [`process_stream`](Splitter::process_stream)
+    pub async fn process_files_async(&mut self, paths: Vec<PathBuf>) -> Result<(), Error> {
+        let lines = self.spawn_files(paths)?;
+        let skipped = lines.skipped_gzip();
+        let timers = lines.timers();
+        let res = self.process_stream(lines.into_stream()).await;
+        self.skipped_gzip += *skipped.lock().unwrap();
+        self.input_timings += timers.get();
+        res
+    }
+
+    /// Like [`process_files`](Splitter::process_files), but up to `parallel` of the files are read and parsed at once,
+    /// each on a thread of its own which routes its lines straight to the output threads, see [`RunCfg::parallel_inputs`].
+    ///
+    /// The lines of each file are written in order, but the lines a key has in different files are interleaved
+    /// however the threads happen to go. Checking, counting and [transforming](RunCfg::transform) lines still takes turns.
+    /// Files are read one after another like [`process_files`](Splitter::process_files) with [`OutputTarget::Stdout`]
+    /// and [`OutputTarget::File`], if any keys are [striped](RunCfg::stripes), or if the last file is [tailed](RunCfg::tail)
+    pub fn process_files_parallel(
+        &mut self,
+        paths: Vec<PathBuf>,
+        parallel: usize,
+    ) -> Result<(), Error> {
+        let parallel = parallel.min(paths.len());
+        let senders = match &self.output {
+            SplitterOutput::Dir { files, .. }
+                if parallel > 1 && !files.is_striped() && self.tail.is_none() =>
+            {
+                files.senders(parallel)
+            }
+            _ => return self.process_files(paths),
+        };
+        self.check_files(&paths)?;
+        if self.stopped {
+            return Ok(());
+        }
+
+        let start = Instant::now();
+        let (trailing_line, gzip_errors, mmap) =
+            (self.trailing_line, self.gzip_errors, self.input_mmap);
+        let (filter, key_fn, metrics) = (
+            self.filter.clone(),
+            self.key_fn.clone(),
+            self.metrics.clone(),
+        );
+        let read = |path: &PathBuf| {
+            JsonLinesRecv::spawn_files_counted(
+                vec![path.clone()],
+                trailing_line,
+                gzip_errors,
+                mmap,
+                metrics.clone(),
+            )
+            .with_filter(filter.clone())
+            .with_key_fn(key_fn.clone())
+        };
+        let next = AtomicUsize::new(0);
+        let splitter = Mutex::new(&mut *self);
+        let senders = std::thread::scope(|s| {
+            let workers = senders
+                .into_iter()
+                .enumerate()
+                .map(|(i, sender)| {
+                    let (splitter, paths, next, read) = (&splitter, &paths, &next, &read);
+                    std::thread::Builder::new()
+                        .name(format!("input-{i}"))
+                        .spawn_scoped(s, move || {
+                            split_files_of(splitter, sender, || {
+                                paths.get(next.fetch_add(1, Ordering::Relaxed)).map(read)
+                            })
+                        })
+                        .expect("Could not spawn an input thread")
+                })
+                .collect::<Vec<_>>();
+            workers
+                .into_iter()
+                .map(|w| w.join().expect("An input thread panicked"))
+                .collect::<Vec<_>>()
+        });
+
+        let SplitterOutput::Dir { files, .. } = &mut self.output else {
+            unreachable!()
+        };
+        let mut stopped_thread = None;
+        for (sender, stopped) in senders {
+            files.merge_sender(sender);
+            stopped_thread = stopped_thread.or(stopped);
+        }
+        if stopped_thread.is_some() {
+            files.thread_stopped();
+        }
+        self.split_time += start.elapsed();
+        Ok(())
+    }
+
+    /// Checks `paths` against the output, and starts reading them
+    fn spawn_files(&self, paths: Vec<PathBuf>) -> Result<JsonLinesRecv, Error> {
+        self.check_files(&paths)?;
+        let lines = match &self.tail {
+            Some(tail_ended) => JsonLinesRecv::spawn_tail_counted(
+                paths,
+                self.trailing_line,
+                self.gzip_errors,
+                tail_ended.clone(),
+                self.metrics.clone(),
+            ),
+            None => JsonLinesRecv::spawn_files_counted(
+                paths,
+                self.trailing_line,
+                self.gzip_errors,
+                self.input_mmap,
+                self.metrics.clone(),
+            ),
+        };
+        Ok(lines
+            .with_filter(self.filter.clone())
+            .with_key_fn(self.key_fn.clone()))
+    }
+
+    /// Fails if any of `paths` isn't a file, or is inside of the output directory
+    fn check_files(&self, paths: &[PathBuf]) -> Result<(), Error> {
+        check_inputs(paths)?;
+        if let SplitterOutput::Dir {
+            dir,
+            existing_files,
+            ..
+        } = &self.output
+        {
+            check_output_dir(paths, dir, Some(*existing_files))?;
+        }
+        Ok(())
+    }
+
+    /// Splits already parsed `lines`, where errors count as invalid lines.
+    ///
+    /// If the run is aborted (such as because of too many invalid lines), this stops early,
+    /// and [`finish`](Splitter::finish) returns the error once it has finished the output.
+    /// Only fails right away if writing to stdout (or the output file) fails
+    pub fn process(
+        &mut self,
+        lines: impl IntoIterator<Item = Result<LineData, ReadError>>,
+    ) -> Result<(), Error> {
+        if self.stopped {
+            return Ok(());
+        }
+        let start = Instant::now();
+        // Breaking out of the loop drops `lines`, which stops the reader thread of a `JsonLinesRecv`
+        for line in lines {
+            let line = match self.next_line(line) {
+                ControlFlow::Continue(Some(line)) => line,
+                ControlFlow::Continue(None) => continue,
+                ControlFlow::Break(()) => break,
+            };
+            count_input_line(&mut self.input_lines, line.source());
+            match &mut self.output {
+                SplitterOutput::Dir { files, .. } => files.write_line(line),
+                SplitterOutput::Stream(stream) => stream.write_line(line)?,
+            }
+            self.written += 1;
+            self.metrics.line_written();
+        }
+        self.split_time += start.elapsed();
+        Ok(())
+    }
+
+    /// Like [`process`](Splitter::process), but awaits lines and room in the output threads' channels instead of blocking.
+    /// Writing to stdout (or the output file) still blocks.
+    ///
+    /// Dropping the returned future stops early like [`max_lines`](RunCfg::max_lines) would,
+    /// except that the line which was being written may be lost
+    pub async fn process_stream(
+        &mut self,
+        lines: impl Stream<Item = Result<LineData, ReadError>>,
+    ) -> Result<(), Error> {
+        if self.stopped {
+            return Ok(());
+        }
+        let start = Instant::now();
+        let mut lines = std::pin::pin!(lines);
+        while let Some(line) = lines.next().await {
+            let line = match self.next_line(line) {
+                ControlFlow::Continue(Some(line)) => line,
+                ControlFlow::Continue(None) => continue,
+                ControlFlow::Break(()) => break,
+            };
+            count_input_line(&mut self.input_lines, line.source());
+            match &mut self.output {
+                SplitterOutput::Dir { files, .. } => files.write_line_async(line).await,
+                SplitterOutput::Stream(stream) => stream.write_line(line)?,
+            }
+            self.written += 1;
+            self.metrics.line_written();
+        }
+        self.split_time += start.elapsed();
+        Ok(())
+    }
+
+    /// Checks and transforms the next line of the input, returning the line to write if there is one.
+    /// Breaks once no more lines are taken, after setting [`stopped`](Splitter::is_stopped)
+    fn next_line(
+        &mut self,
+        line: Result<LineData, ReadError>,
+    ) -> ControlFlow<(), Option<LineData>> {
+        if let SplitterOutput::Dir { files, .. } = &self.output {
+            // The panic is returned by `finish`
+            if files.panicked() {
+                self.stopped = true;
+                return ControlFlow::Break(());
+            }
+            if files.storage_full() {
+                eprintln!("The disk is full, so the rest of the input is skipped");
+                self.stopped = true;
+                return ControlFlow::Break(());
+            }
+            // Every output thread adds to the same counter as it writes, so this is only a load
+            if let Some(max) = self
+                .max_output_bytes
+                .filter(|&max| files.bytes_written() >= max)
+            {
+                eprintln!("Stopping after writing {max} bytes of output");
+                self.stopped = true;
+                return ControlFlow::Break(());
+            }
+        }
+        if self.written == self.max_lines {
+            eprintln!("Stopping after {} lines", self.max_lines);
+            self.stopped = true;
+            return ControlFlow::Break(());
+        }
+        if self
+            .stop
+            .as_ref()
+            .is_some_and(|s| s.load(Ordering::Relaxed))
+        {
+            eprintln!("Stopping early after {} lines", self.written);
+            self.stopped = true;
+            return ControlFlow::Break(());
+        }
+        match &line {
+            Ok(_) => self.metrics.line_parsed(),
+            Err(ReadError::InvalidLine { .. } | ReadError::NoKey { .. }) => {
+                self.metrics.line_rejected()
+            }
+            Err(_) => {}
+        }
+        let transform = &mut self.transform;
+        match self.invalid_lines.check(line).map(|line| {
+            line.and_then(|line| match transform {
+                Some(t) => t(line),
+                None => Some(line),
+            })
+        }) {
+            Ok(line) => ControlFlow::Continue(line),
+            Err(e) => {
+                if let ErrorKind::ReadErr(ReadError::CorruptInput { .. }) = e.kind() {
+                    eprintln!(
+                        "{} lines from before the corrupt input were kept",
+                        self.written
+                    );
+                }
+                self.aborted = Err(e);
+                self.stopped = true;
+                ControlFlow::Break(())
+            }
+        }
+    }
+
+    /// Finishes every output file (and writes the manifest), or flushes stdout (or the output file).
+    ///
+    /// Fails if the run was aborted, an output thread panicked, the disk filled up, or (with [`RunCfg::strict`]) the output is split into tiny files.
+    /// Everything which was written is still finished first
+    pub fn finish(self) -> Result<RunStats, Error> {
+        let Self {
+            output,
+            invalid_lines,
+            written,
+            input_lines,
+            strict,
+            mut aborted,
+            start,
+            skipped_gzip,
+            input_timings,
+            split_time,
+            memory_budget,
+            ..
+        } = self;
+
+        if skipped_gzip.members > 0 {
+            eprintln!(
+                "Warning: skipped {} corrupt gzip members ({} bytes) of the input",
+                skipped_gzip.members, skipped_gzip.bytes
+            );
+        }
+
+        let storage_full =
+            matches!(&output, SplitterOutput::Dir { files, .. } if files.storage_full());
+        if aborted.is_ok() && !storage_full {
+            aborted = invalid_lines.finish();
+        }
+
+        let send_wait = match &output {
+            SplitterOutput::Dir { files, .. } => files.send_wait(),
+            SplitterOutput::Stream(_) => Duration::ZERO,
+        };
+        print_timings(&input_timings, send_wait, split_time);
+
+        let mut thread_timings = vec![];
+        let manifest = match output {
+            SplitterOutput::Dir {
+                files,
+                dir,
+                existing_files,
+                #[cfg(unix)]
+                file_mode,
+                _lock,
+            } => {
+                eprintln!("ELAPSED: {:?}", start.elapsed());
+                let write_manifest = |manifest: &Manifest| {
+                    manifest.write(&dir)?;
+                    #[cfg(unix)]
+                    if let Some(mode) = file_mode {
+                        mode.apply(&dir.join(MANIFEST_FILE_NAME))?;
+                    }
+                    std::io::Result::Ok(())
+                };
+
+                let (mut manifest, timings) = files.finish_with_timings()?;
+                thread_timings = timings;
+                if existing_files == ExistingFilePolicy::Append {
+                    if let Ok(previous) = Manifest::read(&dir) {
+                        manifest.merge_previous(previous);
+                    }
+                }
+
+                if manifest.partial {
+                    // The manifest is small, so there may still be room for it
+                    if let Err(e) = write_manifest(&manifest) {
+                        eprintln!("Could not write the manifest: {e}");
+                    }
+                    let (complete, suspect): (Vec<_>, Vec<_>) =
+                        manifest.files.into_iter().partition(|e| e.complete);
+                    return Err(Error {
+                        kind: Box::new(ErrorKind::StorageFull {
+                            complete: complete.len(),
+                            suspect: suspect.into_iter().map(|e| e.file).collect(),
+                        }),
+                    });
+                }
+                write_manifest(&manifest)
+                    .map_err(|e| Error::io(dir.join(MANIFEST_FILE_NAME), e))?;
+                aborted?;
+
+                if let Some(kind) = check_file_sizes(&manifest) {
+                    if strict {
+                        return Err(Error {
+                            kind: Box::new(kind),
+                        });
+                    }
+                    eprintln!("Warning: {kind}");
+                }
+                Some(manifest)
+            }
+            SplitterOutput::Stream(stream) => {
+                // Also flushes stdout, or the file
+                stream.finish()?;
+                aborted?;
+                None
+            }
+        };
+
+        if input_lines.len() > 1 {
+            for (input, lines) in &input_lines {
+                eprintln!("{lines} lines written from {input}");
+            }
+        }
+        let memory = memory_budget.map(|budget| budget.stats());
+        if let Some(memory) = memory {
+            eprintln!(
+                "MEMORY: at most {} of {} bytes",
+                memory.high_water, memory.limit
+            );
+        }
+        eprintln!("ELAPSED (total): {:?}", start.elapsed());
+        Ok(RunStats {
+            lines_written: written,
+            input_lines,
+            manifest,
+            thread_timings,
+            skipped_gzip,
+            input_timings,
+            send_wait,
+            split_time,
+            memory,
+            elapsed: start.elapsed(),
+        })
+    }
+
+    /// Like [`finish`](Splitter::finish), but finishes on a thread of its own and awaits it, instead of blocking.
+    ///
+    /// Dropping the returned future doesn't stop the output from being finished
+    pub async fn finish_async(self) -> Result<RunStats, Error> {
+        let (tx, rx) = futures::channel::oneshot::channel();
+        std::thread::Builder::new()
+            .name("splitter-finish".to_string())
+            .spawn(move || {
+                let _ = tx.send(self.finish());
+            })
+            .expect("Could not spawn the finishing thread");
+        // The sender is only dropped without sending if finishing panicked, which has already been printed
+        rx.await.expect("Finishing the output panicked")
+    }
+}
+
+/// Splits every input which `next_input` gives it with `sender`, until there are none left or the splitter stops,
+/// which is one of the threads of [`Splitter::process_files_parallel`].
+/// Returns the sender, along with the output thread it couldn't send to if there was one
+fn split_files_of(
+    splitter: &Mutex<&mut Splitter>,
+    mut sender: LineSender,
+    mut next_input: impl FnMut() -> Option<JsonLinesRecv>,
+) -> (LineSender, Option<usize>) {
+    while let Some(lines) = next_input() {
+        let skipped = lines.skipped_gzip();
+        let timers = lines.timers();
+        // Breaking out of the loop drops `lines`, which stops its reader thread
+        for line in lines {
+            let line = {
+                let mut splitter = splitter.lock().unwrap();
+                if splitter.stopped {
+                    break;
+                }
+                let line = match splitter.next_line(line) {
+                    ControlFlow::Continue(Some(line)) => line,
+                    ControlFlow::Continue(None) => continue,
+                    ControlFlow::Break(()) => break,
+                };
+                count_input_line(&mut splitter.input_lines, line.source());
+                splitter.written += 1;
+                splitter.metrics.line_written();
+                line
+            };
+            // Sent without holding the lock, so that the other threads keep going while this one waits for room
+            if let Err(thread_idx) = sender.write_line(line) {
+                splitter.lock().unwrap().stopped = true;
+                return (sender, Some(thread_idx));
+            }
+        }
+
+        let mut splitter = splitter.lock().unwrap();
+        splitter.skipped_gzip += *skipped.lock().unwrap();
+        splitter.input_timings += timers.get();
+        if splitter.stopped {
+            break;
+        }
+    }
+    (sender, None)
+}
+
+/// Finishes the splitter it holds when dropped, such as when the future of [`run_async`](crate::run_async) is cancelled
+pub(crate) struct FinishOnDrop(pub Option<Splitter>);
+
+impl Drop for FinishOnDrop {
+    fn drop(&mut self) {
+        if let Some(splitter) = self.0.take() {
+            eprintln!("The run was cancelled, finishing what was written so far");
+            if let Err(e) = splitter.finish() {
+                eprintln!("Could not finish the cancelled run: {}", e.full_message());
+            }
+        }
+    }
+}
+
+/// Stops the run which its thread is splitting and waits for it to finish when dropped,
+/// such as when the future of [`run_async`](crate::run_async) is cancelled with [`RunCfg::parallel_inputs`]
+pub(crate) struct StopOnDrop {
+    pub stop: Arc<AtomicBool>,
+    pub thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for StopOnDrop {
+    fn drop(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            eprintln!("The run was cancelled, finishing what was written so far");
+            self.stop.store(true, Ordering::Relaxed);
+            // A panic has already been printed by the thread
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Prints where the input thread and the main thread spent the `split_time` of a run.
+/// Output threads print their own timings once they're finished
+fn print_timings(input: &InputTimings, send_wait: Duration, split_time: Duration) {
+    if split_time.is_zero() {
+        return;
+    }
+    let share = |d| fmt_share(d, split_time);
+    eprintln!(
+        "Input thread: {} reading, {} decoding, {} waiting for the main thread",
+        share(input.read),
+        share(input.decode),
+        share(input.send_wait)
+    );
+    eprintln!(
+        "Main thread: {split_time:?} splitting, {} parsing, {} waiting for input, {} waiting for output threads",
+        share(input.parse),
+        share(input.recv_wait),
+        share(send_wait)
+    );
+}
+
+/// Counts a written line towards its input. Lines of one input mostly come one after another,
+/// so the last input is checked before the others
+fn count_input_line(counts: &mut Vec<(InputName, usize)>, source: Option<&InputName>) {
+    let Some(source) = source else {
+        return;
+    };
+    if let Some((input, lines)) = counts.last_mut() {
+        if input == source {
+            *lines += 1;
+            return;
+        }
+    }
+    match counts.iter_mut().find(|(input, _)| input == source) {
+        Some((_, lines)) => *lines += 1,
+        None => counts.push((source.clone(), 1)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tempdir::TempDir;
+
+    use crate::{
+        data::LineData,
+        invalid_lines::InvalidLineLimit,
+        test_utils::{line, output_file, read_lines, write_input},
+        testdata_gen::{generate_testdata, TestdataCfg},
+        ErrorKind, OutputTarget, ReadError, RunCfg, Threads,
+    };
+
+    use super::Splitter;
+
+    #[test]
+    fn test_splitter_reuse() {
+        let tmp = TempDir::new("logsplitter2").unwrap();
+        let out = tmp.path().join("out");
+        let inputs = [tmp.path().join("1.json.gz"), tmp.path().join("2.json.gz")];
+        write_input(&inputs[0], &[line("a", "1"), line("b", "2")]);
+        write_input(&inputs[1], &[line("a", "3")]);
+
+        let mut splitter = Splitter::new(RunCfg {
+            output: OutputTarget::Dir(out.clone()),
+            output_threads: Threads::Fixed(2),
+            max_invalid_lines: InvalidLineLimit::Count(1),
+            ..Default::default()
+        })
+        .unwrap();
+        for input in &inputs {
+            splitter.process_files(vec![input.clone()]).unwrap();
+        }
+        // Lines which don't come from a file at all, sharing the same files
+        splitter
+            .process([
+                Ok(LineData::parse(line("b", "4")).unwrap()),
+                Err(ReadError::InvalidLine {
+                    line: "nope".to_string(),
+                    input: None,
+                }),
+            ])
+            .unwrap();
+        assert!(!splitter.is_stopped());
+
+        let stats = splitter.finish().unwrap();
+        assert_eq!(stats.lines_written, 4);
+        assert_eq!(stats.manifest.unwrap().files.len(), 2);
+        assert_eq!(stats.thread_timings.len(), 2);
+        let read = |service| {
+            read_lines(flate2::read::GzDecoder::new(
+                std::fs::File::open(output_file(&out, service)).unwrap(),
+            ))
+        };
+        assert_eq!(read("a"), [line("a", "1"), line("a", "3")]);
+        assert_eq!(read("b"), [line("b", "2"), line("b", "4")]);
+    }
+
+    #[test]
+    fn test_splitter_aborted() {
+        let tmp = TempDir::new("logsplitter2").unwrap();
+        let out = tmp.path().join("out");
+
+        let mut splitter = Splitter::new(RunCfg {
+            output: OutputTarget::Dir(out.clone()),
+            output_threads: Threads::Fixed(1),
+            ..Default::default()
+        })
+        .unwrap();
+        let invalid = || {
+            Err(ReadError::InvalidLine {
+                line: "nope".to_string(),
+                input: None,
+            })
+        };
+        splitter
+            .process([Ok(LineData::parse(line("a", "1")).unwrap()), invalid()])
+            .unwrap();
+        assert!(splitter.is_stopped());
+        // Skipped, since the run was already aborted
+        splitter
+            .process([Ok(LineData::parse(line("a", "2")).unwrap())])
+            .unwrap();
+
+        let e = splitter.finish().unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::TooManyInvalidLines { .. }));
+        // What was written before aborting is still finished
+        assert_eq!(
+            read_lines(flate2::read::GzDecoder::new(
+                std::fs::File::open(output_file(&out, "a")).unwrap(),
+            )),
+            [line("a", "1")]
+        );
+    }
+
+    #[test]
+    fn test_process_files_parallel() {
+        let tmp = TempDir::new("logsplitter2").unwrap();
+        let out = tmp.path().join("out");
+        let services = ["a", "b", "c", "d", "e"];
+        // Lines are numbered in the order of their input. The first input only has some of the services
+        let inputs = (0..6)
+            .map(|i| {
+                let path = tmp.path().join(format!("{i}.json.gz"));
+                let services = if i == 0 { &services[..2] } else { &services };
+                let lines = (0..200)
+                    .map(|n| line(services[n % services.len()], &format!("{i}-{n}")))
+                    .collect::<Vec<_>>();
+                write_input(&path, &lines);
+                (path, lines)
+            })
+            .collect::<Vec<_>>();
+        let paths = inputs.iter().map(|(p, _)| p.clone()).collect::<Vec<_>>();
+
+        let mut splitter = Splitter::new(RunCfg {
+            output: OutputTarget::Dir(out.clone()),
+            output_threads: Threads::Fixed(3),
+            ..Default::default()
+        })
+        .unwrap();
+        // Keys keep the threads they were assigned round-robin, and new keys the ones the parallel inputs hashed them to
+        splitter.process_files(paths[..1].to_vec()).unwrap();
+        splitter
+            .process_files_parallel(paths[1..5].to_vec(), 3)
+            .unwrap();
+        splitter.process_files(paths[5..].to_vec()).unwrap();
+        let stats = splitter.finish().unwrap();
+        assert_eq!(stats.lines_written, 6 * 200);
+        assert_eq!(stats.input_lines.len(), 6);
+        assert!(stats.input_lines.iter().all(|&(_, n)| n == 200));
+
+        let mut written = vec![];
+        for service in services {
+            let lines = read_lines(flate2::read::MultiGzDecoder::new(
+                std::fs::File::open(output_file(&out, service)).unwrap(),
+            ));
+            // However the inputs were interleaved, each one's lines of the key are all there and in order
+            for (_, input) in &inputs {
+                let from_input = lines.iter().filter(|l| input.contains(l));
+                let of_key = input.iter().filter(|l| lines.contains(l));
+                assert!(from_input.eq(of_key), "{service}");
+            }
+            written.extend(lines);
+        }
+        let mut all = inputs
+            .into_iter()
+            .flat_map(|(_, lines)| lines)
+            .collect::<Vec<_>>();
+        all.sort();
+        written.sort();
+        assert_eq!(written, all);
+    }
+
+    #[test]
+    fn test_stage_timings() {
+        let tmp = TempDir::new("logsplitter2").unwrap();
+        let input = tmp.path().join("input.json.gz");
+        let mut cfg = TestdataCfg {
+            lines: 5_000,
+            seed: Some(0),
+            ..Default::default()
+        };
+        cfg.set_unique_dates(2)
+            .set_services(4, 3..6)
+            .set_envs(2, 3..6)
+            .set_users(10, 5..15);
+        generate_testdata(cfg, &mut std::fs::File::create(&input).unwrap(), None).unwrap();
+
+        let mut splitter = Splitter::new(RunCfg {
+            output: OutputTarget::Dir(tmp.path().join("out")),
+            output_threads: Threads::Fixed(2),
+            ..Default::default()
+        })
+        .unwrap();
+        splitter.process_files(vec![input]).unwrap();
+        let stats = splitter.finish().unwrap();
+        assert_eq!(stats.lines_written, 5_000);
+
+        // Each thread's stages cover most of the time it was busy, without adding up to more than that
+        let (input, wall) = (stats.input_timings, stats.split_time);
+        let within = |stages: Duration, total: Duration| {
+            assert!(
+                stages >= total / 2 && stages <= total + total / 10,
+                "{stages:?} of {total:?} ({stats:?})"
+            );
+        };
+        within(input.read + input.decode + input.send_wait, wall);
+        within(input.parse + input.recv_wait + stats.send_wait, wall);
+        assert_eq!(stats.thread_timings.len(), 2);
+        for t in &stats.thread_timings {
+            assert_eq!(
+                t.compress + t.file_io + t.open_close + t.channel_wait,
+                t.total
+            );
+            assert!(t.total <= stats.elapsed, "{t:?}");
+        }
+    }
+}
@@ -0,0 +1,202 @@
+//! Spreading the lines of a hot key over several files, so that several output threads share its work, see [`KeyStripes`].
+//!
+//! Every line of a key normally goes to the same output thread and file, so a key with most of the lines leaves
+//! its thread as the bottleneck while the others idle. A striped key with `K` stripes is written to
+//! `{name}.0.json.gz` up to `{name}.{K-1}.json.gz` instead, each by a different output thread (as long as there are `K` of them).
+//! Each line goes to the stripe given by a hash of its text, so identical lines always share a stripe.
+//!
+//! As far as the rest of the output is concerned, each stripe is a key of its own, named `{name}.{i}`:
+//! it has its own [manifest](crate::manifest) entry, [line index](crate::index) and file complete event.
+//! The order of the key's lines is only kept within each stripe.
+//!
+//! Readers should take a key's lines from `{name}.json.gz` as well as every file matching `{name}.*.json.gz`
+//! (such as `zcat name.json.gz name.*.json.gz`), since with [`StripeCount::Auto`] the lines a key had
+//! before it was found to be hot stay in `{name}.json.gz`. [Merging](crate::merge) these files by timestamp puts the lines back in order.
+//! The pattern also matches keys whose names start with `{name}.`, which only happens if services or envs have dots in them
+
+use std::{collections::HashMap, str::FromStr};
+
+use crate::data::{hash_builder, HashBuilder, LineData, MsgKey, MsgKeyMap};
+
+/// How many lines [`StripeCount::Auto`] routes before picking which keys to stripe
+pub const AUTO_WARM_UP_LINES: u64 = 10_000;
+
+/// How many stripes each striped key is split into
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StripeCount {
+    /// At least 2
+    Fixed(usize),
+    /// One stripe per output thread for the [named keys](KeyStripes::keys). Other keys are counted over the
+    /// first [`AUTO_WARM_UP_LINES`] lines, and every key with more than one thread's share of them is then split
+    /// into as many stripes as it has threads' shares (up to the number of threads)
+    Auto,
+}
+
+impl FromStr for StripeCount {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Self::Auto),
+            _ => match s.parse() {
+                Ok(n) if n >= 2 => Ok(Self::Fixed(n)),
+                _ => Err(format!(
+                    "Expected `auto` or a number of stripes of at least 2, got `{s}`"
+                )),
+            },
+        }
+    }
+}
+
+/// Which keys are striped, and into how many stripes, see the [module docs](self)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyStripes {
+    /// The names of the keys which are striped from their first line
+    pub keys: Vec<String>,
+    pub count: StripeCount,
+}
+
+/// Picks the stripe of each line, see [`OutputFiles::with_stripes`](crate::output::OutputFiles::with_stripes)
+pub(crate) struct Striper {
+    count: StripeCount,
+    /// The stripes of every striped key by its name, which are made from the key's first line
+    striped: HashMap<String, Vec<MsgKey>, HashBuilder>,
+    /// The lines of every key which isn't striped, until [`AUTO_WARM_UP_LINES`] were routed.
+    /// `None` once they were, or without [`StripeCount::Auto`]
+    warm_up: Option<(u64, MsgKeyMap<u64>)>,
+    hash_seed: u64,
+}
+
+impl Striper {
+    pub(crate) fn new(stripes: KeyStripes, hash_seed: u64) -> Self {
+        let mut striped = HashMap::with_hasher(hash_builder(hash_seed));
+        for key in stripes.keys {
+            striped.insert(key, vec![]);
+        }
+        Self {
+            count: stripes.count,
+            striped,
+            warm_up: (stripes.count == StripeCount::Auto)
+                .then(|| (0, MsgKeyMap::with_hasher(hash_builder(hash_seed)))),
+            hash_seed,
+        }
+    }
+
+    /// The stripe which `ln` goes to along with its index, or `None` if its key isn't striped.
+    /// Stripe `i` should go to the `i`th thread after the one of the key itself
+    pub(crate) fn stripe(&mut self, ln: &LineData, threads: usize) -> Option<(MsgKey, usize)> {
+        let key = ln.key();
+        let Some(stripes) = self.striped.get_mut(key.name()) else {
+            self.count_line(key, threads);
+            return None;
+        };
+        if stripes.is_empty() {
+            let count = match self.count {
+                StripeCount::Fixed(n) => n,
+                StripeCount::Auto => threads,
+            };
+            *stripes = make_stripes(key, count);
+        }
+        if stripes.len() < 2 {
+            return None;
+        }
+        let hash = xxhash_rust::xxh3::xxh3_64_with_seed(
+            ln.original_line_text().as_bytes(),
+            self.hash_seed,
+        );
+        let i = (hash % stripes.len() as u64) as usize;
+        Some((stripes[i].clone(), i))
+    }
+
+    /// Counts a line of a key which isn't striped during the warm-up of [`StripeCount::Auto`],
+    /// and stripes the hot keys once the warm-up is over
+    fn count_line(&mut self, key: &MsgKey, threads: usize) {
+        let Some((routed, lines)) = &mut self.warm_up else {
+            return;
+        };
+        *routed += 1;
+        *lines.entry(key.clone()).or_default() += 1;
+        if *routed < AUTO_WARM_UP_LINES {
+            return;
+        }
+
+        let routed = *routed;
+        let (_, lines) = self.warm_up.take().unwrap();
+        for (key, n) in lines {
+            let shares = (n * threads as u64).div_ceil(routed).min(threads as u64) as usize;
+            if shares < 2 {
+                continue;
+            }
+            eprintln!(
+                "Striping {} over {shares} files, since it had {n} of the first {routed} lines",
+                key.name()
+            );
+            self.striped
+                .insert(key.name().to_string(), make_stripes(&key, shares));
+        }
+    }
+}
+
+fn make_stripes(key: &MsgKey, count: usize) -> Vec<MsgKey> {
+    (0..count)
+        .map(|i| MsgKey::new(&format!("{}.{i}", key.name()), key.date()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use super::{KeyStripes, StripeCount, Striper, AUTO_WARM_UP_LINES};
+    use crate::data::{LineData, MsgKey};
+
+    #[test]
+    fn test_striper() {
+        let date = NaiveDate::from_ymd_opt(2024, 10, 20).unwrap();
+        let hot = MsgKey::new("hot", date);
+        let cold = MsgKey::new("cold", date);
+        let ln = |key: &MsgKey, i: u64| LineData::new(format!("{{\"i\":{i}}}"), key.clone());
+
+        let mut striper = Striper::new(
+            KeyStripes {
+                keys: vec!["hot".to_string()],
+                count: StripeCount::Fixed(3),
+            },
+            0,
+        );
+        let mut seen = [0; 3];
+        for i in 0..300 {
+            let (stripe, idx) = striper.stripe(&ln(&hot, i), 2).unwrap();
+            assert_eq!(stripe.name(), format!("hot.{idx}"));
+            assert_eq!(stripe.date(), date);
+            seen[idx] += 1;
+        }
+        assert!(seen.iter().all(|&n| n > 50), "{seen:?}");
+        // The same line always goes to the same stripe
+        assert_eq!(
+            striper.stripe(&ln(&hot, 7), 2),
+            striper.stripe(&ln(&hot, 7), 2)
+        );
+        assert_eq!(striper.stripe(&ln(&cold, 0), 2), None);
+
+        // A key with 3/4 of the lines has 3 threads' shares out of 4
+        let mut striper = Striper::new(
+            KeyStripes {
+                keys: vec![],
+                count: StripeCount::Auto,
+            },
+            0,
+        );
+        for i in 0..AUTO_WARM_UP_LINES {
+            let key = if i % 4 == 0 { &cold } else { &hot };
+            assert_eq!(striper.stripe(&ln(key, i), 4), None);
+        }
+        let (stripe, _) = striper.stripe(&ln(&hot, 0), 4).unwrap();
+        assert!(["hot.0", "hot.1", "hot.2"].contains(&stripe.name()));
+        assert_eq!(striper.stripe(&ln(&cold, 0), 4), None);
+
+        assert_eq!("auto".parse(), Ok(StripeCount::Auto));
+        assert_eq!("4".parse(), Ok(StripeCount::Fixed(4)));
+        assert!("1".parse::<StripeCount>().is_err());
+    }
+}
@@ -0,0 +1,192 @@
+//! Helpers shared by the tests of several modules
+
+use std::{
+    cell::RefCell,
+    collections::{HashMap, VecDeque},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+use flate2::{write::GzEncoder, Compression};
+
+use crate::file_pool::FileBackend;
+
+/// A valid input line for `service` in the `prod` env on 2024-10-20, without a trailing newline
+pub fn line(service: &str, msg: &str) -> String {
+    json::object! {
+        message: msg,
+        "@timestamp": "2024-10-20T12:00:00Z",
+        "@meta": { service: service, env: "prod" },
+    }
+    .dump()
+}
+
+/// Writes `lines` to a new `.json.gz` file at `path`
+pub fn write_input(path: &Path, lines: &[String]) {
+    let mut enc = GzEncoder::new(std::fs::File::create(path).unwrap(), Compression::default());
+    for ln in lines {
+        writeln!(enc, "{ln}").unwrap();
+    }
+    enc.finish().unwrap();
+}
+
+/// `lines` gzipped like [`write_input`], in memory, such as for [`JsonLinesRecv::from_reader`](crate::input::JsonLinesRecv::from_reader)
+pub fn gz_reader(lines: &[String]) -> io::Cursor<Vec<u8>> {
+    let mut enc = GzEncoder::new(vec![], Compression::default());
+    for ln in lines {
+        writeln!(enc, "{ln}").unwrap();
+    }
+    io::Cursor::new(enc.finish().unwrap())
+}
+
+pub fn read_lines(mut r: impl Read) -> Vec<String> {
+    let mut s = String::new();
+    r.read_to_string(&mut s).unwrap();
+    s.lines().map(String::from).collect()
+}
+
+/// The output file which lines created with [`line`] end up in
+pub fn output_file(dir: &Path, service: &str) -> PathBuf {
+    dir.join(format!("{service}_prod_2024-10-20.json.gz"))
+}
+
+#[derive(Default)]
+struct MemState {
+    files: HashMap<PathBuf, Vec<u8>>,
+    /// How many times an existing file was opened again
+    reopens: usize,
+    /// How many times any file was synced
+    syncs: usize,
+    /// The total size which files can grow to, like the free space of a disk
+    capacity: Option<usize>,
+    /// Errors which the next operations fail with, one per operation
+    failures: VecDeque<io::ErrorKind>,
+}
+
+/// A [`FileBackend`] which keeps every file in memory, and records how often files are reopened
+#[derive(Clone, Default)]
+pub struct MemBackend {
+    state: Rc<RefCell<MemState>>,
+}
+
+pub struct MemFile {
+    path: PathBuf,
+}
+
+impl MemBackend {
+    /// A backend whose writes fail with [`io::ErrorKind::StorageFull`] once its files add up to `capacity` bytes
+    pub fn with_capacity(capacity: usize) -> Self {
+        let backend = Self::default();
+        backend.state.borrow_mut().capacity = Some(capacity);
+        backend
+    }
+
+    /// Makes the next `times` creates, opens, writes, or syncs fail with `kind`
+    pub fn fail_next(&self, kind: io::ErrorKind, times: usize) {
+        let failures = &mut self.state.borrow_mut().failures;
+        failures.extend(std::iter::repeat_n(kind, times));
+    }
+
+    fn injected_failure(&self) -> io::Result<()> {
+        match self.state.borrow_mut().failures.pop_front() {
+            Some(kind) => Err(kind.into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Puts a file at `path`, as if a previous run had left it behind
+    pub fn insert(&self, path: &Path, contents: Vec<u8>) {
+        self.state
+            .borrow_mut()
+            .files
+            .insert(path.to_path_buf(), contents);
+    }
+
+    pub fn contents(&self, path: &Path) -> Option<Vec<u8>> {
+        self.state.borrow().files.get(path).cloned()
+    }
+    pub fn paths(&self) -> Vec<PathBuf> {
+        self.state.borrow().files.keys().cloned().collect()
+    }
+    pub fn reopens(&self) -> usize {
+        self.state.borrow().reopens
+    }
+    pub fn syncs(&self) -> usize {
+        self.state.borrow().syncs
+    }
+
+    fn open_file(&self, path: &Path, truncate: bool) -> MemFile {
+        let mut state = self.state.borrow_mut();
+        match state.files.get_mut(path) {
+            Some(f) if truncate => f.clear(),
+            Some(_) => state.reopens += 1,
+            None => {
+                state.files.insert(path.to_path_buf(), vec![]);
+            }
+        }
+        MemFile {
+            path: path.to_path_buf(),
+        }
+    }
+}
+
+impl FileBackend for MemBackend {
+    type File = MemFile;
+
+    async fn create(&self, path: &Path) -> io::Result<MemFile> {
+        self.injected_failure()?;
+        Ok(self.open_file(path, true))
+    }
+    async fn open(&self, path: &Path) -> io::Result<MemFile> {
+        self.injected_failure()?;
+        Ok(self.open_file(path, false))
+    }
+    async fn file_len(&self, path: &Path) -> io::Result<u64> {
+        Ok(self.contents(path).map_or(0, |f| f.len() as u64))
+    }
+    async fn remove(&self, path: &Path) -> io::Result<()> {
+        self.injected_failure()?;
+        self.state.borrow_mut().files.remove(path);
+        Ok(())
+    }
+    async fn write_at(
+        &self,
+        file: &MemFile,
+        buf: Vec<u8>,
+        pos: u64,
+    ) -> (io::Result<usize>, Vec<u8>) {
+        if let Err(e) = self.injected_failure() {
+            return (Err(e), buf);
+        }
+        let mut state = self.state.borrow_mut();
+        let pos = pos as usize;
+        let mut len = buf.len();
+        if let Some(capacity) = state.capacity {
+            let used = state.files.values().map(Vec::len).sum::<usize>();
+            let growth = (pos + len).saturating_sub(state.files[&file.path].len());
+            let available = capacity.saturating_sub(used);
+            if growth > available {
+                // Writes as much as fits, like a real disk would
+                len = len.saturating_sub(growth - available);
+                if len == 0 {
+                    return (Err(io::ErrorKind::StorageFull.into()), buf);
+                }
+            }
+        }
+        let f = state.files.get_mut(&file.path).unwrap();
+        if f.len() < pos + len {
+            f.resize(pos + len, 0);
+        }
+        f[pos..pos + len].copy_from_slice(&buf[..len]);
+        (Ok(len), buf)
+    }
+    async fn sync(&self, _file: &MemFile) -> io::Result<()> {
+        self.injected_failure()?;
+        self.state.borrow_mut().syncs += 1;
+        Ok(())
+    }
+    async fn close(&self, _file: MemFile) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -1,21 +1,158 @@
-use std::io::Write;
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufWriter, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+};
 
-use chrono::{NaiveDate, NaiveDateTime, TimeDelta};
+use chrono::{NaiveDate, TimeDelta};
 use flate2::{write::GzEncoder, Compression};
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
 
 use crate::math_utils;
 
-use self::gen_format::FullLine;
+use self::gen_format::{FullLine, Missing, Names};
+
+/// How often each of the names of a [`TestdataCfg`] pool is picked
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum KeyDistribution {
+    /// Every name is as likely as every other
+    #[default]
+    Uniform,
+    /// The `k`th name is picked in proportion to `1 / k^exponent`, so a few names get most of the lines,
+    /// like the services of real logs. An exponent of `0` is the same as [`Uniform`](KeyDistribution::Uniform)
+    Zipf { exponent: f64 },
+}
+
+/// The order in which the lines of different dates are generated
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DateOrder {
+    /// All lines of a date in a single block, with dates in increasing order
+    #[default]
+    Sequential,
+    /// Lines of all dates are randomly permuted across the whole dataset,
+    /// so files of older dates keep being reopened long after they were first written
+    Shuffled,
+    /// Mostly in order, but every line is moved by less than `window` lines, like the merged logs of several hosts.
+    /// A window of `0` or `1` leaves lines in the order of [`Sequential`](DateOrder::Sequential)
+    Interleaved { window: usize },
+}
+
+/// How the `@timestamp` of generated lines is written
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum TimestampFormat {
+    /// An RFC 3339 string in a random timezone, such as `2024-10-20T17:03:12-07:00`
+    #[default]
+    Rfc3339,
+    /// A json number of seconds since the unix epoch
+    EpochSeconds,
+    /// A json number of milliseconds since the unix epoch
+    EpochMillis,
+    /// A string formatted in UTC with this [chrono format](chrono::format::strftime), such as `%Y-%m-%d %H:%M:%S%.3f`
+    Custom(String),
+    /// Every line picks one of these formats, in proportion to its weight
+    Mixed(Vec<(TimestampFormat, f64)>),
+}
+
+/// How many characters (not bytes) long generated messages are
+#[derive(Debug, Clone, PartialEq)]
+pub enum MessageLength {
+    Uniform(Range<usize>),
+    /// Log-normally distributed, so that most messages are around `median` long but a few are much longer.
+    /// The larger `sigma`, the longer the tail
+    LogNormal {
+        median: f64,
+        sigma: f64,
+    },
+    /// One of these lengths, each as likely as the others, such as a few short lengths and a multi-megabyte outlier.
+    /// Lengths may repeat, to make them more likely
+    OneOf(Vec<usize>),
+}
+
+/// The characters which generated messages are made of
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MessageCharset {
+    /// `a-z`, `A-Z`, and `0-9`
+    #[default]
+    Alphanumeric,
+    /// ASCII letters mixed with accented Latin, Greek, CJK, and emoji, which take 1 to 4 bytes each as UTF-8
+    Unicode,
+    /// Printable ASCII including quotes, backslashes, and the occasional tab, like the text of real log lines,
+    /// many of which need escaping in json
+    LogLike,
+}
+
+/// What the `message` of generated lines looks like
+#[derive(Debug, Clone, PartialEq)]
+pub struct MessageSpec {
+    /// Not used for templated messages
+    pub length: MessageLength,
+    pub charset: MessageCharset,
+    /// If not empty, every message is one of these templates, with each `{}` replaced by a random word (1 to 8 characters of `charset`)
+    /// and each `{n}` by a random number below 1000, such as `GET /api/{} 200 {n}ms`
+    pub templates: Vec<String>,
+}
+
+impl Default for MessageSpec {
+    fn default() -> Self {
+        Self {
+            length: MessageLength::Uniform(10..100),
+            charset: Default::default(),
+            templates: vec![],
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct TestdataCfg {
     pub lines: usize,
+    /// The gzip compression level of the generated `.json.gz` data
     pub compression: Compression,
     /// The number of unique dates which will be generated
     pub unique_dates: usize,
     pub date_start: NaiveDate,
     /// The approximate distance between two days
     pub date_delta: TimeDelta,
+    pub date_order: DateOrder,
+    pub timestamp_format: TimestampFormat,
+    /// How many files [`generate_testdata_files`] spreads lines over, as evenly as possible
+    pub output_files: usize,
+    /// How many gzip members each file is made of, each with as many of the file's lines as the others.
+    /// Concatenated members are a single valid gzip stream, which decoders must read to its end
+    pub gzip_members: usize,
+    /// How many threads generate and compress lines. With more than one, every [segment](SEGMENT_LINES)
+    /// of lines is compressed as its own gzip member, so the compressed data differs,
+    /// but the lines are the same for any number of threads
+    pub gen_threads: usize,
+    /// How many distinct services lines are spread over
+    pub services: usize,
+    /// How lines are spread over the services
+    pub key_distribution: KeyDistribution,
+    /// The length of each service name, which must allow for `services` distinct names
+    pub service_name_len: Range<usize>,
+    /// How many distinct envs lines are spread over
+    pub envs: usize,
+    pub env_name_len: Range<usize>,
+    /// How many distinct users lines are spread over
+    pub users: usize,
+    pub user_name_len: Range<usize>,
+    pub message: MessageSpec,
+    /// The values of each line's `level`, each with its weight relative to the others.
+    /// Weights which are all the same pick each level uniformly
+    pub levels: Vec<(String, f64)>,
+    /// The chance of each line to be missing `@meta.service` entirely.
+    /// Such lines (like those with any of the other missing fields) are valid json, but have no key
+    pub omit_service_rate: f64,
+    /// The chance of each line to have a `null` `@meta.service`
+    pub null_service_rate: f64,
+    pub omit_env_rate: f64,
+    pub null_env_rate: f64,
+    pub omit_timestamp_rate: f64,
+    pub null_timestamp_rate: f64,
+    /// If set, the same seed (with the same config) always generates the same bytes.
+    /// Otherwise, every call generates different data
+    pub seed: Option<u64>,
 }
 
 impl TestdataCfg {
@@ -29,11 +166,25 @@ impl TestdataCfg {
         self.date_delta = TimeDelta::days(days);
         self
     }
-    #[track_caller]
     pub fn set_unique_dates(&mut self, n: usize) -> &mut Self {
         self.unique_dates = n;
         self
     }
+    pub fn set_services(&mut self, n: usize, name_len: Range<usize>) -> &mut Self {
+        self.services = n;
+        self.service_name_len = name_len;
+        self
+    }
+    pub fn set_envs(&mut self, n: usize, name_len: Range<usize>) -> &mut Self {
+        self.envs = n;
+        self.env_name_len = name_len;
+        self
+    }
+    pub fn set_users(&mut self, n: usize, name_len: Range<usize>) -> &mut Self {
+        self.users = n;
+        self.user_name_len = name_len;
+        self
+    }
 }
 
 impl Default for TestdataCfg {
@@ -44,37 +195,208 @@ impl Default for TestdataCfg {
             unique_dates: 0,
             date_start: Default::default(),
             date_delta: Default::default(),
+            date_order: Default::default(),
+            timestamp_format: Default::default(),
+            output_files: 1,
+            gzip_members: 1,
+            gen_threads: 1,
+            services: 0,
+            key_distribution: Default::default(),
+            service_name_len: 0..0,
+            envs: 0,
+            env_name_len: 0..0,
+            users: 0,
+            user_name_len: 0..0,
+            message: Default::default(),
+            levels: ["debug", "info", "build"]
+                .map(|level| (level.to_string(), 1.0))
+                .to_vec(),
+            omit_service_rate: 0.0,
+            null_service_rate: 0.0,
+            omit_env_rate: 0.0,
+            null_env_rate: 0.0,
+            omit_timestamp_rate: 0.0,
+            null_timestamp_rate: 0.0,
+            seed: None,
         };
         s.set_start_date(2024, 10, 20)
             .set_date_delta(3)
-            .set_unique_dates(300);
+            .set_unique_dates(300)
+            .set_services(900, 3..6)
+            .set_envs(3, 3..6)
+            .set_users(1000, 5..15);
         s
     }
 }
 
+/// What was generated, such as how many lines were missing each field (see [`TestdataCfg::omit_service_rate`])
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TestdataStats {
+    pub lines: usize,
+    pub omitted_service: usize,
+    pub null_service: usize,
+    pub omitted_env: usize,
+    pub null_env: usize,
+    pub omitted_timestamp: usize,
+    pub null_timestamp: usize,
+    /// Lines which [`default_key`](crate::data::default_key) gives no key, because they're missing at least one field
+    /// or their timestamp isn't a string it can parse (such as [`TimestampFormat::EpochSeconds`])
+    pub keyless: usize,
+    /// How many lines have each service, not counting lines without one
+    pub service_lines: BTreeMap<String, usize>,
+    /// How many lines belong to each [`default_key`](crate::data::default_key), by the key's name
+    /// (which is the stem of its output file, such as `auth_prod_2024-10-20`). Lines without a key aren't counted
+    pub key_lines: BTreeMap<String, u64>,
+    /// How many lines have each of the [`TestdataCfg::levels`]. Levels which were never picked aren't counted
+    pub level_lines: BTreeMap<String, usize>,
+    /// The size of every generated line (including its newline), before compression
+    pub bytes_plain: u64,
+    /// The size of the gzipped output
+    pub bytes_compressed: u64,
+}
+
+impl TestdataStats {
+    /// Adds the counts of `other`, which were generated separately from these
+    fn add(&mut self, other: TestdataStats) {
+        self.omitted_service += other.omitted_service;
+        self.null_service += other.null_service;
+        self.omitted_env += other.omitted_env;
+        self.null_env += other.null_env;
+        self.omitted_timestamp += other.omitted_timestamp;
+        self.null_timestamp += other.null_timestamp;
+        self.keyless += other.keyless;
+        for (service, n) in other.service_lines {
+            *self.service_lines.entry(service).or_default() += n;
+        }
+        for (key, n) in other.key_lines {
+            *self.key_lines.entry(key).or_default() += n;
+        }
+        for (level, n) in other.level_lines {
+            *self.level_lines.entry(level).or_default() += n;
+        }
+        self.bytes_plain += other.bytes_plain;
+        self.bytes_compressed += other.bytes_compressed;
+    }
+}
+
+/// Lines are generated in segments of at most this many lines, each with its own random generator,
+/// so that segments can be generated in parallel (see [`TestdataCfg::gen_threads`]).
+/// Segments also end where gzip members do. Tests use smaller segments, so that they cover several quickly
+pub const SEGMENT_LINES: usize = if cfg!(test) { 1 << 10 } else { 1 << 16 };
+
+/// Splits the lines of each gzip member of each file into [segments](SEGMENT_LINES).
+/// Empty members are a single empty segment, so that they're still written
+fn plan_segments(cfg: &TestdataCfg) -> Vec<Vec<Vec<Range<usize>>>> {
+    let mut start = 0;
+    math_utils::get_even_partition(cfg.output_files, cfg.lines)
+        .into_iter()
+        .map(|file_lines| {
+            math_utils::get_even_partition(cfg.gzip_members, file_lines)
+                .into_iter()
+                .map(|member_lines| {
+                    let end = start + member_lines;
+                    let mut segments = vec![];
+                    loop {
+                        // Segments start at multiples of `SEGMENT_LINES`, wherever the members end
+                        let seg_end = ((start / SEGMENT_LINES + 1) * SEGMENT_LINES).min(end);
+                        segments.push(start..seg_end);
+                        start = seg_end;
+                        if start == end {
+                            return segments;
+                        }
+                    }
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Passes writes through to `inner`, counting how many bytes were written
+struct CountingWriter<W> {
+    inner: W,
+    bytes: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.bytes += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
 /// Generates testdata and writes it to two streams:
 ///
-/// * `w_enc` - The gzipped json data, which would be normally written to a `.json.gz` file
-/// * `w_dbg` - The generated json data, human readable
+/// * `w_enc` - The gzipped json data (compressed with [`TestdataCfg::compression`]), which would be normally written to a `.json.gz` file
+/// * `w_dbg` - If given, the generated json data, human readable.
+///   Leaving it out avoids writing the data twice, which matters for large inputs
+///
+/// Returns how many lines and bytes were generated, and how many lines are missing which fields
+///
+/// Panics if [`TestdataCfg::output_files`] isn't `1`, see [`generate_testdata_files`] instead
 ///
 /// Printing the output to stdout and ignoring the encoded output:
 /// ```
+/// # use logsplitter2::testdata_gen::{generate_testdata, TestdataCfg};
 /// generate_testdata(
 ///     TestdataCfg {
 ///         lines: 100,
 ///         ..Default::default()
 ///     },
 ///     &mut std::io::sink(),
-///     &mut std::io::stdout(),
-/// );
-///
+///     Some(&mut std::io::stdout()),
+/// )
+/// .unwrap();
 /// ```
 pub fn generate_testdata(
     cfg: TestdataCfg,
     w_enc: &mut impl std::io::Write,
-    w_dbg: &mut impl std::io::Write,
-) -> Result<(), std::io::Error> {
-    let mut enc = GzEncoder::new(w_enc, Compression::default());
+    w_dbg: Option<&mut dyn std::io::Write>,
+) -> Result<TestdataStats, std::io::Error> {
+    assert_eq!(
+        cfg.output_files, 1,
+        "Use `generate_testdata_files` to generate more than one file"
+    );
+    let mut w_enc = Some(w_enc);
+    generate_into(cfg, |_| Ok(w_enc.take().unwrap()), w_dbg)
+}
+
+/// Like [`generate_testdata`], but splits the gzipped data across [`TestdataCfg::output_files`] files in `dir`,
+/// named `input-000.json.gz`, `input-001.json.gz`, and so on. Each is a valid input on its own,
+/// and together they have the lines of a single input in order.
+///
+/// Returns the paths of the files, in order
+pub fn generate_testdata_files(
+    cfg: TestdataCfg,
+    dir: &Path,
+    w_dbg: Option<&mut dyn std::io::Write>,
+) -> Result<(Vec<PathBuf>, TestdataStats), std::io::Error> {
+    let paths = (0..cfg.output_files)
+        .map(|i| dir.join(format!("input-{i:03}.json.gz")))
+        .collect::<Vec<_>>();
+    let stats = generate_into(cfg, |i| File::create(&paths[i]).map(BufWriter::new), w_dbg)?;
+    Ok((paths, stats))
+}
+
+/// Generates the lines of `cfg`, gzipping the lines of the `i`th output file into `next_file(i)`
+fn generate_into<W: Write>(
+    cfg: TestdataCfg,
+    mut next_file: impl FnMut(usize) -> std::io::Result<W>,
+    mut w_dbg: Option<&mut dyn std::io::Write>,
+) -> Result<TestdataStats, std::io::Error> {
+    let mut stats = TestdataStats {
+        lines: cfg.lines,
+        ..Default::default()
+    };
+    let mut rng = match cfg.seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    };
+    let names = Names::gen(&cfg, &mut rng);
 
     let mut num_messages_per_day = math_utils::get_even_partition(cfg.unique_dates, cfg.lines);
     let mut curr_day = cfg.date_start;
@@ -84,95 +406,377 @@ pub fn generate_testdata(
     //     let day = cfg.date_range.start + Duration::days(day);
     // }
 
+    let mut days = Vec::with_capacity(cfg.lines);
     for _ in 0..cfg.lines {
-        let ln = format!("{}\n", FullLine::generate(&cfg, curr_day).to_json());
-        enc.write_all(ln.as_bytes())?;
-        w_dbg.write_all(ln.as_bytes())?;
-
-        if num_messages_per_day[0] == 0 {
+        // Days may have no messages at all when there are fewer lines than `unique_dates`
+        while num_messages_per_day[0] == 0 {
             num_messages_per_day.swap_remove(0);
             curr_day += cfg.date_delta;
         }
 
         num_messages_per_day[0] -= 1;
+        days.push(curr_day);
+    }
+    match cfg.date_order {
+        DateOrder::Sequential => {}
+        DateOrder::Shuffled => days.shuffle(&mut rng),
+        DateOrder::Interleaved { window } => {
+            // Stable, so lines which end up with the same position keep their order
+            let mut moved = days
+                .into_iter()
+                .enumerate()
+                .map(|(i, day)| (i + rng.gen_range(0..window.max(1)), day))
+                .collect::<Vec<_>>();
+            moved.sort_by_key(|&(pos, _)| pos);
+            days = moved.into_iter().map(|(_, day)| day).collect();
+        }
     }
 
-    enc.finish()?;
-    w_dbg.flush()?;
+    assert!(cfg.gen_threads > 0, "Cannot generate with 0 threads");
+    // The first segment continues with `rng`, the others are seeded by where they start
+    let base_seed = cfg.seed.unwrap_or_else(|| rng.gen());
+    let mut first_rng = Some(rng);
+    let mut segment_rng = |start: usize| match start {
+        0 => first_rng.take().unwrap(),
+        _ => StdRng::seed_from_u64(base_seed ^ (start as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15)),
+    };
 
-    Ok(())
+    for (i, members) in plan_segments(&cfg).into_iter().enumerate() {
+        let mut w = CountingWriter {
+            inner: next_file(i)?,
+            bytes: 0,
+        };
+        if cfg.gen_threads == 1 {
+            for segments in members {
+                let mut enc = GzEncoder::new(w, cfg.compression);
+                for segment in segments {
+                    let mut rng = segment_rng(segment.start);
+                    for &day in &days[segment] {
+                        let ln = gen_line(&cfg, day, &mut rng, &names, &mut stats);
+                        enc.write_all(ln.as_bytes())?;
+                        if let Some(w_dbg) = &mut w_dbg {
+                            w_dbg.write_all(ln.as_bytes())?;
+                        }
+                    }
+                }
+                w = enc.finish()?;
+            }
+        } else {
+            let segments = members.into_iter().flatten().collect::<Vec<_>>();
+            // Only as many segments as there are threads are held in memory at once
+            for round in segments.chunks(cfg.gen_threads) {
+                let rngs = round
+                    .iter()
+                    .map(|s| segment_rng(s.start))
+                    .collect::<Vec<_>>();
+                let keep_plain = w_dbg.is_some();
+                let generated = std::thread::scope(|scope| {
+                    let handles = round
+                        .iter()
+                        .zip(rngs)
+                        .map(|(segment, mut rng)| {
+                            let (cfg, names, days) = (&cfg, &names, &days[segment.clone()]);
+                            scope.spawn(move || {
+                                let mut stats = TestdataStats::default();
+                                let mut plain = String::new();
+                                let mut enc = GzEncoder::new(vec![], cfg.compression);
+                                for &day in days {
+                                    let ln = gen_line(cfg, day, &mut rng, names, &mut stats);
+                                    enc.write_all(ln.as_bytes()).unwrap();
+                                    if keep_plain {
+                                        plain.push_str(&ln);
+                                    }
+                                }
+                                (enc.finish().unwrap(), plain, stats)
+                            })
+                        })
+                        .collect::<Vec<_>>();
+                    handles
+                        .into_iter()
+                        .map(|h| h.join().unwrap())
+                        .collect::<Vec<_>>()
+                });
+                for (member, plain, segment_stats) in generated {
+                    w.write_all(&member)?;
+                    if let Some(w_dbg) = &mut w_dbg {
+                        w_dbg.write_all(plain.as_bytes())?;
+                    }
+                    stats.add(segment_stats);
+                }
+            }
+        }
+        w.flush()?;
+        stats.bytes_compressed += w.bytes;
+    }
+
+    if let Some(w_dbg) = w_dbg {
+        w_dbg.flush()?;
+    }
+
+    Ok(stats)
+}
+
+/// Generates a single line (with its newline), counting it in `stats`
+fn gen_line(
+    cfg: &TestdataCfg,
+    day: NaiveDate,
+    rng: &mut StdRng,
+    names: &Names,
+    stats: &mut TestdataStats,
+) -> String {
+    let line = FullLine::generate(cfg, day, rng, names);
+    for (missing, omitted, null) in [
+        (
+            line.missing_service,
+            &mut stats.omitted_service,
+            &mut stats.null_service,
+        ),
+        (
+            line.missing_env,
+            &mut stats.omitted_env,
+            &mut stats.null_env,
+        ),
+        (
+            line.missing_timestamp,
+            &mut stats.omitted_timestamp,
+            &mut stats.null_timestamp,
+        ),
+    ] {
+        match missing {
+            Some(Missing::Omitted) => *omitted += 1,
+            Some(Missing::Null) => *null += 1,
+            None => {}
+        }
+    }
+    if let Some(service) = line.service() {
+        *stats.service_lines.entry(service.to_string()).or_default() += 1;
+    }
+    match line.key() {
+        Some(key) => *stats.key_lines.entry(key.name().to_string()).or_default() += 1,
+        None => stats.keyless += 1,
+    }
+    *stats
+        .level_lines
+        .entry(line.level().to_string())
+        .or_default() += 1;
+
+    let ln = format!("{}\n", line.to_json());
+    stats.bytes_plain += ln.len() as u64;
+    ln
 }
 
 mod gen_format {
     use rand::prelude::SliceRandom;
-    use std::{cell::OnceCell, fmt::Display, sync::OnceLock};
+    use std::{collections::HashSet, ops::Range};
 
-    use chrono::{
-        DateTime, Datelike, Duration, FixedOffset, NaiveDate, NaiveDateTime, NaiveTime,
-        SecondsFormat, TimeDelta,
-    };
-    use rand::{
-        distributions::{Alphanumeric, Distribution, Standard},
-        thread_rng, Rng,
-    };
+    use chrono::{DateTime, FixedOffset, NaiveDate, NaiveDateTime, NaiveTime, SecondsFormat, Utc};
+    use json::JsonValue;
+    use rand::{distributions::Alphanumeric, Rng};
 
-    use super::TestdataCfg;
+    use crate::data::{default_key, MsgKey};
 
-    #[derive(Debug, Clone, Copy)]
-    enum Level {
-        Debug,
-        Info,
-        Build,
-    }
+    use super::{
+        KeyDistribution, MessageCharset, MessageLength, MessageSpec, TestdataCfg, TimestampFormat,
+    };
 
-    impl Distribution<Level> for Standard {
-        fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> Level {
-            [Level::Debug, Level::Info, Level::Build]
-                .choose(rng)
-                .unwrap()
-                .clone()
+    /// `len` random characters of `charset`
+    fn gen_chars(charset: MessageCharset, len: usize, rng: &mut impl Rng) -> String {
+        match charset {
+            MessageCharset::Alphanumeric => rng
+                .sample_iter(&Alphanumeric)
+                .take(len)
+                .map(char::from)
+                .collect(),
+            MessageCharset::Unicode => (0..len)
+                .map(|_| {
+                    let block = [
+                        0x61..=0x7a,       // a-z
+                        0xc0..=0xff,       // Accented Latin
+                        0x391..=0x3c9,     // Greek
+                        0x4e00..=0x9fff,   // CJK
+                        0x1f600..=0x1f64f, // Emoji
+                    ]
+                    .choose(rng)
+                    .unwrap()
+                    .clone();
+                    char::from_u32(rng.gen_range(block)).unwrap()
+                })
+                .collect(),
+            MessageCharset::LogLike => (0..len)
+                .map(|_| match rng.gen_ratio(1, 50) {
+                    true => '\t',
+                    false => rng.gen_range(' '..='~'),
+                })
+                .collect(),
         }
     }
 
-    impl Display for Level {
-        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-            write!(
-                f,
-                "{}",
-                match self {
-                    Level::Debug => "debug",
-                    Level::Info => "info",
-                    Level::Build => "build",
+    /// A random message according to `spec`
+    fn gen_message(spec: &MessageSpec, rng: &mut impl Rng) -> String {
+        if let Some(template) = spec.templates.choose(rng) {
+            let mut message = String::new();
+            let mut rest = template.as_str();
+            while let Some(start) = rest.find('{') {
+                message.push_str(&rest[..start]);
+                rest = &rest[start..];
+                if let Some(after) = rest.strip_prefix("{}") {
+                    let len = rng.gen_range(1..=8);
+                    message.push_str(&gen_chars(spec.charset, len, rng));
+                    rest = after;
+                } else if let Some(after) = rest.strip_prefix("{n}") {
+                    message.push_str(&rng.gen_range(0..1000).to_string());
+                    rest = after;
+                } else {
+                    message.push('{');
+                    rest = &rest[1..];
                 }
-            )
+            }
+            message.push_str(rest);
+            return message;
         }
+
+        let len = match &spec.length {
+            MessageLength::Uniform(range) => rng.gen_range(range.clone()),
+            MessageLength::LogNormal { median, sigma } => {
+                // Box-Muller, with `1 - u` so that the logarithm is never of 0
+                let (u1, u2) = (1.0 - rng.gen::<f64>(), rng.gen::<f64>());
+                let z = (-2.0 * u1.ln()).sqrt() * (std::f64::consts::TAU * u2).cos();
+                (median * (sigma * z).exp()).round() as usize
+            }
+            MessageLength::OneOf(lengths) => *lengths.choose(rng).unwrap(),
+        };
+        gen_chars(spec.charset, len, rng)
+    }
+
+    /// One of `cfg.levels`, picked by weight
+    fn gen_level(cfg: &TestdataCfg, rng: &mut impl Rng) -> String {
+        let (level, _) = match cfg.levels.first() {
+            // Uniform weights pick like a uniform choice always did, so that seeded output stays the same
+            Some((_, first))
+                if *first > 0.0 && cfg.levels.iter().all(|(_, weight)| weight == first) =>
+            {
+                cfg.levels.choose(rng).unwrap()
+            }
+            _ => cfg
+                .levels
+                .choose_weighted(rng, |(_, weight)| *weight)
+                .expect("Levels need at least one positive weight"),
+        };
+        level.clone()
     }
 
     struct Timestamp {
-        t: String,
+        t: JsonValue,
     }
 
     impl Timestamp {
-        pub fn gen(_cfg: &TestdataCfg, day: NaiveDate) -> Self {
-            let time_secs = thread_rng().gen_range(-86_399..=86_399);
-            let time = FixedOffset::west_opt(time_secs).unwrap();
-            let time = NaiveTime::from_hms_opt(0, 0, 0).unwrap() + time;
+        /// A time during `day` in UTC, written in `cfg.timestamp_format`.
+        /// Its UTC date (which lines are keyed by) is always `day`, whatever the local date in the timezone it's written in is
+        pub fn gen(cfg: &TestdataCfg, day: NaiveDate, rng: &mut impl Rng) -> Self {
+            // Wrapped into the day, rather than drawn from `0..86_400`, so that seeded output stays the same
+            let time_secs: i32 = rng.gen_range(-86_399..=86_399);
+            let time = NaiveTime::from_num_seconds_from_midnight_opt(
+                (-time_secs).rem_euclid(86_400) as u32,
+                0,
+            )
+            .unwrap();
 
-            let tz_hrs = thread_rng().gen_range(-23..=23);
+            let tz_hrs = rng.gen_range(-23..=23);
             let tz = FixedOffset::west_opt((tz_hrs) * 3600).unwrap();
 
-            let datetime = NaiveDateTime::new(day, time);
-            let datetime = datetime.and_utc().with_timezone(&tz);
+            let datetime = NaiveDateTime::new(day, time).and_utc();
 
             Self {
-                t: datetime.to_rfc3339_opts(SecondsFormat::AutoSi, true),
+                t: Self::format(&cfg.timestamp_format, datetime, tz, rng),
             }
         }
+
+        fn format(
+            format: &TimestampFormat,
+            datetime: DateTime<Utc>,
+            tz: FixedOffset,
+            rng: &mut impl Rng,
+        ) -> JsonValue {
+            match format {
+                TimestampFormat::Rfc3339 => datetime
+                    .with_timezone(&tz)
+                    .to_rfc3339_opts(SecondsFormat::AutoSi, true)
+                    .into(),
+                TimestampFormat::EpochSeconds => datetime.timestamp().into(),
+                TimestampFormat::EpochMillis => {
+                    (datetime.timestamp_millis() + rng.gen_range(0..1000)).into()
+                }
+                TimestampFormat::Custom(f) => datetime.format(f).to_string().into(),
+                TimestampFormat::Mixed(formats) => {
+                    let (format, _) = formats
+                        .choose_weighted(rng, |(_, weight)| *weight)
+                        .expect("Mixed timestamp formats need at least one positive weight");
+                    Self::format(format, datetime, tz, rng)
+                }
+            }
+        }
+    }
+
+    /// The services, envs, and users which lines pick from, so that keys repeat
+    pub(super) struct Names {
+        services: Vec<String>,
+        /// The running totals of the services' weights, if they aren't picked uniformly
+        service_weights: Option<Vec<f64>>,
+        envs: Vec<String>,
+        users: Vec<String>,
     }
 
-    impl Display for Timestamp {
-        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-            write!(f, "{}", self.t)
+    impl Names {
+        /// Panics if a pool of `cfg` is empty, or has more names than its name length allows for
+        pub fn gen(cfg: &TestdataCfg, rng: &mut impl Rng) -> Self {
+            /// `count` distinct random strings, each with a length in `elem_len`
+            #[track_caller]
+            fn gen_list(rng: &mut impl Rng, count: usize, elem_len: Range<usize>) -> Vec<String> {
+                assert!(
+                    count > 0 && !elem_len.is_empty(),
+                    "Cannot generate {count} names with a length in {elem_len:?}"
+                );
+                let possible = elem_len.clone().fold(0usize, |n, len| {
+                    n.saturating_add(62usize.saturating_pow(len as u32))
+                });
+                assert!(
+                    count <= possible,
+                    "Only {possible} distinct names have a length in {elem_len:?}, not {count}"
+                );
+
+                let mut seen = HashSet::new();
+                let mut v = vec![];
+                while v.len() < count {
+                    let s_len = rng.gen_range(elem_len.clone());
+                    let s = rng
+                        .sample_iter(&Alphanumeric)
+                        .take(s_len)
+                        .map(char::from)
+                        .collect::<String>();
+                    if seen.insert(s.clone()) {
+                        v.push(s);
+                    }
+                }
+                v
+            }
+
+            let service_weights = match cfg.key_distribution {
+                KeyDistribution::Uniform => None,
+                KeyDistribution::Zipf { exponent } => Some(
+                    (1..=cfg.services)
+                        .scan(0.0, |total, k| {
+                            *total += 1.0 / (k as f64).powf(exponent);
+                            Some(*total)
+                        })
+                        .collect(),
+                ),
+            };
+            Self {
+                services: gen_list(rng, cfg.services, cfg.service_name_len.clone()),
+                service_weights,
+                envs: gen_list(rng, cfg.envs, cfg.env_name_len.clone()),
+                users: gen_list(rng, cfg.users, cfg.user_name_len.clone()),
+            }
         }
     }
 
@@ -183,38 +787,58 @@ mod gen_format {
     }
 
     impl Meta {
-        pub fn gen(_cfg: &TestdataCfg) -> Self {
-            static SERVICES: OnceLock<Vec<String>> = OnceLock::new();
-            static ENVS: OnceLock<Vec<String>> = OnceLock::new();
-            static USERS: OnceLock<Vec<String>> = OnceLock::new();
-
-            /// Gets a random element from a oncelock list
-            fn get_once_list_elem(
-                s: &OnceLock<Vec<String>>,
-                count: usize,
-                elem_len: std::ops::Range<usize>,
-            ) -> String {
-                let idx = thread_rng().gen_range(0..count);
-                s.get_or_init(move || {
-                    let mut v = vec![];
-                    for _ in 0..count {
-                        let s_len = thread_rng().gen_range(elem_len.clone());
-                        let s = thread_rng()
-                            .sample_iter(&Alphanumeric)
-                            .take(s_len)
-                            .map(char::from)
-                            .collect();
-                        v.push(s);
-                    }
-                    v
-                })[idx]
-                    .clone()
+        pub fn gen(_cfg: &TestdataCfg, rng: &mut impl Rng, names: &Names) -> Self {
+            let service = match &names.service_weights {
+                None => names.services.choose(rng).unwrap(),
+                Some(weights) => {
+                    let x = rng.gen::<f64>() * weights.last().unwrap();
+                    // Rounding can leave `x` past the last total
+                    let i = weights.partition_point(|&total| total <= x);
+                    &names.services[i.min(names.services.len() - 1)]
+                }
+            };
+            Self {
+                service: service.clone(),
+                env: names.envs.choose(rng).unwrap().clone(),
+                user: names.users.choose(rng).unwrap().clone(),
             }
+        }
+    }
 
-            Self {
-                service: get_once_list_elem(&SERVICES, 900, 3..6),
-                env: get_once_list_elem(&ENVS, 3, 3..6),
-                user: get_once_list_elem(&USERS, 1000, 5..15),
+    /// How a field of a line is missing
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub(super) enum Missing {
+        /// The field isn't there at all
+        Omitted,
+        /// The field is `null`
+        Null,
+    }
+
+    impl Missing {
+        /// Picks whether a field is missing, with the chances `omit_rate` and `null_rate`.
+        /// Doesn't use `rng` if both are `0`, so that the same seed still generates the same lines
+        fn gen(rng: &mut impl Rng, omit_rate: f64, null_rate: f64) -> Option<Self> {
+            if omit_rate == 0.0 && null_rate == 0.0 {
+                return None;
+            }
+            let x = rng.gen::<f64>();
+            if x < omit_rate {
+                Some(Self::Omitted)
+            } else if x < omit_rate + null_rate {
+                Some(Self::Null)
+            } else {
+                None
+            }
+        }
+
+        /// Removes `field` from `obj`, or sets it to `null`
+        fn apply(missing: Option<Self>, obj: &mut JsonValue, field: &str) {
+            match missing {
+                Some(Self::Omitted) => {
+                    obj.remove(field);
+                }
+                Some(Self::Null) => obj[field] = JsonValue::Null,
+                None => {}
             }
         }
     }
@@ -223,38 +847,623 @@ mod gen_format {
     pub(super) struct FullLine {
         message: String,
         timestamp: Timestamp,
-        level: Level,
+        level: String,
         meta: Meta,
+        pub missing_service: Option<Missing>,
+        pub missing_env: Option<Missing>,
+        pub missing_timestamp: Option<Missing>,
     }
 
     impl FullLine {
         /// Generates a random line given the context
-        pub fn generate(cfg: &TestdataCfg, date: NaiveDate) -> Self {
-            let msg_len = thread_rng().gen_range(10..100);
+        pub fn generate(
+            cfg: &TestdataCfg,
+            date: NaiveDate,
+            rng: &mut impl Rng,
+            names: &Names,
+        ) -> Self {
             Self {
-                message: thread_rng()
-                    .sample_iter(&Alphanumeric)
-                    .take(msg_len)
-                    .map(char::from)
-                    .collect(),
-                timestamp: Timestamp::gen(cfg, date),
-                level: thread_rng().gen(),
-                meta: Meta::gen(cfg),
+                message: gen_message(&cfg.message, rng),
+                timestamp: Timestamp::gen(cfg, date, rng),
+                level: gen_level(cfg, rng),
+                meta: Meta::gen(cfg, rng, names),
+                missing_service: Missing::gen(rng, cfg.omit_service_rate, cfg.null_service_rate),
+                missing_env: Missing::gen(rng, cfg.omit_env_rate, cfg.null_env_rate),
+                missing_timestamp: Missing::gen(
+                    rng,
+                    cfg.omit_timestamp_rate,
+                    cfg.null_timestamp_rate,
+                ),
+            }
+        }
+        /// The service of this line, unless it's missing
+        pub fn service(&self) -> Option<&str> {
+            match self.missing_service {
+                None => Some(&self.meta.service),
+                Some(_) => None,
             }
         }
+        pub fn level(&self) -> &str {
+            &self.level
+        }
+        /// The key which the splitter gives this line, using its own date logic
+        pub fn key(&self) -> Option<MsgKey> {
+            if self.is_keyless() {
+                return None;
+            }
+            default_key(&json::object! {
+                "@timestamp": self.timestamp.t.clone(),
+                "@meta": {
+                    service: self.meta.service.clone(),
+                    env: self.meta.env.clone(),
+                }
+            })
+        }
+        /// Whether any of the fields which lines are keyed by is missing
+        pub fn is_keyless(&self) -> bool {
+            self.missing_service.is_some()
+                || self.missing_env.is_some()
+                || self.missing_timestamp.is_some()
+        }
         pub fn to_json(&self) -> String {
-            let j = json::object! {
+            let mut j = json::object! {
                 message: self.message.clone(),
-                "@timestamp": self.timestamp.to_string(),
-                level: self.level.to_string(),
+                "@timestamp": self.timestamp.t.clone(),
+                level: self.level.clone(),
                 "@meta": {
                     service: self.meta.service.clone(),
                     env: self.meta.env.clone(),
                     user: self.meta.user.clone(),
                 }
             };
+            Missing::apply(self.missing_service, &mut j["@meta"], "service");
+            Missing::apply(self.missing_env, &mut j["@meta"], "env");
+            Missing::apply(self.missing_timestamp, &mut j, "@timestamp");
 
             j.dump()
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use json::JsonValue;
+
+    use flate2::{read::MultiGzDecoder, Compression};
+
+    use crate::{
+        data::{line_date, LineData},
+        filter::LineFilter,
+        test_utils::read_lines,
+        ReadError,
+    };
+
+    use super::{
+        generate_testdata, DateOrder, KeyDistribution, MessageCharset, MessageLength, MessageSpec,
+        TestdataCfg, TimestampFormat, SEGMENT_LINES,
+    };
+
+    /// Generates `lines` lines with `seed`, returning the gzipped and the plain output
+    fn generate(seed: Option<u64>, lines: usize) -> (Vec<u8>, String) {
+        let (mut enc, mut dbg) = (vec![], vec![]);
+        generate_testdata(
+            TestdataCfg {
+                lines,
+                seed,
+                ..Default::default()
+            },
+            &mut enc,
+            Some(&mut dbg),
+        )
+        .unwrap();
+        (enc, String::from_utf8(dbg).unwrap())
+    }
+
+    #[test]
+    fn test_seeded_golden() {
+        // Regenerate with `logsplitter2 generate --seed 42 --lines 5`, which writes `example_sets/rand/input.json`.
+        // Only changes if the generator (or the algorithms of `rand`) change
+        const GOLDEN: &str = include_str!("../example_sets/testdata_seed_42.json");
+        assert_eq!(generate(Some(42), 5).1, GOLDEN);
+    }
+
+    /// The distinct values of `@meta.<field>` in `output`
+    fn distinct(output: &str, field: &str) -> HashSet<String> {
+        output
+            .lines()
+            .map(|ln| json::parse(ln).unwrap()["@meta"][field].to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_cardinalities() {
+        for (services, envs) in [(1, 1), (7, 2), (300, 3)] {
+            let mut cfg = TestdataCfg {
+                lines: 3_000,
+                seed: Some(0),
+                ..Default::default()
+            };
+            // Names of length 1 only allow for 62 services
+            cfg.set_services(services, 1..4).set_envs(envs, 1..2);
+            let (mut enc, mut dbg) = (vec![], vec![]);
+            generate_testdata(cfg, &mut enc, Some(&mut dbg)).unwrap();
+
+            let output = String::from_utf8(dbg).unwrap();
+            assert_eq!(distinct(&output, "service").len(), services);
+            assert_eq!(distinct(&output, "env").len(), envs);
+        }
+    }
+
+    #[test]
+    fn test_missing_fields() {
+        let (mut enc, mut dbg) = (vec![], vec![]);
+        let stats = generate_testdata(
+            TestdataCfg {
+                lines: 2_000,
+                seed: Some(1),
+                omit_service_rate: 0.05,
+                null_env_rate: 0.1,
+                omit_timestamp_rate: 0.02,
+                null_timestamp_rate: 0.03,
+                ..Default::default()
+            },
+            &mut enc,
+            Some(&mut dbg),
+        )
+        .unwrap();
+        let output = String::from_utf8(dbg).unwrap();
+
+        let count = |f: &dyn Fn(&JsonValue) -> bool| {
+            output
+                .lines()
+                .filter(|ln| f(&json::parse(ln).unwrap()))
+                .count()
+        };
+        assert_eq!(
+            stats.omitted_service,
+            count(&|j| !j["@meta"].has_key("service"))
+        );
+        assert_eq!(stats.null_service, 0);
+        assert_eq!(stats.omitted_env, 0);
+        assert_eq!(
+            stats.null_env,
+            count(&|j| j["@meta"]["env"].is_null() && j["@meta"].has_key("env"))
+        );
+        assert_eq!(
+            stats.omitted_timestamp,
+            count(&|j| !j.has_key("@timestamp"))
+        );
+        assert_eq!(
+            stats.null_timestamp,
+            count(&|j| j["@timestamp"].is_null() && j.has_key("@timestamp"))
+        );
+        for n in [
+            stats.omitted_service,
+            stats.null_env,
+            stats.omitted_timestamp,
+            stats.null_timestamp,
+        ] {
+            assert!(n > 0);
+        }
+
+        // Exactly the lines with a missing field have no key
+        let keyless = output
+            .lines()
+            .filter(|ln| {
+                matches!(
+                    LineData::parse(ln.to_string()),
+                    Err(ReadError::NoKey { .. })
+                )
+            })
+            .count();
+        assert_eq!(stats.keyless, keyless);
+        assert_eq!(stats.lines, 2_000);
+    }
+
+    #[test]
+    fn test_zipf_distribution() {
+        /// The share of lines which the busiest service has
+        fn top_share(key_distribution: KeyDistribution) -> f64 {
+            let mut cfg = TestdataCfg {
+                lines: 5_000,
+                seed: Some(3),
+                key_distribution,
+                ..Default::default()
+            };
+            cfg.set_services(50, 3..6);
+            let stats = generate_testdata(cfg, &mut std::io::sink(), None).unwrap();
+            assert_eq!(stats.service_lines.values().sum::<usize>(), 5_000);
+            *stats.service_lines.values().max().unwrap() as f64 / 5_000.0
+        }
+
+        // Uniformly, every service has about 2% of the lines
+        assert!(top_share(KeyDistribution::Uniform) < 0.04);
+        // The first service has 1 / (1^-1.2 + 2^-1.2 + ... + 50^-1.2), or about 30%
+        let zipf = top_share(KeyDistribution::Zipf { exponent: 1.2 });
+        assert!((0.25..0.35).contains(&zipf), "{zipf}");
+        assert!(top_share(KeyDistribution::Zipf { exponent: 0.0 }) < 0.04);
+    }
+
+    #[test]
+    fn test_date_order() {
+        /// The date of each generated line, in order
+        fn dates(date_order: DateOrder, seed: u64) -> Vec<chrono::NaiveDate> {
+            let mut cfg = TestdataCfg {
+                lines: 2_000,
+                seed: Some(seed),
+                date_order,
+                ..Default::default()
+            };
+            cfg.set_unique_dates(40);
+            let (mut enc, mut dbg) = (vec![], vec![]);
+            generate_testdata(cfg, &mut enc, Some(&mut dbg)).unwrap();
+            String::from_utf8(dbg)
+                .unwrap()
+                .lines()
+                .map(|ln| LineData::parse(ln.to_string()).unwrap().key().date())
+                .collect()
+        }
+
+        let sequential = dates(DateOrder::Sequential, 0);
+        assert!(sequential.is_sorted());
+        let mut sorted = sequential.clone();
+        sorted.dedup();
+        assert_eq!(sorted.len(), 40);
+
+        let shuffled = dates(DateOrder::Shuffled, 0);
+        assert_eq!(shuffled, dates(DateOrder::Shuffled, 0));
+        assert_ne!(shuffled, dates(DateOrder::Shuffled, 1));
+        // Most lines are a different date than the line before them
+        let changes = shuffled.windows(2).filter(|w| w[0] != w[1]).count();
+        assert!(changes > 1_500, "{changes}");
+
+        let window = 20;
+        let interleaved = dates(DateOrder::Interleaved { window }, 0);
+        assert_eq!(interleaved, dates(DateOrder::Interleaved { window }, 0));
+        assert!(!interleaved.is_sorted());
+        // Lines only move by less than `window`, so lines at least twice that far apart are in order
+        for (i, date) in interleaved.iter().enumerate().skip(2 * window) {
+            assert!(*date >= interleaved[i - 2 * window]);
+        }
+
+        // The same lines per date, just in a different order
+        for mut other in [shuffled, interleaved] {
+            other.sort();
+            assert_eq!(other, sequential);
+        }
+    }
+
+    #[test]
+    fn test_compression_level() {
+        let generate_with = |level| {
+            let mut enc = vec![];
+            let stats = generate_testdata(
+                TestdataCfg {
+                    lines: 1_000,
+                    seed: Some(4),
+                    compression: Compression::new(level),
+                    ..Default::default()
+                },
+                &mut enc,
+                None,
+            )
+            .unwrap();
+            assert_eq!(stats.bytes_compressed, enc.len() as u64);
+            (enc, stats)
+        };
+
+        let (stored, stored_stats) = generate_with(0);
+        let (best, best_stats) = generate_with(9);
+        assert!(
+            best.len() * 2 < stored.len(),
+            "{} {}",
+            best.len(),
+            stored.len()
+        );
+        // Stored blocks still have some overhead
+        assert!(stored_stats.bytes_compressed > stored_stats.bytes_plain);
+
+        // The same lines either way
+        let (_, plain) = generate(Some(4), 1_000);
+        assert_eq!(stored_stats.bytes_plain, plain.len() as u64);
+        assert_eq!(best_stats.bytes_plain, plain.len() as u64);
+        for enc in [stored, best] {
+            assert_eq!(
+                read_lines(MultiGzDecoder::new(&enc[..])).join("\n") + "\n",
+                plain
+            );
+        }
+    }
+
+    #[test]
+    fn test_message_spec() {
+        /// The messages of 2000 lines generated with `message`
+        fn messages(message: MessageSpec) -> Vec<String> {
+            let cfg = TestdataCfg {
+                lines: 2_000,
+                seed: Some(6),
+                message,
+                ..Default::default()
+            };
+            let mut dbg = vec![];
+            generate_testdata(cfg, &mut std::io::sink(), Some(&mut dbg)).unwrap();
+            String::from_utf8(dbg)
+                .unwrap()
+                .lines()
+                .map(|ln| json::parse(ln).unwrap()["message"].to_string())
+                .collect()
+        }
+
+        let unicode = messages(MessageSpec {
+            charset: MessageCharset::Unicode,
+            ..Default::default()
+        });
+        assert!(unicode
+            .iter()
+            .all(|m| (10..100).contains(&m.chars().count())));
+        // Every block shows up, from 1 to 4 bytes per character
+        for len in 1..=4 {
+            assert!(unicode
+                .iter()
+                .any(|m| m.chars().any(|c| c.len_utf8() == len)));
+        }
+
+        let log_like = messages(MessageSpec {
+            charset: MessageCharset::LogLike,
+            ..Default::default()
+        });
+        for c in ['"', '\\', '\t', '{'] {
+            assert!(log_like.iter().any(|m| m.contains(c)), "{c:?}");
+        }
+
+        let lengths = |length| {
+            let mut lengths = messages(MessageSpec {
+                length,
+                ..Default::default()
+            })
+            .iter()
+            .map(|m| m.len())
+            .collect::<Vec<_>>();
+            lengths.sort();
+            lengths
+        };
+        let one_of = lengths(MessageLength::OneOf(vec![3, 3, 3, 7]));
+        assert!(one_of.iter().all(|&l| l == 3 || l == 7));
+        assert_eq!(one_of[1_400], 3);
+        assert_eq!(one_of[1_600], 7);
+        let log_normal = lengths(MessageLength::LogNormal {
+            median: 50.0,
+            sigma: 1.0,
+        });
+        assert!(
+            (45..55).contains(&log_normal[1_000]),
+            "{}",
+            log_normal[1_000]
+        );
+        // e^(2 sigma) times the median for the top 2.3%
+        assert!(log_normal[1_960] > 300, "{}", log_normal[1_960]);
+
+        let templated = messages(MessageSpec {
+            templates: vec![
+                "GET /api/{} 200 {n}ms".to_string(),
+                "{not a fill}".to_string(),
+            ],
+            ..Default::default()
+        });
+        for m in &templated {
+            if m == "{not a fill}" {
+                continue;
+            }
+            let rest = m.strip_prefix("GET /api/").unwrap();
+            let (word, rest) = rest.split_once(' ').unwrap();
+            assert!(
+                (1..=8).contains(&word.len()) && word.chars().all(|c| c.is_ascii_alphanumeric())
+            );
+            let ms = rest
+                .strip_prefix("200 ")
+                .unwrap()
+                .strip_suffix("ms")
+                .unwrap();
+            assert!(ms.parse::<u32>().unwrap() < 1000);
+        }
+        assert!(templated.iter().any(|m| m == "{not a fill}"));
+    }
+
+    #[test]
+    fn test_timestamps_within_day() {
+        let mut cfg = TestdataCfg {
+            lines: 10_000,
+            seed: Some(5),
+            ..Default::default()
+        };
+        cfg.set_start_date(2024, 10, 20).set_unique_dates(1);
+        let mut dbg = vec![];
+        generate_testdata(cfg, &mut std::io::sink(), Some(&mut dbg)).unwrap();
+
+        let day = chrono::NaiveDate::from_ymd_opt(2024, 10, 20).unwrap();
+        let mut local_dates = HashSet::new();
+        for ln in String::from_utf8(dbg).unwrap().lines() {
+            let timestamp = json::parse(ln).unwrap()["@timestamp"].to_string();
+            local_dates.insert(timestamp[..10].to_string());
+            assert_eq!(line_date(&json::parse(ln).unwrap()), Some(day), "{ln}");
+        }
+        // Even though timestamps are displayed in timezones where it's already (or still) another day
+        assert_eq!(local_dates.len(), 3);
+    }
+
+    #[test]
+    fn test_timestamp_formats() {
+        let day = chrono::NaiveDate::from_ymd_opt(2024, 10, 20).unwrap();
+        // Checks the UTC date of every generated line, decoded according to the json type of its timestamp
+        let generate = |timestamp_format| {
+            let mut cfg = TestdataCfg {
+                lines: 1_000,
+                timestamp_format,
+                seed: Some(6),
+                ..Default::default()
+            };
+            cfg.set_start_date(2024, 10, 20).set_unique_dates(1);
+            let mut dbg = vec![];
+            let stats = generate_testdata(cfg, &mut std::io::sink(), Some(&mut dbg)).unwrap();
+            let mut kinds = HashSet::new();
+            for ln in String::from_utf8(dbg).unwrap().lines() {
+                let info = json::parse(ln).unwrap();
+                let date = match &info["@timestamp"] {
+                    JsonValue::Number(n) => {
+                        let n = f64::from(*n) as i64;
+                        // Seconds of 2024 have 10 digits, and milliseconds 13
+                        let t = match n < 10_000_000_000 {
+                            true => {
+                                kinds.insert("seconds");
+                                chrono::DateTime::from_timestamp(n, 0)
+                            }
+                            false => {
+                                kinds.insert("millis");
+                                chrono::DateTime::from_timestamp_millis(n)
+                            }
+                        };
+                        t.unwrap().date_naive()
+                    }
+                    _ => {
+                        kinds.insert("string");
+                        line_date(&info).unwrap()
+                    }
+                };
+                assert_eq!(date, day, "{ln}");
+            }
+            (stats, kinds)
+        };
+
+        // String timestamps are read by the splitter
+        for format in [
+            TimestampFormat::Rfc3339,
+            TimestampFormat::Custom("%Y-%m-%d %H:%M:%S%.3f".to_string()),
+            TimestampFormat::Custom("%Y-%m-%dT%H:%M:%S%:z".to_string()),
+        ] {
+            let (stats, kinds) = generate(format);
+            assert_eq!(kinds, HashSet::from(["string"]));
+            assert_eq!(stats.keyless, 0);
+            assert_eq!(stats.key_lines.values().sum::<u64>(), 1_000);
+        }
+        // Numbers aren't, so those lines have no key
+        for (format, kind) in [
+            (TimestampFormat::EpochSeconds, "seconds"),
+            (TimestampFormat::EpochMillis, "millis"),
+        ] {
+            let (stats, kinds) = generate(format);
+            assert_eq!(kinds, HashSet::from([kind]));
+            assert_eq!(stats.keyless, 1_000);
+            assert!(stats.key_lines.is_empty());
+        }
+        let (stats, kinds) = generate(TimestampFormat::Mixed(vec![
+            (TimestampFormat::Rfc3339, 8.0),
+            (TimestampFormat::EpochSeconds, 1.0),
+            (TimestampFormat::EpochMillis, 1.0),
+        ]));
+        assert_eq!(kinds, HashSet::from(["string", "seconds", "millis"]));
+        assert!((700..900).contains(&stats.key_lines.values().sum::<u64>()));
+    }
+
+    #[test]
+    fn test_levels() {
+        let generate = |levels: &[(&str, f64)]| {
+            let cfg = TestdataCfg {
+                lines: 2_000,
+                levels: levels.iter().map(|&(l, w)| (l.to_string(), w)).collect(),
+                seed: Some(2),
+                ..Default::default()
+            };
+            let mut dbg = vec![];
+            let stats = generate_testdata(cfg, &mut std::io::sink(), Some(&mut dbg)).unwrap();
+            (stats, String::from_utf8(dbg).unwrap())
+        };
+
+        let (stats, output) = generate(&[("info", 0.9), ("debug", 0.08), ("error", 0.02)]);
+        assert_eq!(stats.level_lines.values().sum::<usize>(), 2_000);
+        assert!((1_700..1_900).contains(&stats.level_lines["info"]));
+        assert!((10..70).contains(&stats.level_lines["error"]));
+        // The counts are exact, so a filter keeps exactly as many lines
+        let filter = LineFilter::new(vec!["level=error".parse().unwrap()]);
+        let kept = output
+            .lines()
+            .map(|ln| json::parse(ln).unwrap())
+            .filter(|info| filter.matches(info, line_date(info).unwrap()))
+            .count();
+        assert_eq!(kept, stats.level_lines["error"]);
+
+        // Levels with no weight are never picked
+        let (stats, _) = generate(&[("info", 1.0), ("fatal", 0.0)]);
+        assert_eq!(stats.level_lines.keys().collect::<Vec<_>>(), ["info"]);
+        let (stats, _) = generate(&[("warn", 2.0), ("warn2", 2.0)]);
+        assert_eq!(stats.level_lines.len(), 2);
+    }
+
+    #[test]
+    #[should_panic = "Only 62 distinct names"]
+    fn test_too_many_names() {
+        let mut cfg = TestdataCfg {
+            lines: 1,
+            ..Default::default()
+        };
+        cfg.set_envs(63, 1..2);
+        generate_testdata(cfg, &mut std::io::sink(), None).unwrap();
+    }
+
+    #[test]
+    fn test_gen_threads() {
+        let generate = |gen_threads| {
+            let (mut enc, mut dbg) = (vec![], vec![]);
+            let stats = generate_testdata(
+                TestdataCfg {
+                    // Members end in the middle of segments
+                    lines: 5 * SEGMENT_LINES + 100,
+                    gzip_members: 2,
+                    gen_threads,
+                    message: MessageSpec {
+                        length: MessageLength::Uniform(1..5),
+                        ..Default::default()
+                    },
+                    seed: Some(3),
+                    ..Default::default()
+                },
+                &mut enc,
+                Some(&mut dbg),
+            )
+            .unwrap();
+            (enc, String::from_utf8(dbg).unwrap(), stats)
+        };
+
+        let (enc_1, plain_1, stats_1) = generate(1);
+        let (enc_4, plain_4, stats_4) = generate(4);
+        // The same lines in the same order, only compressed into more members
+        assert_eq!(plain_1, plain_4);
+        assert_eq!(
+            read_lines(plain_1.as_bytes()),
+            read_lines(MultiGzDecoder::new(&enc_4[..]))
+        );
+        assert_ne!(enc_1, enc_4);
+        assert_eq!(
+            super::TestdataStats {
+                bytes_compressed: 0,
+                ..stats_1
+            },
+            super::TestdataStats {
+                bytes_compressed: 0,
+                ..stats_4
+            }
+        );
+        assert_eq!(stats_4.bytes_compressed, enc_4.len() as u64);
+        // Deterministic regardless of how the threads are scheduled
+        assert_eq!(generate(4).0, enc_4);
+    }
+
+    #[test]
+    fn test_seeded_reproducible() {
+        let a = generate(Some(7), 2_000);
+        assert_eq!(a, generate(Some(7), 2_000));
+        assert_ne!(a.1, generate(Some(8), 2_000).1);
+        assert_ne!(generate(None, 100).1, generate(None, 100).1);
+    }
+}
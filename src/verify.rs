@@ -0,0 +1,256 @@
+//! Checks that the files of an output directory still match its [manifest](crate::manifest).
+//!
+//! Every complete file listed in the manifest is read back in full, and its line count and size are compared to the manifest's.
+//! Files which the manifest marks as incomplete are skipped, since nothing is known about what they should contain
+
+use std::{
+    fmt::Display,
+    io,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    manifest::{FileFormat, Manifest, ManifestEntry, MANIFEST_FILE_NAME},
+    merge::{merge, MergeOrder},
+    Error, ReadError,
+};
+
+/// What is wrong with a file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    Missing,
+    /// The file couldn't be read in full, such as because of corrupt compressed data
+    Unreadable(String),
+    Lines {
+        expected: u64,
+        actual: u64,
+    },
+    Bytes {
+        expected: u64,
+        actual: u64,
+    },
+}
+
+/// A file which doesn't match its manifest entry
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mismatch {
+    /// The file name, relative to the output directory
+    pub file: String,
+    pub problem: Problem,
+}
+
+impl Display for Mismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let file = &self.file;
+        match &self.problem {
+            Problem::Missing => write!(f, "{file}: missing"),
+            Problem::Unreadable(e) => write!(f, "{file}: could not be read: {e}"),
+            Problem::Lines { expected, actual } => {
+                write!(f, "{file}: expected {expected} lines, found {actual}")
+            }
+            Problem::Bytes { expected, actual } => {
+                write!(f, "{file}: expected {expected} bytes, found {actual}")
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// How many files were checked
+    pub checked: usize,
+    /// Files which the manifest marks as incomplete, and so weren't checked
+    pub skipped: Vec<String>,
+    pub mismatches: Vec<Mismatch>,
+}
+
+impl VerifyReport {
+    /// Whether every checked file matched the manifest
+    pub fn is_ok(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+}
+
+/// Checks every file listed in the manifest of the output directory `dir`.
+///
+/// Fails only if the manifest itself can't be read. Problems with the files are listed in the report
+pub fn verify(dir: &Path) -> Result<VerifyReport, Error> {
+    let manifest = Manifest::read(dir).map_err(|e| Error::io(dir.join(MANIFEST_FILE_NAME), e))?;
+
+    let mut report = VerifyReport::default();
+    for entry in manifest.files {
+        if !entry.complete {
+            report.skipped.push(entry.file);
+            continue;
+        }
+        report.checked += 1;
+        report
+            .mismatches
+            .extend(check_file(dir, &entry).into_iter().map(|problem| Mismatch {
+                file: entry.file.clone(),
+                problem,
+            }));
+    }
+    Ok(report)
+}
+
+/// Every way in which the file of `entry` doesn't match it
+fn check_file(dir: &Path, entry: &ManifestEntry) -> Vec<Problem> {
+    let path = dir.join(&entry.file);
+    let bytes = match std::fs::metadata(&path) {
+        Ok(meta) => meta.len(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return vec![Problem::Missing],
+        Err(e) => return vec![Problem::Unreadable(e.to_string())],
+    };
+
+    let mut problems = vec![];
+    match count_lines(path, entry.format) {
+        Ok(lines) if lines != entry.lines => problems.push(Problem::Lines {
+            expected: entry.lines,
+            actual: lines,
+        }),
+        Ok(_) => {}
+        Err(e) => problems.push(Problem::Unreadable(e)),
+    }
+    if bytes != entry.bytes {
+        problems.push(Problem::Bytes {
+            expected: entry.bytes,
+            actual: bytes,
+        });
+    }
+    problems
+}
+
+/// The number of lines (or rows) in the file at `path`.
+///
+/// Json lines are read the same way as when [merging](crate::merge) output files,
+/// so invalid lines still count, but corrupt compressed data doesn't
+fn count_lines(path: PathBuf, format: FileFormat) -> Result<u64, String> {
+    match format {
+        FileFormat::Gzip | FileFormat::Plain => {
+            let mut lines = 0;
+            for line in merge(&[path], MergeOrder::Concatenate).map_err(|e| e.to_string())? {
+                match line {
+                    Ok(_) | Err(ReadError::InvalidLine { .. } | ReadError::NoKey { .. }) => {
+                        lines += 1
+                    }
+                    Err(e) => return Err(e.to_string()),
+                }
+            }
+            Ok(lines)
+        }
+        #[cfg(feature = "parquet")]
+        FileFormat::Parquet => {
+            use parquet::file::reader::{FileReader, SerializedFileReader};
+
+            let f = std::fs::File::open(path).map_err(|e| e.to_string())?;
+            let reader = SerializedFileReader::new(f).map_err(|e| e.to_string())?;
+            Ok(reader.metadata().file_metadata().num_rows() as u64)
+        }
+        #[cfg(not(feature = "parquet"))]
+        FileFormat::Parquet => Err("parquet support isn't compiled in".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempdir::TempDir;
+
+    use crate::{
+        manifest::{Manifest, MANIFEST_FILE_NAME},
+        run,
+        test_utils::{line, output_file, write_input},
+        ErrorKind, OutputTarget, RunCfg, Threads,
+    };
+
+    use super::{verify, Mismatch, Problem};
+
+    #[test]
+    fn test_verify() {
+        let tmp = TempDir::new("logsplitter2").unwrap();
+        let out = tmp.path().join("out");
+        let input = tmp.path().join("input.json.gz");
+
+        let lines = ["a", "b", "c", "d"]
+            .iter()
+            .flat_map(|s| (0..3).map(move |i| line(s, &i.to_string())))
+            .collect::<Vec<_>>();
+        write_input(&input, &lines);
+        run(RunCfg {
+            input_files: vec![input],
+            output: OutputTarget::Dir(out.clone()),
+            output_threads: Threads::Fixed(2),
+            ..Default::default()
+        })
+        .unwrap();
+
+        let report = verify(&out).unwrap();
+        assert!(report.is_ok());
+        assert_eq!(report.checked, 4);
+
+        let mismatch = |service, problem| Mismatch {
+            file: output_file(&out, service)
+                .file_name()
+                .unwrap()
+                .to_string_lossy()
+                .into_owned(),
+            problem,
+        };
+
+        // A file with a line missing
+        write_input(&output_file(&out, "a"), &[line("a", "0"), line("a", "1")]);
+        let a_len = std::fs::metadata(output_file(&out, "a")).unwrap().len();
+        // A file which isn't gzip anymore
+        std::fs::write(output_file(&out, "b"), "garbage").unwrap();
+        std::fs::remove_file(output_file(&out, "c")).unwrap();
+
+        let mut manifest = Manifest::read(&out).unwrap();
+        let expected_a_bytes = manifest.files[0].bytes;
+        // Incomplete files aren't checked
+        manifest.files[3].complete = false;
+        manifest.write(&out).unwrap();
+
+        let report = verify(&out).unwrap();
+        assert_eq!(report.checked, 3);
+        assert_eq!(report.skipped, [manifest.files[3].file.clone()]);
+        assert_eq!(report.mismatches.len(), 5);
+        assert_eq!(
+            report.mismatches[..2],
+            [
+                mismatch(
+                    "a",
+                    Problem::Lines {
+                        expected: 3,
+                        actual: 2
+                    }
+                ),
+                mismatch(
+                    "a",
+                    Problem::Bytes {
+                        expected: expected_a_bytes,
+                        actual: a_len
+                    }
+                ),
+            ]
+        );
+        assert!(matches!(
+            report.mismatches[2].problem,
+            Problem::Unreadable(_)
+        ));
+        assert!(matches!(
+            report.mismatches[3].problem,
+            Problem::Bytes { actual: 7, .. }
+        ));
+        assert_eq!(report.mismatches[4], mismatch("c", Problem::Missing));
+        assert_eq!(
+            report.mismatches[4].to_string(),
+            "c_prod_2024-10-20.json.gz: missing"
+        );
+
+        std::fs::remove_file(out.join(MANIFEST_FILE_NAME)).unwrap();
+        assert!(matches!(
+            verify(&out).unwrap_err().kind(),
+            ErrorKind::Io { .. }
+        ));
+    }
+}
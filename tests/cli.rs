@@ -0,0 +1,106 @@
+//! Running the command line binary, to check what only it does, such as streaming every kept line to stdout
+//! and taking options from its environment
+
+use std::{fs::File, io::Read, process::Command};
+
+use flate2::read::MultiGzDecoder;
+use logsplitter2::testdata_gen::{generate_testdata, TestdataCfg};
+use tempdir::TempDir;
+
+#[test]
+fn test_stdout_output() {
+    let tmp = TempDir::new("logsplitter2_cli").unwrap();
+    let input = tmp.path().join("input.json.gz");
+    let mut cfg = TestdataCfg {
+        lines: 2_000,
+        seed: Some(11),
+        ..Default::default()
+    };
+    cfg.set_unique_dates(3)
+        .set_services(4, 3..6)
+        .set_envs(2, 3..6);
+    let mut plain = vec![];
+    generate_testdata(cfg, &mut File::create(&input).unwrap(), Some(&mut plain)).unwrap();
+    let input_lines = String::from_utf8(plain).unwrap();
+
+    // The kept lines come out in the order of the input, without being split
+    let service = |line: &str| json::parse(line).unwrap()["@meta"]["service"].to_string();
+    let kept = service(input_lines.lines().next().unwrap());
+    let expected = input_lines
+        .lines()
+        .filter(|line| service(line) == kept)
+        .map(|line| format!("{line}\n"))
+        .collect::<String>();
+    assert!(expected.len() < input_lines.len());
+
+    let split = |args: &[&str]| {
+        let out = Command::new(env!("CARGO_BIN_EXE_logsplitter2"))
+            .arg("--input")
+            .arg(&input)
+            .args(["--output", "-", "--filter", &format!("service={kept}")])
+            .args(args)
+            .current_dir(tmp.path())
+            .output()
+            .unwrap();
+        assert!(
+            out.status.success(),
+            "{}",
+            String::from_utf8_lossy(&out.stderr)
+        );
+        out.stdout
+    };
+
+    assert_eq!(String::from_utf8(split(&[])).unwrap(), expected);
+
+    let mut decoded = String::new();
+    MultiGzDecoder::new(&split(&["--gzip"])[..])
+        .read_to_string(&mut decoded)
+        .unwrap();
+    assert_eq!(decoded, expected);
+
+    // Nothing is written besides the input
+    assert_eq!(std::fs::read_dir(tmp.path()).unwrap().count(), 1);
+}
+
+#[test]
+fn test_flag_overrides_conflicting_env() {
+    let tmp = TempDir::new("logsplitter2_cli").unwrap();
+    let input = tmp.path().join("input.json.gz");
+    let cfg = TestdataCfg {
+        lines: 500,
+        seed: Some(5),
+        ..Default::default()
+    };
+    generate_testdata(cfg, &mut File::create(&input).unwrap(), None).unwrap();
+    let out = tmp.path().join("out");
+
+    let split = |args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_logsplitter2"))
+            .arg("--input")
+            .arg(&input)
+            .arg("--output")
+            .arg(&out)
+            .args(args)
+            .env("LOGSPLITTER_APPEND", "true")
+            .output()
+            .unwrap()
+    };
+
+    // `--write-index` can't be used with `append`, so it overrides the environment's
+    let run = split(&["--write-index"]);
+    assert!(
+        run.status.success(),
+        "{}",
+        String::from_utf8_lossy(&run.stderr)
+    );
+    let names = std::fs::read_dir(&out)
+        .unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .collect::<Vec<_>>();
+    assert!(names.iter().any(|name| name.ends_with(".idx")), "{names:?}");
+
+    // Conflicting flags are still an error
+    let run = split(&["--write-index", "--append"]);
+    assert!(!run.status.success());
+    assert!(String::from_utf8_lossy(&run.stderr).contains("`append` and `write-index`"));
+}
@@ -0,0 +1,26 @@
+//! Helpers shared by the integration tests
+
+use std::{collections::BTreeMap, fs::File, io::Read, path::Path};
+
+use flate2::read::MultiGzDecoder;
+use logsplitter2::{lock::LOCK_FILE_NAME, manifest::MANIFEST_FILE_NAME};
+
+/// The decompressed text of every output file in `dir`, by the file's key
+pub fn read_output(dir: &Path) -> BTreeMap<String, String> {
+    let mut output = BTreeMap::new();
+    for entry in std::fs::read_dir(dir).unwrap() {
+        let name = entry.unwrap().file_name().into_string().unwrap();
+        if name == MANIFEST_FILE_NAME || name == LOCK_FILE_NAME {
+            continue;
+        }
+        let key = name
+            .strip_suffix(".json.gz")
+            .unwrap_or_else(|| panic!("Unexpected output file {name}"));
+        let mut text = String::new();
+        MultiGzDecoder::new(File::open(dir.join(&name)).unwrap())
+            .read_to_string(&mut text)
+            .unwrap();
+        output.insert(key.to_string(), text);
+    }
+    output
+}
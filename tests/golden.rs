@@ -0,0 +1,117 @@
+//! Splitting fixed input with a fixed configuration, and comparing every output file against the checked-in
+//! goldens in `tests/goldens/split`, to catch any change in key naming, newline handling, or line order.
+//!
+//! Run with `UPDATE_GOLDENS=1` to write the current output as the new goldens instead of comparing
+
+mod common;
+
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    path::{Path, PathBuf},
+};
+
+use logsplitter2::{
+    file_pool::ExistingFilePolicy,
+    run,
+    testdata_gen::{generate_testdata, TestdataCfg},
+    OutputTarget, RunCfg, Threads,
+};
+use tempdir::TempDir;
+
+use common::read_output;
+
+/// Each output file is checked in decompressed, named by its key, since the gzip headers hold the run's start time
+fn goldens_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/goldens/split")
+}
+
+/// The checked-in text of every key, as written by [`write_goldens`]
+fn read_goldens(dir: &Path) -> BTreeMap<String, String> {
+    std::fs::read_dir(dir)
+        .unwrap_or_else(|e| {
+            panic!(
+                "Could not read the goldens in {}, run with UPDATE_GOLDENS=1 to create them: {e}",
+                dir.display()
+            )
+        })
+        .map(|entry| {
+            let path = entry.unwrap().path();
+            let key = path.file_stem().unwrap().to_str().unwrap().to_string();
+            (key, std::fs::read_to_string(&path).unwrap())
+        })
+        .collect()
+}
+
+fn write_goldens(dir: &Path, output: &BTreeMap<String, String>) {
+    if dir.exists() {
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+    std::fs::create_dir_all(dir).unwrap();
+    for (key, text) in output {
+        std::fs::write(dir.join(format!("{key}.json")), text).unwrap();
+    }
+}
+
+/// Panics naming the first key or line (1-based) where `output` differs from `goldens`
+fn assert_matches_goldens(output: &BTreeMap<String, String>, goldens: &BTreeMap<String, String>) {
+    let hint = "run with UPDATE_GOLDENS=1 if the change is intended";
+    for key in goldens.keys() {
+        assert!(
+            output.contains_key(key),
+            "`{key}.json.gz` wasn't written, but has a golden ({hint})"
+        );
+    }
+    for (key, text) in output {
+        let golden = goldens
+            .get(key)
+            .unwrap_or_else(|| panic!("`{key}.json.gz` was written, but has no golden ({hint})"));
+        // Split on newlines only, so that a missing or extra newline (or `\r`) is a difference too
+        let lines = text.split('\n').collect::<Vec<_>>();
+        let golden_lines = golden.split('\n').collect::<Vec<_>>();
+        for i in 0..lines.len().max(golden_lines.len()) {
+            let (got, expected) = (lines.get(i), golden_lines.get(i));
+            assert!(
+                got == expected,
+                "`{key}.json.gz` differs from its golden at line {} ({hint}):\n  expected: {expected:?}\n       got: {got:?}",
+                i + 1
+            );
+        }
+    }
+}
+
+#[test]
+fn test_goldens() {
+    let tmp = TempDir::new("logsplitter2_golden").unwrap();
+    let input = tmp.path().join("input.json.gz");
+    let mut cfg = TestdataCfg {
+        lines: 150,
+        gzip_members: 2,
+        seed: Some(20241020),
+        ..Default::default()
+    };
+    cfg.set_unique_dates(2)
+        .set_services(4, 3..6)
+        .set_envs(2, 3..6)
+        .set_users(10, 5..15);
+    generate_testdata(cfg, &mut File::create(&input).unwrap(), None).unwrap();
+
+    let out = tmp.path().join("out");
+    run(RunCfg {
+        input_files: vec![input],
+        output: OutputTarget::Dir(out.clone()),
+        output_threads: Threads::Fixed(2),
+        existing_files: Some(ExistingFilePolicy::Truncate),
+        ..Default::default()
+    })
+    .unwrap();
+    let output = read_output(&out);
+    assert!(!output.is_empty());
+
+    let dir = goldens_dir();
+    if std::env::var_os("UPDATE_GOLDENS").is_some_and(|v| v == "1") {
+        write_goldens(&dir, &output);
+        return;
+    }
+    assert_matches_goldens(&output, &read_goldens(&dir));
+}
@@ -0,0 +1,110 @@
+//! Splitting generated input and reading every output file back, to check that no line is lost, duplicated,
+//! misplaced, or reordered, whatever the number of output threads and however often files are evicted
+
+mod common;
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+};
+
+use logsplitter2::{
+    data::default_key,
+    file_pool::ExistingFilePolicy,
+    run,
+    testdata_gen::{generate_testdata, TestdataCfg},
+    OutputTarget, RunCfg, Threads,
+};
+use tempdir::TempDir;
+
+use common::read_output;
+
+/// The key of a line, by the name its output file is stemmed with
+fn key_of(line: &str) -> String {
+    let info = json::parse(line).unwrap();
+    default_key(&info).unwrap().name().to_string()
+}
+
+/// How many times each line appears in `lines`
+fn counts<'a>(lines: impl Iterator<Item = &'a String>) -> HashMap<&'a String, usize> {
+    let mut counts = HashMap::new();
+    for line in lines {
+        *counts.entry(line).or_default() += 1;
+    }
+    counts
+}
+
+#[test]
+fn test_round_trip() {
+    let tmp = TempDir::new("logsplitter2_round_trip").unwrap();
+    let input = tmp.path().join("input.json.gz");
+    let mut cfg = TestdataCfg {
+        lines: 4_000,
+        gzip_members: 3,
+        seed: Some(7),
+        ..Default::default()
+    };
+    // Around 200 keys, which is far more than either pool keeps open
+    cfg.set_unique_dates(5)
+        .set_services(20, 3..6)
+        .set_envs(2, 3..6);
+    let mut plain = vec![];
+    let stats =
+        generate_testdata(cfg, &mut File::create(&input).unwrap(), Some(&mut plain)).unwrap();
+    let input_lines = String::from_utf8(plain)
+        .unwrap()
+        .lines()
+        .map(String::from)
+        .collect::<Vec<_>>();
+    assert_eq!(stats.keyless, 0);
+
+    // The input's lines of each key, in order
+    let mut expected = BTreeMap::<String, Vec<String>>::new();
+    for line in &input_lines {
+        expected.entry(key_of(line)).or_default().push(line.clone());
+    }
+    assert!(expected.len() > 100);
+    for (key, lines) in &expected {
+        assert_eq!(stats.key_lines[key], lines.len() as u64, "{key}");
+    }
+
+    for threads in [1, 2, 8] {
+        for max_active_files in [2, 64] {
+            let case = format!("{threads} threads, {max_active_files} open files");
+            let out = tmp.path().join(format!("out_{threads}_{max_active_files}"));
+            run(RunCfg {
+                input_files: vec![input.clone()],
+                output: OutputTarget::Dir(out.clone()),
+                output_threads: Threads::Fixed(threads),
+                max_active_files,
+                existing_files: Some(ExistingFilePolicy::Truncate),
+                ..Default::default()
+            })
+            .unwrap();
+            let output = read_output(&out)
+                .into_iter()
+                .map(|(key, text)| (key, text.lines().map(String::from).collect::<Vec<_>>()))
+                .collect::<BTreeMap<_, _>>();
+
+            // Every output line was in the input exactly as many times, and every input line was written exactly once
+            assert_eq!(
+                counts(output.values().flatten()),
+                counts(input_lines.iter()),
+                "{case}"
+            );
+            for (key, lines) in &output {
+                // Each file only has lines of its own key
+                for line in lines {
+                    assert_eq!(&key_of(line), key, "{case}: {line}");
+                }
+                // In the order they were read
+                assert_eq!(lines, &expected[key], "{case}: {key}");
+            }
+            assert_eq!(
+                output.keys().collect::<Vec<_>>(),
+                expected.keys().collect::<Vec<_>>(),
+                "{case}"
+            );
+        }
+    }
+}